@@ -0,0 +1,68 @@
+//! A message for a 100 occupant room, sealed the way each session used to do it, serialized
+//! again for every recipient, against `prepare_broadcast`, serialized once and encrypted per
+//! recipient.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use marain_server::{
+    domain::{chat_log::MessageLog, crypto::SessionKey, protocol::PROTOCOL_VERSION, user::User},
+    services::{
+        frame_metrics::{frame_metrics, BodyKind},
+        message_builder::{ServerMsgFactory, SocketSendAdaptor},
+    },
+};
+
+const OCCUPANTS: u8 = 100;
+
+fn recipients() -> Vec<(String, SessionKey)> {
+    (0..OCCUPANTS)
+        .map(|n| (format!("user-{n}"), SessionKey::from_bytes([n; 32])))
+        .collect()
+}
+
+fn message() -> MessageLog {
+    let sender = User::new("sender".into(), "sender".into(), SessionKey::default());
+    MessageLog::from_user(&sender, "a".repeat(200))
+}
+
+fn per_recipient(msg: &MessageLog, recipients: &[(String, SessionKey)]) {
+    for (_, key) in recipients {
+        black_box(
+            SocketSendAdaptor::prepare_send_msg_log(msg.clone(), key, PROTOCOL_VERSION).unwrap(),
+        );
+    }
+}
+
+fn once_for_all(msg: &MessageLog, recipients: &[(String, SessionKey)]) {
+    let server_msg = ServerMsgFactory::build_msg_log_server_msg(msg.clone(), PROTOCOL_VERSION);
+    black_box(SocketSendAdaptor::prepare_broadcast(&server_msg, recipients).unwrap());
+}
+
+/// How many times one fan out to the room serializes the message.
+fn serializations(fan_out: impl Fn()) -> u64 {
+    let serialized = &frame_metrics().body(BodyKind::ChatRecv).serialized;
+    let before = serialized.count();
+    fan_out();
+    serialized.count() - before
+}
+
+fn broadcast(c: &mut Criterion) {
+    let msg = message();
+    let recipients = recipients();
+    println!(
+        "serializations per fan out to {OCCUPANTS} occupants: per recipient {}, prepare_broadcast {}",
+        serializations(|| per_recipient(&msg, &recipients)),
+        serializations(|| once_for_all(&msg, &recipients)),
+    );
+
+    let mut group = c.benchmark_group("room fan out");
+    group.bench_function("serialize per recipient", |b| {
+        b.iter(|| per_recipient(&msg, &recipients))
+    });
+    group.bench_function("prepare_broadcast", |b| {
+        b.iter(|| once_for_all(&msg, &recipients))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, broadcast);
+criterion_main!(benches);
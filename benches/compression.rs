@@ -0,0 +1,81 @@
+//! A 500 message RoomData sealed as it is and deflated, for the frame size each comes to and
+//! what deflating costs.
+
+use chrono::Utc;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use marain_api::prelude::Timestamp;
+use marain_server::{
+    domain::{
+        chat_log::{HistoryQuery, MessageLog},
+        crypto::SessionKey,
+        events::RoomSnapshot,
+        protocol::PROTOCOL_VERSION,
+        room::Room,
+        user::User,
+    },
+    services::{
+        compression::{Compression, DEFAULT_COMPRESSION_THRESHOLD},
+        message_builder::{Delivery, SocketSendAdaptor},
+    },
+};
+
+const MESSAGES: usize = 500;
+
+fn snapshot() -> RoomSnapshot {
+    let sender = User::new("sender".into(), "sender".into(), SessionKey::default());
+    RoomSnapshot {
+        msg_log: (0..MESSAGES)
+            .map(|n| MessageLog::from_user(&sender, format!("message {n}, {}", "chat ".repeat(10))))
+            .collect(),
+        occupant_names: (0..20).map(|n| format!("user-{n}")).collect(),
+        ..Default::default()
+    }
+}
+
+/// The room's history in as many frames as it takes, their sizes added up.
+fn room_data_bytes(snapshot: &RoomSnapshot, compression: Compression) -> usize {
+    let delivery = Delivery {
+        compression,
+        protocol_version: PROTOCOL_VERSION,
+        max_frame_bytes: usize::MAX,
+    };
+    SocketSendAdaptor::room_data_response(
+        &SessionKey::from_bytes([1; 32]),
+        &Room::lobby(),
+        snapshot.clone(),
+        &HistoryQuery {
+            before: None,
+            limit: MESSAGES,
+        },
+        delivery,
+        Timestamp::from(Utc::now()),
+    )
+    .unwrap()
+    .iter()
+    .map(|frame| frame.len())
+    .sum()
+}
+
+fn compression(c: &mut Criterion) {
+    let snapshot = snapshot();
+    let deflate = Compression::Deflate {
+        threshold: DEFAULT_COMPRESSION_THRESHOLD,
+    };
+    println!(
+        "{MESSAGES} message RoomData frame: {} bytes as it is, {} bytes deflated",
+        room_data_bytes(&snapshot, Compression::Off),
+        room_data_bytes(&snapshot, deflate),
+    );
+
+    let mut group = c.benchmark_group("room data");
+    group.bench_function("uncompressed", |b| {
+        b.iter(|| black_box(room_data_bytes(&snapshot, Compression::Off)))
+    });
+    group.bench_function("deflate", |b| {
+        b.iter(|| black_box(room_data_bytes(&snapshot, deflate)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, compression);
+criterion_main!(benches);
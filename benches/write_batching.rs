@@ -0,0 +1,87 @@
+//! A burst of room traffic fanned out to 200 loopback connections, each written by an
+//! `OutboundWriter` that batches up to `DEFAULT_MAX_BATCH` frames a flush, against one that
+//! flushes every frame the way the sessions used to write. The time is until every client
+//! has read the whole burst. For the writes themselves run it under
+//! `strace -f -c -e trace=write,sendto`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::StreamExt;
+use marain_server::services::outbound::{OutboundWriter, DEFAULT_FLUSH_MILLIS, DEFAULT_MAX_BATCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio_tungstenite::{
+    tungstenite::{protocol::Role, Message},
+    WebSocketStream,
+};
+
+const OCCUPANTS: usize = 200;
+const BURST: usize = 100;
+/// About what a sealed chat message comes to.
+const FRAME_BYTES: usize = 300;
+
+struct Room {
+    writers: Vec<UnboundedSender<Message>>,
+    clients: Vec<WebSocketStream<TcpStream>>,
+}
+
+async fn room(max_batch: usize) -> Room {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut writers = Vec::with_capacity(OCCUPANTS);
+    let mut clients = Vec::with_capacity(OCCUPANTS);
+    for n in 0..OCCUPANTS {
+        let client = TcpStream::connect(address).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (socket, _) = WebSocketStream::from_raw_socket(server, Role::Server, None)
+            .await
+            .split();
+        writers.push(OutboundWriter::spawn(
+            socket,
+            format!("user-{n}"),
+            max_batch,
+            DEFAULT_FLUSH_MILLIS,
+        ));
+        clients.push(WebSocketStream::from_raw_socket(client, Role::Client, None).await);
+    }
+    Room { writers, clients }
+}
+
+async fn storm(room: &mut Room) {
+    let frame = Message::Binary(vec![7; FRAME_BYTES]);
+    for _ in 0..BURST {
+        for writer in &room.writers {
+            writer.unbounded_send(frame.clone()).unwrap();
+        }
+    }
+    for client in &mut room.clients {
+        for _ in 0..BURST {
+            client.next().await.unwrap().unwrap();
+        }
+    }
+}
+
+fn write_batching(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut batched = runtime.block_on(room(DEFAULT_MAX_BATCH));
+    let mut unbatched = runtime.block_on(room(1));
+
+    let mut group = c.benchmark_group("broadcast storm");
+    group.sample_size(20);
+    group.bench_function("flush every frame", |b| {
+        b.iter(|| runtime.block_on(storm(&mut unbatched)))
+    });
+    group.bench_function("batched writes", |b| {
+        b.iter(|| runtime.block_on(storm(&mut batched)))
+    });
+    group.finish();
+
+    runtime.block_on(async {
+        for client in batched.clients.iter_mut().chain(&mut unbatched.clients) {
+            client.close(None).await.unwrap_or(());
+        }
+    });
+}
+
+criterion_group!(benches, write_batching);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+#![no_main]
+
+//! Whatever arrives as a frame, reading it back has to end in a message or an error. The
+//! first 32 bytes are the key, the rest the frame.
+
+use libfuzzer_sys::fuzz_target;
+use marain_server::{domain::crypto::SessionKey, services::message_builder::SocketSendAdaptor};
+use tokio_tungstenite::tungstenite::Message;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((key, frame)) = data.split_first_chunk::<32>() else {
+        return;
+    };
+    let key = SessionKey::from_bytes(*key);
+    let _ = SocketSendAdaptor::read_server_msg(&key, Message::Binary(frame.to_vec()));
+});
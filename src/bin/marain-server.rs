@@ -1,13 +1,17 @@
 extern crate marain_server;
 
-use env_logger;
-use futures_channel::mpsc::unbounded;
+use anyhow::Result;
+use chrono::Utc;
 use marain_server::{
-    domain::commands::Command,
-    services::login::{create_key_pair, setup_listener, spawn_user_session},
+    domain::{audit_log::AuditLog, commands::Command, server_info::ServerInfo},
+    services::{
+        bounded_channel::{bounded, DropCounter, Tracked},
+        login::{create_key_pair, getenv, setup_listener, spawn_user_session},
+        server_config::ServerConfig,
+    },
     workers::{app::App, app_gateway::AppGateway},
 };
-use tokio_tungstenite::tungstenite::Result;
+use tokio::sync::watch;
 use x25519_dalek::{PublicKey, ReusableSecret};
 #[macro_use]
 extern crate lazy_static;
@@ -21,30 +25,65 @@ lazy_static! {
 #[tokio::main]
 async fn main() -> Result<()> {
     let _ = env_logger::try_init();
-    let (app_sink, gateway_source) = unbounded::<Command>();
-    let (session_sink, session_worker_source) = unbounded::<Command>();
+    let server_info = ServerInfo::new(Utc::now(), getenv("MARAIN_MOTD"));
+    let config = ServerConfig::from_env();
+    if let Err(e) = config.validate() {
+        log::error!("{e}");
+        std::process::exit(1);
+    }
+    let queue_capacity = config.command_queue;
+    // Only the sessions drop commands, the gateway waits for the App.
+    let (app_sink, gateway_source) = bounded::<Command>(queue_capacity, DropCounter::new());
+    let (session_sink, session_worker_source) =
+        bounded::<Tracked<Command>>(queue_capacity, server_info.dropped_commands.clone());
     let app_gateway = AppGateway::init(app_sink, session_worker_source);
 
-    let app = App::init(gateway_source);
-    app.run();
+    let (shutdown_signal, mut shutdown) = watch::channel(false);
+
+    let audit_log = match getenv("MARAIN_AUDIT_LOG") {
+        path if path.is_empty() => AuditLog::new(),
+        path => AuditLog::with_file(path),
+    };
+
+    let mut app = App::init(gateway_source, server_info, shutdown_signal)
+        .with_config(&config)
+        .with_audit_log(audit_log);
+    // Room history is only kept in memory, this is where it goes when the server stops.
+    let transcript_dir = getenv("MARAIN_TRANSCRIPT_DIR");
+    if !transcript_dir.is_empty() {
+        app = app.with_transcript_dir(transcript_dir);
+    }
+    let app_handle = app.run();
     app_gateway.run();
-    let listener = setup_listener().await;
+    let listener = setup_listener(config.port).await;
     // Create the event loop and TCP listener we'll accept connections on.
-    while let Ok((stream, _)) = listener.accept().await {
-        match spawn_user_session(
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => {
+                match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(_) => break,
+                }
+            }
+            _ = shutdown.changed() => {
+                log::info!("Shutting down, no longer accepting connections.");
+                break;
+            }
+        };
+        if let Err(e) = spawn_user_session(
             stream,
             session_sink.clone(),
             (SECRET_KEY.clone(), *PUBLIC_KEY),
+            &config,
         )
         .await
         {
-            Err(e) => {
-                log::error!("Could not spawn user_session due to error: {e}");
-                continue;
-            }
-            _ => {}
+            log::error!("Could not spawn user_session due to error: {e}");
+            continue;
         };
     }
 
+    // The App finishes once every session has been closed.
+    let _ = app_handle.await;
     Ok(())
 }
@@ -0,0 +1,114 @@
+use std::fmt;
+
+/// A chat message carrying an attachment goes out with this line first, ahead of any text.
+/// Like emotes, plain chat can never start with it because the slash makes it a command.
+pub const ATTACHMENT_PREFIX: &str = "/attachment ";
+
+pub const DEFAULT_MAX_ATTACHMENT_BYTES: u64 = 25 * 1024 * 1024;
+pub const MAX_FILENAME_BYTES: usize = 255;
+pub const MAX_MIME_TYPE_BYTES: usize = 127;
+/// The content hash is a hex encoded SHA-256.
+pub const HASH_HEX_LEN: usize = 64;
+
+/// Describes a file sent alongside a chat message. The server only checks and passes on the
+/// description, the bytes themselves never come through the chat.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub filename: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachmentError {
+    TooLarge {
+        size: u64,
+        max: u64,
+    },
+    InvalidFilename,
+    InvalidMimeType,
+    InvalidHash,
+    /// Only chat can carry an attachment, not commands.
+    NotChat,
+}
+
+impl fmt::Display for AttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttachmentError::TooLarge { size, max } => write!(
+                f,
+                "Attachments cannot be larger than {max} bytes, this one is {size}"
+            ),
+            AttachmentError::InvalidFilename => write!(f, "The attachment's filename is not valid"),
+            AttachmentError::InvalidMimeType => {
+                write!(f, "The attachment's mime type is not valid")
+            }
+            AttachmentError::InvalidHash => {
+                write!(f, "The attachment's hash must be a hex encoded SHA-256")
+            }
+            AttachmentError::NotChat => write!(f, "Only chat messages can carry an attachment"),
+        }
+    }
+}
+
+impl std::error::Error for AttachmentError {}
+
+impl Attachment {
+    pub fn validate(&self, max_bytes: u64) -> Result<(), AttachmentError> {
+        if self.size > max_bytes {
+            return Err(AttachmentError::TooLarge {
+                size: self.size,
+                max: max_bytes,
+            });
+        }
+        if !valid_filename(&self.filename) {
+            return Err(AttachmentError::InvalidFilename);
+        }
+        if !valid_mime_type(&self.mime_type) {
+            return Err(AttachmentError::InvalidMimeType);
+        }
+        if self.hash.len() != HASH_HEX_LEN || !self.hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(AttachmentError::InvalidHash);
+        }
+        Ok(())
+    }
+
+    /// `/attachment <size> <mime type> <hash> <filename>`, the filename last since it may
+    /// have spaces in it.
+    pub fn wire_line(&self) -> String {
+        format!(
+            "{ATTACHMENT_PREFIX}{} {} {} {}",
+            self.size,
+            self.mime_type,
+            self.hash.to_lowercase(),
+            self.filename
+        )
+    }
+}
+
+/// A bare name, no paths, nothing hidden and nothing that would break the wire line.
+fn valid_filename(filename: &str) -> bool {
+    !filename.trim().is_empty()
+        && filename.len() <= MAX_FILENAME_BYTES
+        && filename != "."
+        && filename != ".."
+        && !filename.starts_with(char::is_whitespace)
+        && !filename.ends_with(char::is_whitespace)
+        && !filename
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c.is_control())
+}
+
+/// `type/subtype` made of RFC 6838 name characters, parameters aren't allowed.
+fn valid_mime_type(mime_type: &str) -> bool {
+    let is_name = |part: &str| {
+        !part.is_empty()
+            && part.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+    mime_type.len() <= MAX_MIME_TYPE_BYTES
+        && matches!(mime_type.split_once('/'), Some((kind, subtype)) if is_name(kind) && is_name(subtype))
+}
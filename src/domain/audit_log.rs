@@ -0,0 +1,137 @@
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use chrono::{DateTime, Utc};
+
+use super::user::User;
+
+pub const MAX_AUDIT_ENTRIES: usize = 500;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Done,
+    /// The command was refused by its handler, with the reason given to the user.
+    Rejected(String),
+    /// The user didn't have the role the command needs.
+    Denied,
+}
+
+/// One moderation or admin command, whether or not it went through.
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub actor_id: String,
+    pub actor_name: String,
+    pub command: &'static str,
+    pub target: Option<String>,
+    pub arguments: String,
+    pub outcome: AuditOutcome,
+}
+
+impl AuditEntry {
+    pub fn new(
+        actor: &User,
+        command: &'static str,
+        target: Option<String>,
+        arguments: String,
+        outcome: AuditOutcome,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            actor_id: actor.id.clone(),
+            actor_name: actor.name.clone(),
+            command,
+            target,
+            arguments,
+            outcome,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let outcome = match &self.outcome {
+            AuditOutcome::Done => "done".to_string(),
+            AuditOutcome::Rejected(reason) => format!("rejected: {reason}"),
+            AuditOutcome::Denied => "denied".to_string(),
+        };
+        format!(
+            "[{}] {} ({}) /{}{} {} -> {outcome}",
+            self.timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
+            self.actor_name,
+            self.actor_id,
+            self.command,
+            self.target
+                .as_ref()
+                .map(|target| format!(" {target}"))
+                .unwrap_or_default(),
+            self.arguments,
+        )
+    }
+}
+
+/// The newest audit entries in memory, and optionally every entry appended to a file. Writes
+/// to the file happen on their own thread so recording an entry never blocks on disk.
+pub struct AuditLog {
+    entries: VecDeque<AuditEntry>,
+    file_writer: Option<Sender<String>>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            file_writer: None,
+        }
+    }
+
+    pub fn with_file(path: String) -> Self {
+        let (sender, receiver) = channel::<String>();
+        thread::spawn(move || {
+            let mut file = match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    log::error!("Could not open audit log {path}: {e}");
+                    return;
+                }
+            };
+            for line in receiver {
+                if let Err(e) = writeln!(file, "{line}") {
+                    log::error!("Could not write to audit log {path}: {e}");
+                }
+            }
+        });
+
+        Self {
+            entries: VecDeque::new(),
+            file_writer: Some(sender),
+        }
+    }
+
+    pub fn record(&mut self, entry: AuditEntry) {
+        log::info!("audit: {}", entry.describe());
+        if let Some(writer) = &self.file_writer {
+            // A failed writer thread has already logged why.
+            let _ = writer.send(entry.describe());
+        }
+        self.entries.push_back(entry);
+        if self.entries.len() > MAX_AUDIT_ENTRIES {
+            self.entries.pop_front();
+        }
+    }
+
+    /// The newest `count` entries, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<&AuditEntry> {
+        self.entries
+            .iter()
+            .skip(self.entries.len().saturating_sub(count))
+            .collect()
+    }
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
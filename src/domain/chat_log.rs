@@ -3,27 +3,91 @@ use std::fmt::Display;
 use chrono::{DateTime, Utc};
 use marain_api::prelude::{ClientMsg, ClientMsgBody};
 
+use super::attachment::Attachment;
+use super::protocol::MESSAGE_IDS_VERSION;
 use super::user::User;
 
+/// Emotes go out as ordinary chat with this prefix, clients can render them differently.
+/// A plain chat message can never start with it because `/me` is always taken as a command.
+pub const ACTION_PREFIX: &str = "/me ";
+
+/// Room chat sent to clients that know about message ids leads with this line, there is no
+/// field for the id in ChatMsg.
+pub const MESSAGE_ID_PREFIX: &str = "/id ";
+
+/// Unique within a room, assigned when the message is recorded. They only ever go up, so
+/// they stay in order across every page of history.
+pub type MessageId = u64;
+
 #[derive(Debug, Clone)]
 pub struct MessageLog {
+    pub id: MessageId,
     pub username: String,
     pub timestamp: DateTime<Utc>,
     pub contents: String,
+    pub action: bool,
+    pub attachment: Option<Attachment>,
 }
 
 impl MessageLog {
     pub fn from_user(user: &User, text: String) -> Self {
         Self {
+            id: 0,
             username: user.name.clone(),
             timestamp: Utc::now(),
             contents: text,
+            action: false,
+            attachment: None,
+        }
+    }
+
+    pub fn action_from_user(user: &User, text: String) -> Self {
+        Self {
+            action: true,
+            ..Self::from_user(user, text)
+        }
+    }
+
+    pub fn with_attachment(mut self, attachment: Option<Attachment>) -> Self {
+        self.attachment = attachment;
+        self
+    }
+
+    /// The contents as sent to clients, with emotes marked and any attachment described on
+    /// a line of its own ahead of the text.
+    pub fn wire_contents(&self) -> String {
+        let text = if self.action {
+            format!("{ACTION_PREFIX}{}", self.contents)
+        } else {
+            self.contents.clone()
+        };
+        match &self.attachment {
+            Some(attachment) if text.is_empty() => attachment.wire_line(),
+            Some(attachment) => format!("{}\n{text}", attachment.wire_line()),
+            None => text,
         }
     }
 
+    /// What a client speaking `protocol_version` is sent as the message's contents. Messages
+    /// that were never recorded in a room have no id to send.
+    pub fn wire_contents_for(&self, protocol_version: u32) -> String {
+        if protocol_version < MESSAGE_IDS_VERSION || self.id == 0 {
+            return self.wire_contents();
+        }
+        format!("{MESSAGE_ID_PREFIX}{}\n{}", self.id, self.wire_contents())
+    }
+
+    pub fn find<'a>(
+        logs: impl IntoIterator<Item = &'a MessageLog>,
+        id: MessageId,
+    ) -> Option<&'a MessageLog> {
+        logs.into_iter().find(|msg| msg.id == id)
+    }
+
     pub fn from_client_msg(client_msg: ClientMsg, username: &str) -> Option<Self> {
         match client_msg.body {
             ClientMsgBody::SendToRoom { contents } => Some(MessageLog {
+                id: 0,
                 username: username.into(),
                 timestamp: match client_msg.timestamp.into() {
                     Some(ts) => ts,
@@ -31,6 +95,8 @@ impl MessageLog {
                     None => Utc::now(),
                 },
                 contents,
+                action: false,
+                attachment: None,
             }),
             _ => None,
         }
@@ -44,7 +110,69 @@ impl Display for MessageLog {
             "[ {} | {} ]: {}",
             self.username,
             self.timestamp.format("%H-%M-%S"),
-            self.contents
+            self.wire_contents()
         )
     }
 }
+
+/// How many of a room's newest messages a client is sent when it joins, older ones are
+/// fetched a page at a time with /history.
+pub const DEFAULT_ROOM_DATA_LIMIT: usize = 20;
+
+/// Which window of a room's history to send: the newest `limit` messages older than
+/// `before`, or the newest overall without a cursor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryQuery {
+    pub before: Option<DateTime<Utc>>,
+    pub limit: usize,
+}
+
+impl Default for HistoryQuery {
+    fn default() -> Self {
+        Self {
+            before: None,
+            limit: DEFAULT_ROOM_DATA_LIMIT,
+        }
+    }
+}
+
+impl HistoryQuery {
+    pub fn includes(&self, timestamp: DateTime<Utc>) -> bool {
+        self.before.is_none_or(|before| timestamp < before)
+    }
+
+    /// Takes the window out of `logs`, which are oldest first.
+    pub fn page<'a>(&self, logs: impl DoubleEndedIterator<Item = &'a MessageLog>) -> HistoryPage {
+        let mut older = logs.rev().filter(|msg| self.includes(msg.timestamp));
+        let mut page: Vec<MessageLog> = older.by_ref().take(self.limit).cloned().collect();
+        let has_more = older.next().is_some();
+        page.reverse();
+        let next_before = if has_more {
+            page.first().map(|msg| msg.timestamp)
+        } else {
+            None
+        };
+        HistoryPage {
+            logs: page,
+            has_more,
+            next_before,
+        }
+    }
+}
+
+/// One window of history, oldest first. `next_before` is the cursor for the page before
+/// this one and is only set while there is more to fetch.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryPage {
+    pub logs: Vec<MessageLog>,
+    pub has_more: bool,
+    pub next_before: Option<DateTime<Utc>>,
+}
+
+impl HistoryPage {
+    /// Whether something that happened at `timestamp` falls between this page and the
+    /// cursor it was fetched with.
+    pub fn spans(&self, query: &HistoryQuery, timestamp: DateTime<Utc>) -> bool {
+        query.includes(timestamp) && self.next_before.is_none_or(|start| timestamp >= start)
+    }
+}
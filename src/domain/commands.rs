@@ -1,20 +1,878 @@
+use chrono::{DateTime, Utc};
 use futures_channel::mpsc::UnboundedSender;
 use marain_api::prelude::Timestamp;
 
-use super::{events::Event, room::Room, user::User};
+use crate::services::command_parser::{Args, ParseError};
+
+use super::{
+    attachment::Attachment,
+    chat_log::MessageId,
+    connection_stats::ConnectionStats,
+    crypto::SessionKey,
+    events::Event,
+    room::Room,
+    transcript::ExportFormat,
+    user::{Role, User},
+};
+
+/// Sessions number the messages a client sends, starting from 1, so that replies can say
+/// which request they answer.
+pub type RequestId = u64;
 
 #[derive(Debug, Clone)]
 pub struct Command {
     pub user: User,
     pub payload: CommandPayload,
+    pub request_id: Option<RequestId>,
 }
 
 #[derive(Debug, Clone)]
 pub enum CommandPayload {
     RegisterUser(UnboundedSender<Event>),
+    /// A reconnecting client asking for the detached session its login token belonged to.
+    ResumeUser {
+        token: String,
+        channel: UnboundedSender<Event>,
+    },
     DropUser,
-    MoveUser { target_room: Room },
-    RecordMessage { message: String },
+    /// The session logged in again on the same connection and was given a new token and key.
+    Reauthenticate {
+        token: String,
+        key: SessionKey,
+    },
+    /// The session's latest counts of what its connection has sent.
+    ReportStats(ConnectionStats),
+    /// The connection was lost, the user is kept in their room for a while in case they come
+    /// back.
+    DetachUser,
+    /// The session warned its client about being quiet, an online user is marked away.
+    WentIdle,
+    MoveUser {
+        target_room: Room,
+    },
+    Leave,
+    CreateRoom(String),
+    DeleteRoom(String),
+    SetPrivate(bool),
+    LockRoom,
+    UnlockRoom,
+    Invite(String),
+    Uninvite(String),
+    RecordMessage {
+        message: String,
+        attachment: Option<Attachment>,
+        /// The client's own id for the message, echoed in its ack or nack.
+        msg_id: Option<String>,
+    },
+    Action(String),
+    Roll {
+        spec: String,
+    },
     GetRecipients,
-    Time(Timestamp),
+    Who,
+    ClearNotifications,
+    Export {
+        format: ExportFormat,
+    },
+    Search {
+        query: String,
+        limit: usize,
+    },
+    History {
+        limit: usize,
+        before: Option<Timestamp>,
+    },
+    CurrentRoom,
+    RoomStats,
+    /// The sizes of everything the server has sent, by kind of message.
+    ServerStats,
+    WhoAmI,
+    WhoIs(String),
+    Audit(usize),
+    Seen(String),
+    Uptime,
+    Shutdown {
+        delay_secs: u64,
+    },
+    CancelShutdown,
+    Motd,
+    Announce(String),
+    SetMotd(String),
+    Help(Option<String>),
+    Capabilities,
+    Rename(String),
+    ListRooms,
+    Away(Option<String>),
+    Back,
+    Ignore(String),
+    Unignore(String),
+    Block(String),
+    Unblock(String),
+    ListBlocks,
+    DirectMessage {
+        to: String,
+        content: String,
+    },
+    Promote {
+        user: String,
+        role: Role,
+    },
+    Demote {
+        user: String,
+        role: Role,
+    },
+    Kick(String),
+    Ban(String),
+    Unban(String),
+    ListBans,
+    Mute {
+        user: String,
+        duration_secs: Option<u64>,
+    },
+    Unmute(String),
+    /// Mutes the user in every room.
+    Silence {
+        user: String,
+        duration_secs: Option<u64>,
+    },
+    ShadowBan(String),
+    /// Lifts a silence or shadow ban.
+    Pardon(String),
+    SetTopic(String),
+    Pin(MessageId),
+    Unpin(MessageId),
+    Purge(usize),
+    SlowMode(u64),
+    /// `client_ts` is when the client says it asked, `server_ts` when the session read it.
+    Time {
+        client_ts: Option<Timestamp>,
+        server_ts: Timestamp,
+    },
+    Ping {
+        client_ts: Option<Timestamp>,
+        received_at: Timestamp,
+    },
+}
+
+impl CommandPayload {
+    /// The registry name of the command that produces this payload, other payloads get a
+    /// name of their own. Plugins are registered under these names.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandPayload::RegisterUser(_) => "register",
+            CommandPayload::ResumeUser { .. } => "resume",
+            CommandPayload::DropUser => "drop",
+            CommandPayload::Reauthenticate { .. } => "reauth",
+            CommandPayload::ReportStats(_) => "report-stats",
+            CommandPayload::DetachUser => "detach",
+            CommandPayload::WentIdle => "idle",
+            CommandPayload::MoveUser { .. } => "mv",
+            CommandPayload::Leave => "leave",
+            CommandPayload::CreateRoom(_) => "create",
+            CommandPayload::DeleteRoom(_) => "delete-room",
+            CommandPayload::SetPrivate(_) => "private",
+            CommandPayload::LockRoom => "lock",
+            CommandPayload::UnlockRoom => "unlock",
+            CommandPayload::Invite(_) => "invite",
+            CommandPayload::Uninvite(_) => "uninvite",
+            CommandPayload::RecordMessage { .. } => "message",
+            CommandPayload::Action(_) => "me",
+            CommandPayload::Roll { .. } => "roll",
+            CommandPayload::GetRecipients => "recipients",
+            CommandPayload::Who => "who",
+            CommandPayload::ClearNotifications => "clear",
+            CommandPayload::Export { .. } => "export",
+            CommandPayload::Search { .. } => "search",
+            CommandPayload::History { .. } => "history",
+            CommandPayload::CurrentRoom => "crm",
+            CommandPayload::RoomStats => "stats",
+            CommandPayload::ServerStats => "stats",
+            CommandPayload::WhoAmI => "whoami",
+            CommandPayload::WhoIs(_) => "whois",
+            CommandPayload::Audit(_) => "audit",
+            CommandPayload::Seen(_) => "seen",
+            CommandPayload::Uptime => "uptime",
+            CommandPayload::Shutdown { .. } => "shutdown",
+            CommandPayload::CancelShutdown => "shutdown",
+            CommandPayload::Motd => "motd",
+            CommandPayload::Announce(_) => "announce",
+            CommandPayload::SetMotd(_) => "setmotd",
+            CommandPayload::Help(_) => "help",
+            CommandPayload::Capabilities => "capabilities",
+            CommandPayload::Rename(_) => "nick",
+            CommandPayload::ListRooms => "rooms",
+            CommandPayload::Away(_) => "away",
+            CommandPayload::Back => "back",
+            CommandPayload::Ignore(_) => "ignore",
+            CommandPayload::Unignore(_) => "unignore",
+            CommandPayload::Block(_) => "block",
+            CommandPayload::Unblock(_) => "unblock",
+            CommandPayload::ListBlocks => "blocks",
+            CommandPayload::DirectMessage { .. } => "msg",
+            CommandPayload::Promote { .. } => "promote",
+            CommandPayload::Demote { .. } => "demote",
+            CommandPayload::Kick(_) => "kick",
+            CommandPayload::Ban(_) => "ban",
+            CommandPayload::Unban(_) => "unban",
+            CommandPayload::ListBans => "bans",
+            CommandPayload::Mute { .. } => "mute",
+            CommandPayload::Unmute(_) => "unmute",
+            CommandPayload::Silence { .. } => "silence",
+            CommandPayload::ShadowBan(_) => "shadowban",
+            CommandPayload::Pardon(_) => "pardon",
+            CommandPayload::SetTopic(_) => "topic",
+            CommandPayload::Pin(_) => "pin",
+            CommandPayload::Unpin(_) => "unpin",
+            CommandPayload::Purge(_) => "purge",
+            CommandPayload::SlowMode(_) => "slowmode",
+            CommandPayload::Time { .. } => "time",
+            CommandPayload::Ping { .. } => "ping",
+        }
+    }
+
+    /// Chat has slow mode instead, and session bookkeeping must always get through.
+    pub fn is_rate_limited(&self) -> bool {
+        !matches!(
+            self,
+            CommandPayload::RegisterUser(_)
+                | CommandPayload::ResumeUser { .. }
+                | CommandPayload::DropUser
+                | CommandPayload::DetachUser
+                | CommandPayload::WentIdle
+                | CommandPayload::Reauthenticate { .. }
+                | CommandPayload::ReportStats(_)
+                | CommandPayload::RecordMessage { .. }
+                | CommandPayload::Action(_)
+                | CommandPayload::Ping { .. }
+        )
+    }
+
+    /// Moderation and admin commands that change something go in the audit log.
+    pub fn is_audited(&self) -> bool {
+        self.required_role() > Role::Member
+            && !matches!(
+                self,
+                CommandPayload::WhoIs(_)
+                    | CommandPayload::ListBans
+                    | CommandPayload::Audit(_)
+                    | CommandPayload::ServerStats
+            )
+    }
+
+    /// The user or room a command acts on, for the audit log.
+    pub fn target(&self) -> Option<String> {
+        match self {
+            CommandPayload::DeleteRoom(target)
+            | CommandPayload::Kick(target)
+            | CommandPayload::Ban(target)
+            | CommandPayload::Unban(target)
+            | CommandPayload::Unmute(target)
+            | CommandPayload::ShadowBan(target)
+            | CommandPayload::Pardon(target)
+            | CommandPayload::Silence { user: target, .. }
+            | CommandPayload::Promote { user: target, .. }
+            | CommandPayload::Demote { user: target, .. }
+            | CommandPayload::Mute { user: target, .. } => Some(target.clone()),
+            _ => None,
+        }
+    }
+
+    /// The lowest role allowed to issue this command, as declared in the registry. `/stats
+    /// server` is for admins though `/stats` isn't.
+    pub fn required_role(&self) -> Role {
+        if let CommandPayload::ServerStats = self {
+            return Role::Admin;
+        }
+        CommandRegistry::get(self.name())
+            .map(|spec| spec.role)
+            .unwrap_or(Role::Member)
+    }
+}
+
+/// Describes a text command that users can type into the chat box.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub args: &'static str,
+    /// The lowest role allowed to use the command.
+    pub role: Role,
+    pub description: &'static str,
+    pub parse: fn(&mut Args) -> Result<CommandPayload, ParseError>,
+}
+
+const DEFAULT_HISTORY_LIMIT: usize = 25;
+const DEFAULT_SEARCH_LIMIT: usize = 10;
+const DEFAULT_SHUTDOWN_DELAY_SECS: u64 = 30;
+const DEFAULT_AUDIT_LIMIT: usize = 20;
+
+/// The optional role after the user in /promote and /demote.
+fn parse_role(args: &mut Args, default: Role) -> Result<Role, ParseError> {
+    match args.word()? {
+        None => Ok(default),
+        Some(role) => Role::parse(&role).ok_or_else(|| args.invalid("role", role)),
+    }
+}
+
+impl CommandSpec {
+    pub fn usage(&self) -> String {
+        match self.args {
+            "" => format!("/{}", self.name),
+            args => format!("/{} {}", self.name, args),
+        }
+    }
+}
+
+/// Shorthand that expands into an ordinary chat message, trailing text goes before it.
+pub struct AliasSpec {
+    pub name: &'static str,
+    pub expansion: &'static str,
+}
+
+impl AliasSpec {
+    pub fn usage(&self) -> String {
+        format!("/{} [text]", self.name)
+    }
+
+    pub fn expand(&self, args: &str) -> String {
+        match args {
+            "" => self.expansion.to_string(),
+            text => format!("{text} {}", self.expansion),
+        }
+    }
+}
+
+/// The registry is the only place text commands are declared, both the session parser
+/// and /help read from it.
+pub struct CommandRegistry;
+
+impl CommandRegistry {
+    const COMMANDS: &'static [CommandSpec] = &[
+        CommandSpec {
+            name: "mv",
+            args: "<room_name>",
+            role: Role::Member,
+            description: "Move to another room, creating it if it does not exist.",
+            parse: |args| {
+                Ok(CommandPayload::MoveUser {
+                    target_room: Room::from(args.required_rest("room name")?.as_str()),
+                })
+            },
+        },
+        CommandSpec {
+            name: "create",
+            args: "<room_name>",
+            role: Role::Member,
+            description: "Create a new room that you own and move into it.",
+            parse: |args| Ok(CommandPayload::CreateRoom(args.required_rest("room name")?)),
+        },
+        CommandSpec {
+            name: "delete-room",
+            args: "<room_name>",
+            role: Role::Admin,
+            description: "Admins only. Delete a room, moving its occupants to the lobby.",
+            parse: |args| Ok(CommandPayload::DeleteRoom(args.required_rest("room name")?)),
+        },
+        CommandSpec {
+            name: "private",
+            args: "[on|off]",
+            role: Role::Member,
+            description: "Room owners only. Make your room invite only, or public again.",
+            parse: |args| match args.word()?.as_deref() {
+                None | Some("on") => Ok(CommandPayload::SetPrivate(true)),
+                Some("off") => Ok(CommandPayload::SetPrivate(false)),
+                Some(other) => Err(args.invalid("setting", other)),
+            },
+        },
+        CommandSpec {
+            name: "lock",
+            args: "",
+            role: Role::Moderator,
+            description: "Owner or moderators only. Stop anyone new joining your room.",
+            parse: |_| Ok(CommandPayload::LockRoom),
+        },
+        CommandSpec {
+            name: "unlock",
+            args: "",
+            role: Role::Moderator,
+            description: "Owner or moderators only. Let people join your room again.",
+            parse: |_| Ok(CommandPayload::UnlockRoom),
+        },
+        CommandSpec {
+            name: "invite",
+            args: "<user>",
+            role: Role::Member,
+            description: "Room owners only. Let a user into your private room.",
+            parse: |args| Ok(CommandPayload::Invite(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "uninvite",
+            args: "<user>",
+            role: Role::Member,
+            description: "Room owners only. Revoke an invite to your private room.",
+            parse: |args| Ok(CommandPayload::Uninvite(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "leave",
+            args: "",
+            role: Role::Member,
+            description: "Leave your current room and go back to the lobby.",
+            parse: |_| Ok(CommandPayload::Leave),
+        },
+        CommandSpec {
+            name: "history",
+            args: "[count] [before]",
+            role: Role::Member,
+            description:
+                "Fetch earlier messages in this room, before is the cursor from the last page.",
+            parse: |args| {
+                let limit = args.parsed("count")?.unwrap_or(DEFAULT_HISTORY_LIMIT);
+                let before = match args.parsed::<i64>("before")? {
+                    None => None,
+                    Some(millis) => Some(Timestamp::from(
+                        DateTime::from_timestamp_millis(millis)
+                            .ok_or_else(|| args.invalid("before", millis.to_string()))?,
+                    )),
+                };
+                Ok(CommandPayload::History { limit, before })
+            },
+        },
+        CommandSpec {
+            name: "search",
+            args: "<text>",
+            role: Role::Member,
+            description: "Find recent messages in this room by text or sender, newest first.",
+            parse: |args| {
+                Ok(CommandPayload::Search {
+                    query: args.required_rest("search text")?,
+                    limit: DEFAULT_SEARCH_LIMIT,
+                })
+            },
+        },
+        CommandSpec {
+            name: "export",
+            args: "[text|json]",
+            role: Role::Member,
+            description: "Download this room's history and notifications as a transcript.",
+            parse: |args| {
+                let format = args.word()?.unwrap_or_default();
+                match ExportFormat::parse(&format) {
+                    Some(format) => Ok(CommandPayload::Export { format }),
+                    None => Err(args.invalid("format", format)),
+                }
+            },
+        },
+        CommandSpec {
+            name: "clear",
+            args: "",
+            role: Role::Member,
+            description: "Mark this room's notifications as read so they aren't shown again.",
+            parse: |_| Ok(CommandPayload::ClearNotifications),
+        },
+        CommandSpec {
+            name: "who",
+            args: "",
+            role: Role::Member,
+            description: "List the occupants of your current room.",
+            parse: |_| Ok(CommandPayload::Who),
+        },
+        CommandSpec {
+            name: "crm",
+            args: "",
+            role: Role::Member,
+            description: "Show the name and id of your current room.",
+            parse: |_| Ok(CommandPayload::CurrentRoom),
+        },
+        CommandSpec {
+            name: "stats",
+            args: "[server]",
+            role: Role::Member,
+            description: "Show activity statistics for the room you are in. Admins can give server to see how big the frames sent are.",
+            parse: |args| match args.word()?.as_deref() {
+                None => Ok(CommandPayload::RoomStats),
+                Some("server") => Ok(CommandPayload::ServerStats),
+                Some(scope) => Err(args.invalid("scope", scope)),
+            },
+        },
+        CommandSpec {
+            name: "whoami",
+            args: "",
+            role: Role::Member,
+            description: "Show the details of your session.",
+            parse: |_| Ok(CommandPayload::WhoAmI),
+        },
+        CommandSpec {
+            name: "whois",
+            args: "<user>",
+            role: Role::Admin,
+            description: "Admin only. Show session and connection details for a user.",
+            parse: |args| Ok(CommandPayload::WhoIs(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "audit",
+            args: "[count]",
+            role: Role::Admin,
+            description: "Admin only. Show the newest moderation and admin commands and how they went.",
+            parse: |args| {
+                Ok(CommandPayload::Audit(
+                    args.parsed("count")?.unwrap_or(DEFAULT_AUDIT_LIMIT),
+                ))
+            },
+        },
+        CommandSpec {
+            name: "seen",
+            args: "<user>",
+            role: Role::Member,
+            description: "Show when a user was last active and where.",
+            parse: |args| Ok(CommandPayload::Seen(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "uptime",
+            args: "",
+            role: Role::Member,
+            description: "Show how long the server has been running and how busy it is.",
+            parse: |_| Ok(CommandPayload::Uptime),
+        },
+        CommandSpec {
+            name: "motd",
+            args: "",
+            role: Role::Member,
+            description: "Show the message of the day.",
+            parse: |_| Ok(CommandPayload::Motd),
+        },
+        CommandSpec {
+            name: "setmotd",
+            args: "[message]",
+            role: Role::Admin,
+            description: "Admin only. Set the message of the day, or clear it when empty.",
+            parse: |args| Ok(CommandPayload::SetMotd(args.rest()?)),
+        },
+        CommandSpec {
+            name: "announce",
+            args: "<message>",
+            role: Role::Admin,
+            description: "Admin only. Send a message from the server to every room.",
+            parse: |args| Ok(CommandPayload::Announce(args.required_rest("message")?)),
+        },
+        CommandSpec {
+            name: "shutdown",
+            args: "[seconds|cancel]",
+            role: Role::Admin,
+            description: "Admin only. Stop the server after a warning, or cancel a pending stop.",
+            parse: |args| match args.word()?.as_deref() {
+                Some("cancel") => Ok(CommandPayload::CancelShutdown),
+                None => Ok(CommandPayload::Shutdown {
+                    delay_secs: DEFAULT_SHUTDOWN_DELAY_SECS,
+                }),
+                Some(delay) => match delay.parse() {
+                    Ok(delay_secs) => Ok(CommandPayload::Shutdown { delay_secs }),
+                    Err(_) => Err(args.invalid("delay", delay)),
+                },
+            },
+        },
+        CommandSpec {
+            name: "rooms",
+            args: "",
+            role: Role::Member,
+            description: "List all rooms and how many people are in them.",
+            parse: |_| Ok(CommandPayload::ListRooms),
+        },
+        CommandSpec {
+            name: "me",
+            args: "<action>",
+            role: Role::Member,
+            description: "Describe what you are doing to the room, e.g. /me waves.",
+            parse: |args| Ok(CommandPayload::Action(args.required_rest("action")?)),
+        },
+        CommandSpec {
+            name: "roll",
+            args: "<NdM[+K]>",
+            role: Role::Member,
+            description: "Roll dice for the whole room to see, e.g. /roll 2d6.",
+            parse: |args| {
+                Ok(CommandPayload::Roll {
+                    spec: args.required("dice")?,
+                })
+            },
+        },
+        CommandSpec {
+            name: "msg",
+            args: "<user> <text>",
+            role: Role::Member,
+            description: "Send a private message to a connected user in any room.",
+            parse: |args| {
+                Ok(CommandPayload::DirectMessage {
+                    to: args.required("user")?,
+                    content: args.required_rest("message")?,
+                })
+            },
+        },
+        CommandSpec {
+            name: "away",
+            args: "[message]",
+            role: Role::Member,
+            description: "Mark yourself as away, chatting again marks you as back.",
+            parse: |args| {
+                Ok(CommandPayload::Away(
+                    Some(args.rest()?).filter(|message| !message.is_empty()),
+                ))
+            },
+        },
+        CommandSpec {
+            name: "back",
+            args: "",
+            role: Role::Member,
+            description: "Mark yourself as no longer away.",
+            parse: |_| Ok(CommandPayload::Back),
+        },
+        CommandSpec {
+            name: "ignore",
+            args: "[user]",
+            role: Role::Member,
+            description: "Stop receiving messages from a user, or list who you are ignoring.",
+            parse: |args| Ok(CommandPayload::Ignore(args.word()?.unwrap_or_default())),
+        },
+        CommandSpec {
+            name: "unignore",
+            args: "<user>",
+            role: Role::Member,
+            description: "Start receiving messages from an ignored user again.",
+            parse: |args| Ok(CommandPayload::Unignore(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "promote",
+            args: "<user> [moderator|admin]",
+            role: Role::Admin,
+            description: "Admin only. Raise a user's role, to moderator unless given.",
+            parse: |args| {
+                Ok(CommandPayload::Promote {
+                    user: args.required("user")?,
+                    role: parse_role(args, Role::Moderator)?,
+                })
+            },
+        },
+        CommandSpec {
+            name: "demote",
+            args: "<user> [member|moderator]",
+            role: Role::Admin,
+            description: "Admin only. Lower a user's role, to member unless given.",
+            parse: |args| {
+                Ok(CommandPayload::Demote {
+                    user: args.required("user")?,
+                    role: parse_role(args, Role::Member)?,
+                })
+            },
+        },
+        CommandSpec {
+            name: "block",
+            args: "<user>",
+            role: Role::Member,
+            description: "Refuse direct messages from a user.",
+            parse: |args| Ok(CommandPayload::Block(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "unblock",
+            args: "<user>",
+            role: Role::Member,
+            description: "Accept direct messages from a blocked user again.",
+            parse: |args| Ok(CommandPayload::Unblock(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "blocks",
+            args: "",
+            role: Role::Member,
+            description: "List who you are blocking.",
+            parse: |_| Ok(CommandPayload::ListBlocks),
+        },
+        CommandSpec {
+            name: "kick",
+            args: "<user>",
+            role: Role::Moderator,
+            description: "Owner or moderators only. Send a user in your room back to the lobby, or off the server from the lobby.",
+            parse: |args| Ok(CommandPayload::Kick(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "ban",
+            args: "<user>",
+            role: Role::Moderator,
+            description: "Owner or moderators only. Kick a user and stop them rejoining your room.",
+            parse: |args| Ok(CommandPayload::Ban(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "unban",
+            args: "<user>",
+            role: Role::Moderator,
+            description: "Owner or moderators only. Lift a ban from your room.",
+            parse: |args| Ok(CommandPayload::Unban(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "bans",
+            args: "",
+            role: Role::Moderator,
+            description: "Owner or moderators only. List the users banned from your room.",
+            parse: |_| Ok(CommandPayload::ListBans),
+        },
+        CommandSpec {
+            name: "mute",
+            args: "<user> [seconds]",
+            role: Role::Moderator,
+            description: "Owner or moderators only. Stop a user chatting in your room.",
+            parse: |args| {
+                Ok(CommandPayload::Mute {
+                    user: args.required("user")?,
+                    duration_secs: args.parsed("duration")?,
+                })
+            },
+        },
+        CommandSpec {
+            name: "unmute",
+            args: "<user>",
+            role: Role::Moderator,
+            description: "Owner or moderators only. Let a muted user chat again.",
+            parse: |args| Ok(CommandPayload::Unmute(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "silence",
+            args: "<user> [seconds]",
+            role: Role::Moderator,
+            description: "Moderators only. Stop a user chatting in any room.",
+            parse: |args| {
+                Ok(CommandPayload::Silence {
+                    user: args.required("user")?,
+                    duration_secs: args.parsed("duration")?,
+                })
+            },
+        },
+        CommandSpec {
+            name: "shadowban",
+            args: "<user>",
+            role: Role::Moderator,
+            description:
+                "Moderators only. Keep a user's chat from everyone but them, without telling them.",
+            parse: |args| Ok(CommandPayload::ShadowBan(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "pardon",
+            args: "<user>",
+            role: Role::Moderator,
+            description: "Moderators only. Lift a silence or shadow ban.",
+            parse: |args| Ok(CommandPayload::Pardon(args.required("user")?)),
+        },
+        CommandSpec {
+            name: "slowmode",
+            args: "<seconds>",
+            role: Role::Moderator,
+            description:
+                "Owner or moderators only. Limit everyone else to one message per interval, 0 turns it off.",
+            parse: |args| Ok(CommandPayload::SlowMode(args.required_parsed("interval")?)),
+        },
+        CommandSpec {
+            name: "purge",
+            args: "<count>",
+            role: Role::Moderator,
+            description: "Owner or moderators only. Delete the newest messages from this room's history.",
+            parse: |args| Ok(CommandPayload::Purge(args.required_parsed("count")?)),
+        },
+        CommandSpec {
+            name: "pin",
+            args: "<message_id>",
+            role: Role::Moderator,
+            description: "Owner or moderators only. Pin a message so everyone joining sees it.",
+            parse: |args| Ok(CommandPayload::Pin(args.required_parsed("message id")?)),
+        },
+        CommandSpec {
+            name: "unpin",
+            args: "<message_id>",
+            role: Role::Moderator,
+            description: "Owner or moderators only. Unpin a pinned message.",
+            parse: |args| Ok(CommandPayload::Unpin(args.required_parsed("message id")?)),
+        },
+        CommandSpec {
+            name: "topic",
+            args: "[topic]",
+            role: Role::Moderator,
+            description: "Owner or moderators only. Set the room topic, or clear it if none is given.",
+            parse: |args| Ok(CommandPayload::SetTopic(args.rest()?)),
+        },
+        CommandSpec {
+            name: "nick",
+            args: "<new_name>",
+            role: Role::Member,
+            description: "Change your display name.",
+            parse: |args| Ok(CommandPayload::Rename(args.required_rest("name")?)),
+        },
+        CommandSpec {
+            name: "ping",
+            args: "",
+            role: Role::Member,
+            description:
+                "Echo your message timestamp back with the server's, for measuring latency.",
+            parse: |_| {
+                Ok(CommandPayload::Ping {
+                    client_ts: None,
+                    received_at: Timestamp::from(Utc::now()),
+                })
+            },
+        },
+        CommandSpec {
+            name: "capabilities",
+            args: "",
+            role: Role::Member,
+            description: "List every command as JSON lines, with whether you can use it here.",
+            parse: |_| Ok(CommandPayload::Capabilities),
+        },
+        CommandSpec {
+            name: "help",
+            args: "[command]",
+            role: Role::Member,
+            description: "List available commands, or describe a single command.",
+            parse: |args| {
+                Ok(CommandPayload::Help(
+                    args.word()?
+                        .map(|name| name.trim_start_matches('/').to_string()),
+                ))
+            },
+        },
+    ];
+
+    /// Commands the CommandHandler has no built in handling for, they only work when a plugin
+    /// provides them.
+    pub const PLUGIN_ONLY: &'static [&'static str] = &["roll"];
+
+    /// Seconds a user must wait between uses of the more expensive commands, admins don't.
+    const COOLDOWNS: &'static [(&'static str, u64)] =
+        &[("export", 60), ("search", 5), ("history", 5)];
+
+    const ALIASES: &'static [AliasSpec] = &[
+        AliasSpec {
+            name: "shrug",
+            expansion: r"¯\_(ツ)_/¯",
+        },
+        AliasSpec {
+            name: "tableflip",
+            expansion: "(╯°□°)╯︵ ┻━┻",
+        },
+    ];
+
+    pub fn all() -> &'static [CommandSpec] {
+        Self::COMMANDS
+    }
+
+    pub fn get(name: &str) -> Option<&'static CommandSpec> {
+        Self::COMMANDS.iter().find(|spec| spec.name == name)
+    }
+
+    pub fn cooldowns() -> &'static [(&'static str, u64)] {
+        Self::COOLDOWNS
+    }
+
+    pub fn aliases() -> &'static [AliasSpec] {
+        Self::ALIASES
+    }
+
+    /// Commands always win over an alias of the same name.
+    pub fn get_alias(name: &str) -> Option<&'static AliasSpec> {
+        match Self::get(name) {
+            Some(_) => None,
+            None => Self::ALIASES.iter().find(|alias| alias.name == name),
+        }
+    }
 }
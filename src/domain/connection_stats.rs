@@ -0,0 +1,70 @@
+use std::fmt;
+
+use tokio_tungstenite::tungstenite::Message;
+
+/// What one connection has sent the server. The session owns and updates it, the App only
+/// ever sees the copies the session reports.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionStats {
+    pub text_frames: u64,
+    pub binary_frames: u64,
+    pub ping_frames: u64,
+    pub pong_frames: u64,
+    pub close_frames: u64,
+    pub bytes: u64,
+    /// Frames that couldn't be read and messages that didn't parse as a command.
+    pub parse_failures: u64,
+    /// Binary frames of plain bincode, counted among the parse failures as well.
+    pub unencrypted_frames: u64,
+    /// Commands sent on to the App.
+    pub forwarded: u64,
+    /// Messages the session answered itself, like /time and /ping.
+    pub answered: u64,
+    /// Frames dropped for going over the connection's rate.
+    pub rate_limited: u64,
+}
+
+impl ConnectionStats {
+    pub fn record_frame(&mut self, frame: &Message) {
+        match frame {
+            Message::Text(text) => {
+                self.text_frames += 1;
+                self.bytes += text.len() as u64;
+            }
+            Message::Binary(data) => {
+                self.binary_frames += 1;
+                self.bytes += data.len() as u64;
+            }
+            Message::Ping(data) => {
+                self.ping_frames += 1;
+                self.bytes += data.len() as u64;
+            }
+            Message::Pong(data) => {
+                self.pong_frames += 1;
+                self.bytes += data.len() as u64;
+            }
+            Message::Close(_) => self.close_frames += 1,
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for ConnectionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} text, {} binary, {} ping, {} pong, {} close frame(s), {} byte(s), {} parse failure(s) ({} unencrypted), {} forwarded, {} answered, {} rate limited",
+            self.text_frames,
+            self.binary_frames,
+            self.ping_frames,
+            self.pong_frames,
+            self.close_frames,
+            self.bytes,
+            self.parse_failures,
+            self.unencrypted_frames,
+            self.forwarded,
+            self.answered,
+            self.rate_limited
+        )
+    }
+}
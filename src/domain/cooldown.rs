@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+use super::commands::CommandRegistry;
+
+/// When each user last ran each command that has a cooldown.
+#[derive(Debug, Clone)]
+pub struct Cooldowns {
+    secs: HashMap<&'static str, u64>,
+    last_used: HashMap<(String, &'static str), DateTime<Utc>>,
+}
+
+impl Cooldowns {
+    /// Starts from the registry's cooldown table.
+    pub fn new() -> Self {
+        Self::from_table(CommandRegistry::cooldowns().iter().copied())
+    }
+
+    /// Cooldowns for just the given commands, those of 0 seconds are left out.
+    pub fn from_table(table: impl IntoIterator<Item = (&'static str, u64)>) -> Self {
+        Self {
+            secs: table.into_iter().filter(|(_, secs)| *secs > 0).collect(),
+            last_used: HashMap::new(),
+        }
+    }
+
+    /// Sets a command's cooldown, 0 removes it.
+    pub fn set(&mut self, command: &'static str, secs: u64) {
+        match secs {
+            0 => self.secs.remove(command),
+            secs => self.secs.insert(command, secs),
+        };
+    }
+
+    /// The seconds left before the user can use the command again, if it is cooling down.
+    pub fn check(
+        &self,
+        user_id: &str,
+        command: &'static str,
+        now: DateTime<Utc>,
+    ) -> Result<(), u64> {
+        let Some(&secs) = self.secs.get(command) else {
+            return Ok(());
+        };
+        if let Some(last) = self.last_used.get(&(user_id.to_string(), command)) {
+            let elapsed = now.signed_duration_since(*last).num_seconds().max(0) as u64;
+            if elapsed < secs {
+                return Err(secs - elapsed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts the command's cooldown for the user, only once it has done what was asked.
+    pub fn record(&mut self, user_id: &str, command: &'static str, now: DateTime<Utc>) {
+        if self.secs.contains_key(command) {
+            self.last_used.insert((user_id.to_string(), command), now);
+        }
+    }
+
+    pub fn forget(&mut self, user_id: &str) {
+        self.last_used.retain(|(id, _), _| id != user_id);
+    }
+}
+
+impl Default for Cooldowns {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn only_a_recorded_use_starts_the_cooldown() {
+        let mut cooldowns = Cooldowns::new();
+        cooldowns.set("search", 5);
+        let now = Utc::now();
+
+        // Checking alone, as for a use that failed, leaves the command free.
+        assert_eq!(cooldowns.check("ann", "search", now), Ok(()));
+        assert_eq!(cooldowns.check("ann", "search", now), Ok(()));
+
+        cooldowns.record("ann", "search", now);
+        assert_eq!(
+            cooldowns.check("ann", "search", now + Duration::seconds(2)),
+            Err(3)
+        );
+        assert_eq!(cooldowns.check("bob", "search", now), Ok(()));
+        assert_eq!(
+            cooldowns.check("ann", "search", now + Duration::seconds(5)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn commands_without_a_cooldown_are_never_held_back() {
+        let mut cooldowns = Cooldowns::new();
+        cooldowns.set("search", 0);
+        let now = Utc::now();
+
+        cooldowns.record("ann", "search", now);
+        assert_eq!(cooldowns.check("ann", "search", now), Ok(()));
+    }
+}
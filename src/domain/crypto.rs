@@ -0,0 +1,104 @@
+use std::fmt;
+
+use x25519_dalek::SharedSecret;
+use zeroize::Zeroize;
+
+/// The key a session's frames are encrypted with, agreed with the client at login. Kept
+/// apart from other 32 byte arrays so a public key can't be passed where it belongs, never
+/// printed, and wiped when the last copy goes.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SessionKey([u8; 32]);
+
+impl SessionKey {
+    /// What the key exchange at login came to.
+    pub fn from_key_exchange(shared_secret: SharedSecret) -> Self {
+        SessionKey(shared_secret.to_bytes())
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        SessionKey(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// The key as the cipher takes it.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+}
+
+/// Before the key exchange there is nothing to encrypt with.
+impl Default for SessionKey {
+    fn default() -> Self {
+        SessionKey([0; 32])
+    }
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionKey(<redacted>)")
+    }
+}
+
+impl Drop for SessionKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Whether `given` is the secret `expected`, compared in constant time so that a forged
+/// one can't be found a byte at a time. An empty secret matches nothing.
+pub fn secrets_match(expected: &str, given: &str) -> bool {
+    let (expected, given) = (expected.as_bytes(), given.as_bytes());
+    !expected.is_empty()
+        && given.len() == expected.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::OsRng;
+    use x25519_dalek::{EphemeralSecret, PublicKey};
+
+    use super::*;
+    use crate::domain::user::User;
+
+    #[test]
+    fn debug_never_shows_the_bytes() {
+        let key = SessionKey::from_bytes([0xAB; 32]);
+        assert_eq!(format!("{key:?}"), "SessionKey(<redacted>)");
+
+        let user = User::new("id-ann".into(), "ann".into(), key);
+        let printed = format!("{user:?}");
+        assert!(printed.contains("SessionKey(<redacted>)"));
+        assert!(!printed.contains("171, 171"), "{printed}");
+    }
+
+    #[test]
+    fn both_ends_of_the_key_exchange_agree() {
+        let server = EphemeralSecret::random_from_rng(OsRng);
+        let client = EphemeralSecret::random_from_rng(OsRng);
+        let server_public = PublicKey::from(&server);
+        let client_public = PublicKey::from(&client);
+
+        let ours = SessionKey::from_key_exchange(server.diffie_hellman(&client_public));
+        let theirs = SessionKey::from_key_exchange(client.diffie_hellman(&server_public));
+
+        assert_eq!(ours, theirs);
+        assert_ne!(ours, SessionKey::default());
+    }
+
+    #[test]
+    fn the_cipher_gets_the_bytes_it_was_made_from() {
+        let bytes: [u8; 32] = std::array::from_fn(|n| n as u8);
+        let key = SessionKey::from_bytes(bytes);
+        assert_eq!(key.as_bytes(), &bytes);
+        assert_eq!(key.to_vec(), bytes.to_vec());
+    }
+}
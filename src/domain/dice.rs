@@ -0,0 +1,193 @@
+use rand_core::RngCore;
+
+pub const MAX_DICE: u32 = 100;
+pub const MAX_SIDES: u32 = 10000;
+pub const MAX_MODIFIER: i64 = 10000;
+
+/// A roll in NdM+K notation, e.g. `2d6` or `1d20+3`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiceSpec {
+    pub count: u32,
+    pub sides: u32,
+    pub modifier: i64,
+}
+
+impl DiceSpec {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let usage = || format!("Cannot roll {spec:?}, usage: /roll NdM[+K], e.g. /roll 2d6");
+
+        let (count, rest) = spec.trim().split_once(['d', 'D']).ok_or_else(usage)?;
+        let (sides, modifier) = match rest.find(['+', '-']) {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+
+        let count: u32 = match count {
+            "" => 1,
+            count => count.parse().map_err(|_| usage())?,
+        };
+        let sides: u32 = sides.parse().map_err(|_| usage())?;
+        let modifier: i64 = match modifier.strip_prefix('+') {
+            Some(modifier) => modifier.parse().map_err(|_| usage())?,
+            None if modifier.is_empty() => 0,
+            None => modifier.parse().map_err(|_| usage())?,
+        };
+
+        if count == 0 || count > MAX_DICE {
+            return Err(format!("You can roll between 1 and {MAX_DICE} dice"));
+        }
+        if sides == 0 || sides > MAX_SIDES {
+            return Err(format!("Dice can have between 1 and {MAX_SIDES} sides"));
+        }
+        if !(-MAX_MODIFIER..=MAX_MODIFIER).contains(&modifier) {
+            return Err(format!(
+                "The modifier can be between -{MAX_MODIFIER} and +{MAX_MODIFIER}"
+            ));
+        }
+
+        Ok(Self {
+            count,
+            sides,
+            modifier,
+        })
+    }
+
+    pub fn roll(&self, rng: &mut dyn RngCore) -> Vec<u32> {
+        (0..self.count)
+            .map(|_| (rng.next_u64() % self.sides as u64) as u32 + 1)
+            .collect()
+    }
+
+    /// Reads like `2d6: 4 + 3 = 7`, or `1d20+3: 12 + 3 = 15` with a modifier.
+    pub fn describe(&self, rolls: &[u32]) -> String {
+        let total = rolls.iter().fold(self.modifier, |total, &roll| {
+            total.saturating_add(roll as i64)
+        });
+        let mut spec = format!("{}d{}", self.count, self.sides);
+        let mut sum = rolls
+            .iter()
+            .map(|roll| roll.to_string())
+            .collect::<Vec<String>>()
+            .join(" + ");
+        if self.modifier != 0 {
+            spec.push_str(&format!("{:+}", self.modifier));
+            let sign = if self.modifier > 0 { '+' } else { '-' };
+            sum.push_str(&format!(" {sign} {}", self.modifier.unsigned_abs()));
+        }
+        format!("{spec}: {sum} = {total}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_chacha::{rand_core::SeedableRng, ChaCha8Rng};
+
+    /// Hands out the given values in order, so a test can pick the faces rolled.
+    struct ScriptedRng(std::vec::IntoIter<u64>);
+
+    impl ScriptedRng {
+        fn new(values: Vec<u64>) -> Self {
+            Self(values.into_iter())
+        }
+    }
+
+    impl RngCore for ScriptedRng {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0.next().expect("the script ran out of values")
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            rand_core::impls::fill_bytes_via_next(self, dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parses_count_sides_and_modifier() {
+        let spec = DiceSpec::parse("2d6+3").unwrap();
+        assert_eq!(
+            spec,
+            DiceSpec {
+                count: 2,
+                sides: 6,
+                modifier: 3
+            }
+        );
+        assert_eq!(DiceSpec::parse("d20").unwrap().count, 1);
+        assert_eq!(DiceSpec::parse("1D8-2").unwrap().modifier, -2);
+    }
+
+    #[test]
+    fn refuses_specs_outside_the_limits() {
+        assert!(DiceSpec::parse("0d6").is_err());
+        assert!(DiceSpec::parse(&format!("{}d6", MAX_DICE + 1)).is_err());
+        assert!(DiceSpec::parse("1d0").is_err());
+        assert!(DiceSpec::parse(&format!("1d{}", MAX_SIDES + 1)).is_err());
+        assert!(DiceSpec::parse("2d6+").is_err());
+        assert!(DiceSpec::parse("six").is_err());
+        assert!(DiceSpec::parse(&format!("{MAX_DICE}d{MAX_SIDES}")).is_ok());
+    }
+
+    #[test]
+    fn refuses_modifiers_outside_the_limit() {
+        assert!(DiceSpec::parse(&format!("1d6+{MAX_MODIFIER}")).is_ok());
+        assert!(DiceSpec::parse(&format!("1d6-{MAX_MODIFIER}")).is_ok());
+        assert!(DiceSpec::parse(&format!("1d6+{}", MAX_MODIFIER + 1)).is_err());
+        assert!(DiceSpec::parse(&format!("1d6-{}", MAX_MODIFIER + 1)).is_err());
+        assert!(DiceSpec::parse(&format!("1d6+{}", i64::MAX)).is_err());
+        assert!(DiceSpec::parse(&format!("1d6{}", i64::MIN)).is_err());
+    }
+
+    #[test]
+    fn rolls_the_scripted_faces() {
+        let spec = DiceSpec::parse("3d6+2").unwrap();
+        let rolls = spec.roll(&mut ScriptedRng::new(vec![0, 5, 13]));
+        assert_eq!(rolls, vec![1, 6, 2]);
+        assert_eq!(spec.describe(&rolls), "3d6+2: 1 + 6 + 2 + 2 = 11");
+    }
+
+    #[test]
+    fn seeded_rolls_repeat_and_stay_on_the_dice() {
+        let spec = DiceSpec::parse("100d20").unwrap();
+        let first = spec.roll(&mut ChaCha8Rng::seed_from_u64(7));
+        let second = spec.roll(&mut ChaCha8Rng::seed_from_u64(7));
+        assert_eq!(first, second);
+        assert!(first.iter().all(|roll| (1..=20).contains(roll)));
+    }
+
+    #[test]
+    fn describes_negative_and_extreme_modifiers() {
+        let spec = DiceSpec::parse("1d20-3").unwrap();
+        assert_eq!(spec.describe(&[1]), "1d20-3: 1 - 3 = -2");
+
+        let spec = DiceSpec {
+            count: 1,
+            sides: 6,
+            modifier: i64::MIN,
+        };
+        assert_eq!(
+            spec.describe(&[6]),
+            format!(
+                "1d6{}: 6 - {} = {}",
+                i64::MIN,
+                i64::MIN.unsigned_abs(),
+                i64::MIN + 6
+            )
+        );
+        let spec = DiceSpec {
+            count: 1,
+            sides: 6,
+            modifier: i64::MAX,
+        };
+        assert!(spec.describe(&[6]).ends_with(&format!("= {}", i64::MAX)));
+    }
+}
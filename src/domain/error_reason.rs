@@ -0,0 +1,90 @@
+use std::fmt;
+
+/// What kind of thing went wrong, sent as a code ahead of the human readable detail so that
+/// clients can branch on it without matching the text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorReason {
+    InvalidToken,
+    RateLimited,
+    PermissionDenied,
+    /// A frame that couldn't be decoded, or a message with nothing usable in it.
+    MalformedMessage,
+    /// A binary frame that was never encrypted, or plaintext where it isn't accepted.
+    Unencrypted,
+    MessageTooLarge,
+    /// A message claiming a protocol version other than the one agreed at login.
+    UnsupportedVersion,
+    UnknownCommand,
+    /// A known command given arguments it can't use.
+    InvalidArguments,
+    RoomNotFound,
+    UserNotFound,
+    Muted,
+    Internal,
+}
+
+impl ErrorReason {
+    pub const ALL: [ErrorReason; 13] = [
+        ErrorReason::InvalidToken,
+        ErrorReason::RateLimited,
+        ErrorReason::PermissionDenied,
+        ErrorReason::MalformedMessage,
+        ErrorReason::Unencrypted,
+        ErrorReason::MessageTooLarge,
+        ErrorReason::UnsupportedVersion,
+        ErrorReason::UnknownCommand,
+        ErrorReason::InvalidArguments,
+        ErrorReason::RoomNotFound,
+        ErrorReason::UserNotFound,
+        ErrorReason::Muted,
+        ErrorReason::Internal,
+    ];
+
+    /// The code on the wire, these never change once a client may depend on them.
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorReason::InvalidToken => "invalid_token",
+            ErrorReason::RateLimited => "rate_limited",
+            ErrorReason::PermissionDenied => "permission_denied",
+            ErrorReason::MalformedMessage => "malformed_message",
+            ErrorReason::Unencrypted => "unencrypted",
+            ErrorReason::MessageTooLarge => "message_too_large",
+            ErrorReason::UnsupportedVersion => "unsupported_protocol_version",
+            ErrorReason::UnknownCommand => "unknown_command",
+            ErrorReason::InvalidArguments => "invalid_arguments",
+            ErrorReason::RoomNotFound => "room_not_found",
+            ErrorReason::UserNotFound => "user_not_found",
+            ErrorReason::Muted => "muted",
+            ErrorReason::Internal => "internal",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        ErrorReason::ALL
+            .into_iter()
+            .find(|reason| reason.code() == code)
+    }
+}
+
+impl fmt::Display for ErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn codes_are_unique_and_read_back() {
+        let codes: HashSet<&str> = ErrorReason::ALL.iter().map(|r| r.code()).collect();
+        assert_eq!(codes.len(), ErrorReason::ALL.len());
+        for reason in ErrorReason::ALL {
+            assert_eq!(ErrorReason::from_code(reason.code()), Some(reason));
+        }
+        assert_eq!(ErrorReason::from_code("Muted"), None);
+    }
+}
@@ -1,6 +1,31 @@
 // use super::{app::Room, chat_log::MessageLog, notification_log::NotificationLog, user::User};
 
-use super::{chat_log::MessageLog, notification_log::NotificationLog, room::Room, user::User};
+use chrono::{DateTime, Utc};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{
+    chat_log::{HistoryPage, MessageId, MessageLog},
+    commands::RequestId,
+    error_reason::ErrorReason,
+    notification_log::NotificationLog,
+    room::Room,
+    transcript::ExportFormat,
+    user::{PresenceStatus, User},
+};
+
+/// Everything a client is sent about a room when it joins, leaves or resumes, taken from
+/// the App's state at that moment. The session picks out the window it actually sends.
+#[derive(Clone, Default)]
+pub struct RoomSnapshot {
+    pub msg_log: Vec<MessageLog>,
+    pub notifications: Vec<NotificationLog>,
+    pub occupant_names: Vec<String>,
+    pub topic: Option<String>,
+    pub pinned: Vec<MessageLog>,
+    /// How many times the room's occupants have changed, the delta that made this snapshot
+    /// carries the same number.
+    pub occupancy_epoch: u64,
+}
 
 #[derive(Clone)]
 pub enum Event {
@@ -10,21 +35,101 @@ pub enum Event {
     UserJoined {
         user: User,
         room: Room,
-        msg_log: Vec<MessageLog>,
-        notifications: Vec<NotificationLog>,
-        occupant_names: Vec<String>,
+        snapshot: RoomSnapshot,
     },
     UserLeft {
         user: User,
         room: Room,
-        msg_log: Vec<MessageLog>,
-        notifications: Vec<NotificationLog>,
+        snapshot: RoomSnapshot,
+    },
+    History {
+        room: Room,
+        page: HistoryPage,
         occupant_names: Vec<String>,
     },
+    Export {
+        room: Room,
+        format: ExportFormat,
+        logs: Vec<MessageLog>,
+        notifications: Vec<NotificationLog>,
+    },
+    SearchResults {
+        query: String,
+        matches: Vec<MessageLog>,
+    },
+    NotificationsCleared {
+        room: Room,
+        notifications: Vec<NotificationLog>,
+    },
     MsgReceived {
         msg: MessageLog,
     },
-    // Notify {
-    //     notice: Vec<NotificationLog>,
-    // }
+    /// Serialized and encrypted by the App for this session, to go out as it is.
+    Frame {
+        frame: Message,
+    },
+    WhoAmI {
+        user: User,
+        room: Option<Room>,
+    },
+    RoomList {
+        rooms: Vec<(Room, usize)>,
+    },
+    Motd {
+        motd: String,
+    },
+    Announcement {
+        notice: NotificationLog,
+    },
+    Notify {
+        notice: NotificationLog,
+    },
+    /// Replies and rejections addressed to just the commander carry their request id.
+    Reply {
+        notice: NotificationLog,
+        request_id: Option<RequestId>,
+    },
+    Rejected {
+        reason: String,
+        request_id: Option<RequestId>,
+        /// Set for the failures clients are likely to branch on, free form refusals have none.
+        kind: Option<ErrorReason>,
+    },
+    /// For the sender, once their chat is in the log and on its way to the room. Queued after
+    /// the room's copy so the sender always sees their own message first.
+    SendAck {
+        msg_id: Option<String>,
+        id: MessageId,
+        timestamp: DateTime<Utc>,
+        request_id: Option<RequestId>,
+    },
+    /// For the sender, their chat went nowhere and why.
+    SendNack {
+        msg_id: Option<String>,
+        kind: ErrorReason,
+        reason: String,
+        request_id: Option<RequestId>,
+    },
+    /// Someone in the room went away or came back, after the notification saying so.
+    PresenceChanged {
+        user_name: String,
+        status: PresenceStatus,
+        timestamp: DateTime<Utc>,
+    },
+    /// Sessions close their socket and drop out when they see this.
+    ServerShutdown,
+    /// A moderator kicked the user off the server, their session hangs up on them.
+    Kicked,
+    /// The user is being kept on after losing their connection, their session can finish.
+    UserDetached {
+        user: User,
+    },
+    /// For a reconnected session, who it belongs to now, their room and what was said there
+    /// while they were gone.
+    SessionResumed {
+        user: User,
+        room: Room,
+        snapshot: RoomSnapshot,
+        missed: Vec<MessageLog>,
+    },
 }
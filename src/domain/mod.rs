@@ -1,6 +1,20 @@
+pub mod attachment;
+pub mod audit_log;
 pub mod chat_log;
+pub mod clock;
 pub mod commands;
+pub mod connection_stats;
+pub mod cooldown;
+pub mod crypto;
+pub mod dice;
+pub mod error_reason;
 pub mod events;
 pub mod notification_log;
+pub mod protocol;
+pub mod rate_limit;
+pub mod recent_ids;
 pub mod room;
+pub mod sequence;
+pub mod server_info;
+pub mod transcript;
 pub mod user;
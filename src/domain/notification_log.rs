@@ -1,18 +1,60 @@
 use chrono::{DateTime, Utc};
 
+use super::user::SERVER_NAME;
+
+/// How much a notification matters, so clients can filter or highlight it. Anything from
+/// before severities existed is Info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn label(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+
+    /// Contents as clients are sent them, led by `[warning]` or `[critical]` when they are
+    /// more than Info. Info is sent as it always was.
+    pub fn tag(self, contents: &str) -> String {
+        match self {
+            Severity::Info => contents.to_string(),
+            severity => format!("[{}] {contents}", severity.label()),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NotificationLog {
     pub notifier: String,
     pub timestamp: DateTime<Utc>,
     pub contents: String,
+    pub severity: Severity,
 }
 
 impl NotificationLog {
     pub fn new(text: String) -> Self {
         NotificationLog {
-            notifier: "SERVER".into(),
+            notifier: SERVER_NAME.into(),
             timestamp: Utc::now(),
             contents: text,
+            severity: Severity::Info,
         }
     }
+
+    pub fn with_severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    pub fn wire_contents(&self) -> String {
+        self.severity.tag(&self.contents)
+    }
 }
@@ -0,0 +1,22 @@
+/// The wire protocol this server speaks. Bump it whenever a message changes shape in a way
+/// that older clients can't read.
+pub const PROTOCOL_VERSION: u32 = 3;
+/// The oldest client protocol the server still understands.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// From this version on, room chat leads with a line giving the message's id.
+pub const MESSAGE_IDS_VERSION: u32 = 2;
+/// From this version on, the rest of a room hears who joined or left as a delta rather than
+/// being sent the whole RoomData again.
+pub const OCCUPANT_DELTAS_VERSION: u32 = 3;
+/// From this version on, presence changes come as updates a client can read as well as the
+/// notification saying so.
+pub const PRESENCE_UPDATES_VERSION: u32 = 3;
+
+/// The version a session will speak, or the unsupported version the client asked for.
+/// Clients from before versioning don't send one and speak the first version.
+pub fn negotiate(claimed: Option<u32>) -> Result<u32, u32> {
+    match claimed.unwrap_or(MIN_PROTOCOL_VERSION) {
+        version if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) => Ok(version),
+        version => Err(version),
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+pub const DEFAULT_BURST: u32 = 10;
+pub const DEFAULT_WINDOW_SECS: u64 = 10;
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated: DateTime<Utc>,
+}
+
+/// A token bucket per user id, refilling `burst` tokens evenly over `window_secs`.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    burst: f64,
+    refill_per_sec: f64,
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, window_secs: u64) -> Self {
+        Self {
+            burst: burst.max(1) as f64,
+            refill_per_sec: burst.max(1) as f64 / window_secs.max(1) as f64,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// A bucket of `burst` tokens refilling at `rate` tokens a second.
+    pub fn per_second(rate: u32, burst: u32) -> Self {
+        Self {
+            burst: burst.max(1) as f64,
+            refill_per_sec: rate.max(1) as f64,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token for the user, or gives the seconds until the next one comes in.
+    pub fn try_acquire(&mut self, user_id: &str, now: DateTime<Utc>) -> Result<(), u64> {
+        let bucket = self
+            .buckets
+            .entry(user_id.to_string())
+            .or_insert(TokenBucket {
+                tokens: self.burst,
+                updated: now,
+            });
+
+        let elapsed = now.signed_duration_since(bucket.updated).num_milliseconds() as f64;
+        bucket.tokens =
+            (bucket.tokens + elapsed.max(0.0) / 1000.0 * self.refill_per_sec).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.refill_per_sec).ceil() as u64)
+        }
+    }
+
+    pub fn forget(&mut self, user_id: &str) {
+        self.buckets.remove(user_id);
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_BURST, DEFAULT_WINDOW_SECS)
+    }
+}
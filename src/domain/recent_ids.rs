@@ -0,0 +1,105 @@
+use std::collections::{HashMap, VecDeque};
+
+pub const DEFAULT_RECENT_IDS: usize = 128;
+
+/// The ids most recently seen and what was kept for each, the least recently seen is
+/// forgotten first once there are more than the capacity.
+#[derive(Debug, Clone)]
+pub struct RecentIds<V> {
+    capacity: usize,
+    order: VecDeque<String>,
+    seen: HashMap<String, V>,
+}
+
+impl<V> RecentIds<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            order: VecDeque::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// What was kept for the id if it is one of the recent ones, which makes it the most
+    /// recently seen again.
+    pub fn get(&mut self, id: &str) -> Option<&V> {
+        if self.seen.contains_key(id) {
+            self.refresh(id);
+        }
+        self.seen.get(id)
+    }
+
+    /// Records the id, replacing what was kept for it if it was already there.
+    pub fn insert(&mut self, id: String, value: V) {
+        if self.seen.insert(id.clone(), value).is_some() {
+            self.refresh(&id);
+            return;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.order.clear();
+        self.seen.clear();
+    }
+
+    fn refresh(&mut self, id: &str) {
+        if let Some(index) = self.order.iter().position(|seen| seen == id) {
+            let id = self.order.remove(index).unwrap_or_default();
+            self.order.push_back(id);
+        }
+    }
+}
+
+impl<V> Default for RecentIds<V> {
+    fn default() -> Self {
+        Self::new(DEFAULT_RECENT_IDS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_id_is_only_remembered_once_it_is_inserted() {
+        let mut ids = RecentIds::new(4);
+        assert_eq!(ids.get("a"), None);
+        ids.insert("a".into(), 1);
+        assert_eq!(ids.get("a"), Some(&1));
+        ids.insert("a".into(), 2);
+        assert_eq!(ids.get("a"), Some(&2));
+    }
+
+    #[test]
+    fn the_least_recently_seen_id_is_forgotten_first() {
+        let mut ids = RecentIds::new(2);
+        ids.insert("a".into(), ());
+        ids.insert("b".into(), ());
+        // Looking "a" up again leaves "b" as the oldest.
+        assert!(ids.get("a").is_some());
+        ids.insert("c".into(), ());
+
+        assert!(ids.get("b").is_none());
+        assert!(ids.get("a").is_some());
+        assert!(ids.get("c").is_some());
+
+        // Once forgotten an id can be used again.
+        ids.insert("b".into(), ());
+        assert!(ids.get("b").is_some());
+        assert!(ids.get("a").is_none());
+    }
+
+    #[test]
+    fn clearing_forgets_everything() {
+        let mut ids = RecentIds::default();
+        ids.insert("a".into(), ());
+        ids.clear();
+        assert!(ids.get("a").is_none());
+    }
+}
@@ -1,11 +1,21 @@
+use chrono::{DateTime, Utc};
+
+use super::{chat_log::MessageId, user::User};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+};
+
 #[derive(Debug, Hash, Clone, PartialEq, Eq)]
 pub struct Room {
     pub name: String,
 }
 
+pub const LOBBY_NAME: &str = "Hub";
+
 impl Default for Room {
     fn default() -> Self {
-        Room::from("Hub")
+        Room::lobby()
     }
 }
 
@@ -14,3 +24,171 @@ impl From<&str> for Room {
         Self { name: value.into() }
     }
 }
+
+impl Room {
+    /// Everyone lands here on login and when they leave or are thrown out of a room.
+    pub fn lobby() -> Self {
+        Room::from(LOBBY_NAME)
+    }
+
+    pub fn is_lobby(&self) -> bool {
+        self.name == LOBBY_NAME
+    }
+
+    /// Stable within a server lifetime, handy for clients that want a short room identifier.
+    pub fn hash_code(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// There are no accounts to ban, so a ban catches the name in any case and every session
+/// it has thrown out, by id, so that the user can't get back in with /nick.
+#[derive(Debug, Clone)]
+pub struct Ban {
+    /// As the moderator gave it.
+    pub name: String,
+    pub user_ids: HashSet<String>,
+}
+
+impl Ban {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            user_ids: HashSet::new(),
+        }
+    }
+
+    /// Bans are kept under this, so that one name can't be banned twice in different cases.
+    pub fn key(name: &str) -> String {
+        name.to_ascii_lowercase()
+    }
+
+    pub fn catches(&self, user: &User) -> bool {
+        self.user_ids.contains(&user.id) || self.catches_name(&user.name)
+    }
+
+    pub fn catches_name(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+}
+
+/// Per room state that moderators can change, kept apart from `Room` so that rooms
+/// stay cheap to use as keys.
+#[derive(Debug, Clone, Default)]
+pub struct RoomSettings {
+    /// Keyed by `Ban::key` of the banned name.
+    pub banned: HashMap<String, Ban>,
+    /// Keyed by user id, a `None` expiry lasts until the user is unmuted.
+    pub muted: HashMap<String, Option<DateTime<Utc>>>,
+    pub topic: Option<String>,
+    /// Id of the user that created the room with /create.
+    pub owner: Option<String>,
+    pub private: bool,
+    /// Ids of the users the owner has invited into a private room.
+    pub invited: HashSet<String>,
+    pub locked: bool,
+    /// Ids of users who left while the room was locked and when, so they can come back.
+    pub lock_leavers: HashMap<String, DateTime<Utc>>,
+    /// Zero when slow mode is off.
+    pub slow_mode_secs: u64,
+    /// When each occupant last chatted, keyed by user id, only kept while they are in the room.
+    pub last_sent: HashMap<String, DateTime<Utc>>,
+    /// Pinned messages in the order they were pinned.
+    pub pinned: Vec<MessageId>,
+}
+
+impl RoomSettings {
+    /// Nothing a moderator or owner set is left, so forgetting the settings loses nothing.
+    /// Who last chatted and who left a lock behind only matter while the room is in use.
+    pub fn is_untouched(&self) -> bool {
+        self.banned.is_empty()
+            && self.muted.is_empty()
+            && self.topic.is_none()
+            && self.owner.is_none()
+            && !self.private
+            && self.invited.is_empty()
+            && !self.locked
+            && self.slow_mode_secs == 0
+            && self.pinned.is_empty()
+    }
+}
+
+/// Activity counters for a room, updated as messages are recorded.
+#[derive(Debug, Clone)]
+pub struct RoomStats {
+    pub created_at: DateTime<Utc>,
+    pub total_messages: u64,
+    pub peak_occupants: usize,
+    /// Timestamps of the messages inside the sliding window, oldest first.
+    recent: VecDeque<DateTime<Utc>>,
+}
+
+impl RoomStats {
+    pub const WINDOW_SECS: i64 = 60;
+
+    pub fn new(created_at: DateTime<Utc>) -> Self {
+        Self {
+            created_at,
+            total_messages: 0,
+            peak_occupants: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn record_message(&mut self, at: DateTime<Utc>) {
+        self.total_messages += 1;
+        self.recent.push_back(at);
+        self.expire(at);
+    }
+
+    pub fn record_occupancy(&mut self, occupants: usize) {
+        self.peak_occupants = self.peak_occupants.max(occupants);
+    }
+
+    pub fn messages_in_window(&mut self, now: DateTime<Utc>) -> usize {
+        self.expire(now);
+        self.recent.len()
+    }
+
+    fn expire(&mut self, now: DateTime<Utc>) {
+        while let Some(oldest) = self.recent.front() {
+            if now.signed_duration_since(*oldest).num_seconds() < Self::WINDOW_SECS {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn a_burst_of_messages_decays_out_of_the_window() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut stats = RoomStats::new(start);
+        for n in 0..10 {
+            stats.record_message(start + Duration::seconds(n));
+        }
+        assert_eq!(stats.messages_in_window(start + Duration::seconds(10)), 10);
+
+        // The first five are now a window or more old.
+        assert_eq!(stats.messages_in_window(start + Duration::seconds(64)), 5);
+        assert_eq!(stats.messages_in_window(start + Duration::seconds(70)), 0);
+        assert_eq!(stats.total_messages, 10);
+    }
+
+    #[test]
+    fn the_peak_only_goes_up() {
+        let mut stats = RoomStats::new(Utc::now());
+        stats.record_occupancy(3);
+        stats.record_occupancy(1);
+        assert_eq!(stats.peak_occupants, 3);
+        stats.record_occupancy(4);
+        assert_eq!(stats.peak_occupants, 4);
+    }
+}
@@ -0,0 +1,36 @@
+/// Where a client's sequence numbers went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceGap {
+    pub expected: u64,
+    pub received: u64,
+}
+
+/// Follows the sequence numbers a client puts on its messages. Any number is fine to start
+/// from, after that each message should carry one more than the last.
+#[derive(Debug, Clone, Default)]
+pub struct SequenceTracker {
+    last: Option<u64>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the number, giving the gap if it isn't the one that was expected. The message
+    /// is still taken, the new number is what the next one follows on from.
+    pub fn observe(&mut self, received: u64) -> Option<SequenceGap> {
+        let gap = self
+            .last
+            .map(|last| last.saturating_add(1))
+            .filter(|expected| *expected != received)
+            .map(|expected| SequenceGap { expected, received });
+        self.last = Some(received);
+        gap
+    }
+
+    /// Forgets the last number so the client can start again from any value.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
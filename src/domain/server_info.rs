@@ -0,0 +1,50 @@
+use chrono::{DateTime, Duration, Utc};
+
+use crate::services::bounded_channel::DropCounter;
+
+/// Facts about the running server that commands can report on.
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    pub started_at: DateTime<Utc>,
+    /// Message of the day, an empty string means there isn't one.
+    pub motd: String,
+    /// Commands turned away from connections that already had too many waiting for the App.
+    pub dropped_commands: DropCounter,
+}
+
+impl ServerInfo {
+    pub fn new(started_at: DateTime<Utc>, motd: String) -> Self {
+        Self {
+            started_at,
+            motd,
+            dropped_commands: DropCounter::new(),
+        }
+    }
+
+    pub fn motd(&self) -> Option<String> {
+        match self.motd.trim() {
+            "" => None,
+            motd => Some(motd.to_string()),
+        }
+    }
+
+    pub fn uptime(&self, now: DateTime<Utc>) -> Duration {
+        now.signed_duration_since(self.started_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uptime_is_counted_from_the_start_time() {
+        let started_at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let info = ServerInfo::new(started_at, String::new());
+
+        let now = started_at + Duration::seconds(93_784);
+
+        assert_eq!(info.uptime(now), Duration::seconds(93_784));
+        assert_eq!(info.uptime(started_at), Duration::zero());
+    }
+}
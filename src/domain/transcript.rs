@@ -0,0 +1,160 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use super::{chat_log::MessageLog, notification_log::NotificationLog};
+
+/// Frames are kept comfortably under typical websocket message limits once encrypted.
+pub const MAX_CHUNK_BYTES: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Text,
+    JsonLines,
+}
+
+impl ExportFormat {
+    pub fn parse(name: &str) -> Option<ExportFormat> {
+        match name.to_lowercase().as_str() {
+            "" | "text" | "txt" => Some(ExportFormat::Text),
+            "json" | "jsonl" => Some(ExportFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+enum Entry<'a> {
+    Chat(&'a MessageLog),
+    Notice(&'a NotificationLog),
+}
+
+impl Entry<'_> {
+    fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            Entry::Chat(msg) => msg.timestamp,
+            Entry::Notice(notice) => notice.timestamp,
+        }
+    }
+}
+
+/// Chat and notifications merged in time order, rendered as one UTF-8 document.
+pub fn render(
+    logs: &[MessageLog],
+    notifications: &[NotificationLog],
+    format: ExportFormat,
+) -> String {
+    let mut entries: Vec<Entry> = logs
+        .iter()
+        .map(Entry::Chat)
+        .chain(notifications.iter().map(Entry::Notice))
+        .collect();
+    entries.sort_by_key(Entry::timestamp);
+
+    entries
+        .iter()
+        .map(|entry| {
+            let timestamp = entry.timestamp().format("%Y-%m-%d %H:%M:%S UTC");
+            match (format, entry) {
+                (ExportFormat::Text, Entry::Chat(msg)) => {
+                    format!("[{timestamp}] {}: {}\n", msg.username, msg.wire_contents())
+                }
+                (ExportFormat::Text, Entry::Notice(notice)) => {
+                    format!("[{timestamp}] * {}\n", notice.contents)
+                }
+                (ExportFormat::JsonLines, Entry::Chat(msg)) => format!(
+                    "{{\"type\":\"chat\",\"timestamp\":\"{timestamp}\",\"sender\":{},\"content\":{}}}\n",
+                    json_string(&msg.username),
+                    json_string(&msg.wire_contents())
+                ),
+                (ExportFormat::JsonLines, Entry::Notice(notice)) => format!(
+                    "{{\"type\":\"notification\",\"timestamp\":\"{timestamp}\",\"sender\":{},\"content\":{}}}\n",
+                    json_string(&notice.notifier),
+                    json_string(&notice.contents)
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Where a room's transcript is kept in `dir`. Anything in the room name that can't safely
+/// go in a file name becomes an underscore.
+pub fn path_for(dir: &Path, room: &str) -> PathBuf {
+    let stem: String = room
+        .chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() || c == '-' || c == '_' => c,
+            _ => '_',
+        })
+        .collect();
+    dir.join(format!("{stem}.jsonl"))
+}
+
+/// Appends a rendered transcript to the room's file, so each run adds to what the last one
+/// left rather than replacing it.
+pub fn save(dir: &Path, room: &str, text: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = path_for(dir, room);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?
+        .write_all(text.as_bytes())?;
+    Ok(path)
+}
+
+/// Splits on char boundaries so that every chunk is valid UTF-8 by itself.
+pub fn chunk(text: &str, max_bytes: usize) -> Vec<&str> {
+    let mut chunks = vec![];
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(max_bytes);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (head, tail) = rest.split_at(end);
+        chunks.push(head);
+        rest = tail;
+    }
+    chunks
+}
+
+pub fn json_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_never_split_a_character() {
+        let text = "aé🎉".repeat(50);
+
+        let chunks = chunk(&text, 5);
+
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 5 && !chunk.is_empty()));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn room_names_are_made_safe_for_a_file_name() {
+        let path = path_for(Path::new("/tmp/logs"), "../den/ 2");
+        assert_eq!(path, Path::new("/tmp/logs/___den__2.jsonl"));
+    }
+}
@@ -1,16 +1,222 @@
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use super::connection_stats::ConnectionStats;
+use super::crypto::{secrets_match, SessionKey};
+use super::protocol::MIN_PROTOCOL_VERSION;
+use std::{
+    collections::HashSet,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The sender of everything the server says itself, nobody may log in or rename to it.
+pub const SERVER_NAME: &str = "SERVER";
+
+/// Reserved names are refused in any case, so that nobody can pass for the server.
+pub fn is_reserved_name(name: &str) -> bool {
+    name.trim().eq_ignore_ascii_case(SERVER_NAME)
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Member,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(name: &str) -> Option<Role> {
+        match name.to_lowercase().as_str() {
+            "member" => Some(Role::Member),
+            "moderator" | "mod" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PresenceStatus {
+    Online,
+    Away(Option<String>),
+}
+
+/// Server wide moderation of what a user says, which follows them from room to room.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub enum Moderation {
+    #[default]
+    Normal,
+    /// Refused chat until the time, or until pardoned.
+    Silenced(Option<DateTime<Utc>>),
+    /// Chat looks to the user like it went through, nobody else ever sees it.
+    ShadowBanned,
+}
+
+/// Users are identified by their id alone, the rest of the fields can change over the
+/// lifetime of a session without the user becoming someone else.
+#[derive(Clone, Debug)]
 pub struct User {
     pub id: String,
     pub name: String,
-    pub shared_secret: [u8; 32],
+    pub shared_secret: SessionKey,
+    pub role: Role,
+    pub logged_in_at: DateTime<Utc>,
+    pub last_active: DateTime<Utc>,
+    /// Ids of users whose messages this user does not want delivered.
+    pub ignored: HashSet<String>,
+    /// Ids of users this user refuses direct messages from.
+    pub blocked: HashSet<String>,
+    pub status: PresenceStatus,
+    pub messages_sent: u64,
+    pub peer_addr: Option<SocketAddr>,
+    /// Issued at login, every message from the client afterwards has to carry it.
+    pub session_token: String,
+    /// Gaps and regressions in the client's sequence numbers, counted by the session and
+    /// shared with every copy of the user so /whois can report them.
+    pub sequence_gaps: Arc<AtomicU64>,
+    pub moderation: Moderation,
+    /// As of the session's last report.
+    pub connection_stats: ConnectionStats,
+    /// Agreed at login, so that the App can build frames in the shape the client reads.
+    pub protocol_version: u32,
 }
 
 impl User {
-    pub fn new(id: String, name: String, shared_secret: [u8; 32]) -> Self {
+    pub fn new(id: String, name: String, shared_secret: SessionKey) -> Self {
+        let now = Utc::now();
         User {
             id,
             name,
             shared_secret,
+            role: Role::Member,
+            logged_in_at: now,
+            last_active: now,
+            ignored: HashSet::new(),
+            blocked: HashSet::new(),
+            status: PresenceStatus::Online,
+            messages_sent: 0,
+            peer_addr: None,
+            session_token: String::new(),
+            sequence_gaps: Arc::new(AtomicU64::new(0)),
+            moderation: Moderation::Normal,
+            connection_stats: ConnectionStats::default(),
+            protocol_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    pub fn sequence_gap_count(&self) -> u64 {
+        self.sequence_gaps.load(Ordering::Relaxed)
+    }
+
+    /// A user who was never issued a token matches nothing.
+    pub fn has_session_token(&self, token: Option<&str>) -> bool {
+        token.is_some_and(|given| secrets_match(&self.session_token, given))
+    }
+
+    pub fn is_moderator(&self) -> bool {
+        self.role >= Role::Moderator
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// The moderation in force now, a silence that has run out is no longer.
+    pub fn moderation_at(&self, now: DateTime<Utc>) -> &Moderation {
+        match &self.moderation {
+            Moderation::Silenced(Some(until)) if *until <= now => &Moderation::Normal,
+            moderation => moderation,
         }
     }
+
+    pub fn is_away(&self) -> bool {
+        self.status != PresenceStatus::Online
+    }
+
+    /// The name as shown in occupant lists, with any away status after it.
+    pub fn display_name(&self) -> String {
+        match &self.status {
+            PresenceStatus::Online => self.name.clone(),
+            PresenceStatus::Away(None) => format!("{} (away)", self.name),
+            PresenceStatus::Away(Some(message)) => format!("{} (away: {message})", self.name),
+        }
+    }
+
+    pub fn ignores(&self, other: &User) -> bool {
+        !self.ignored.is_empty() && self.ignored.contains(&other.id)
+    }
+
+    pub fn blocks(&self, other: &User) -> bool {
+        !self.blocked.is_empty() && self.blocked.contains(&other.id)
+    }
+
+    /// The first 8 hex characters of the session token's SHA-256, enough to tell sessions
+    /// apart without revealing the token. Empty for a user who was never issued one.
+    pub fn token_fingerprint(&self) -> String {
+        if self.session_token.is_empty() {
+            return String::new();
+        }
+        Sha256::digest(self.session_token.as_bytes())[..4]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl PartialEq for User {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for User {}
+
+impl Hash for User {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_server_name_is_reserved_in_any_case() {
+        assert!(is_reserved_name("SERVER"));
+        assert!(is_reserved_name("server"));
+        assert!(is_reserved_name(" Server "));
+        assert!(!is_reserved_name("servers"));
+    }
+
+    #[test]
+    fn only_the_issued_token_matches() {
+        let mut user = User::new("id".into(), "ann".into(), SessionKey::default());
+        assert!(!user.has_session_token(None));
+        assert!(!user.has_session_token(Some("")));
+
+        user.session_token = "0123ABCD".into();
+        assert!(user.has_session_token(Some("0123ABCD")));
+        assert!(!user.has_session_token(None));
+        assert!(!user.has_session_token(Some("0123ABCE")));
+        assert!(!user.has_session_token(Some("0123ABC")));
+        assert!(!user.has_session_token(Some("0123ABCDE")));
+    }
+
+    #[test]
+    fn the_fingerprint_follows_the_token_not_the_user() {
+        let mut user = User::new("id".into(), "ann".into(), SessionKey::default());
+        assert_eq!(user.token_fingerprint(), "");
+
+        user.session_token = "abc".into();
+        // The SHA-256 of "abc" starts ba7816bf.
+        assert_eq!(user.token_fingerprint(), "ba7816bf");
+        user.session_token = "abd".into();
+        assert_ne!(user.token_fingerprint(), "ba7816bf");
+    }
 }
@@ -0,0 +1,117 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures_channel::mpsc::{channel, Receiver, SendError, Sender};
+use futures_util::SinkExt;
+
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// How many items a full channel has turned away, shared by every clone of the sender and by
+/// whatever reports on it.
+#[derive(Debug, Clone, Default)]
+pub struct DropCounter(Arc<AtomicU64>);
+
+impl DropCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// How many items one sender has sent that the receiver hasn't finished with.
+#[derive(Debug, Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Wraps the item so that it counts as in flight until the receiver drops its permit.
+    pub fn track<T>(&self, item: T) -> Tracked<T> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Tracked {
+            item,
+            permit: InFlightPermit(self.0.clone()),
+        }
+    }
+}
+
+/// Held while an item is in flight, dropping it lets the sender have another.
+#[derive(Debug)]
+pub struct InFlightPermit(Arc<AtomicUsize>);
+
+impl Drop for InFlightPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct Tracked<T> {
+    pub item: T,
+    pub permit: InFlightPermit,
+}
+
+/// Why an item didn't go, handed back so that the sender can say something about it.
+pub enum Overflow<T> {
+    Full(T),
+    Closed(T),
+}
+
+/// The sending half of a bounded channel. Senders on a hot path use `try_send` and are never
+/// held up by a slow receiver, the few items that must get through wait with `send`.
+#[derive(Debug)]
+pub struct BoundedSender<T> {
+    sender: Sender<T>,
+    dropped: DropCounter,
+}
+
+impl<T> Clone for BoundedSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            dropped: self.dropped.clone(),
+        }
+    }
+}
+
+impl<T> BoundedSender<T> {
+    /// A full channel counts the item as dropped and gives it back.
+    pub fn try_send(&mut self, item: T) -> Result<(), Overflow<T>> {
+        match self.sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_full() => {
+                self.dropped.increment();
+                Err(Overflow::Full(e.into_inner()))
+            }
+            Err(e) => Err(Overflow::Closed(e.into_inner())),
+        }
+    }
+
+    /// Waits until there is room.
+    pub async fn send(&mut self, item: T) -> Result<(), SendError> {
+        self.sender.send(item).await
+    }
+
+    /// Counts an item the sender gave up on without offering it.
+    pub fn count_dropped(&self) {
+        self.dropped.increment();
+    }
+}
+
+pub fn bounded<T>(capacity: usize, dropped: DropCounter) -> (BoundedSender<T>, Receiver<T>) {
+    let (sender, receiver) = channel(capacity.max(1));
+    (BoundedSender { sender, dropped }, receiver)
+}
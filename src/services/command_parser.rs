@@ -0,0 +1,398 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::domain::attachment::AttachmentError;
+use crate::domain::commands::{CommandPayload, CommandRegistry};
+use crate::domain::error_reason::ErrorReason;
+
+/// Why a client message could not be turned into a CommandPayload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnknownCommand(String),
+    /// A ClientMsgBody the session has no use for, named by its variant.
+    UnexpectedMessage(String),
+    /// A second Login on a session that is already logged in.
+    AlreadyLoggedIn,
+    /// A message with nothing left in it once sanitized.
+    EmptyMessage,
+    InvalidAttachment(AttachmentError),
+    MissingArgument {
+        command: String,
+        argument: &'static str,
+    },
+    InvalidArgument {
+        command: String,
+        argument: &'static str,
+        value: String,
+    },
+    UnterminatedQuote {
+        command: String,
+    },
+}
+
+impl ParseError {
+    /// The command the user was trying to run, without the leading slash.
+    pub fn command(&self) -> &str {
+        match self {
+            ParseError::AlreadyLoggedIn => "login",
+            ParseError::EmptyMessage | ParseError::InvalidAttachment(_) => "message",
+            ParseError::UnknownCommand(command)
+            | ParseError::UnexpectedMessage(command)
+            | ParseError::MissingArgument { command, .. }
+            | ParseError::InvalidArgument { command, .. }
+            | ParseError::UnterminatedQuote { command } => command,
+        }
+    }
+
+    pub fn error_reason(&self) -> ErrorReason {
+        match self {
+            ParseError::UnknownCommand(_) => ErrorReason::UnknownCommand,
+            ParseError::UnexpectedMessage(_)
+            | ParseError::AlreadyLoggedIn
+            | ParseError::EmptyMessage
+            | ParseError::InvalidAttachment(_) => ErrorReason::MalformedMessage,
+            ParseError::MissingArgument { .. }
+            | ParseError::InvalidArgument { .. }
+            | ParseError::UnterminatedQuote { .. } => ErrorReason::InvalidArguments,
+        }
+    }
+
+    /// The registry's usage line for a known command that was given bad arguments.
+    pub fn usage(&self) -> Option<String> {
+        match self {
+            ParseError::UnknownCommand(_)
+            | ParseError::UnexpectedMessage(_)
+            | ParseError::AlreadyLoggedIn
+            | ParseError::EmptyMessage
+            | ParseError::InvalidAttachment(_) => None,
+            _ => CommandRegistry::get(self.command()).map(|spec| spec.usage()),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnknownCommand(command) => write!(f, "Unknown command /{command}"),
+            ParseError::UnexpectedMessage(body) => {
+                write!(f, "The server does not accept {body} messages here")
+            }
+            ParseError::AlreadyLoggedIn => write!(f, "You are already logged in"),
+            ParseError::EmptyMessage => write!(f, "Messages cannot be empty"),
+            ParseError::InvalidAttachment(e) => write!(f, "{e}"),
+            ParseError::MissingArgument { command, argument } => {
+                write!(f, "/{command} needs a {argument}")
+            }
+            ParseError::InvalidArgument {
+                command,
+                argument,
+                value,
+            } => write!(f, "'{value}' is not a valid {argument} for /{command}"),
+            ParseError::UnterminatedQuote { command } => {
+                write!(f, "/{command} has an unterminated quote")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The arguments following a command name. Words are split on whitespace, a double quoted
+/// word may contain spaces and `\"` for a literal quote.
+pub struct Args<'a> {
+    command: &'a str,
+    remaining: &'a str,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(command: &'a str, text: &'a str) -> Self {
+        Args {
+            command,
+            remaining: text.trim(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining.is_empty()
+    }
+
+    /// The next word, or `None` once the arguments run out.
+    pub fn word(&mut self) -> Result<Option<String>, ParseError> {
+        let text = self.remaining.trim_start();
+        if text.is_empty() {
+            self.remaining = text;
+            return Ok(None);
+        }
+
+        let Some(quoted) = text.strip_prefix('"') else {
+            let end = text.find(char::is_whitespace).unwrap_or(text.len());
+            self.remaining = &text[end..];
+            return Ok(Some(text[..end].to_string()));
+        };
+
+        let mut word = String::new();
+        let mut chars = quoted.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.remaining = &quoted[i + 1..];
+                    return Ok(Some(word));
+                }
+                '\\' => match chars.next() {
+                    Some((_, escaped)) => word.push(escaped),
+                    None => break,
+                },
+                c => word.push(c),
+            }
+        }
+        Err(ParseError::UnterminatedQuote {
+            command: self.command.to_string(),
+        })
+    }
+
+    /// The next word, which the command cannot do without.
+    pub fn required(&mut self, argument: &'static str) -> Result<String, ParseError> {
+        self.word()?.ok_or_else(|| ParseError::MissingArgument {
+            command: self.command.to_string(),
+            argument,
+        })
+    }
+
+    /// The next word parsed as a `T`, `None` if there isn't one.
+    pub fn parsed<T: FromStr>(&mut self, argument: &'static str) -> Result<Option<T>, ParseError> {
+        match self.word()? {
+            None => Ok(None),
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| ParseError::InvalidArgument {
+                    command: self.command.to_string(),
+                    argument,
+                    value,
+                }),
+        }
+    }
+
+    pub fn required_parsed<T: FromStr>(&mut self, argument: &'static str) -> Result<T, ParseError> {
+        self.parsed(argument)?
+            .ok_or_else(|| ParseError::MissingArgument {
+                command: self.command.to_string(),
+                argument,
+            })
+    }
+
+    /// Everything left as free text. A single quoted word has its quotes removed so that
+    /// `/topic "  spaced  "` keeps the spaces, anything else is kept as typed.
+    pub fn rest(&mut self) -> Result<String, ParseError> {
+        let text = self.remaining.trim();
+        self.remaining = "";
+        if text.starts_with('"') {
+            let mut quoted = Args::new(self.command, text);
+            if let Ok(Some(word)) = quoted.word() {
+                if quoted.is_empty() {
+                    return Ok(word);
+                }
+            }
+        }
+        Ok(text.to_string())
+    }
+
+    /// Free text the command cannot do without.
+    pub fn required_rest(&mut self, argument: &'static str) -> Result<String, ParseError> {
+        match self.rest()? {
+            text if text.is_empty() => Err(ParseError::MissingArgument {
+                command: self.command.to_string(),
+                argument,
+            }),
+            text => Ok(text),
+        }
+    }
+
+    pub fn invalid(&self, argument: &'static str, value: impl Into<String>) -> ParseError {
+        ParseError::InvalidArgument {
+            command: self.command.to_string(),
+            argument,
+            value: value.into(),
+        }
+    }
+}
+
+/// Parses a room message that may be a slash command. Ordinary chat gives `Ok(None)`, aliases
+/// expand into chat and anything else starting with a slash must be in the CommandRegistry.
+pub fn parse(text: &str) -> Result<Option<CommandPayload>, ParseError> {
+    let Some(command) = text.strip_prefix('/') else {
+        return Ok(None);
+    };
+    let (name, args) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+
+    if let Some(spec) = CommandRegistry::get(name) {
+        return (spec.parse)(&mut Args::new(name, args)).map(Some);
+    }
+    match CommandRegistry::get_alias(name) {
+        Some(alias) => Ok(Some(CommandPayload::RecordMessage {
+            message: alias.expand(args.trim()),
+            attachment: None,
+            msg_id: None,
+        })),
+        None => Err(ParseError::UnknownCommand(name.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Result<Vec<String>, ParseError> {
+        let mut args = Args::new("test", text);
+        std::iter::from_fn(|| args.word().transpose()).collect()
+    }
+
+    #[test]
+    fn quoted_words_keep_their_spaces_and_escapes() {
+        assert_eq!(
+            words(r#"one "two words"  "say \"hi\"" """#).unwrap(),
+            vec!["one", "two words", r#"say "hi""#, ""]
+        );
+    }
+
+    #[test]
+    fn an_unterminated_quote_is_an_error() {
+        assert_eq!(
+            parse(r#"/msg "ann smith hello"#).unwrap_err(),
+            ParseError::UnterminatedQuote {
+                command: "msg".into()
+            }
+        );
+    }
+
+    #[test]
+    fn a_quoted_name_is_one_argument_and_the_rest_is_free_text() {
+        let Some(CommandPayload::DirectMessage { to, content }) =
+            parse(r#"/msg "ann smith" see you  at "noon""#).unwrap()
+        else {
+            panic!("/msg parses to a DirectMessage");
+        };
+        assert_eq!(to, "ann smith");
+        assert_eq!(content, r#"see you  at "noon""#);
+    }
+
+    #[test]
+    fn a_missing_argument_is_named() {
+        assert_eq!(
+            parse("/msg ann").unwrap_err(),
+            ParseError::MissingArgument {
+                command: "msg".into(),
+                argument: "message",
+            }
+        );
+        assert_eq!(
+            parse("/kick").unwrap_err(),
+            ParseError::MissingArgument {
+                command: "kick".into(),
+                argument: "user",
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_commands_are_refused_and_chat_is_not_a_command() {
+        assert_eq!(
+            parse("/frobnicate now").unwrap_err(),
+            ParseError::UnknownCommand("frobnicate".into())
+        );
+        assert!(parse("hello /kick ann").unwrap().is_none());
+    }
+
+    #[test]
+    fn unicode_arguments_come_through_untouched() {
+        let Some(CommandPayload::DirectMessage { to, content }) =
+            parse("/msg Zoë 你好, ¿qué tal? 🎉").unwrap()
+        else {
+            panic!("/msg parses to a DirectMessage");
+        };
+        assert_eq!(to, "Zoë");
+        assert_eq!(content, "你好, ¿qué tal? 🎉");
+
+        assert_eq!(words("\"日本 語\" ü").unwrap(), vec!["日本 語", "ü"]);
+    }
+
+    #[test]
+    fn a_single_quoted_rest_keeps_its_inner_spaces() {
+        let Some(CommandPayload::SetTopic(topic)) = parse(r#"/topic "  spaced  ""#).unwrap() else {
+            panic!("/topic parses to SetTopic");
+        };
+        assert_eq!(topic, "  spaced  ");
+    }
+
+    #[test]
+    fn arguments_that_wont_parse_are_invalid() {
+        assert_eq!(
+            parse("/history lots").unwrap_err(),
+            ParseError::InvalidArgument {
+                command: "history".into(),
+                argument: "count",
+                value: "lots".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn every_command_with_a_required_argument_shows_its_usage_without_it() {
+        for spec in CommandRegistry::all() {
+            let error = match parse(&format!("/{}", spec.name)) {
+                Ok(_) if !spec.args.starts_with('<') => continue,
+                Ok(_) => panic!("/{} ran without its {}", spec.name, spec.args),
+                Err(error) => error,
+            };
+            assert!(
+                matches!(error, ParseError::MissingArgument { .. }),
+                "/{} gave {error:?}",
+                spec.name
+            );
+            assert_eq!(error.error_reason(), ErrorReason::InvalidArguments);
+            assert_eq!(error.usage(), Some(spec.usage()), "/{}", spec.name);
+        }
+    }
+
+    #[test]
+    fn numeric_arguments_that_wont_parse_show_the_usage() {
+        for text in [
+            "/slowmode soon",
+            "/purge all",
+            "/pin first",
+            "/mute ann forever",
+        ] {
+            let error = parse(text).unwrap_err();
+            assert!(
+                matches!(error, ParseError::InvalidArgument { .. }),
+                "{text} gave {error:?}"
+            );
+            let name = error.command().to_string();
+            assert_eq!(
+                error.usage(),
+                CommandRegistry::get(&name).map(|spec| spec.usage())
+            );
+        }
+        assert_eq!(
+            parse("/msg bob").unwrap_err().usage().as_deref(),
+            Some("/msg <user> <text>")
+        );
+    }
+
+    #[test]
+    fn history_errors_name_the_argument_its_usage_shows() {
+        let error = parse("/history 10 yesterday").unwrap_err();
+
+        assert_eq!(
+            error,
+            ParseError::InvalidArgument {
+                command: "history".into(),
+                argument: "before",
+                value: "yesterday".into(),
+            }
+        );
+        assert_eq!(error.usage().as_deref(), Some("/history [count] [before]"));
+    }
+}
@@ -0,0 +1,135 @@
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+/// Payloads smaller than this go out as they are, deflating them saves next to nothing.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Leads a deflated payload, inside the encryption. A bincode ServerMsg starts with its
+/// status as a little endian u32 of 0 or 1, so an uncompressed payload never starts with it.
+pub const DEFLATE_PREFIX: u8 = 0xFF;
+
+/// Whether a session's large payloads are deflated before they are encrypted. Nothing
+/// compressed is sent to a client that didn't ask for it at login.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    Off,
+    Deflate {
+        threshold: usize,
+    },
+}
+
+impl Compression {
+    /// What the client asked for, as far as the server allows it. A threshold of 0 means the
+    /// server has compression turned off.
+    pub fn negotiate(requested: Option<&str>, threshold: usize) -> Self {
+        match requested {
+            Some("deflate") if threshold > 0 => Compression::Deflate { threshold },
+            _ => Compression::Off,
+        }
+    }
+
+    /// The name the client is told was agreed, `None` when nothing was.
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            Compression::Off => None,
+            Compression::Deflate { .. } => Some("deflate"),
+        }
+    }
+
+    /// The payload as it should be encrypted. Anything under the threshold, or that doesn't
+    /// come out smaller, is left alone.
+    pub fn apply(self, serialized: Vec<u8>) -> Vec<u8> {
+        let Compression::Deflate { threshold } = self else {
+            return serialized;
+        };
+        if serialized.len() < threshold {
+            return serialized;
+        }
+        match deflate(&serialized) {
+            Ok(compressed) if compressed.len() + 1 < serialized.len() => {
+                let mut framed = Vec::with_capacity(compressed.len() + 1);
+                framed.push(DEFLATE_PREFIX);
+                framed.extend(compressed);
+                framed
+            }
+            Ok(_) => serialized,
+            Err(e) => {
+                log::warn!("Could not deflate a {} byte payload: {e}", serialized.len());
+                serialized
+            }
+        }
+    }
+}
+
+/// Undoes `Compression::apply`, whatever was agreed. A payload without the prefix is returned
+/// as it is.
+pub fn inflate(payload: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    let Some((&DEFLATE_PREFIX, compressed)) = payload.split_first() else {
+        return Ok(payload);
+    };
+    let mut inflated = vec![];
+    DeflateDecoder::new(compressed).read_to_end(&mut inflated)?;
+    Ok(inflated)
+}
+
+fn deflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ON: Compression = Compression::Deflate { threshold: 1024 };
+
+    #[test]
+    fn only_a_known_algorithm_on_a_server_that_allows_it_is_agreed() {
+        assert_eq!(Compression::negotiate(Some("deflate"), 1024), ON);
+        assert_eq!(Compression::negotiate(Some("deflate"), 0), Compression::Off);
+        assert_eq!(Compression::negotiate(Some("zstd"), 1024), Compression::Off);
+        assert_eq!(Compression::negotiate(None, 1024), Compression::Off);
+    }
+
+    #[test]
+    fn a_large_payload_is_deflated_and_inflates_back() {
+        let payload = "the same line of chat again\n".repeat(100).into_bytes();
+
+        let applied = ON.apply(payload.clone());
+
+        assert_eq!(applied[0], DEFLATE_PREFIX);
+        assert!(applied.len() < payload.len() / 4);
+        assert_eq!(inflate(applied).unwrap(), payload);
+    }
+
+    #[test]
+    fn small_and_incompressible_payloads_go_as_they_are() {
+        let small = vec![0u8; 1023];
+        assert_eq!(ON.apply(small.clone()), small);
+
+        // Bytes that deflate can't make any smaller.
+        let mut state = 0x2545_f491_u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        assert_eq!(ON.apply(noise.clone()), noise);
+
+        let large = vec![0u8; 4096];
+        assert_eq!(Compression::Off.apply(large.clone()), large);
+    }
+
+    #[test]
+    fn an_uncompressed_payload_passes_through_inflate() {
+        let payload = vec![1, 0, 0, 0, 42];
+        assert_eq!(inflate(payload.clone()).unwrap(), payload);
+        assert_eq!(inflate(vec![]).unwrap(), Vec::<u8>::new());
+    }
+}
@@ -0,0 +1,225 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use marain_api::prelude::ServerMsgBody;
+
+/// Upper bounds of the size buckets in bytes, anything bigger lands in the last bucket.
+pub const BUCKET_BOUNDS: [u64; 6] = [256, 1024, 4096, 16 * 1024, 64 * 1024, 256 * 1024];
+
+/// Which kind of ServerMsg a frame carried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    Empty,
+    LoginSuccess,
+    RoomData,
+    ChatRecv,
+}
+
+impl BodyKind {
+    pub const ALL: [BodyKind; 4] = [
+        BodyKind::Empty,
+        BodyKind::LoginSuccess,
+        BodyKind::RoomData,
+        BodyKind::ChatRecv,
+    ];
+
+    pub fn of(body: &ServerMsgBody) -> Self {
+        match body {
+            ServerMsgBody::Empty => BodyKind::Empty,
+            ServerMsgBody::LoginSuccess { .. } => BodyKind::LoginSuccess,
+            ServerMsgBody::RoomData { .. } => BodyKind::RoomData,
+            ServerMsgBody::ChatRecv { .. } => BodyKind::ChatRecv,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BodyKind::Empty => "Empty",
+            BodyKind::LoginSuccess => "LoginSuccess",
+            BodyKind::RoomData => "RoomData",
+            BodyKind::ChatRecv => "ChatRecv",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Counts of sizes by bucket, with their total and the largest seen. Recording is a handful
+/// of atomic adds, nothing is allocated or locked.
+#[derive(Debug, Default)]
+pub struct SizeHistogram {
+    buckets: [AtomicU64; BUCKET_BOUNDS.len() + 1],
+    count: AtomicU64,
+    total_bytes: AtomicU64,
+    max_bytes: AtomicU64,
+}
+
+impl SizeHistogram {
+    pub fn record(&self, bytes: usize) {
+        let bytes = bytes as u64;
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|bound| bytes <= *bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.max_bytes.fetch_max(bytes, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes.load(Ordering::Relaxed)
+    }
+
+    /// How many sizes fell in each bucket, in the order of `BUCKET_BOUNDS` with the overflow
+    /// bucket last.
+    pub fn buckets(&self) -> [u64; BUCKET_BOUNDS.len() + 1] {
+        std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for SizeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.count();
+        let mean = self.total_bytes().checked_div(count).unwrap_or_default();
+        write!(
+            f,
+            "{count} frame(s), mean {mean} byte(s), max {} byte(s), buckets {:?}",
+            self.max_bytes(),
+            self.buckets()
+        )
+    }
+}
+
+/// Sizes of one kind of message, before and after encryption.
+#[derive(Debug, Default)]
+pub struct BodyMetrics {
+    pub serialized: SizeHistogram,
+    pub encrypted: SizeHistogram,
+}
+
+/// The sizes of everything the server has sent since it started, by kind of message.
+#[derive(Debug, Default)]
+pub struct FrameMetrics {
+    bodies: [BodyMetrics; BodyKind::ALL.len()],
+}
+
+impl FrameMetrics {
+    pub fn body(&self, kind: BodyKind) -> &BodyMetrics {
+        &self.bodies[kind.index()]
+    }
+
+    pub fn record_serialized(&self, kind: BodyKind, bytes: usize) {
+        self.body(kind).serialized.record(bytes);
+    }
+
+    pub fn record_encrypted(&self, kind: BodyKind, bytes: usize) {
+        self.body(kind).encrypted.record(bytes);
+    }
+
+    /// One line per kind of message that has been sent at all.
+    pub fn report(&self) -> String {
+        let lines: Vec<String> = BodyKind::ALL
+            .into_iter()
+            .filter(|kind| self.body(*kind).serialized.count() > 0)
+            .map(|kind| {
+                let body = self.body(kind);
+                format!(
+                    "{}: serialized {}; encrypted {}",
+                    kind.name(),
+                    body.serialized,
+                    body.encrypted
+                )
+            })
+            .collect();
+        if lines.is_empty() {
+            return "Nothing has been sent yet".to_string();
+        }
+        lines.join("\n")
+    }
+}
+
+static FRAME_METRICS: OnceLock<FrameMetrics> = OnceLock::new();
+
+/// Shared by every session and whatever reports on them.
+pub fn frame_metrics() -> &'static FrameMetrics {
+    FRAME_METRICS.get_or_init(FrameMetrics::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::*;
+    use crate::domain::crypto::SessionKey;
+    use crate::domain::notification_log::NotificationLog;
+    use crate::services::message_builder::SocketSendAdaptor;
+
+    #[test]
+    fn sizes_fall_in_the_first_bucket_that_holds_them() {
+        let histogram = SizeHistogram::default();
+        for bytes in [0, 256, 257, 5000, 1 << 20] {
+            histogram.record(bytes);
+        }
+
+        assert_eq!(histogram.buckets(), [2, 1, 0, 1, 0, 0, 1]);
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.total_bytes(), 256 + 257 + 5000 + (1 << 20));
+        assert_eq!(histogram.max_bytes(), 1 << 20);
+    }
+
+    #[test]
+    fn the_report_covers_only_what_has_been_sent() {
+        let metrics = FrameMetrics::default();
+        assert_eq!(metrics.report(), "Nothing has been sent yet");
+
+        metrics.record_serialized(BodyKind::ChatRecv, 100);
+        metrics.record_encrypted(BodyKind::ChatRecv, 128);
+
+        assert_eq!(
+            metrics.report(),
+            "ChatRecv: serialized 1 frame(s), mean 100 byte(s), max 100 byte(s), buckets [1, 0, 0, 0, 0, 0, 0]; \
+             encrypted 1 frame(s), mean 128 byte(s), max 128 byte(s), buckets [1, 0, 0, 0, 0, 0, 0]"
+        );
+    }
+
+    #[test]
+    fn sending_records_sizes_by_kind_of_message() {
+        // Other tests send through the same registry, so only growth can be relied on.
+        let chat = frame_metrics().body(BodyKind::ChatRecv);
+        let empty = frame_metrics().body(BodyKind::Empty);
+        let (chats, encrypted_chats, empties) = (
+            chat.serialized.count(),
+            chat.encrypted.count(),
+            empty.serialized.count(),
+        );
+        let key = SessionKey::from_bytes([3; 32]);
+
+        let notice = NotificationLog::new("x".repeat(300_000));
+        let Message::Binary(sealed) = SocketSendAdaptor::prepare_send_notice(&key, notice).unwrap()
+        else {
+            panic!("sealed frames are binary");
+        };
+        SocketSendAdaptor::prepare_send_time(&key, None, Utc::now().into(), None).unwrap();
+        SocketSendAdaptor::on_login_failed().unwrap();
+
+        assert!(chat.serialized.count() >= chats + 2);
+        assert!(chat.encrypted.count() >= encrypted_chats + 2);
+        assert!(empty.serialized.count() > empties);
+        assert!(chat.serialized.max_bytes() >= 300_000);
+        assert!(chat.encrypted.max_bytes() >= sealed.len() as u64);
+        assert!(chat.serialized.buckets()[BUCKET_BOUNDS.len()] >= 1);
+    }
+}
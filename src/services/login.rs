@@ -1,24 +1,37 @@
-use chrono::Utc;
-use futures_channel::mpsc::UnboundedSender;
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
 use log::info;
-use marain_api::prelude::{ClientMsg, ClientMsgBody, ServerMsg, ServerMsgBody, Status, Timestamp};
+use marain_api::prelude::{ClientMsg, ClientMsgBody};
+use std::net::SocketAddr;
 
 use rand_core::OsRng;
 
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio_tungstenite::{
+    tungstenite::{protocol::WebSocketConfig, Message},
+    WebSocketStream,
+};
 
 use anyhow::{anyhow, Result};
 use uuid::Uuid;
 use x25519_dalek::{PublicKey, ReusableSecret};
 
-use crate::{domain::{commands::Command, user::User}, workers::user_session::SessionWorker};
+use crate::{
+    domain::{
+        crypto::SessionKey,
+        protocol,
+        user::{is_reserved_name, Role, User},
+    },
+    workers::app_gateway::GatewaySink,
+    workers::user_session::{JsonExtras, SessionWorker, WireFormat},
+};
 
+use super::compression::Compression;
 use super::message_builder::SocketSendAdaptor;
+use super::outbound::OutboundWriter;
+use super::server_config::{AdminToken, ServerConfig};
 
 type KeyPair = (ReusableSecret, PublicKey);
 
@@ -36,12 +49,7 @@ pub fn create_key_pair() -> (ReusableSecret, PublicKey) {
 
     (ss, server_public)
 }
-pub async fn setup_listener() -> TcpListener {
-    let mut port = getenv("MARAIN_PORT");
-    if port.len() == 0 {
-        port = "8080".to_string();
-        log::warn!("Could not find MARAIN_PORT environment variable. Falling back to 8080.");
-    }
+pub async fn setup_listener(port: u16) -> TcpListener {
     let addr = format!("0.0.0.0:{}", port);
     let try_socket = TcpListener::bind(&addr).await;
     let listener = try_socket.expect("Failed to bind");
@@ -49,9 +57,17 @@ pub async fn setup_listener() -> TcpListener {
     listener
 }
 
-pub async fn handle_initial_connection(stream: TcpStream) -> SplitSocket {
-    let user_addr = stream.peer_addr().unwrap().to_string();
-    let ws_stream = tokio_tungstenite::accept_async(stream)
+pub async fn handle_initial_connection(stream: TcpStream, config: &ServerConfig) -> SplitSocket {
+    let peer_addr = stream.peer_addr().unwrap();
+    let user_addr = peer_addr.to_string();
+    // The session refuses anything over the limit itself, so that a client gets a few strikes.
+    // Frames many times larger aren't buffered at all, tungstenite ends the connection.
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(config.max_frame_bytes * 4),
+        max_frame_size: Some(config.max_frame_bytes * 4),
+        ..Default::default()
+    };
+    let ws_stream = tokio_tungstenite::accept_async_with_config(stream, Some(ws_config))
         .await
         .expect("Error during the websocket handshake occurred");
     info!("Websocket connection from: {}", user_addr,);
@@ -60,26 +76,21 @@ pub async fn handle_initial_connection(stream: TcpStream) -> SplitSocket {
     SplitSocket {
         sink: ws_sink,
         source: ws_source,
+        peer_addr: Some(peer_addr),
     }
 }
 
 pub struct SplitSocket {
     pub sink: SplitSink<WebSocketStream<TcpStream>, Message>,
     pub source: SplitStream<WebSocketStream<TcpStream>>,
+    pub peer_addr: Option<SocketAddr>,
 }
 
 pub fn on_login_failed(mut socket_sink: SplitSink<WebSocketStream<TcpStream>, Message>) {
     tokio::spawn(async move {
-        let login_fail = ServerMsg {
-            status: Status::JustNo,
-            timestamp: Timestamp::from(Utc::now()),
-            body: ServerMsgBody::Empty,
-        };
-
-        socket_sink
-            .send(Message::Binary(bincode::serialize(&login_fail).unwrap()))
-            .await
-            .unwrap_or(());
+        if let Ok(login_fail) = SocketSendAdaptor::on_login_failed() {
+            socket_sink.send(login_fail).await.unwrap_or(());
+        }
         socket_sink.close().await.unwrap_or(());
     });
 }
@@ -89,56 +100,103 @@ pub async fn on_login_success(
     mut sink: SplitSink<WebSocketStream<TcpStream>, Message>,
     source: SplitStream<WebSocketStream<TcpStream>>,
     server_public_key: PublicKey,
-    gateway_sink: UnboundedSender<Command>,
+    gateway_sink: GatewaySink,
+    config: &ServerConfig,
 ) -> Result<SessionWorker> {
-    let login_success_response =
-        SocketSendAdaptor::on_login_success(user.id.clone(), server_public_key.to_bytes())?;
+    let login_success_response = SocketSendAdaptor::on_login_success(
+        user.session_token.clone(),
+        server_public_key.to_bytes(),
+    )?;
 
-    match sink.send(login_success_response).await {
-        Err(e) => {
-            return Err(anyhow!(
-                "Failed to send successful login response: Error: {e}"
-            ));
-        }
-        _ => {}
+    if let Err(e) = sink.send(login_success_response).await {
+        return Err(anyhow!(
+            "Failed to send successful login response: Error: {e}"
+        ));
     };
 
-    let session_worker = SessionWorker::new(user, gateway_sink, sink, source);
+    let outbound = OutboundWriter::spawn(
+        sink,
+        user.id.clone(),
+        config.write_batch,
+        config.flush_millis,
+    );
+    let session_worker = SessionWorker::new(user, gateway_sink, outbound, source)
+        .accept_json_frames(config.accept_json)
+        .with_max_frame_bytes(config.max_frame_bytes)
+        .with_max_outbound_frame_bytes(config.max_outbound_frame_bytes)
+        .with_max_frame_strikes(config.max_frame_strikes)
+        .with_frame_rate(config.frame_rate, config.frame_burst)
+        .with_max_in_flight(config.max_in_flight)
+        .with_keepalive(config.keepalive())
+        .with_idle_timeout(config.idle_timeout())
+        .with_max_attachment_bytes(config.max_attachment_bytes)
+        .with_plaintext_policy(config.plaintext_policy)
+        // The LoginSuccess above gave the client the server's key.
+        .key_established();
 
     Ok(session_worker)
 }
 
+/// A login normally carries no token. Presenting the admin token instead logs the user in
+/// as an admin, any other token is the session token of an earlier login that the client
+/// wants to resume.
+fn login_role(token: Option<String>, admin_token: &AdminToken) -> (Role, Option<String>) {
+    match token {
+        None => (Role::Member, None),
+        Some(t) if admin_token.matches(&t) => (Role::Admin, None),
+        resume_token => (Role::Member, resume_token),
+    }
+}
+
 /// handle_login_attempt consumes a deserialised login message and takes care of key shared
 /// secret management.
 pub async fn handle_login_attempt(
     login_msg: ClientMsg,
     socket_sink: SplitSink<WebSocketStream<TcpStream>, Message>,
     socket_source: SplitStream<WebSocketStream<TcpStream>>,
-    gateway_sink: UnboundedSender<Command>,
-    server_secret: ReusableSecret,
-    server_public_key: PublicKey,
+    gateway_sink: GatewaySink,
+    key_pair: KeyPair,
+    peer_addr: Option<SocketAddr>,
+    config: &ServerConfig,
 ) -> Result<SessionWorker> {
+    let (server_secret, server_public_key) = key_pair;
     // Deserialise the initial login message from a client.
     if let ClientMsg {
-        token: None,
+        token,
         body: ClientMsgBody::Login(uname, client_public_key), // Unpack a users public key here
         ..
     } = login_msg
     {
+        if is_reserved_name(&uname) {
+            on_login_failed(socket_sink);
+            return Err(anyhow!("Login failed: {uname} is a reserved name"));
+        }
+        let (role, resume_token) = login_role(token, &config.admin_token);
         let name = uname;
         let public_key = PublicKey::from(client_public_key);
         let id = format!("{:X}", Uuid::new_v4().as_u128());
 
-        let shared_secret = *server_secret.diffie_hellman(&public_key).as_bytes();
+        let shared_secret =
+            SessionKey::from_key_exchange(server_secret.diffie_hellman(&public_key));
+        let mut user = User::new(id, name, shared_secret);
+        user.role = role;
+        user.peer_addr = peer_addr;
+        user.session_token = format!("{:X}", Uuid::new_v4().as_u128());
 
         on_login_success(
-            User::new(id, name, shared_secret),
+            user,
             socket_sink,
             socket_source,
             server_public_key,
             gateway_sink,
+            config,
         )
         .await
+        .map(|session| {
+            session
+                .resuming(resume_token)
+                .with_server_keys(server_secret, server_public_key)
+        })
     } else {
         on_login_failed(socket_sink);
         Err(anyhow!(
@@ -152,59 +210,111 @@ pub async fn handle_login_attempt(
 /// establishing the websocket connection.
 pub async fn handle_client_initiation(
     mut socket_source: SplitStream<WebSocketStream<TcpStream>>,
-    sink: SplitSink<WebSocketStream<TcpStream>, Message>,
+    mut sink: SplitSink<WebSocketStream<TcpStream>, Message>,
     server_secret: ReusableSecret,
     server_public_key: PublicKey,
-    gateway_sink: UnboundedSender<Command>,
+    gateway_sink: GatewaySink,
+    peer_addr: Option<SocketAddr>,
+    config: &ServerConfig,
 ) -> Result<SessionWorker> {
-    match socket_source.next().await {
-        Some(Ok(Message::Binary(data))) => {
-            let deserialized: ClientMsg = match bincode::deserialize(&data[..]) {
-                Ok(m) => m,
-                Err(e) => {
-                    let err_msg =
-                        format!("Error during user client initiation, unrecognised message: {e}");
-                    log::error!("{err_msg}");
-                    return Err(anyhow!("{err_msg}"));
-                }
-            };
-
-            return handle_login_attempt(
-                deserialized,
-                sink,
-                socket_source,
-                gateway_sink,
-                server_secret,
-                server_public_key,
-            )
-            .await;
-        }
+    // The login frame's type tells us what the client would rather send from here on.
+    // Either kind can say which protocol version it speaks, or ask for compression, in the
+    // extras. A binary login that won't decode has none.
+    let (deserialized, format, extras) = match socket_source.next().await {
+        Some(Ok(Message::Binary(data))) => match SessionWorker::deserialize(&data) {
+            Ok((client_msg, extras)) => (Ok(client_msg), WireFormat::Bincode, extras),
+            Err(e) => (Err(e), WireFormat::Bincode, JsonExtras::default()),
+        },
+        Some(Ok(Message::Text(text))) if config.accept_json => (
+            serde_json::from_str::<ClientMsg>(&text).map_err(|e| anyhow!("{e}")),
+            WireFormat::Json,
+            JsonExtras::read(&text),
+        ),
         _ => {
             log::error!("Could not read inbound connection from user");
             return Err(anyhow!("Could not read inbound connection from user"));
         }
-    }
+    };
+    // Checked before the message is looked at, a client on another version may well have
+    // sent something that doesn't decode.
+    let version = match protocol::negotiate(extras.protocol_version) {
+        Ok(version) => version,
+        Err(claimed) => {
+            log::warn!("Refused a login speaking protocol version {claimed}");
+            sink.send(SocketSendAdaptor::on_unsupported_version(claimed))
+                .await
+                .unwrap_or(());
+            sink.close().await.unwrap_or(());
+            return Err(anyhow!(
+                "Login failed: Unsupported protocol version {claimed}"
+            ));
+        }
+    };
+    let deserialized = match deserialized {
+        Ok(m) => m,
+        Err(e) => {
+            let err_msg = format!("Error during user client initiation, unrecognised message: {e}");
+            log::error!("{err_msg}");
+            return Err(anyhow!("{err_msg}"));
+        }
+    };
+
+    handle_login_attempt(
+        deserialized,
+        sink,
+        socket_source,
+        gateway_sink,
+        (server_secret, server_public_key),
+        peer_addr,
+        config,
+    )
+    .await
+    .map(|session| {
+        session
+            .with_inbound_format(format)
+            .with_protocol_version(version)
+            .with_version_claimed(extras.protocol_version.is_some())
+            .with_compression(Compression::negotiate(
+                extras.compression.as_deref(),
+                config.compression_threshold,
+            ))
+    })
 }
 
 pub async fn login_handshake(
     socket: SplitSocket,
-    gateway_sink: UnboundedSender<Command>,
+    gateway_sink: GatewaySink,
     key_pair: KeyPair,
+    config: &ServerConfig,
 ) -> Result<SessionWorker> {
     // Generate a key pair for the server
     let (server_secret, server_public) = key_pair;
-    let SplitSocket { sink, source } = socket;
+    let SplitSocket {
+        sink,
+        source,
+        peer_addr,
+    } = socket;
 
-    handle_client_initiation(source, sink, server_secret, server_public, gateway_sink).await
+    handle_client_initiation(
+        source,
+        sink,
+        server_secret,
+        server_public,
+        gateway_sink,
+        peer_addr,
+        config,
+    )
+    .await
 }
 
 pub async fn spawn_user_session(
     stream: TcpStream,
-    gateway_sink: UnboundedSender<Command>,
+    gateway_sink: GatewaySink,
     key_pair: KeyPair,
+    config: &ServerConfig,
 ) -> Result<()> {
-    let split_socket = handle_initial_connection(stream).await;
-    let mut user_session = login_handshake(split_socket, gateway_sink, key_pair).await?;
+    let split_socket = handle_initial_connection(stream, config).await;
+    let mut user_session = login_handshake(split_socket, gateway_sink, key_pair, config).await?;
     tokio::spawn(async move {
         if let Err(e) = user_session.run().await {
             log::error!("User session quit unexpectedly with error: {e}");
@@ -1,34 +1,126 @@
-use chrono::Utc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Utc};
 
 use marain_api::prelude::{ChatMsg, Notification, ServerMsg, ServerMsgBody, Status, Timestamp};
 
-use sphinx::prelude::{cbc_encode, get_rng};
+use sphinx::prelude::{cbc_decode, cbc_encode, get_rng};
 use tokio_tungstenite::tungstenite::Message;
 
 use crate::domain::{
-    chat_log::MessageLog, notification_log::NotificationLog, room::Room, user::User,
+    chat_log::{HistoryPage, HistoryQuery, MessageId, MessageLog},
+    commands::{CommandRegistry, RequestId},
+    crypto::SessionKey,
+    error_reason::ErrorReason,
+    events::RoomSnapshot,
+    notification_log::{NotificationLog, Severity},
+    protocol::{
+        MESSAGE_IDS_VERSION, MIN_PROTOCOL_VERSION, OCCUPANT_DELTAS_VERSION, PROTOCOL_VERSION,
+    },
+    room::Room,
+    transcript,
+    user::{PresenceStatus, User, SERVER_NAME},
 };
+use crate::services::command_parser::ParseError;
+use crate::services::compression::{self, Compression};
+use crate::services::frame_metrics::{frame_metrics, BodyKind};
+use crate::services::wire_trace;
 
 use anyhow::{anyhow, Result};
+use bincode::Options;
+
+/// Responses that would serialize bigger than this are sent in chunks.
+pub const DEFAULT_MAX_OUTBOUND_FRAME_BYTES: usize = 64 * 1024;
+/// Left spare in each chunk for its tag and what encryption adds.
+const CHUNK_HEADROOM_BYTES: usize = 256;
+
+/// Shared by every session, so that a client can tell one chunked response from another.
+static NEXT_STREAM_ID: AtomicU64 = AtomicU64::new(1);
+/// How many messages have gone out as the bare refusal because they wouldn't serialize.
+static FALLBACK_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+/// Messages replaced by a bare refusal since the server started.
+pub fn fallback_frames_sent() -> u64 {
+    FALLBACK_FRAMES.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Makes the next message serialized on this thread fail, nothing else can.
+    static FAIL_NEXT_SERIALIZE: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
 
 pub struct SocketSendAdaptor;
 
 impl SocketSendAdaptor {
     pub fn serialized_server_msg(s: ServerMsg) -> Result<Vec<u8>> {
-        let serialized = match bincode::serialize(&s) {
+        SocketSendAdaptor::serialize_ref(&s)
+    }
+
+    /// A message that won't serialize is logged and replaced by a bare refusal, so the client
+    /// is told something went wrong rather than left waiting.
+    fn serialize_ref(s: &ServerMsg) -> Result<Vec<u8>> {
+        let serialized = match SocketSendAdaptor::bincode_serialize(s) {
             Ok(ser) => ser,
             Err(e) => {
-                return Err(
-                    anyhow!(
-                "Bincode::serialize failed with Error: {e:?}. Failed serializing ServerMsg: {s:?}"),
+                log::error!(
+                    "Bincode::serialize failed with Error: {e:?}. Failed serializing ServerMsg: {s:?}"
                 );
+                let fallback = ServerMsg {
+                    status: Status::JustNo,
+                    timestamp: Timestamp::from(Utc::now()),
+                    body: ServerMsgBody::Empty,
+                };
+                let serialized = bincode::serialize(&fallback).map_err(|fallback_error| {
+                    anyhow!("Could not serialize the fallback either: {fallback_error:?}")
+                })?;
+                FALLBACK_FRAMES.fetch_add(1, Ordering::Relaxed);
+                frame_metrics().record_serialized(BodyKind::Empty, serialized.len());
+                return Ok(serialized);
             }
         };
+        wire_trace::encoded(s, serialized.len());
+        frame_metrics().record_serialized(BodyKind::of(&s.body), serialized.len());
 
         Ok(serialized)
     }
 
-    pub fn encrypt_message(key: &[u8; 32], serialized: Vec<u8>) -> Result<Message> {
+    fn bincode_serialize(s: &ServerMsg) -> bincode::Result<Vec<u8>> {
+        #[cfg(test)]
+        if FAIL_NEXT_SERIALIZE.with(|fail| fail.replace(false)) {
+            return Err(Box::new(bincode::ErrorKind::Custom(
+                "failure forced by a test".into(),
+            )));
+        }
+        bincode::serialize(s)
+    }
+
+    /// One message for many keys, serialized once and encrypted per recipient. A recipient
+    /// whose frame can't be encrypted is left out, and logged, rather than failing the rest.
+    pub fn prepare_broadcast(
+        msg: &ServerMsg,
+        recipients: &[(String, SessionKey)],
+    ) -> Result<Vec<(String, Message)>> {
+        let kind = BodyKind::of(&msg.body);
+        let serialized = SocketSendAdaptor::serialize_ref(msg)?;
+        Ok(recipients
+            .iter()
+            .filter_map(|(user_id, key)| {
+                match SocketSendAdaptor::encrypt_message(key, serialized.clone()) {
+                    Ok(frame) => {
+                        frame_metrics().record_encrypted(kind, frame.len());
+                        Some((user_id.clone(), frame))
+                    }
+                    Err(e) => {
+                        log::warn!("Could not encrypt a broadcast for {user_id}: {e}");
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+
+    pub fn encrypt_message(key: &SessionKey, serialized: Vec<u8>) -> Result<Message> {
         let rng = get_rng();
         match cbc_encode(key.to_vec(), serialized, rng) {
             Ok(enc) => Ok(Message::Binary(enc)),
@@ -36,41 +128,422 @@ impl SocketSendAdaptor {
         }
     }
 
+    /// What `encrypt_message` was given. Anything that isn't a whole ciphertext for this key,
+    /// truncated for one, is an error. CBC carries no MAC, so not all tampering is caught
+    /// here and a tampered frame can decrypt to something else.
+    pub fn decrypt_message(key: &SessionKey, encrypted: Vec<u8>) -> Result<Vec<u8>> {
+        cbc_decode(key.to_vec(), encrypted).map_err(|e| anyhow!("Decryption error: {e:?}"))
+    }
+
+    /// Reads back a frame as a client would, decrypted, inflated if it was deflated and
+    /// deserialized. Bytes left over after the message are an error, a tampered final block
+    /// can otherwise leave the original message followed by garbage.
+    pub fn read_server_msg(key: &SessionKey, frame: Message) -> Result<ServerMsg> {
+        let Message::Binary(encrypted) = frame else {
+            return Err(anyhow!("Expected a binary frame"));
+        };
+        let decrypted = SocketSendAdaptor::decrypt_message(key, encrypted)?;
+        let serialized = compression::inflate(decrypted)
+            .map_err(|e| anyhow!("Could not inflate a payload: {e}"))?;
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .deserialize::<ServerMsg>(&serialized)
+            .map_err(|e| anyhow!("Deserialization error: {e}"))
+    }
+
+    /// A response too big for one frame, split where it can be so that each frame stays under
+    /// `max_frame_bytes`. Every frame leads with `chunk <stream id> <seq>/<total>` and a
+    /// last frame says the stream is done, live chat sent between the chunks has no tag so
+    /// can't be taken for one.
+    pub fn prepare_send_chunked(
+        key: &SessionKey,
+        server_msgs: Vec<ServerMsg>,
+        max_frame_bytes: usize,
+        compression: Compression,
+    ) -> Result<Vec<Message>> {
+        let stream_id = NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed);
+        let max_part_bytes = max_frame_bytes.saturating_sub(CHUNK_HEADROOM_BYTES).max(1);
+        let mut parts = vec![];
+        for server_msg in server_msgs {
+            parts.extend(ServerMsgFactory::split_to_fit(server_msg, max_part_bytes)?);
+        }
+        let total = parts.len();
+        log::debug!("Sending a response as {total} chunk(s) in stream {stream_id}");
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(seq, part)| ServerMsgFactory::tag_chunk(part, stream_id, seq + 1, total))
+            .chain(std::iter::once(ServerMsgFactory::build_chunks_done(
+                stream_id, total,
+            )))
+            .map(|server_msg| SocketSendAdaptor::seal(key, server_msg, compression))
+            .collect()
+    }
+
+    /// One frame when it fits, chunks when it doesn't.
+    fn prepare_send_sized(
+        key: &SessionKey,
+        server_msg: ServerMsg,
+        max_frame_bytes: usize,
+        compression: Compression,
+    ) -> Result<Vec<Message>> {
+        // One that can't be sized can't be serialized either, and gets the fallback below.
+        if ServerMsgFactory::serialized_size(&server_msg).is_ok_and(|size| size > max_frame_bytes) {
+            return SocketSendAdaptor::prepare_send_chunked(
+                key,
+                vec![server_msg],
+                max_frame_bytes,
+                compression,
+            );
+        }
+        Ok(vec![SocketSendAdaptor::seal(key, server_msg, compression)?])
+    }
+
+    /// Serialized, deflated first if the session agreed to it, and encrypted, with the sizes
+    /// recorded on the way. `Compression::Off` for the payloads that never get big.
+    fn seal(key: &SessionKey, server_msg: ServerMsg, compression: Compression) -> Result<Message> {
+        let kind = BodyKind::of(&server_msg.body);
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, compression.apply(serialized))?;
+        frame_metrics().record_encrypted(kind, encrypted.len());
+        Ok(encrypted)
+    }
+
     pub fn on_login_success(token: String, public_key: [u8; 32]) -> Result<Message> {
         let server_msg = ServerMsgFactory::build_login_success_server_msg(token, public_key);
         let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
         Ok(Message::Binary(serialized))
     }
 
-    pub fn prepare_send_msg_log(msg: MessageLog, user: &User, key: &[u8; 32]) -> Result<Message> {
-        let server_msg = ServerMsgFactory::build_msg_log_server_msg(msg, user);
+    /// There is no shared secret yet when a login fails, so this is the one other reply
+    /// besides a login success that goes out unencrypted.
+    pub fn on_login_failed() -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_login_failed_server_msg();
         let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
-        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
-        Ok(encrypted)
+        Ok(Message::Binary(serialized))
     }
 
-    pub fn prepare_send_time(key: &[u8; 32], t: Timestamp) -> Result<Message> {
-        let server_msg = ServerMsgFactory::build_time_server_msg(t);
-        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
-        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
-        Ok(encrypted)
+    /// Plain JSON in a text frame, so that a client too old or too new to decode anything else
+    /// can still say why it was turned away.
+    pub fn on_unsupported_version(client_version: u32) -> Message {
+        Message::Text(format!(
+            "{{\"error\":\"unsupported_protocol_version\",\"client_version\":{client_version},\"min_version\":{MIN_PROTOCOL_VERSION},\"max_version\":{PROTOCOL_VERSION},\"message\":\"This server speaks protocol versions {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}, please update your client\"}}"
+        ))
     }
 
-    pub fn room_data_response(
-        key: &[u8; 32],
-        chat_logs: Vec<MessageLog>,
-        notifications: Vec<NotificationLog>,
-        occupants: Vec<String>,
+    /// The binary counterpart of the text frame below, an encrypted notice reading
+    /// `protocol <agreed> server <latest>`, then `compression <name>` if one was agreed.
+    pub fn prepare_send_protocol_notice(
+        key: &SessionKey,
+        version: u32,
+        compression: Compression,
+    ) -> Result<Message> {
+        let compression = match compression.name() {
+            Some(name) => format!(" compression {name}"),
+            None => String::new(),
+        };
+        SocketSendAdaptor::prepare_send_notice(
+            key,
+            NotificationLog::new(format!(
+                "protocol {version} server {PROTOCOL_VERSION}{compression}"
+            )),
+        )
+    }
+
+    /// LoginSuccess has no field for it, so JSON clients are told the server's version in a
+    /// text frame of its own, along with the compression agreed if there is one.
+    pub fn prepare_send_protocol_version(version: u32, compression: Compression) -> Message {
+        let compression = match compression.name() {
+            Some(name) => format!(",\"compression\":\"{name}\""),
+            None => String::new(),
+        };
+        Message::Text(format!(
+            "{{\"protocol_version\":{version},\"server_version\":{PROTOCOL_VERSION}{compression}}}"
+        ))
+    }
+
+    pub fn prepare_send_msg_log(
+        msg: MessageLog,
+        key: &SessionKey,
+        protocol_version: u32,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_msg_log_server_msg(msg, protocol_version);
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    /// Sealed by the App with the key it holds for the recipient, their session only passes
+    /// the frame on.
+    pub fn prepare_send_direct(
+        recipient_key: &SessionKey,
+        from: &User,
+        content: String,
+        ts: Timestamp,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_direct_msg(from, content, ts);
+        SocketSendAdaptor::seal(recipient_key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_time(
+        key: &SessionKey,
+        client_ts: Option<Timestamp>,
+        server_ts: Timestamp,
+        request_id: Option<RequestId>,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::tag_request(
+            ServerMsgFactory::build_time_server_msg(client_ts, server_ts),
+            request_id,
+        );
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_pong(
+        key: &SessionKey,
+        client_ts: Option<Timestamp>,
+        received_at: Timestamp,
+        request_id: Option<RequestId>,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::tag_request(
+            ServerMsgFactory::build_pong(client_ts, received_at),
+            request_id,
+        );
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_motd(key: &SessionKey, motd: String) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_motd(motd);
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_notification(key: &SessionKey, notice: NotificationLog) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_notification_server_msg(notice);
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_occupant_update(
+        key: &SessionKey,
+        joined: Vec<String>,
+        left: Vec<String>,
+        epoch: u64,
+        ts: Timestamp,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_occupant_update(joined, left, epoch, ts);
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_presence_update(
+        key: &SessionKey,
+        username: &str,
+        status: &PresenceStatus,
+        ts: Timestamp,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_presence_update(username, status, ts);
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_announcement(key: &SessionKey, notice: NotificationLog) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_announcement(notice);
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_notice(key: &SessionKey, notice: NotificationLog) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_notice_server_msg(notice);
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_reply(
+        key: &SessionKey,
+        notice: NotificationLog,
+        request_id: Option<RequestId>,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::tag_request(
+            ServerMsgFactory::build_notice_server_msg(notice),
+            request_id,
+        );
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_rejection(
+        key: &SessionKey,
+        reason: String,
+        request_id: Option<RequestId>,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::tag_request(
+            ServerMsgFactory::build_rejection_server_msg(reason),
+            request_id,
+        );
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_error(
+        key: &SessionKey,
+        reason: ErrorReason,
+        detail: String,
+        request_id: Option<RequestId>,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::tag_request(
+            ServerMsgFactory::build_error(reason, detail),
+            request_id,
+        );
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_ack(
+        key: &SessionKey,
+        client_msg_id: Option<String>,
+        server_msg_id: MessageId,
+        ts: Timestamp,
+        request_id: Option<RequestId>,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::tag_request(
+            ServerMsgFactory::build_send_ack(client_msg_id, server_msg_id, ts),
+            request_id,
+        );
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_nack(
+        key: &SessionKey,
+        client_msg_id: Option<String>,
+        reason: ErrorReason,
+        detail: String,
+        request_id: Option<RequestId>,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::tag_request(
+            ServerMsgFactory::build_send_nack(client_msg_id, reason, detail),
+            request_id,
+        );
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_parse_error(
+        key: &SessionKey,
+        error: &ParseError,
+        request_id: Option<RequestId>,
+    ) -> Result<Message> {
+        let server_msg =
+            ServerMsgFactory::tag_request(ServerMsgFactory::build_parse_error(error), request_id);
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_help(
+        key: &SessionKey,
+        command: Option<String>,
+        request_id: Option<RequestId>,
+    ) -> Result<Message> {
+        let server_msg =
+            ServerMsgFactory::tag_request(ServerMsgFactory::build_help(command), request_id);
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_whoami(
+        key: &SessionKey,
+        user: &User,
+        room: Option<Room>,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_whoami(user, room);
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    pub fn prepare_send_room_list(key: &SessionKey, rooms: Vec<(Room, usize)>) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_room_list(rooms);
+        SocketSendAdaptor::seal(key, server_msg, Compression::Off)
+    }
+
+    /// A page of history is RoomData holding just that page, led by the notification saying
+    /// whether there is more and the cursor to pass to /history for the next page.
+    pub fn prepare_send_history(
+        key: &SessionKey,
         room: &Room,
+        page: HistoryPage,
+        occupants: Vec<String>,
+        delivery: Delivery,
+        now: Timestamp,
+    ) -> Result<Vec<Message>> {
+        let snapshot = RoomSnapshot {
+            occupant_names: occupants,
+            ..RoomSnapshot::default()
+        };
+        let server_msg =
+            ServerMsgFactory::build_room_data(page, snapshot, room, delivery.protocol_version, now);
+        SocketSendAdaptor::prepare_send_sized(
+            key,
+            server_msg,
+            delivery.max_frame_bytes,
+            delivery.compression,
+        )
+    }
+
+    pub fn prepare_send_search_results(
+        key: &SessionKey,
+        query: String,
+        matches: Vec<MessageLog>,
+        compression: Compression,
     ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_search_results(query, matches);
+        SocketSendAdaptor::seal(key, server_msg, compression)
+    }
+
+    /// One frame per chunk of the transcript, then a final frame marking it done.
+    pub fn prepare_send_export(
+        key: &SessionKey,
+        room: &Room,
+        transcript: &str,
+        compression: Compression,
+        now: Timestamp,
+    ) -> Result<Vec<Message>> {
+        let chunks = transcript::chunk(transcript, transcript::MAX_CHUNK_BYTES);
+        let total = chunks.len();
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(seq, chunk)| {
+                ServerMsgFactory::build_export_chunk(room, seq + 1, total, chunk, now.clone())
+            })
+            .chain(std::iter::once(ServerMsgFactory::build_export_done(
+                room,
+                total,
+                transcript.len(),
+                now.clone(),
+            )))
+            .map(|server_msg| SocketSendAdaptor::seal(key, server_msg, compression))
+            .collect()
+    }
+
+    /// The window of the room's history picked out by `query`, with the notifications from
+    /// the same stretch of time. Chunked if it comes to more than the delivery's frame size.
+    pub fn room_data_response(
+        key: &SessionKey,
+        room: &Room,
+        mut snapshot: RoomSnapshot,
+        query: &HistoryQuery,
+        delivery: Delivery,
+        now: Timestamp,
+    ) -> Result<Vec<Message>> {
+        let page = query.page(std::mem::take(&mut snapshot.msg_log).iter());
+        snapshot
+            .notifications
+            .retain(|notice| page.spans(query, notice.timestamp));
         let server_msg =
-            ServerMsgFactory::build_room_data(chat_logs, notifications, occupants, room);
-        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
-        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
-        Ok(encrypted)
+            ServerMsgFactory::build_room_data(page, snapshot, room, delivery.protocol_version, now);
+        SocketSendAdaptor::prepare_send_sized(
+            key,
+            server_msg,
+            delivery.max_frame_bytes,
+            delivery.compression,
+        )
     }
 }
 
+/// How the responses that can get big are sent to one session, as agreed at login.
+#[derive(Debug, Clone, Copy)]
+pub struct Delivery {
+    pub compression: Compression,
+    pub protocol_version: u32,
+    /// Anything bigger than this is sent in chunks.
+    pub max_frame_bytes: usize,
+}
+
 pub struct ServerMsgFactory;
 
 impl ServerMsgFactory {
@@ -82,59 +555,1256 @@ impl ServerMsgFactory {
         }
     }
 
+    fn build_login_failed_server_msg() -> ServerMsg {
+        ServerMsg {
+            status: Status::JustNo,
+            timestamp: Timestamp::from(Utc::now()),
+            body: ServerMsgBody::Empty,
+        }
+    }
+
+    /// `now` stamps both the message and its `query_ts`, and anything else that has no time
+    /// of its own, so a cursor worked out against one RoomData lines up with the next. The
+    /// snapshot's own log is not read, the page stands in for it.
     fn build_room_data(
-        chat_logs: Vec<MessageLog>,
-        notifications: Vec<NotificationLog>,
-        occupants: Vec<String>,
+        page: HistoryPage,
+        snapshot: RoomSnapshot,
         room: &Room,
+        protocol_version: u32,
+        now: Timestamp,
     ) -> ServerMsg {
+        let RoomSnapshot {
+            notifications,
+            occupant_names: occupants,
+            topic,
+            pinned,
+            occupancy_epoch,
+            ..
+        } = snapshot;
+        // RoomData has no topic, pins or paging fields, so they lead the notifications instead.
+        let page_info = Notification {
+            sender: SERVER_NAME.into(),
+            timestamp: now.clone(),
+            content: match page.next_before {
+                Some(cursor) if page.has_more => format!(
+                    "{} message(s), has_more: true, before: {}",
+                    page.logs.len(),
+                    cursor.timestamp_millis()
+                ),
+                _ => format!("{} message(s), has_more: false", page.logs.len()),
+            },
+        };
+        // What occupant deltas after this one count on from.
+        let epoch_notification =
+            (protocol_version >= OCCUPANT_DELTAS_VERSION).then(|| Notification {
+                sender: SERVER_NAME.into(),
+                timestamp: now.clone(),
+                content: format!("occupants {occupancy_epoch}"),
+            });
+        let topic_notification = topic.map(|topic| Notification {
+            sender: SERVER_NAME.into(),
+            timestamp: now.clone(),
+            content: format!("Topic: {topic}"),
+        });
+        let pin_notifications = pinned.iter().map(|pin| Notification {
+            sender: SERVER_NAME.into(),
+            timestamp: Timestamp::from(pin.timestamp),
+            content: if protocol_version < MESSAGE_IDS_VERSION {
+                format!("Pinned: [{}] {}", pin.username, pin.wire_contents())
+            } else {
+                format!(
+                    "Pinned #{}: [{}] {}",
+                    pin.id,
+                    pin.username,
+                    pin.wire_contents()
+                )
+            },
+        });
+
         ServerMsg {
             status: Status::Yes,
-            timestamp: Timestamp::from(Utc::now()),
+            timestamp: now.clone(),
             body: ServerMsgBody::RoomData {
-                query_ts: Timestamp::from(Utc::now()),
+                query_ts: now,
                 room_name: room.name.clone(),
-                logs: chat_logs
+                logs: page
+                    .logs
                     .iter()
                     .map(|ml| ChatMsg {
                         sender: ml.username.clone(),
                         timestamp: Timestamp::from(ml.timestamp),
-                        content: ml.contents.clone(),
+                        content: ml.wire_contents_for(protocol_version),
                     })
                     .collect(),
-                notifications: notifications
-                    .iter()
-                    .map(|nl| Notification {
-                        sender: "SERVER".into(),
+                notifications: std::iter::once(page_info)
+                    .chain(epoch_notification)
+                    .chain(topic_notification)
+                    .chain(pin_notifications)
+                    .chain(notifications.iter().map(|nl| Notification {
+                        sender: SERVER_NAME.into(),
                         timestamp: Timestamp::from(nl.timestamp),
-                        content: nl.contents.clone(),
-                    })
+                        content: nl.wire_contents(),
+                    }))
                     .collect(),
                 occupants,
             },
         }
     }
 
-    fn build_msg_log_server_msg(msg: MessageLog, user: &User) -> ServerMsg {
+    pub fn build_msg_log_server_msg(msg: MessageLog, protocol_version: u32) -> ServerMsg {
         ServerMsg {
             status: Status::Yes,
             timestamp: msg.timestamp.into(),
             body: ServerMsgBody::ChatRecv {
                 direct: false,
                 chat_msg: ChatMsg {
-                    sender: user.name.clone(),
+                    sender: msg.username.clone(),
                     timestamp: msg.timestamp.into(),
-                    content: msg.contents.clone(),
+                    content: msg.wire_contents_for(protocol_version),
                 },
             },
         }
     }
 
-    fn build_time_server_msg(time: Timestamp) -> ServerMsg {
+    /// A room-wide notice sent live, from "SERVER" like the notifications in RoomData.
+    pub fn build_notification(content: String, ts: Timestamp) -> ServerMsg {
+        ServerMsgFactory::build_notification_with_severity(content, Severity::Info, ts)
+    }
+
+    /// A room-wide notification tagged with how much it matters.
+    pub fn build_notification_with_severity(
+        content: String,
+        severity: Severity,
+        ts: Timestamp,
+    ) -> ServerMsg {
+        let content = severity.tag(&content);
         ServerMsg {
             status: Status::Yes,
-            timestamp: Timestamp::from(time),
-            body: ServerMsgBody::Empty,
+            timestamp: ts.clone(),
+            body: ServerMsgBody::ChatRecv {
+                direct: false,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: ts,
+                    content,
+                },
+            },
+        }
+    }
+
+    /// Who came and went since the client last heard, `occupants <epoch>` then a line each of
+    /// the names joined and left as JSON arrays. An epoch more than one past the last seen
+    /// means a delta was missed and the occupants should be taken from a fresh RoomData.
+    pub fn build_occupant_update(
+        joined: Vec<String>,
+        left: Vec<String>,
+        epoch: u64,
+        ts: Timestamp,
+    ) -> ServerMsg {
+        let names = |names: Vec<String>| serde_json::to_string(&names).unwrap_or_default();
+        ServerMsgFactory::build_notification(
+            format!(
+                "occupants {epoch}\njoined {}\nleft {}",
+                names(joined),
+                names(left)
+            ),
+            ts,
+        )
+    }
+
+    /// `presence <name> online` or `presence <name> away`, the name JSON quoted, with the away
+    /// message on a line of its own when there is one.
+    pub fn build_presence_update(
+        username: &str,
+        status: &PresenceStatus,
+        ts: Timestamp,
+    ) -> ServerMsg {
+        let name = serde_json::to_string(username).unwrap_or_default();
+        let content = match status {
+            PresenceStatus::Online => format!("presence {name} online"),
+            PresenceStatus::Away(None) => format!("presence {name} away"),
+            PresenceStatus::Away(Some(message)) => format!("presence {name} away\n{message}"),
+        };
+        ServerMsgFactory::build_notification(content, ts)
+    }
+
+    /// Room-wide notices that happen between RoomData refreshes, e.g. renames.
+    pub fn build_notification_server_msg(notice: NotificationLog) -> ServerMsg {
+        ServerMsgFactory::build_notification_with_severity(
+            notice.contents,
+            notice.severity,
+            Timestamp::from(notice.timestamp),
+        )
+    }
+
+    /// Announcements go to everyone at once, so they are not marked direct.
+    fn build_announcement(notice: NotificationLog) -> ServerMsg {
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: Timestamp::from(notice.timestamp),
+            body: ServerMsgBody::ChatRecv {
+                direct: false,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: Timestamp::from(notice.timestamp),
+                    content: notice
+                        .severity
+                        .tag(&format!("Announcement: {}", notice.contents)),
+                },
+            },
+        }
+    }
+
+    /// Replies addressed to a single user are delivered as a direct ChatRecv from the server.
+    fn build_notice_server_msg(notice: NotificationLog) -> ServerMsg {
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: Timestamp::from(Utc::now()),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: notice.notifier,
+                    timestamp: Timestamp::from(notice.timestamp),
+                    content: notice.severity.tag(&notice.contents),
+                },
+            },
+        }
+    }
+
+    fn build_rejection_server_msg(reason: String) -> ServerMsg {
+        let now = Utc::now();
+        ServerMsg {
+            status: Status::JustNo,
+            timestamp: Timestamp::from(now),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: Timestamp::from(now),
+                    content: reason,
+                },
+            },
+        }
+    }
+
+    /// A rejection whose first line is `error <code>`, so that clients can tell what went
+    /// wrong without reading the detail.
+    pub fn build_error(reason: ErrorReason, detail: String) -> ServerMsg {
+        ServerMsgFactory::build_rejection_server_msg(format!("error {}\n{detail}", reason.code()))
+    }
+
+    /// Tells the sender their chat was logged and sent to the room, `ack <id>` with the id the
+    /// server gave it, followed by the client's own id for it as a JSON string if it sent one.
+    /// The chat timestamp is the one the message was logged with.
+    pub fn build_send_ack(
+        client_msg_id: Option<String>,
+        server_msg_id: MessageId,
+        ts: Timestamp,
+    ) -> ServerMsg {
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: Timestamp::from(Utc::now()),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: ts,
+                    content: format!("ack {server_msg_id}{}", quoted_msg_id(client_msg_id)),
+                },
+            },
+        }
+    }
+
+    /// Like an error, but `nack <code>` with the client's id for the message after it, so a
+    /// client with several sends in flight knows which one failed.
+    fn build_send_nack(
+        client_msg_id: Option<String>,
+        reason: ErrorReason,
+        detail: String,
+    ) -> ServerMsg {
+        ServerMsgFactory::build_rejection_server_msg(format!(
+            "nack {}{}\n{detail}",
+            reason.code(),
+            quoted_msg_id(client_msg_id)
+        ))
+    }
+
+    /// Names what was attempted and shows how to use it, or points at /help when there's no
+    /// usage to show.
+    fn build_parse_error(error: &ParseError) -> ServerMsg {
+        let hint = match error.usage() {
+            Some(usage) => format!("usage: {usage}"),
+            None => "try /help".to_string(),
+        };
+        ServerMsgFactory::build_error(
+            error.error_reason(),
+            format!("{error}\nattempted: {}\n{hint}", error.command()),
+        )
+    }
+
+    fn build_whoami(user: &User, room: Option<Room>) -> ServerMsg {
+        let content = format!(
+            "name: {}\nid: {}\nroom: {}\nlogged in: {}\nrole: {:?}\ntoken: {}",
+            user.name,
+            user.id,
+            room.map(|room| room.name).unwrap_or_default(),
+            user.logged_in_at.format("%Y-%m-%d %H:%M:%S UTC"),
+            user.role,
+            user.token_fingerprint(),
+        );
+
+        let now = Utc::now();
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: Timestamp::from(now),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: Timestamp::from(now),
+                    content,
+                },
+            },
+        }
+    }
+
+    fn build_search_results(query: String, matches: Vec<MessageLog>) -> ServerMsg {
+        let header = format!("{} match(es) for \"{query}\"", matches.len());
+        let content = std::iter::once(header)
+            .chain(matches.iter().map(|msg| {
+                format!(
+                    "#{} [{}] {}: {}",
+                    msg.id,
+                    msg.timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
+                    msg.username,
+                    msg.wire_contents()
+                )
+            }))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let now = Utc::now();
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: Timestamp::from(now),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: Timestamp::from(now),
+                    content,
+                },
+            },
+        }
+    }
+
+    /// Export frames are direct ChatRecvs whose first line is a header like
+    /// `export Hub 2/3`, the rest of the content is the chunk exactly as rendered.
+    fn build_export_chunk(
+        room: &Room,
+        seq: usize,
+        total: usize,
+        chunk: &str,
+        now: Timestamp,
+    ) -> ServerMsg {
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: now.clone(),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: now,
+                    content: format!("export {} {seq}/{total}\n{chunk}", room.name),
+                },
+            },
+        }
+    }
+
+    fn build_export_done(room: &Room, total: usize, bytes: usize, now: Timestamp) -> ServerMsg {
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: now.clone(),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: now,
+                    content: format!(
+                        "export {} done, {total} chunk(s), {bytes} byte(s)",
+                        room.name
+                    ),
+                },
+            },
+        }
+    }
+
+    fn build_motd(motd: String) -> ServerMsg {
+        let now = Utc::now();
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: Timestamp::from(now),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: Timestamp::from(now),
+                    content: format!("MOTD: {motd}"),
+                },
+            },
+        }
+    }
+
+    fn build_room_list(rooms: Vec<(Room, usize)>) -> ServerMsg {
+        let content = rooms
+            .iter()
+            .map(|(room, occupants)| {
+                format!(
+                    "{} ({:X}) - {} occupant(s)",
+                    room.name,
+                    room.hash_code(),
+                    occupants
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let now = Utc::now();
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: Timestamp::from(now),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: Timestamp::from(now),
+                    content,
+                },
+            },
+        }
+    }
+
+    fn build_help(command: Option<String>) -> ServerMsg {
+        let (status, content) = match command {
+            None => (
+                Status::Yes,
+                CommandRegistry::all()
+                    .iter()
+                    .map(|spec| format!("{} - {}", spec.usage(), spec.description))
+                    .chain(
+                        CommandRegistry::aliases()
+                            .iter()
+                            .map(|alias| format!("{} - Sends {}", alias.usage(), alias.expansion)),
+                    )
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+            ),
+            Some(name) => match (
+                CommandRegistry::get(&name),
+                CommandRegistry::get_alias(&name),
+            ) {
+                (Some(spec), _) => (
+                    Status::Yes,
+                    format!("usage: {}\n{}", spec.usage(), spec.description),
+                ),
+                (None, Some(alias)) => (
+                    Status::Yes,
+                    format!(
+                        "usage: {}\nSends your text followed by {}",
+                        alias.usage(),
+                        alias.expansion
+                    ),
+                ),
+                (None, None) => (
+                    Status::JustNo,
+                    format!("Unknown command /{name}, try /help for a list of commands"),
+                ),
+            },
+        };
+
+        let now = Utc::now();
+        ServerMsg {
+            status,
+            timestamp: Timestamp::from(now),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: Timestamp::from(now),
+                    content,
+                },
+            },
+        }
+    }
+
+    /// A ChatRecv with `direct: true`. `from` should be the App's record of the sender, whose
+    /// name is current even if the sender's session holds an older copy.
+    pub fn build_direct_msg(from: &User, content: String, ts: Timestamp) -> ServerMsg {
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: ts.clone(),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: from.name.clone(),
+                    timestamp: ts,
+                    content,
+                },
+            },
+        }
+    }
+
+    /// The ServerMsg carries the server receive time and the ChatMsg echoes the client's
+    /// timestamp, so the client can work out round trip time and clock skew. Without a usable
+    /// client timestamp only the server time is reported.
+    fn build_pong(client_ts: Option<Timestamp>, received_at: Timestamp) -> ServerMsg {
+        let client_time: Option<DateTime<Utc>> = client_ts.and_then(|ts| ts.into());
+        let (echoed, content) = match client_time {
+            Some(client_time) => (Timestamp::from(client_time), "pong".to_string()),
+            None => (
+                received_at.clone(),
+                "pong (no client timestamp)".to_string(),
+            ),
+        };
+
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: received_at,
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: echoed,
+                    content,
+                },
+            },
+        }
+    }
+
+    fn serialized_size(server_msg: &ServerMsg) -> Result<usize> {
+        let size = bincode::serialized_size(server_msg).map_err(|e| anyhow!("{e}"))?;
+        Ok(size as usize)
+    }
+
+    /// RoomData over `max_bytes` is halved by its logs until every part fits, the
+    /// notifications staying with the first part. Anything else, or a single message too big
+    /// on its own, is left whole.
+    fn split_to_fit(server_msg: ServerMsg, max_bytes: usize) -> Result<Vec<ServerMsg>> {
+        let too_big = ServerMsgFactory::serialized_size(&server_msg)? > max_bytes;
+        match server_msg {
+            ServerMsg {
+                status: Status::Yes,
+                timestamp,
+                body:
+                    ServerMsgBody::RoomData {
+                        query_ts,
+                        room_name,
+                        mut logs,
+                        notifications,
+                        occupants,
+                    },
+            } if too_big && logs.len() > 1 => {
+                let later = logs.split_off(logs.len() / 2);
+                let first = ServerMsg {
+                    status: Status::Yes,
+                    timestamp: timestamp.clone(),
+                    body: ServerMsgBody::RoomData {
+                        query_ts: query_ts.clone(),
+                        room_name: room_name.clone(),
+                        logs,
+                        notifications,
+                        occupants: occupants.clone(),
+                    },
+                };
+                let second = ServerMsg {
+                    status: Status::Yes,
+                    timestamp,
+                    body: ServerMsgBody::RoomData {
+                        query_ts,
+                        room_name,
+                        logs: later,
+                        notifications: vec![],
+                        occupants,
+                    },
+                };
+                let mut parts = ServerMsgFactory::split_to_fit(first, max_bytes)?;
+                parts.extend(ServerMsgFactory::split_to_fit(second, max_bytes)?);
+                Ok(parts)
+            }
+            server_msg => Ok(vec![server_msg]),
+        }
+    }
+
+    /// RoomData carries the tag as its first notification, chat as its first line.
+    fn tag_chunk(mut server_msg: ServerMsg, stream_id: u64, seq: usize, total: usize) -> ServerMsg {
+        let tag = format!("chunk {stream_id} {seq}/{total}");
+        match &mut server_msg.body {
+            ServerMsgBody::RoomData { notifications, .. } => notifications.insert(
+                0,
+                Notification {
+                    sender: SERVER_NAME.into(),
+                    timestamp: server_msg.timestamp.clone(),
+                    content: tag,
+                },
+            ),
+            ServerMsgBody::ChatRecv { chat_msg, .. } => {
+                chat_msg.content = format!("{tag}\n{}", chat_msg.content);
+            }
+            ServerMsgBody::Empty | ServerMsgBody::LoginSuccess { .. } => {}
+        }
+        server_msg
+    }
+
+    fn build_chunks_done(stream_id: u64, total: usize) -> ServerMsg {
+        let now = Utc::now();
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: Timestamp::from(now),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: Timestamp::from(now),
+                    content: format!("chunk {stream_id} done, {total} chunk(s)"),
+                },
+            },
+        }
+    }
+
+    /// Direct replies name the request they answer on their first line, `request <id>`.
+    fn tag_request(mut server_msg: ServerMsg, request_id: Option<RequestId>) -> ServerMsg {
+        if let (Some(id), ServerMsgBody::ChatRecv { chat_msg, .. }) =
+            (request_id, &mut server_msg.body)
+        {
+            chat_msg.content = format!("request {id}\n{}", chat_msg.content);
+        }
+        server_msg
+    }
+
+    /// The envelope carries the server's time as it always has. The body gives it again in
+    /// milliseconds, `time <server>` or `time <server> client <client>` when the client's
+    /// request had a readable timestamp, so the client can work out its skew.
+    fn build_time_server_msg(client_ts: Option<Timestamp>, server_ts: Timestamp) -> ServerMsg {
+        let server_time: Option<DateTime<Utc>> = server_ts.clone().into();
+        let server_time = server_time.unwrap_or_else(Utc::now);
+        let client_time: Option<DateTime<Utc>> = client_ts.and_then(|ts| ts.into());
+        let (echoed, content) = match client_time {
+            Some(client_time) => (
+                Timestamp::from(client_time),
+                format!(
+                    "time {} client {}",
+                    server_time.timestamp_millis(),
+                    client_time.timestamp_millis()
+                ),
+            ),
+            None => (
+                server_ts.clone(),
+                format!("time {}", server_time.timestamp_millis()),
+            ),
+        };
+
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: server_ts,
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ChatMsg {
+                    sender: SERVER_NAME.into(),
+                    timestamp: echoed,
+                    content,
+                },
+            },
+        }
+    }
+}
+
+/// The client's id for a message, JSON quoted since it can hold anything, with the space that
+/// separates it from what comes before.
+fn quoted_msg_id(client_msg_id: Option<String>) -> String {
+    client_msg_id
+        .and_then(|id| serde_json::to_string(&id).ok())
+        .map(|quoted| format!(" {quoted}"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    /// The plain data a ServerMsg is built from, `ServerMsg` itself can't be cloned.
+    type MsgSpec = (bool, i64, u8, String, Vec<String>, [u8; 32], bool);
+
+    fn text() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just(String::new()),
+            any::<String>(),
+            "\\PC{0,4000}",
+            "[a-z ]{1,20}(\n\\PC{0,80}){0,5}",
+        ]
+    }
+
+    fn msg_spec() -> impl Strategy<Value = MsgSpec> {
+        (
+            any::<bool>(),
+            0i64..4_102_444_800,
+            0u8..4,
+            text(),
+            vec(text(), 0..8),
+            any::<[u8; 32]>(),
+            any::<bool>(),
+        )
+    }
+
+    fn build((ok, secs, variant, name, texts, public_key, direct): &MsgSpec) -> ServerMsg {
+        let ts = || Timestamp::from(DateTime::from_timestamp(*secs, 0).unwrap());
+        let chat = |content: &String| ChatMsg {
+            sender: name.clone(),
+            timestamp: ts(),
+            content: content.clone(),
+        };
+        let body = match variant {
+            0 => ServerMsgBody::Empty,
+            1 => ServerMsgBody::LoginSuccess {
+                token: name.clone(),
+                public_key: *public_key,
+            },
+            2 => ServerMsgBody::RoomData {
+                query_ts: ts(),
+                room_name: name.clone(),
+                logs: texts.iter().map(chat).collect(),
+                notifications: texts
+                    .iter()
+                    .map(|content| Notification {
+                        sender: SERVER_NAME.into(),
+                        timestamp: ts(),
+                        content: content.clone(),
+                    })
+                    .collect(),
+                occupants: texts.clone(),
+            },
+            _ => ServerMsgBody::ChatRecv {
+                direct: *direct,
+                chat_msg: chat(texts.first().unwrap_or(name)),
+            },
+        };
+        ServerMsg {
+            status: if *ok { Status::Yes } else { Status::JustNo },
+            timestamp: ts(),
+            body,
+        }
+    }
+
+    fn session_key() -> impl Strategy<Value = SessionKey> {
+        any::<[u8; 32]>().prop_map(SessionKey::from_bytes)
+    }
+
+    fn compression() -> impl Strategy<Value = Compression> {
+        prop_oneof![
+            Just(Compression::Off),
+            (0usize..2048).prop_map(|threshold| Compression::Deflate { threshold }),
+        ]
+    }
+
+    fn frame_bytes(frame: Message) -> Vec<u8> {
+        match frame {
+            Message::Binary(bytes) => bytes,
+            _ => panic!("sealed frames are binary"),
+        }
+    }
+
+    proptest! {
+        // Each case can carry a few thousand characters of text, fewer of them go a long way.
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn sealed_messages_read_back_the_same(
+            spec in msg_spec(),
+            key in session_key(),
+            compression in compression(),
+        ) {
+            let frame = SocketSendAdaptor::seal(&key, build(&spec), compression).unwrap();
+            let read = SocketSendAdaptor::read_server_msg(&key, frame).unwrap();
+            prop_assert_eq!(format!("{read:?}"), format!("{:?}", build(&spec)));
+        }
+
+        #[test]
+        fn serialize_encrypt_decrypt_deserialize_round_trips(
+            spec in msg_spec(),
+            key in session_key(),
+        ) {
+            let serialized = SocketSendAdaptor::serialized_server_msg(build(&spec)).unwrap();
+            let frame = SocketSendAdaptor::encrypt_message(&key, serialized.clone()).unwrap();
+            let decrypted = SocketSendAdaptor::decrypt_message(&key, frame_bytes(frame)).unwrap();
+            prop_assert_eq!(&decrypted, &serialized);
+            let read: ServerMsg = bincode::deserialize(&decrypted).unwrap();
+            prop_assert_eq!(format!("{read:?}"), format!("{:?}", build(&spec)));
+        }
+
+        #[test]
+        fn corrupted_frames_are_errors_not_panics(
+            spec in msg_spec(),
+            key in session_key(),
+            index in any::<prop::sample::Index>(),
+            flip in 1u8..=255,
+        ) {
+            let original = format!("{:?}", build(&spec));
+            let mut bytes = frame_bytes(
+                SocketSendAdaptor::seal(&key, build(&spec), Compression::Off).unwrap(),
+            );
+            let at = index.index(bytes.len());
+            bytes[at] ^= flip;
+            // A flipped byte can leave a frame that still decodes, just never as the original.
+            if let Ok(read) = SocketSendAdaptor::read_server_msg(&key, Message::Binary(bytes)) {
+                prop_assert_ne!(format!("{read:?}"), original);
+            }
+        }
+
+        #[test]
+        fn truncated_frames_are_errors_not_panics(
+            spec in msg_spec(),
+            key in session_key(),
+            index in any::<prop::sample::Index>(),
+        ) {
+            let bytes = frame_bytes(
+                SocketSendAdaptor::seal(&key, build(&spec), Compression::Off).unwrap(),
+            );
+            let truncated = bytes[..index.index(bytes.len())].to_vec();
+            prop_assert!(SocketSendAdaptor::read_server_msg(&key, Message::Binary(truncated)).is_err());
+        }
+
+        #[test]
+        fn frames_do_not_read_back_under_another_key(
+            spec in msg_spec(),
+            key in session_key(),
+            other in session_key(),
+        ) {
+            prop_assume!(key != other);
+            let original = format!("{:?}", build(&spec));
+            let frame = SocketSendAdaptor::seal(&other, build(&spec), Compression::Off).unwrap();
+            if let Ok(read) = SocketSendAdaptor::read_server_msg(&key, frame) {
+                prop_assert_ne!(format!("{read:?}"), original);
+            }
+        }
+    }
+
+    #[test]
+    fn text_frames_are_refused() {
+        let frame = Message::Text("{}".into());
+        assert!(SocketSendAdaptor::read_server_msg(&SessionKey::default(), frame).is_err());
+    }
+
+    #[test]
+    fn a_time_reply_decrypts_to_a_server_msg() {
+        let key = SessionKey::from_bytes([8; 32]);
+        let now = DateTime::from_timestamp_millis(1_700_000_000_123).unwrap();
+
+        let frame =
+            SocketSendAdaptor::prepare_send_time(&key, None, Timestamp::from(now), None).unwrap();
+
+        let read = SocketSendAdaptor::read_server_msg(&key, frame).unwrap();
+        assert!(matches!(read.status, Status::Yes));
+        let ServerMsgBody::ChatRecv { chat_msg, .. } = read.body else {
+            panic!("the time arrives as ChatRecv");
+        };
+        assert_eq!(chat_msg.sender, SERVER_NAME);
+        assert_eq!(chat_msg.content, "time 1700000000123");
+    }
+
+    #[test]
+    fn a_tagged_time_reply_leads_with_its_request_id() {
+        let key = SessionKey::from_bytes([8; 32]);
+        let now = DateTime::from_timestamp_millis(1_700_000_000_123).unwrap();
+
+        let frame = SocketSendAdaptor::prepare_send_time(&key, None, Timestamp::from(now), Some(1))
+            .unwrap();
+
+        let read = SocketSendAdaptor::read_server_msg(&key, frame).unwrap();
+        let ServerMsgBody::ChatRecv { chat_msg, .. } = read.body else {
+            panic!("the time arrives as ChatRecv");
+        };
+        assert_eq!(chat_msg.content, "request 1\ntime 1700000000123");
+    }
+
+    #[test]
+    fn every_error_reason_reads_back_from_its_first_line() {
+        let key = SessionKey::from_bytes([8; 32]);
+        for reason in ErrorReason::ALL {
+            let frame = SocketSendAdaptor::prepare_send_error(&key, reason, "details".into(), None)
+                .unwrap();
+
+            let read = SocketSendAdaptor::read_server_msg(&key, frame).unwrap();
+            assert!(matches!(read.status, Status::JustNo));
+            let ServerMsgBody::ChatRecv { chat_msg, .. } = read.body else {
+                panic!("{reason} arrives as ChatRecv");
+            };
+            let (code, detail) = chat_msg.content.split_once('\n').unwrap();
+            assert_eq!(
+                code.strip_prefix("error ").and_then(ErrorReason::from_code),
+                Some(reason)
+            );
+            assert_eq!(detail, "details");
+        }
+    }
+
+    fn room_data_frames(msgs: usize, compression: Compression) -> Vec<Message> {
+        let sender = User::new("id-ann".into(), "ann".into(), SessionKey::default());
+        let snapshot = RoomSnapshot {
+            msg_log: (0..msgs)
+                .map(|n| MessageLog::from_user(&sender, format!("message number {n} in the room")))
+                .collect(),
+            occupant_names: vec!["ann".into()],
+            ..Default::default()
+        };
+        let delivery = Delivery {
+            compression,
+            protocol_version: PROTOCOL_VERSION,
+            max_frame_bytes: usize::MAX,
+        };
+        SocketSendAdaptor::room_data_response(
+            &SessionKey::from_bytes([8; 32]),
+            &Room::lobby(),
+            snapshot,
+            &HistoryQuery {
+                before: None,
+                limit: msgs,
+            },
+            delivery,
+            Timestamp::from(Utc::now()),
+        )
+        .unwrap()
+    }
+
+    fn logs_in(frame: Message) -> usize {
+        let read =
+            SocketSendAdaptor::read_server_msg(&SessionKey::from_bytes([8; 32]), frame).unwrap();
+        let ServerMsgBody::RoomData { logs, .. } = read.body else {
+            panic!("room data arrives as RoomData");
+        };
+        logs.len()
+    }
+
+    #[test]
+    fn a_large_room_data_is_deflated_inside_the_encryption() {
+        let deflate = Compression::Deflate { threshold: 1024 };
+        let [plain] = &room_data_frames(500, Compression::Off)[..] else {
+            panic!("one frame without compression");
+        };
+        let [compressed] = &room_data_frames(500, deflate)[..] else {
+            panic!("one frame with compression");
+        };
+
+        assert!(compressed.len() < plain.len() / 2);
+        let key = SessionKey::from_bytes([8; 32]);
+        let decrypted =
+            SocketSendAdaptor::decrypt_message(&key, compressed.clone().into_data()).unwrap();
+        assert_eq!(decrypted[0], compression::DEFLATE_PREFIX);
+        assert_eq!(logs_in(plain.clone()), 500);
+        assert_eq!(logs_in(compressed.clone()), 500);
+    }
+
+    #[test]
+    fn a_small_room_data_is_not_deflated() {
+        let deflate = Compression::Deflate { threshold: 1024 };
+        let frame = room_data_frames(1, deflate).remove(0);
+
+        let key = SessionKey::from_bytes([8; 32]);
+        let decrypted =
+            SocketSendAdaptor::decrypt_message(&key, frame.clone().into_data()).unwrap();
+        assert_ne!(decrypted[0], compression::DEFLATE_PREFIX);
+        assert_eq!(logs_in(frame), 1);
+    }
+
+    #[test]
+    fn a_time_reply_carries_both_times_in_order() {
+        let key = SessionKey::from_bytes([8; 32]);
+        let sent = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let now = DateTime::from_timestamp_millis(1_700_000_000_250).unwrap();
+
+        let frame = SocketSendAdaptor::prepare_send_time(
+            &key,
+            Some(Timestamp::from(sent)),
+            Timestamp::from(now),
+            None,
+        )
+        .unwrap();
+
+        let read = SocketSendAdaptor::read_server_msg(&key, frame).unwrap();
+        let envelope: Option<DateTime<Utc>> = read.timestamp.into();
+        assert_eq!(envelope, Some(now));
+        let ServerMsgBody::ChatRecv { chat_msg, .. } = read.body else {
+            panic!("the time arrives as ChatRecv");
+        };
+        let echoed: Option<DateTime<Utc>> = chat_msg.timestamp.into();
+        assert_eq!(echoed, Some(sent));
+        let times: Vec<i64> = chat_msg
+            .content
+            .split(' ')
+            .filter_map(|word| word.parse().ok())
+            .collect();
+        assert_eq!(chat_msg.content, "time 1700000000250 client 1700000000000");
+        let [server, client] = times[..] else {
+            panic!("two times in {}", chat_msg.content);
+        };
+        assert!(client <= server);
+    }
+
+    #[test]
+    fn frames_sealed_with_a_session_key_open_with_its_raw_bytes() {
+        let bytes = [8; 32];
+        let key = SessionKey::from_bytes(bytes);
+        let payload = b"the same bytes either way".to_vec();
+
+        let Message::Binary(sealed) =
+            SocketSendAdaptor::encrypt_message(&key, payload.clone()).unwrap()
+        else {
+            panic!("sealed frames are binary");
+        };
+        assert_eq!(cbc_decode(bytes.to_vec(), sealed).unwrap(), payload);
+
+        let raw = cbc_encode(bytes.to_vec(), payload.clone(), get_rng()).unwrap();
+        assert_eq!(
+            SocketSendAdaptor::decrypt_message(&key, raw).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    fn an_oversized_room_data_is_chunked_and_reassembles() {
+        let key = SessionKey::from_bytes([8; 32]);
+        let sender = User::new("id-ann".into(), "ann".into(), SessionKey::default());
+        let msg_log: Vec<MessageLog> = (0..4)
+            .map(|n| MessageLog::from_user(&sender, format!("message {n} ").repeat(40)))
+            .collect();
+        let notice = NotificationLog::new("a notice long enough to weigh ".repeat(20));
+        let room_data = |msg_log: Vec<MessageLog>, max_frame_bytes: usize| {
+            let snapshot = RoomSnapshot {
+                msg_log,
+                notifications: vec![notice.clone()],
+                occupant_names: vec!["ann".into()],
+                ..Default::default()
+            };
+            let delivery = Delivery {
+                compression: Compression::Off,
+                protocol_version: PROTOCOL_VERSION,
+                max_frame_bytes,
+            };
+            SocketSendAdaptor::room_data_response(
+                &key,
+                &Room::lobby(),
+                snapshot,
+                &HistoryQuery {
+                    before: None,
+                    limit: 4,
+                },
+                delivery,
+                Timestamp::from(Utc::now()),
+            )
+            .unwrap()
+        };
+        // Room for the first message with the notice, not the first two: the front half is
+        // split again and the back half, without the notice, goes whole.
+        let [one] = &room_data(msg_log[..1].to_vec(), usize::MAX)[..] else {
+            panic!("one frame without a limit");
+        };
+        let budget = ServerMsgFactory::serialized_size(
+            &SocketSendAdaptor::read_server_msg(&key, one.clone()).unwrap(),
+        )
+        .unwrap();
+
+        let frames = room_data(msg_log.clone(), budget + CHUNK_HEADROOM_BYTES);
+        let mut read: Vec<ServerMsg> = frames
+            .into_iter()
+            .map(|frame| SocketSendAdaptor::read_server_msg(&key, frame).unwrap())
+            .collect();
+        let ServerMsgBody::ChatRecv { chat_msg: done, .. } = read.pop().unwrap().body else {
+            panic!("the stream ends with a ChatRecv");
+        };
+        assert_eq!(read.len(), 3);
+        let (stream_id, _) = done
+            .content
+            .split_once(' ')
+            .unwrap()
+            .1
+            .split_once(' ')
+            .unwrap();
+        assert_eq!(done.content, format!("chunk {stream_id} done, 3 chunk(s)"));
+
+        let mut logs = vec![];
+        let mut notices = vec![];
+        for (seq, server_msg) in read.into_iter().enumerate() {
+            let ServerMsgBody::RoomData {
+                logs: part,
+                notifications,
+                ..
+            } = server_msg.body
+            else {
+                panic!("chunk {} is not RoomData", seq + 1);
+            };
+            let (tag, rest) = notifications.split_first().unwrap();
+            assert_eq!(tag.content, format!("chunk {stream_id} {}/3", seq + 1));
+            logs.extend(part.into_iter().map(|log| log.content));
+            notices.extend(rest.iter().map(|notice| notice.content.clone()));
+        }
+        let sent: Vec<String> = msg_log.into_iter().map(|msg| msg.contents).collect();
+        assert_eq!(logs, sent);
+        // Once, alongside the summary lines every RoomData carries.
+        assert_eq!(
+            notices
+                .iter()
+                .filter(|content| **content == notice.contents)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn a_failed_login_is_refused_in_plain_bincode() {
+        let Message::Binary(bytes) = SocketSendAdaptor::on_login_failed().unwrap() else {
+            panic!("the refusal is a binary frame");
+        };
+        let read: ServerMsg = bincode::deserialize(&bytes).unwrap();
+        assert!(matches!(read.status, Status::JustNo));
+        assert!(matches!(read.body, ServerMsgBody::Empty));
+    }
+
+    #[test]
+    fn a_multi_chunk_export_reassembles_byte_for_byte() {
+        let key = SessionKey::from_bytes([7; 32]);
+        let user = User::new("id-ann".into(), "ann".into(), key.clone());
+        let logs: Vec<MessageLog> = (0..400)
+            .map(|n| MessageLog::from_user(&user, format!("message {n}: héllo wörld ✓ 🎉")))
+            .collect();
+        let notifications = vec![NotificationLog::new("ann joined".into())];
+        let transcript =
+            transcript::render(&logs, &notifications, transcript::ExportFormat::JsonLines);
+        let room = Room::from("den");
+
+        let frames = SocketSendAdaptor::prepare_send_export(
+            &key,
+            &room,
+            &transcript,
+            Compression::Deflate { threshold: 1024 },
+            Timestamp::from(Utc::now()),
+        )
+        .unwrap();
+
+        let contents: Vec<String> = frames
+            .into_iter()
+            .map(|frame| {
+                match SocketSendAdaptor::read_server_msg(&key, frame)
+                    .unwrap()
+                    .body
+                {
+                    ServerMsgBody::ChatRecv { chat_msg, .. } => chat_msg.content,
+                    _ => panic!("export frames are ChatRecv"),
+                }
+            })
+            .collect();
+        let (done, chunks) = contents.split_last().unwrap();
+        assert!(chunks.len() > 1, "the transcript fit in one chunk");
+        assert_eq!(
+            done,
+            &format!(
+                "export den done, {} chunk(s), {} byte(s)",
+                chunks.len(),
+                transcript.len()
+            )
+        );
+        let mut reassembled = String::new();
+        for (seq, chunk) in chunks.iter().enumerate() {
+            let (header, body) = chunk.split_once('\n').unwrap();
+            assert_eq!(header, format!("export den {}/{}", seq + 1, chunks.len()));
+            reassembled.push_str(body);
+        }
+        assert_eq!(reassembled.as_bytes(), transcript.as_bytes());
+    }
+
+    #[test]
+    fn a_message_that_wont_serialize_goes_out_as_the_bare_refusal() {
+        let key = SessionKey::from_bytes([6; 32]);
+        let before = fallback_frames_sent();
+
+        FAIL_NEXT_SERIALIZE.with(|fail| fail.set(true));
+        let frame = SocketSendAdaptor::prepare_send_notice(&key, NotificationLog::new("hi".into()))
+            .unwrap();
+
+        let read = SocketSendAdaptor::read_server_msg(&key, frame).unwrap();
+        assert!(matches!(read.status, Status::JustNo));
+        assert!(matches!(read.body, ServerMsgBody::Empty));
+        assert!(fallback_frames_sent() > before);
+
+        // Only the one message was affected.
+        let frame = SocketSendAdaptor::prepare_send_notice(&key, NotificationLog::new("hi".into()))
+            .unwrap();
+        let read = SocketSendAdaptor::read_server_msg(&key, frame).unwrap();
+        assert!(matches!(read.body, ServerMsgBody::ChatRecv { .. }));
+    }
+
+    #[test]
+    fn binary_clients_read_the_agreed_version_from_an_encrypted_notice() {
+        let key = SessionKey::from_bytes([5; 32]);
+        let compression = Compression::Deflate { threshold: 1024 };
+
+        let frame = SocketSendAdaptor::prepare_send_protocol_notice(&key, 2, compression).unwrap();
+
+        let read = SocketSendAdaptor::read_server_msg(&key, frame).unwrap();
+        let ServerMsgBody::ChatRecv { chat_msg, .. } = read.body else {
+            panic!("the notice arrives as ChatRecv");
+        };
+        assert_eq!(chat_msg.sender, SERVER_NAME);
+        assert!(chat_msg.content.ends_with(&format!(
+            "protocol 2 server {PROTOCOL_VERSION} compression deflate"
+        )));
+    }
+
+    #[test]
+    fn direct_messages_read_back_as_direct_from_the_sender() {
+        let from = User::new(
+            "id-ann".into(),
+            "ann".into(),
+            SessionKey::from_bytes([1; 32]),
+        );
+        let recipient_key = SessionKey::from_bytes([2; 32]);
+        let ts = Timestamp::from(Utc::now());
+
+        let frame =
+            SocketSendAdaptor::prepare_send_direct(&recipient_key, &from, "psst".into(), ts)
+                .unwrap();
+
+        assert!(SocketSendAdaptor::read_server_msg(&from.shared_secret, frame.clone()).is_err());
+        let read = SocketSendAdaptor::read_server_msg(&recipient_key, frame).unwrap();
+        let ServerMsgBody::ChatRecv { direct, chat_msg } = read.body else {
+            panic!("a direct message arrives as ChatRecv");
+        };
+        assert!(direct);
+        assert_eq!(chat_msg.sender, "ann");
+        assert_eq!(chat_msg.content, "psst");
+    }
+
+    #[test]
+    fn a_broadcast_reads_back_the_same_for_every_recipient() {
+        let spec: MsgSpec = (
+            true,
+            1_700_000_000,
+            3,
+            "sender".into(),
+            vec!["hi".into()],
+            [0; 32],
+            false,
+        );
+        let msg = build(&spec);
+        let recipients: Vec<(String, SessionKey)> = (0..100u8)
+            .map(|n| (format!("user-{n}"), SessionKey::from_bytes([n; 32])))
+            .collect();
+
+        let frames = SocketSendAdaptor::prepare_broadcast(&msg, &recipients).unwrap();
+
+        assert_eq!(frames.len(), recipients.len());
+        for ((user_id, frame), (recipient, key)) in frames.into_iter().zip(&recipients) {
+            assert_eq!(&user_id, recipient);
+            let read = SocketSendAdaptor::read_server_msg(key, frame).unwrap();
+            assert_eq!(format!("{read:?}"), format!("{msg:?}"));
         }
     }
 }
@@ -1,2 +1,10 @@
+pub mod bounded_channel;
+pub mod command_parser;
+pub mod compression;
+pub mod frame_metrics;
 pub mod login;
-pub mod message_builder;
\ No newline at end of file
+pub mod message_builder;
+pub mod outbound;
+pub mod sanitize;
+pub mod server_config;
+pub mod wire_trace;
\ No newline at end of file
@@ -0,0 +1,90 @@
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Duration, Instant};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+use super::wire_trace;
+
+/// Frames written to the socket before it is flushed, however many more are waiting.
+pub const DEFAULT_MAX_BATCH: usize = 32;
+/// How long a part filled batch waits for more frames before it is flushed anyway.
+pub const DEFAULT_FLUSH_MILLIS: u64 = 5;
+
+type SocketSink = SplitSink<WebSocketStream<TcpStream>, Message>;
+
+/// Owns the write half of a client's socket. Frames sent to it are written as they come and
+/// flushed together, so a burst of room traffic goes out in a few writes rather than one per
+/// frame.
+pub struct OutboundWriter {
+    user_id: String,
+    socket: SocketSink,
+    frames: UnboundedReceiver<Message>,
+    max_batch: usize,
+    flush_after: Duration,
+}
+
+impl OutboundWriter {
+    /// Starts the writer and hands back its sender. The socket is closed once every sender is
+    /// dropped, or after a close frame has gone out.
+    pub fn spawn(
+        socket: SocketSink,
+        user_id: String,
+        max_batch: usize,
+        flush_millis: u64,
+    ) -> UnboundedSender<Message> {
+        let (sender, frames) = unbounded();
+        let writer = OutboundWriter {
+            user_id,
+            socket,
+            frames,
+            max_batch: max_batch.max(1),
+            flush_after: Duration::from_millis(flush_millis),
+        };
+        tokio::spawn(writer.run());
+        sender
+    }
+
+    async fn run(mut self) {
+        while let Some(first) = self.frames.next().await {
+            let mut closing = matches!(first, Message::Close(_));
+            wire_trace::outbound_frame(&self.user_id, &first);
+            if let Err(e) = self.socket.feed(first).await {
+                log::debug!("Could not write to {}: {e}", self.user_id);
+                return;
+            }
+
+            let mut batched = 1;
+            let mut senders_gone = false;
+            let deadline = Instant::now() + self.flush_after;
+            while !closing && batched < self.max_batch {
+                match timeout_at(deadline, self.frames.next()).await {
+                    Ok(Some(frame)) => {
+                        closing = matches!(frame, Message::Close(_));
+                        wire_trace::outbound_frame(&self.user_id, &frame);
+                        if let Err(e) = self.socket.feed(frame).await {
+                            log::debug!("Could not write to {}: {e}", self.user_id);
+                            return;
+                        }
+                        batched += 1;
+                    }
+                    Ok(None) => {
+                        senders_gone = true;
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            if let Err(e) = self.socket.flush().await {
+                log::debug!("Could not flush to {}: {e}", self.user_id);
+                return;
+            }
+            log::trace!("Flushed {batched} frames to {}", self.user_id);
+            if closing || senders_gone {
+                break;
+            }
+        }
+        self.socket.close().await.unwrap_or(());
+    }
+}
@@ -0,0 +1,88 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// Newlines in a row that survive sanitizing, longer runs are cut down to this many.
+pub const MAX_CONSECUTIVE_NEWLINES: usize = 2;
+
+/// Makes client text safe to show on every other client. Control characters other than
+/// newline and tab are removed, as are the bidi overrides and isolates that make text read
+/// differently to how it was typed. Runs of newlines are capped, and the result is NFC so
+/// that the same text is always stored the same way.
+pub fn sanitize(text: &str) -> String {
+    let mut clean = String::with_capacity(text.len());
+    let mut newlines = 0;
+    for c in text.chars() {
+        if c == '\n' {
+            newlines += 1;
+            if newlines <= MAX_CONSECUTIVE_NEWLINES {
+                clean.push(c);
+            }
+            continue;
+        }
+        if is_stripped(c) {
+            continue;
+        }
+        newlines = 0;
+        clean.push(c);
+    }
+    clean.nfc().collect()
+}
+
+/// C0 and C1 controls, DEL, and the bidi embedding, override and isolate characters. The
+/// left-to-right and right-to-left marks stay, RTL text needs them.
+fn is_stripped(c: char) -> bool {
+    (c.is_control() && c != '\t') || matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_characters_are_stripped_but_newlines_and_tabs_stay() {
+        assert_eq!(sanitize("\u{1b}[31mred\u{1b}[0m"), "[31mred[0m");
+        assert_eq!(sanitize("nul\0 del\u{7f} nel\u{85} cr\r"), "nul del nel cr");
+        assert_eq!(sanitize("a\tb\nc"), "a\tb\nc");
+    }
+
+    #[test]
+    fn runs_of_newlines_are_capped() {
+        assert_eq!(sanitize("a\n\n\n\n\nb"), "a\n\nb");
+        // A control character between them doesn't break up the run.
+        assert_eq!(sanitize("a\n\n\0\n\nb"), "a\n\nb");
+        assert_eq!(sanitize("a\n\nb\n\nc"), "a\n\nb\n\nc");
+    }
+
+    #[test]
+    fn emoji_come_through_whole() {
+        for text in [
+            "grinning 😀",
+            "family 👩\u{200D}👩\u{200D}👧",
+            "thumbs 👍🏽",
+            "flag 🇬🇧",
+            "keycap 1\u{FE0F}\u{20E3}",
+        ] {
+            assert_eq!(sanitize(text), text);
+        }
+    }
+
+    #[test]
+    fn combining_characters_are_composed() {
+        assert_eq!(sanitize("cafe\u{301}"), "café");
+        assert_eq!(sanitize("\u{1100}\u{1161}"), "가");
+        // Nothing composes with these, they're left as they are.
+        assert_eq!(sanitize("q\u{307}\u{323}"), "q\u{323}\u{307}");
+    }
+
+    #[test]
+    fn rtl_text_keeps_its_marks_but_not_overrides() {
+        assert_eq!(sanitize("שלום עולם"), "שלום עולם");
+        assert_eq!(sanitize("مرحبا\u{200F} 123"), "مرحبا\u{200F} 123");
+        assert_eq!(sanitize("\u{202E}txt.exe\u{202C}"), "txt.exe");
+        assert_eq!(sanitize("\u{2067}isolated\u{2069}"), "isolated");
+    }
+
+    #[test]
+    fn text_of_nothing_but_controls_ends_up_empty() {
+        assert_eq!(sanitize("\0\u{1b}\u{202E}\u{7}"), "");
+    }
+}
@@ -0,0 +1,463 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::domain::attachment::DEFAULT_MAX_ATTACHMENT_BYTES;
+use crate::domain::commands::CommandRegistry;
+use crate::domain::crypto::secrets_match;
+use crate::domain::rate_limit::{DEFAULT_BURST, DEFAULT_WINDOW_SECS};
+use crate::services::bounded_channel::DEFAULT_CAPACITY;
+use crate::services::compression::DEFAULT_COMPRESSION_THRESHOLD;
+use crate::services::login::getenv;
+use crate::services::message_builder::DEFAULT_MAX_OUTBOUND_FRAME_BYTES;
+use crate::services::outbound::{DEFAULT_FLUSH_MILLIS, DEFAULT_MAX_BATCH};
+use crate::workers::app::{DEFAULT_MAX_MESSAGE_LEN, DEFAULT_RESUME_GRACE_SECS};
+use crate::workers::user_session::{
+    IdleTimeout, Keepalive, PlaintextPolicy, DEFAULT_AWAY_IDLE_TIMEOUT_SECS, DEFAULT_FRAME_BURST,
+    DEFAULT_FRAME_RATE, DEFAULT_IDLE_GRACE_SECS, DEFAULT_IDLE_TIMEOUT_SECS, DEFAULT_KEEPALIVE_SECS,
+    DEFAULT_MAX_FRAME_BYTES, DEFAULT_MAX_FRAME_STRIKES, DEFAULT_MAX_IN_FLIGHT,
+    DEFAULT_MAX_MISSED_PONGS,
+};
+
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Everything the accept loop, the sessions and the App can be tuned with. Built from the
+/// environment in `main` and checked once before anything starts, missing fields in a file
+/// take their defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub port: u16,
+    /// Whether clients may send plaintext JSON as well as encrypted bincode.
+    pub accept_json: bool,
+    pub max_frame_bytes: usize,
+    /// Responses bigger than this are sent in chunks.
+    pub max_outbound_frame_bytes: usize,
+    /// Payloads at least this big are deflated for clients that ask, 0 turns compression off.
+    pub compression_threshold: usize,
+    pub max_frame_strikes: u32,
+    pub frame_rate: u32,
+    pub frame_burst: u32,
+    pub max_in_flight: usize,
+    /// 0 turns the keepalive off.
+    pub keepalive_secs: u64,
+    pub keepalive_misses: u32,
+    /// 0 turns the idle timeout off.
+    pub idle_timeout_secs: u64,
+    pub away_idle_timeout_secs: u64,
+    pub idle_grace_secs: u64,
+    pub write_batch: usize,
+    pub flush_millis: u64,
+    pub max_attachment_bytes: u64,
+    pub plaintext_policy: PlaintextPolicy,
+    pub command_queue: usize,
+    pub command_burst: u32,
+    pub command_window_secs: u64,
+    pub resume_grace_secs: u64,
+    pub max_message_len: usize,
+    /// Seconds between uses of each command by the same user, by command name. 0 or a
+    /// command left out means no cooldown.
+    pub cooldowns: BTreeMap<String, u64>,
+    /// Presented at login, logs the client in as an admin. Unset means nobody can be.
+    #[serde(skip_serializing)]
+    pub admin_token: AdminToken,
+}
+
+/// Kept out of the logs and of any config written back out.
+#[derive(Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct AdminToken(String);
+
+impl AdminToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        AdminToken(token.into())
+    }
+
+    pub fn matches(&self, given: &str) -> bool {
+        secrets_match(&self.0, given)
+    }
+}
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AdminToken(<redacted>)")
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            accept_json: false,
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_outbound_frame_bytes: DEFAULT_MAX_OUTBOUND_FRAME_BYTES,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            max_frame_strikes: DEFAULT_MAX_FRAME_STRIKES,
+            frame_rate: DEFAULT_FRAME_RATE,
+            frame_burst: DEFAULT_FRAME_BURST,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            keepalive_secs: DEFAULT_KEEPALIVE_SECS,
+            keepalive_misses: DEFAULT_MAX_MISSED_PONGS,
+            idle_timeout_secs: DEFAULT_IDLE_TIMEOUT_SECS,
+            away_idle_timeout_secs: DEFAULT_AWAY_IDLE_TIMEOUT_SECS,
+            idle_grace_secs: DEFAULT_IDLE_GRACE_SECS,
+            write_batch: DEFAULT_MAX_BATCH,
+            flush_millis: DEFAULT_FLUSH_MILLIS,
+            max_attachment_bytes: DEFAULT_MAX_ATTACHMENT_BYTES,
+            plaintext_policy: PlaintextPolicy::Allow,
+            command_queue: DEFAULT_CAPACITY,
+            command_burst: DEFAULT_BURST,
+            command_window_secs: DEFAULT_WINDOW_SECS,
+            resume_grace_secs: DEFAULT_RESUME_GRACE_SECS,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            cooldowns: CommandRegistry::cooldowns()
+                .iter()
+                .map(|(name, secs)| (name.to_string(), *secs))
+                .collect(),
+            admin_token: AdminToken::default(),
+        }
+    }
+}
+
+/// A setting that can't work, or can't work with another one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub field: &'static str,
+    pub reason: String,
+}
+
+impl ConfigError {
+    fn new(field: &'static str, reason: impl Into<String>) -> Self {
+        Self {
+            field,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid {}: {}", self.field, self.reason)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// An unset or unreadable variable keeps the default.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    getenv(name).parse().unwrap_or(default)
+}
+
+/// A comma separated list of `command=secs`, e.g. `export=60,search=0`.
+fn parse_cooldowns(text: &str) -> Option<Vec<(String, u64)>> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, secs) = entry.split_once('=')?;
+            let name = name.trim().trim_start_matches('/');
+            Some((name.to_string(), secs.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// MARAIN_COOLDOWNS is laid over the defaults, so it only has to name the commands it
+/// changes. An unreadable list keeps the defaults.
+fn env_cooldowns(mut defaults: BTreeMap<String, u64>) -> BTreeMap<String, u64> {
+    match parse_cooldowns(&getenv("MARAIN_COOLDOWNS")) {
+        Some(entries) => defaults.extend(entries),
+        None => log::warn!("Could not read MARAIN_COOLDOWNS, keeping the default cooldowns"),
+    }
+    defaults
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        if getenv("MARAIN_PORT").is_empty() {
+            log::warn!(
+                "Could not find MARAIN_PORT environment variable. Falling back to {}.",
+                defaults.port
+            );
+        }
+        Self {
+            port: env_or("MARAIN_PORT", defaults.port),
+            accept_json: env_or("MARAIN_ACCEPT_JSON", defaults.accept_json),
+            max_frame_bytes: env_or("MARAIN_MAX_FRAME_BYTES", defaults.max_frame_bytes),
+            max_outbound_frame_bytes: env_or(
+                "MARAIN_MAX_OUTBOUND_FRAME_BYTES",
+                defaults.max_outbound_frame_bytes,
+            ),
+            compression_threshold: env_or(
+                "MARAIN_COMPRESSION_THRESHOLD",
+                defaults.compression_threshold,
+            ),
+            max_frame_strikes: env_or("MARAIN_MAX_FRAME_STRIKES", defaults.max_frame_strikes),
+            frame_rate: env_or("MARAIN_FRAME_RATE", defaults.frame_rate),
+            frame_burst: env_or("MARAIN_FRAME_BURST", defaults.frame_burst),
+            max_in_flight: env_or("MARAIN_MAX_IN_FLIGHT", defaults.max_in_flight),
+            keepalive_secs: env_or("MARAIN_KEEPALIVE_SECS", defaults.keepalive_secs),
+            keepalive_misses: env_or("MARAIN_KEEPALIVE_MISSES", defaults.keepalive_misses),
+            idle_timeout_secs: env_or("MARAIN_IDLE_TIMEOUT_SECS", defaults.idle_timeout_secs),
+            away_idle_timeout_secs: env_or(
+                "MARAIN_AWAY_IDLE_TIMEOUT_SECS",
+                defaults.away_idle_timeout_secs,
+            ),
+            idle_grace_secs: env_or("MARAIN_IDLE_GRACE_SECS", defaults.idle_grace_secs),
+            write_batch: env_or("MARAIN_WRITE_BATCH", defaults.write_batch),
+            flush_millis: env_or("MARAIN_FLUSH_MILLIS", defaults.flush_millis),
+            max_attachment_bytes: env_or(
+                "MARAIN_MAX_ATTACHMENT_BYTES",
+                defaults.max_attachment_bytes,
+            ),
+            plaintext_policy: env_or("MARAIN_PLAINTEXT_POLICY", defaults.plaintext_policy),
+            command_queue: env_or("MARAIN_COMMAND_QUEUE", defaults.command_queue),
+            command_burst: env_or("MARAIN_COMMAND_BURST", defaults.command_burst),
+            command_window_secs: env_or("MARAIN_COMMAND_WINDOW_SECS", defaults.command_window_secs),
+            resume_grace_secs: env_or("MARAIN_RESUME_GRACE_SECS", defaults.resume_grace_secs),
+            max_message_len: env_or("MARAIN_MAX_MESSAGE_LEN", defaults.max_message_len),
+            cooldowns: env_cooldowns(defaults.cooldowns),
+            admin_token: AdminToken::new(getenv("MARAIN_ADMIN_TOKEN")),
+        }
+    }
+
+    /// The first setting that is out of range or contradicts another.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let at_least_one = [
+            ("port", self.port as u64),
+            ("max_frame_bytes", self.max_frame_bytes as u64),
+            (
+                "max_outbound_frame_bytes",
+                self.max_outbound_frame_bytes as u64,
+            ),
+            ("max_frame_strikes", self.max_frame_strikes as u64),
+            ("frame_rate", self.frame_rate as u64),
+            ("frame_burst", self.frame_burst as u64),
+            ("max_in_flight", self.max_in_flight as u64),
+            ("write_batch", self.write_batch as u64),
+            ("command_queue", self.command_queue as u64),
+            ("command_burst", self.command_burst as u64),
+            ("command_window_secs", self.command_window_secs),
+            ("max_message_len", self.max_message_len as u64),
+        ];
+        if let Some((field, _)) = at_least_one.iter().find(|(_, value)| *value == 0) {
+            return Err(ConfigError::new(field, "must be at least 1"));
+        }
+        if self.keepalive_secs > 0 && self.keepalive_misses == 0 {
+            return Err(ConfigError::new(
+                "keepalive_misses",
+                "must be at least 1 while the keepalive is on",
+            ));
+        }
+        // The warning has to give the client a chance before the timeout comes round again.
+        if self.idle_timeout_secs > 0 && self.idle_grace_secs >= self.idle_timeout_secs {
+            return Err(ConfigError::new(
+                "idle_grace_secs",
+                format!(
+                    "{} must be less than idle_timeout_secs ({})",
+                    self.idle_grace_secs, self.idle_timeout_secs
+                ),
+            ));
+        }
+        if self.idle_timeout_secs > 0
+            && self.away_idle_timeout_secs > 0
+            && self.away_idle_timeout_secs < self.idle_timeout_secs
+        {
+            return Err(ConfigError::new(
+                "away_idle_timeout_secs",
+                format!(
+                    "{} must not be shorter than idle_timeout_secs ({})",
+                    self.away_idle_timeout_secs, self.idle_timeout_secs
+                ),
+            ));
+        }
+        if let Some(name) = self
+            .cooldowns
+            .keys()
+            .find(|name| CommandRegistry::get(name).is_none())
+        {
+            return Err(ConfigError::new(
+                "cooldowns",
+                format!("/{name} is not a command"),
+            ));
+        }
+        // A keepalive ping waiting behind a flush as long as its interval would always be late.
+        if self.keepalive_secs > 0 && self.flush_millis >= self.keepalive_secs * 1000 {
+            return Err(ConfigError::new(
+                "flush_millis",
+                "must be less than the keepalive interval",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn keepalive(&self) -> Keepalive {
+        Keepalive {
+            interval_secs: self.keepalive_secs,
+            max_missed: self.keepalive_misses,
+        }
+    }
+
+    pub fn idle_timeout(&self) -> IdleTimeout {
+        IdleTimeout {
+            timeout_secs: self.idle_timeout_secs,
+            away_timeout_secs: self.away_idle_timeout_secs,
+            grace_secs: self.idle_grace_secs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_field(config: ServerConfig) -> &'static str {
+        config
+            .validate()
+            .expect_err("the config was accepted")
+            .field
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        assert_eq!(ServerConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn sizes_and_rates_of_zero_are_refused() {
+        let zeroed = [
+            ServerConfig {
+                max_frame_bytes: 0,
+                ..Default::default()
+            },
+            ServerConfig {
+                frame_rate: 0,
+                ..Default::default()
+            },
+            ServerConfig {
+                max_in_flight: 0,
+                ..Default::default()
+            },
+            ServerConfig {
+                command_window_secs: 0,
+                ..Default::default()
+            },
+        ];
+        let fields: Vec<&str> = zeroed.into_iter().map(invalid_field).collect();
+        assert_eq!(
+            fields,
+            [
+                "max_frame_bytes",
+                "frame_rate",
+                "max_in_flight",
+                "command_window_secs"
+            ]
+        );
+    }
+
+    #[test]
+    fn a_keepalive_needs_a_miss_allowance_unless_it_is_off() {
+        let config = ServerConfig {
+            keepalive_misses: 0,
+            ..Default::default()
+        };
+        assert_eq!(invalid_field(config.clone()), "keepalive_misses");
+
+        let off = ServerConfig {
+            keepalive_secs: 0,
+            ..config
+        };
+        assert_eq!(off.validate(), Ok(()));
+    }
+
+    #[test]
+    fn the_idle_warning_comes_before_the_timeout() {
+        let config = ServerConfig {
+            idle_timeout_secs: 60,
+            idle_grace_secs: 60,
+            ..Default::default()
+        };
+        let error = config.validate().unwrap_err();
+        assert_eq!(error.field, "idle_grace_secs");
+        assert_eq!(
+            error.to_string(),
+            "Invalid idle_grace_secs: 60 must be less than idle_timeout_secs (60)"
+        );
+    }
+
+    #[test]
+    fn away_users_are_not_timed_out_sooner() {
+        let config = ServerConfig {
+            idle_timeout_secs: 600,
+            away_idle_timeout_secs: 300,
+            idle_grace_secs: 30,
+            ..Default::default()
+        };
+        assert_eq!(invalid_field(config), "away_idle_timeout_secs");
+    }
+
+    #[test]
+    fn a_flush_as_slow_as_the_keepalive_is_refused() {
+        let config = ServerConfig {
+            keepalive_secs: 2,
+            flush_millis: 2000,
+            ..Default::default()
+        };
+        assert_eq!(invalid_field(config), "flush_millis");
+    }
+
+    #[test]
+    fn cooldowns_are_read_as_a_list_of_commands_and_seconds() {
+        assert_eq!(
+            parse_cooldowns(" export=120, /search=0 ,,"),
+            Some(vec![("export".into(), 120), ("search".into(), 0)])
+        );
+        assert_eq!(parse_cooldowns(""), Some(vec![]));
+        assert_eq!(parse_cooldowns("export"), None);
+        assert_eq!(parse_cooldowns("export=soon"), None);
+    }
+
+    #[test]
+    fn cooldowns_only_name_real_commands() {
+        let mut config = ServerConfig::default();
+        config.cooldowns.insert("who".into(), 10);
+        assert_eq!(config.validate(), Ok(()));
+
+        config.cooldowns.insert("teleport".into(), 10);
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid cooldowns: /teleport is not a command"
+        );
+    }
+
+    #[test]
+    fn the_admin_token_is_never_printed_or_written_out() {
+        let config = ServerConfig {
+            admin_token: AdminToken::new("hunter2"),
+            ..Default::default()
+        };
+        assert!(config.admin_token.matches("hunter2"));
+        assert!(!config.admin_token.matches("hunter3"));
+        assert!(!AdminToken::default().matches(""));
+
+        assert!(!format!("{config:?}").contains("hunter2"));
+        assert!(!serde_json::to_string(&config).unwrap().contains("hunter2"));
+        let read: ServerConfig = serde_json::from_str(r#"{"admin_token":"hunter2"}"#).unwrap();
+        assert!(read.admin_token.matches("hunter2"));
+    }
+
+    #[test]
+    fn a_config_file_only_needs_what_it_changes() {
+        let config: ServerConfig = serde_json::from_str(r#"{"port":9000,"frame_rate":5}"#).unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.frame_rate, 5);
+        assert_eq!(config.max_frame_bytes, DEFAULT_MAX_FRAME_BYTES);
+
+        let written = serde_json::to_string(&config).unwrap();
+        let read: ServerConfig = serde_json::from_str(&written).unwrap();
+        assert_eq!(format!("{read:?}"), format!("{config:?}"));
+    }
+}
@@ -0,0 +1,215 @@
+// Debug logging of what each connection sends and receives and where the session routes it,
+// for chasing protocol bugs. Only built with the `wire-trace` feature, without it every
+// function here is empty and nothing is formatted or cloned. Chat contents are logged as
+// their length unless `trace-content` is on as well, session tokens and keys never are.
+
+pub use trace::*;
+
+#[cfg(feature = "wire-trace")]
+mod trace {
+    use marain_api::prelude::{ClientMsg, ClientMsgBody, ServerMsg, ServerMsgBody};
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::domain::commands::CommandPayload;
+    use crate::services::command_parser::ParseError;
+
+    const TARGET: &str = "wire";
+
+    /// What may be said about some chat text.
+    pub fn content(text: &str) -> String {
+        if cfg!(feature = "trace-content") {
+            format!("{text:?}")
+        } else {
+            format!("<{} chars>", text.chars().count())
+        }
+    }
+
+    fn frame_kind(frame: &Message) -> &'static str {
+        match frame {
+            Message::Text(_) => "text",
+            Message::Binary(_) => "binary",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Close(_) => "close",
+            Message::Frame(_) => "raw",
+        }
+    }
+
+    pub fn inbound_frame(user_id: &str, frame: &Message) {
+        log::debug!(
+            target: TARGET,
+            "{user_id} <- {} frame, {} bytes",
+            frame_kind(frame),
+            frame.len()
+        );
+    }
+
+    pub fn outbound_frame(user_id: &str, frame: &Message) {
+        log::debug!(
+            target: TARGET,
+            "{user_id} -> {} frame, {} bytes",
+            frame_kind(frame),
+            frame.len()
+        );
+    }
+
+    pub fn decoded(user_id: &str, msg: &ClientMsg) {
+        log::debug!(target: TARGET, "{user_id} decoded {}", client_body(msg));
+    }
+
+    fn client_body(msg: &ClientMsg) -> String {
+        // No wildcard, a new ClientMsgBody has to say what of it is safe to log.
+        match &msg.body {
+            ClientMsgBody::Login(name, _) => format!("Login as {name:?}"),
+            ClientMsgBody::SendToRoom { contents } => format!("SendToRoom {}", content(contents)),
+            ClientMsgBody::Move { target } => format!("Move to {target:?}"),
+            ClientMsgBody::GetTime => "GetTime".to_string(),
+        }
+    }
+
+    /// Which of the session's handlers a parsed message went to.
+    pub fn routed(user_id: &str, payload: &CommandPayload) {
+        let route = match payload {
+            CommandPayload::Time { .. } | CommandPayload::Ping { .. } | CommandPayload::Help(_) => {
+                "answered by the session"
+            }
+            _ => "forwarded to the App",
+        };
+        log::debug!(target: TARGET, "{user_id} /{} {route}", payload.name());
+    }
+
+    /// The parse error's text can repeat what the user typed, so only the command is given.
+    pub fn refused(user_id: &str, error: &ParseError) {
+        log::debug!(target: TARGET, "{user_id} refused{}", refusal(error));
+    }
+
+    fn refusal(error: &ParseError) -> String {
+        if cfg!(feature = "trace-content") {
+            format!(": {error}")
+        } else {
+            format!(" /{}", error.command())
+        }
+    }
+
+    pub fn encoded(msg: &ServerMsg, serialized_bytes: usize) {
+        log::debug!(
+            target: TARGET,
+            "encoded {}, {serialized_bytes} bytes",
+            server_body(msg)
+        );
+    }
+
+    fn server_body(msg: &ServerMsg) -> String {
+        // No wildcard, a new ServerMsgBody has to say what of it is safe to log.
+        match &msg.body {
+            ServerMsgBody::Empty => "Empty".to_string(),
+            ServerMsgBody::LoginSuccess { .. } => "LoginSuccess".to_string(),
+            ServerMsgBody::RoomData {
+                room_name,
+                logs,
+                notifications,
+                occupants,
+                ..
+            } => format!(
+                "RoomData for {room_name:?}, {} logs, {} notifications, {} occupants",
+                logs.len(),
+                notifications.len(),
+                occupants.len()
+            ),
+            ServerMsgBody::ChatRecv { direct, chat_msg } => format!(
+                "ChatRecv{} from {:?} {}",
+                if *direct { " (direct)" } else { "" },
+                chat_msg.sender,
+                content(&chat_msg.content)
+            ),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use chrono::Utc;
+        use marain_api::prelude::{ChatMsg, Status, Timestamp};
+
+        use super::*;
+
+        const SECRET: &str = "the plans are in the shed";
+
+        fn chat_from_client() -> ClientMsg {
+            ClientMsg {
+                token: Some("token".into()),
+                timestamp: Timestamp::from(Utc::now()),
+                body: ClientMsgBody::SendToRoom {
+                    contents: SECRET.into(),
+                },
+            }
+        }
+
+        fn chat_to_client() -> ServerMsg {
+            ServerMsg {
+                status: Status::Yes,
+                timestamp: Timestamp::from(Utc::now()),
+                body: ServerMsgBody::ChatRecv {
+                    direct: true,
+                    chat_msg: ChatMsg {
+                        sender: "ann".into(),
+                        timestamp: Timestamp::from(Utc::now()),
+                        content: SECRET.into(),
+                    },
+                },
+            }
+        }
+
+        #[cfg(not(feature = "trace-content"))]
+        #[test]
+        fn chat_contents_are_traced_as_their_length() {
+            assert_eq!(client_body(&chat_from_client()), "SendToRoom <25 chars>");
+            assert_eq!(
+                server_body(&chat_to_client()),
+                "ChatRecv (direct) from \"ann\" <25 chars>"
+            );
+            let error = ParseError::InvalidArgument {
+                command: "msg".into(),
+                argument: "user",
+                value: SECRET.into(),
+            };
+            assert_eq!(refusal(&error), " /msg");
+        }
+
+        #[cfg(feature = "trace-content")]
+        #[test]
+        fn trace_content_shows_chat_in_full() {
+            assert_eq!(
+                client_body(&chat_from_client()),
+                format!("SendToRoom {SECRET:?}")
+            );
+            assert!(server_body(&chat_to_client()).ends_with(&format!("{SECRET:?}")));
+        }
+    }
+}
+
+#[cfg(not(feature = "wire-trace"))]
+mod trace {
+    use marain_api::prelude::{ClientMsg, ServerMsg};
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::domain::commands::CommandPayload;
+    use crate::services::command_parser::ParseError;
+
+    #[inline(always)]
+    pub fn inbound_frame(_user_id: &str, _frame: &Message) {}
+
+    #[inline(always)]
+    pub fn outbound_frame(_user_id: &str, _frame: &Message) {}
+
+    #[inline(always)]
+    pub fn decoded(_user_id: &str, _msg: &ClientMsg) {}
+
+    #[inline(always)]
+    pub fn routed(_user_id: &str, _payload: &CommandPayload) {}
+
+    #[inline(always)]
+    pub fn refused(_user_id: &str, _error: &ParseError) {}
+
+    #[inline(always)]
+    pub fn encoded(_msg: &ServerMsg, _serialized_bytes: usize) {}
+}
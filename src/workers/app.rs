@@ -1,17 +1,42 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
 
-use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use chrono::{DateTime, Duration, Utc};
+use futures_channel::mpsc::{Receiver, UnboundedSender};
 use futures_util::StreamExt;
+use marain_api::prelude::{ServerMsg, Timestamp};
+use rand_core::{OsRng, RngCore};
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+    time::{interval, sleep_until, Instant},
+};
+use tokio_tungstenite::tungstenite::Message;
 
 use crate::domain::{
-    chat_log::MessageLog,
-    commands::{Command, CommandPayload},
-    events::Event,
-    notification_log::NotificationLog,
-    room::Room,
-    user::User,
+    audit_log::{AuditEntry, AuditLog, AuditOutcome},
+    chat_log::{HistoryPage, HistoryQuery, MessageId, MessageLog},
+    commands::{Command, CommandPayload, CommandRegistry},
+    cooldown::Cooldowns,
+    crypto::SessionKey,
+    error_reason::ErrorReason,
+    events::{Event, RoomSnapshot},
+    notification_log::{NotificationLog, Severity},
+    rate_limit::RateLimiter,
+    room::{Ban, Room, RoomSettings, RoomStats},
+    server_info::ServerInfo,
+    transcript::{self, ExportFormat},
+    user::{is_reserved_name, Moderation, PresenceStatus, Role, User},
+};
+
+use crate::services::{
+    frame_metrics::frame_metrics,
+    message_builder::{self, ServerMsgFactory, SocketSendAdaptor},
+    server_config::ServerConfig,
 };
 
+use super::plugins::DicePlugin;
+
 use anyhow::{anyhow, Result};
 
 struct EventBus {
@@ -25,12 +50,31 @@ impl EventBus {
         }
     }
 
-    pub fn publish(&mut self, broadcast: &Broadcast) {
+    /// Returns the subscribers whose sessions have gone away, they are unsubscribed here and
+    /// still need dropping from the AppState.
+    pub fn publish(&mut self, broadcast: &Broadcast) -> Vec<User> {
+        let mut closed = vec![];
+        let mut frames = broadcast.seal();
         for user in &broadcast.subscribers {
             if let Some(channel) = self.subscribers.get(user) {
-                channel.unbounded_send(broadcast.event.clone()).unwrap();
+                let event = match frames.remove(&user.id) {
+                    Some(frame) => Event::Frame { frame },
+                    None => broadcast.event.clone(),
+                };
+                if let Err(e) = channel.unbounded_send(event) {
+                    log::debug!("Session for {} closed before delivery: {e}", user.name);
+                    closed.push(user.clone());
+                }
             }
         }
+        for user in &closed {
+            self.subscribers.remove(user);
+        }
+        closed
+    }
+
+    pub fn is_subscribed(&self, user: &User) -> bool {
+        self.subscribers.contains_key(user)
     }
 
     pub fn subscribe(
@@ -63,22 +107,214 @@ impl Broadcast {
     fn new(event: Event, subscribers: Vec<User>) -> Self {
         Self { event, subscribers }
     }
+
+    /// Room chat and notices are serialized once for each protocol version among the
+    /// subscribers and encrypted per subscriber, by user id, instead of every session
+    /// building the same message again. Anyone missing is sent the event to build it.
+    fn seal(&self) -> HashMap<String, Message> {
+        let build: Box<dyn Fn(u32) -> ServerMsg> = match &self.event {
+            Event::MsgReceived { msg } => {
+                Box::new(|version| ServerMsgFactory::build_msg_log_server_msg(msg.clone(), version))
+            }
+            Event::Notify { notice } => {
+                Box::new(|_| ServerMsgFactory::build_notification_server_msg(notice.clone()))
+            }
+            _ => return HashMap::new(),
+        };
+        let mut by_version: HashMap<u32, Vec<(String, SessionKey)>> = HashMap::new();
+        for user in &self.subscribers {
+            by_version
+                .entry(user.protocol_version)
+                .or_default()
+                .push((user.id.clone(), user.shared_secret.clone()));
+        }
+        let mut frames = HashMap::new();
+        for (version, recipients) in by_version {
+            match SocketSendAdaptor::prepare_broadcast(&build(version), &recipients) {
+                Ok(sealed) => frames.extend(sealed),
+                Err(e) => log::warn!("Could not seal a broadcast for version {version}: {e}"),
+            }
+        }
+        frames
+    }
+
+    fn reply(user: &User, text: impl Into<String>) -> Self {
+        Self::new(
+            Event::Reply {
+                notice: NotificationLog::new(text.into()),
+                request_id: None,
+            },
+            vec![user.clone()],
+        )
+    }
+
+    fn rejection(user: &User, reason: impl Into<String>) -> Self {
+        Self::new(
+            Event::Rejected {
+                reason: reason.into(),
+                request_id: None,
+                kind: None,
+            },
+            vec![user.clone()],
+        )
+    }
+
+    /// A rejection clients can recognise by its kind as well as read.
+    fn error(user: &User, kind: ErrorReason, detail: impl Into<String>) -> Self {
+        Self::new(
+            Event::Rejected {
+                reason: detail.into(),
+                request_id: None,
+                kind: Some(kind),
+            },
+            vec![user.clone()],
+        )
+    }
+
+    fn send_nack(
+        user: &User,
+        msg_id: Option<String>,
+        kind: ErrorReason,
+        reason: impl Into<String>,
+    ) -> Self {
+        Self::new(
+            Event::SendNack {
+                msg_id,
+                kind,
+                reason: reason.into(),
+                request_id: None,
+            },
+            vec![user.clone()],
+        )
+    }
+}
+
+/// What a CommandPlugin gets to work with: the issuing user and their view of the App.
+pub struct CommandContext<'a> {
+    pub user: &'a User,
+    state: &'a mut AppState,
+    event_buf: &'a mut VecDeque<Broadcast>,
+}
+
+impl<'a> CommandContext<'a> {
+    pub fn room(&self) -> Room {
+        self.state.get_occupied_room(self.user).unwrap_or_default()
+    }
+
+    pub fn reply(&mut self, text: impl Into<String>) {
+        self.event_buf.push_back(Broadcast::reply(self.user, text));
+    }
+
+    pub fn reject(&mut self, reason: impl Into<String>) {
+        self.event_buf
+            .push_back(Broadcast::rejection(self.user, reason));
+    }
+
+    /// Records a notification in the user's room and sends it to everyone there.
+    pub fn notify_room(&mut self, text: impl Into<String>) {
+        let room = self.room();
+        let broadcast = self
+            .state
+            .broadcast_notification(&room, Severity::Info, text);
+        self.event_buf.push_back(broadcast);
+    }
+}
+
+/// A command implemented outside the CommandHandler's own match. Plugins are looked up by
+/// CommandPayload::name once the rate limit and permission gates have passed, and take
+/// precedence over the built in handling of the same name.
+pub trait CommandPlugin: Send {
+    fn handle(&mut self, ctx: &mut CommandContext, payload: CommandPayload) -> Result<()>;
+}
+
+const MAX_USERNAME_LEN: usize = 32;
+const MAX_ROOM_NAME_LEN: usize = 32;
+const MAX_DEPARTED: usize = 50;
+const MAX_HISTORY_LIMIT: usize = 200;
+const MAX_PURGE: usize = 100;
+const MAX_PINS: usize = 20;
+const MIN_SEARCH_LEN: usize = 2;
+
+/// How long someone who leaves a locked room can still get back in.
+const LOCK_GRACE_SECS: i64 = 300;
+/// A user's presence changes within this long of the last one the room heard about are only
+/// told to the user, the room hears the latest once the window is over.
+const PRESENCE_DEBOUNCE_SECS: i64 = 3;
+/// The away message of a user who went quiet for long enough to be warned about it.
+const IDLE_AWAY_MESSAGE: &str = "idle";
+
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 2000;
+
+pub const DEFAULT_RESUME_GRACE_SECS: u64 = 120;
+/// How often detached users are checked for having run out of time.
+const DETACHED_SWEEP_SECS: u64 = 5;
+
+/// How long without a message before `/seen` calls a connected user idle.
+const IDLE_AFTER_SECS: i64 = 300;
+
+/// Enough of a user who has disconnected to answer `/seen` about them.
+struct Departure {
+    name: String,
+    room: Room,
+    last_active: DateTime<Utc>,
+    left_at: DateTime<Utc>,
+}
+
+fn presence_text(name: &str, status: &PresenceStatus) -> String {
+    match status {
+        PresenceStatus::Online => format!("{name} is back"),
+        PresenceStatus::Away(None) => format!("{name} is away"),
+        PresenceStatus::Away(Some(message)) => format!("{name} is away: {message}"),
+    }
+}
+
+/// What taking a user out of their room did to it.
+#[derive(Debug)]
+enum RemovalOutcome {
+    /// The user wasn't in any room.
+    NotFound,
+    Removed {
+        room: Room,
+        /// Nobody is left in the room.
+        emptied: bool,
+        /// The room was left empty and nobody owned it, so it is gone. The lobby never is.
+        collected: bool,
+    },
 }
 
 struct AppState {
     occupancy: HashMap<Room, Vec<User>>,
     chat_logs: HashMap<Room, VecDeque<MessageLog>>,
     notifications: HashMap<Room, VecDeque<NotificationLog>>,
+    settings: HashMap<Room, RoomSettings>,
+    stats: HashMap<Room, RoomStats>,
+    /// The id the next message recorded in each room will get.
+    next_message_ids: HashMap<Room, MessageId>,
+    /// Counts each room's changes of occupants, so a client can tell it has missed one.
+    occupancy_epochs: HashMap<Room, u64>,
+    departed: VecDeque<Departure>,
+    /// What shadow banned users said, by user id, kept for moderators and nobody else.
+    shadow_logs: HashMap<String, VecDeque<MessageLog>>,
     max_logs: usize,
+    max_topic_len: usize,
+    max_rooms: usize,
 }
 
 impl AppState {
     fn new() -> Self {
         Self {
-            occupancy: HashMap::from([(Room::default(), vec![])]),
-            chat_logs: HashMap::from([(Room::default(), VecDeque::new())]),
-            notifications: HashMap::from([(Room::default(), VecDeque::new())]),
+            occupancy: HashMap::from([(Room::lobby(), vec![])]),
+            chat_logs: HashMap::from([(Room::lobby(), VecDeque::new())]),
+            notifications: HashMap::from([(Room::lobby(), VecDeque::new())]),
+            settings: HashMap::new(),
+            stats: HashMap::from([(Room::lobby(), RoomStats::new(Utc::now()))]),
+            next_message_ids: HashMap::new(),
+            occupancy_epochs: HashMap::new(),
+            departed: VecDeque::new(),
+            shadow_logs: HashMap::new(),
             max_logs: 25,
+            max_topic_len: 200,
+            max_rooms: 100,
         }
     }
 
@@ -88,65 +324,445 @@ impl AppState {
 
     fn room_chat_logs(&self, room: &Room) -> Vec<MessageLog> {
         self.chat_logs
-            .get(&room)
+            .get(room)
             .unwrap_or(&VecDeque::new())
             .iter()
-            .map(|msg| msg.clone())
+            .cloned()
             .collect()
     }
 
+    fn history_page(&self, room: &Room, query: &HistoryQuery) -> HistoryPage {
+        match self.chat_logs.get(room) {
+            Some(logs) => query.page(logs.iter()),
+            None => HistoryPage::default(),
+        }
+    }
+
+    fn room_snapshot(&self, room: &Room) -> RoomSnapshot {
+        RoomSnapshot {
+            msg_log: self.room_chat_logs(room),
+            notifications: self.room_notifications(room),
+            occupant_names: self.occupant_names(room),
+            topic: self.room_topic(room),
+            pinned: self.room_pins(room),
+            occupancy_epoch: self.occupancy_epoch(room),
+        }
+    }
+
+    fn occupancy_epoch(&self, room: &Room) -> u64 {
+        self.occupancy_epochs.get(room).copied().unwrap_or_default()
+    }
+
+    fn bump_occupancy_epoch(&mut self, room: &Room) {
+        *self.occupancy_epochs.entry(room.clone()).or_default() += 1;
+    }
+
+    /// Drops the newest `count` messages from the room's history, giving how many went.
+    fn purge_messages(&mut self, room: &Room, count: usize) -> usize {
+        let Some(logs) = self.chat_logs.get_mut(room) else {
+            return 0;
+        };
+        let purged = count.min(logs.len());
+        logs.truncate(logs.len() - purged);
+        purged
+    }
+
+    fn next_message_id(&mut self, room: &Room) -> MessageId {
+        let next = self.next_message_ids.entry(room.clone()).or_insert(1);
+        let id = *next;
+        *next += 1;
+        id
+    }
+
+    fn find_message(&self, room: &Room, id: MessageId) -> Option<&MessageLog> {
+        MessageLog::find(self.chat_logs.get(room)?, id)
+    }
+
+    /// Pins whose message has since been purged or aged out of the log are forgotten.
+    fn prune_pins(&mut self, room: &Room) {
+        let Some(settings) = self.settings.get(room) else {
+            return;
+        };
+        let live: Vec<MessageId> = settings
+            .pinned
+            .iter()
+            .copied()
+            .filter(|id| self.find_message(room, *id).is_some())
+            .collect();
+        self.room_settings_mut(room).pinned = live;
+    }
+
+    fn room_pins(&self, room: &Room) -> Vec<MessageLog> {
+        self.settings
+            .get(room)
+            .map(|settings| {
+                settings
+                    .pinned
+                    .iter()
+                    .filter_map(|id| self.find_message(room, *id).cloned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Every room with chat or notifications in it, whether or not anyone is there.
+    fn rooms_with_history(&self) -> Vec<Room> {
+        let mut rooms: Vec<Room> = self
+            .chat_logs
+            .keys()
+            .chain(self.notifications.keys())
+            .cloned()
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms.dedup();
+        rooms
+    }
+
     fn room_notifications(&self, room: &Room) -> Vec<NotificationLog> {
         self.notifications
-            .get(&room)
+            .get(room)
             .unwrap_or(&VecDeque::new())
             .iter()
-            .map(|msg| msg.clone())
+            .cloned()
             .collect()
     }
 
     fn occupant_names(&self, room: &Room) -> Vec<String> {
-        self.room_subscribers(&room)
+        self.room_subscribers(room)
             .iter()
-            .map(|sub| sub.name.clone())
+            .map(|sub| sub.display_name())
             .collect()
     }
 
+    /// Private rooms are left out, busiest rooms first, ties broken by name so the listing is stable.
+    fn room_occupancy(&self) -> Vec<(Room, usize)> {
+        let mut rooms: Vec<(Room, usize)> = self
+            .occupancy
+            .iter()
+            .filter(|(room, _)| !self.is_private(room))
+            .map(|(room, occupants)| (room.clone(), occupants.len()))
+            .collect();
+        rooms.sort_by(|(a, a_count), (b, b_count)| {
+            b_count.cmp(a_count).then_with(|| a.name.cmp(&b.name))
+        });
+        rooms
+    }
+
+    fn room_topic(&self, room: &Room) -> Option<String> {
+        self.settings
+            .get(room)
+            .and_then(|settings| settings.topic.clone())
+    }
+
+    fn room_exists(&self, room: &Room) -> bool {
+        self.occupancy.contains_key(room)
+    }
+
+    /// Rooms are created on demand, so this guards both /create and moving into a
+    /// room that doesn't exist yet.
+    fn validate_new_room(&self, room: &Room) -> Result<(), String> {
+        let name = room.name.as_str();
+        if name.trim().is_empty() {
+            return Err("Room names cannot be empty".into());
+        }
+        if name != name.trim() || name.chars().count() > MAX_ROOM_NAME_LEN {
+            return Err(format!(
+                "Room names must be at most {MAX_ROOM_NAME_LEN} characters without leading or trailing spaces"
+            ));
+        }
+        if !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ' ')
+        {
+            return Err("Room names may only contain letters, numbers, spaces, - and _".into());
+        }
+        if self.occupancy.len() >= self.max_rooms {
+            return Err(format!(
+                "The server cannot hold more than {} rooms",
+                self.max_rooms
+            ));
+        }
+        Ok(())
+    }
+
+    fn create_room(&mut self, room: &Room, owner: &User) {
+        self.occupancy.insert(room.clone(), vec![]);
+        self.chat_logs.insert(room.clone(), VecDeque::new());
+        self.notifications.insert(room.clone(), VecDeque::new());
+        self.room_settings_mut(room).owner = Some(owner.id.clone());
+        self.stats.insert(room.clone(), RoomStats::new(Utc::now()));
+    }
+
+    /// Drops everything held for the room and hands back whoever was still in it.
+    fn delete_room(&mut self, room: &Room) -> Option<Vec<User>> {
+        let occupants = self.occupancy.remove(room)?;
+        self.chat_logs.remove(room);
+        self.notifications.remove(room);
+        self.settings.remove(room);
+        self.stats.remove(room);
+        self.next_message_ids.remove(room);
+        self.occupancy_epochs.remove(room);
+        Some(occupants)
+    }
+
+    fn is_owner(&self, room: &Room, user: &User) -> bool {
+        self.settings
+            .get(room)
+            .is_some_and(|settings| settings.owner.as_ref() == Some(&user.id))
+    }
+
+    fn is_locked(&self, room: &Room) -> bool {
+        self.settings
+            .get(room)
+            .is_some_and(|settings| settings.locked)
+    }
+
+    fn is_private(&self, room: &Room) -> bool {
+        self.settings
+            .get(room)
+            .is_some_and(|settings| settings.private)
+    }
+
+    fn may_enter(&self, room: &Room, user: &User) -> bool {
+        match self.settings.get(room) {
+            Some(settings) if settings.private => {
+                user.is_admin()
+                    || settings.owner.as_ref() == Some(&user.id)
+                    || settings.invited.contains(&user.id)
+            }
+            _ => true,
+        }
+    }
+
+    /// Locked rooms still admit the owner, moderators and recent leavers.
+    fn locked_out(&self, room: &Room, user: &User) -> bool {
+        let Some(settings) = self.settings.get(room).filter(|settings| settings.locked) else {
+            return false;
+        };
+        let recently_left = settings.lock_leavers.get(&user.id).is_some_and(|left_at| {
+            Utc::now().signed_duration_since(*left_at).num_seconds() < LOCK_GRACE_SECS
+        });
+        !(user.is_moderator() || self.is_owner(room, user) || recently_left)
+    }
+
+    /// Seconds left before the user may chat again under slow mode, recording the send when
+    /// there are none.
+    fn slow_mode_wait(&mut self, room: &Room, user: &User, now: DateTime<Utc>) -> Option<i64> {
+        if user.is_moderator() {
+            return None;
+        }
+        let settings = self.settings.get_mut(room)?;
+        if settings.slow_mode_secs == 0 {
+            return None;
+        }
+        if let Some(last_sent) = settings.last_sent.get(&user.id) {
+            let waited = now.signed_duration_since(*last_sent).num_seconds();
+            let interval = settings.slow_mode_secs as i64;
+            if waited < interval {
+                return Some(interval - waited);
+            }
+        }
+        settings.last_sent.insert(user.id.clone(), now);
+        None
+    }
+
+    fn room_settings_mut(&mut self, room: &Room) -> &mut RoomSettings {
+        self.settings.entry(room.clone()).or_default()
+    }
+
+    fn is_banned(&self, room: &Room, user: &User) -> bool {
+        self.settings
+            .get(room)
+            .is_some_and(|settings| settings.banned.values().any(|ban| ban.catches(user)))
+    }
+
+    fn is_name_banned(&self, room: &Room, name: &str) -> bool {
+        self.settings
+            .get(room)
+            .is_some_and(|settings| settings.banned.contains_key(&Ban::key(name)))
+    }
+
+    /// Some(None) is a mute without an expiry. Expired mutes are cleared here rather than
+    /// by a background task.
+    fn active_mute(&mut self, room: &Room, user: &User) -> Option<Option<DateTime<Utc>>> {
+        let settings = self.settings.get_mut(room)?;
+        let expiry = *settings.muted.get(&user.id)?;
+        match expiry {
+            Some(until) if until <= Utc::now() => {
+                settings.muted.remove(&user.id);
+                None
+            }
+            _ => Some(expiry),
+        }
+    }
+
     fn add_user_to_room(&mut self, user: &User, room: &Room) {
         self.occupancy
             .entry(room.clone())
             .and_modify(|members| members.push(user.clone()))
             .or_insert(vec![user.clone()]);
+        self.bump_occupancy_epoch(room);
+        let occupants = self.occupancy[room].len();
+        self.room_stats_mut(room).record_occupancy(occupants);
+    }
+
+    fn room_stats_mut(&mut self, room: &Room) -> &mut RoomStats {
+        self.stats
+            .entry(room.clone())
+            .or_insert_with(|| RoomStats::new(Utc::now()))
+    }
+
+    fn find_user(&self, user: &User) -> Option<&User> {
+        self.occupancy
+            .values()
+            .flatten()
+            .find(|occupant| *occupant == user)
+    }
+
+    fn find_user_mut(&mut self, user: &User) -> Option<&mut User> {
+        self.occupancy
+            .values_mut()
+            .flatten()
+            .find(|occupant| *occupant == user)
+    }
+
+    fn admin_count(&self) -> usize {
+        self.occupancy
+            .values()
+            .flatten()
+            .filter(|occupant| occupant.is_admin())
+            .count()
+    }
+
+    /// Sorted names of whichever of the given user ids are still connected.
+    fn online_names(&self, ids: &HashSet<String>) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .occupancy
+            .values()
+            .flatten()
+            .filter(|occupant| ids.contains(&occupant.id))
+            .map(|occupant| occupant.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn record_shadow_message(&mut self, user: &User, msg: MessageLog) {
+        let logs = self.shadow_logs.entry(user.id.clone()).or_default();
+        logs.push_back(msg);
+        if logs.len() > self.max_logs {
+            logs.pop_front();
+        }
+    }
+
+    fn find_user_by_name(&self, name: &str) -> Option<&User> {
+        self.occupancy
+            .values()
+            .flatten()
+            .find(|occupant| occupant.name.eq_ignore_ascii_case(name))
+    }
+
+    fn name_taken(&self, name: &str, asking: &User) -> bool {
+        self.occupancy
+            .values()
+            .flatten()
+            .any(|occupant| occupant != asking && occupant.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The session stamps its copy of the user on every message, carry that over to ours.
+    fn touch_user(&mut self, user: &User) {
+        for occupant in self.occupancy.values_mut().flatten() {
+            if *occupant == *user && occupant.last_active < user.last_active {
+                occupant.last_active = user.last_active;
+            }
+        }
+    }
+
+    fn record_departure(&mut self, user: &User, room: &Room) {
+        self.departed
+            .retain(|departure| departure.name != user.name);
+        self.departed.push_back(Departure {
+            name: user.name.clone(),
+            room: room.clone(),
+            last_active: user.last_active,
+            left_at: Utc::now(),
+        });
+        if self.departed.len() > MAX_DEPARTED {
+            self.departed.pop_front();
+        }
+    }
+
+    fn find_departure(&self, name: &str) -> Option<&Departure> {
+        self.departed
+            .iter()
+            .rev()
+            .find(|departure| departure.name == name)
+    }
+
+    fn set_user_name(&mut self, user: &User, name: &str) {
+        for occupant in self.occupancy.values_mut().flatten() {
+            if *occupant == *user {
+                occupant.name = name.into();
+            }
+        }
     }
 
     fn get_occupied_room(&self, user: &User) -> Option<Room> {
         for (room, occupants) in &self.occupancy {
-            if occupants.contains(&user) {
+            if occupants.contains(user) {
                 return Some(room.clone());
             }
         }
-        return None;
+        None
     }
 
-    fn remove_user_from_room(&mut self, user: &User, notice: NotificationLog) {
-        let Some(room) = self.get_occupied_room(user) else {
-            log::warn!(
-                "Could not find user {user:?} in any room occpancy list when trying to remove"
-            );
-            return;
-        };
-
-        let Some(occupants) = self.occupancy.get_mut(&room) else {
-            log::warn!("Could not find occupants associated to a room when trying to remove.");
-            return;
-        };
+    /// Rooms that come and go with their occupants, made by moving into them rather than
+    /// with /create.
+    fn is_transient(&self, room: &Room) -> bool {
+        !room.is_lobby()
+            && self
+                .settings
+                .get(room)
+                .is_none_or(|settings| settings.owner.is_none())
+    }
 
-        let Some(index) = occupants.iter().position(|occupant| *occupant == *user) else {
-            log::warn!("Could not find index of user {user:?} in occupants when trying to remove.");
-            return;
+    /// Takes the user out of their room, leaving the notice there, and deletes the room if
+    /// it was transient and is now empty.
+    fn remove_user_from_room(&mut self, user: &User) -> Result<RemovalOutcome> {
+        let Some(room) = self.get_occupied_room(user) else {
+            return Ok(RemovalOutcome::NotFound);
         };
 
+        let occupants = self
+            .occupancy
+            .get_mut(&room)
+            .ok_or_else(|| anyhow!("{} has no occupant list", room.name))?;
+        let index = occupants
+            .iter()
+            .position(|occupant| *occupant == *user)
+            .ok_or_else(|| anyhow!("{} is not among the occupants of {}", user.id, room.name))?;
         occupants.swap_remove(index);
-        self.record_notification(user, notice);
+        let emptied = occupants.is_empty();
+        self.bump_occupancy_epoch(&room);
+
+        if let Some(settings) = self.settings.get_mut(&room) {
+            settings.last_sent.remove(&user.id);
+        }
+        // A moderated room keeps its bans, mutes and the rest even with nobody in it.
+        let collected = emptied
+            && self.is_transient(&room)
+            && self
+                .settings
+                .get(&room)
+                .is_none_or(RoomSettings::is_untouched);
+        if collected {
+            self.delete_room(&room);
+        }
+        Ok(RemovalOutcome::Removed {
+            room,
+            emptied,
+            collected,
+        })
     }
 
     fn record_chat_message(&mut self, user: &User, msg: MessageLog) -> &[User] {
@@ -161,219 +777,4073 @@ impl AppState {
                             logs.pop_front();
                         }
                     })
-                    .or_insert(vec![msg].into());
+                    .or_insert(vec![msg.clone()].into());
+                self.stats
+                    .entry(room.clone())
+                    .or_insert_with(|| RoomStats::new(Utc::now()))
+                    .record_message(msg.timestamp);
                 return occupants;
             }
         }
 
-        return &[];
+        &[]
     }
 
-    fn record_notification(&mut self, user: &User, notice: NotificationLog) {
-        for (room, occupants) in &self.occupancy {
-            if occupants.contains(user) {
-                self.notifications
-                    .entry(room.clone())
-                    .and_modify(|logs| {
-                        logs.push_back(notice.clone());
-                        if logs.len() > self.max_logs {
-                            logs.pop_front();
-                        }
-                    })
-                    .or_insert(vec![notice.clone()].into());
+    fn record_notification_everywhere(&mut self, notice: NotificationLog) {
+        for logs in self.notifications.values_mut() {
+            logs.push_back(notice.clone());
+            if logs.len() > self.max_logs {
+                logs.pop_front();
             }
         }
     }
+
+    fn record_notification(&mut self, room: &Room, notice: NotificationLog) {
+        let logs = self.notifications.entry(room.clone()).or_default();
+        logs.push_back(notice);
+        if logs.len() > self.max_logs {
+            logs.pop_front();
+        }
+    }
+
+    /// Every room-wide server notice goes through here, so what occupants are sent live is
+    /// exactly what the room's next RoomData lists.
+    fn broadcast_notification(
+        &mut self,
+        room: &Room,
+        severity: Severity,
+        content: impl Into<String>,
+    ) -> Broadcast {
+        let notice = NotificationLog::new(content.into()).with_severity(severity);
+        self.record_notification(room, notice.clone());
+        Broadcast::new(Event::Notify { notice }, self.room_subscribers(room))
+    }
 }
 
 pub struct CommandHandler {
     state: AppState,
+    server_info: ServerInfo,
+    plugins: HashMap<&'static str, Box<dyn CommandPlugin>>,
+    audit_log: AuditLog,
+    shutdown_at: Option<Instant>,
+    shutting_down: bool,
+    limiter: RateLimiter,
+    cooldowns: Cooldowns,
+    /// Users who lost their connection, by the session token a reconnect presents, with when.
+    detached: HashMap<String, (User, DateTime<Utc>)>,
+    resume_grace_secs: u64,
+    max_message_len: usize,
+    /// When each user's room last heard their presence change and what it heard, by user id.
+    presence_broadcasts: HashMap<String, (DateTime<Utc>, PresenceStatus)>,
+    /// Where each room's history is written when the server shuts down, nowhere if unset.
+    transcript_dir: Option<PathBuf>,
 }
 
 impl CommandHandler {
-    fn new(state: AppState) -> Self {
-        Self { state }
+    fn new(state: AppState, server_info: ServerInfo) -> Self {
+        Self {
+            state,
+            server_info,
+            plugins: HashMap::new(),
+            audit_log: AuditLog::new(),
+            shutdown_at: None,
+            shutting_down: false,
+            limiter: RateLimiter::default(),
+            cooldowns: Cooldowns::new(),
+            detached: HashMap::new(),
+            resume_grace_secs: DEFAULT_RESUME_GRACE_SECS,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            transcript_dir: None,
+            presence_broadcasts: HashMap::new(),
+        }
     }
 
-    fn handle(&mut self, command: Command, event_buf: &mut VecDeque<Broadcast>) -> Result<()> {
-        // Changed return from Result<Broadcast> -> Result<Vec<Broadcast>> -> Result<()>.
-        // This is because some Commands may produce multiple broadcasts,
-        // eg. MoveRoom should produce a UserLeft & UserJoined Broadcast for each event.
-        // Was creating an empty Vec<Broadcast> here but it seems wasteful,
-        // Decided to pass buffer instead?
-        // Still returning Result<()> for fault tolerance around publishing
-
-        let user = command.user.clone();
-
-        match command.payload.clone() {
-            CommandPayload::DropUser => {
-                self.handle_drop_user(&user, event_buf);
-                Ok(())
+    /// Detached users whose grace window has run out, or all of them once the server is
+    /// shutting down. They are forgotten here and still need dropping.
+    fn expired_detachments(&mut self, now: DateTime<Utc>) -> Vec<User> {
+        let mut expired = vec![];
+        let grace = self.resume_grace_secs as i64;
+        let shutting_down = self.shutting_down;
+        self.detached.retain(|_, (user, detached_at)| {
+            let keep =
+                !shutting_down && now.signed_duration_since(*detached_at).num_seconds() < grace;
+            if !keep {
+                expired.push(user.clone());
             }
+            keep
+        });
+        expired
+    }
 
-            CommandPayload::RegisterUser(..) => {
-                event_buf.push_back(self.register_user(user.clone()));
-                event_buf.push_back(self.insert_occupant(&user, &Room::from("Hub")));
-                Ok(())
+    /// Puts a reconnecting client back into the session its token was detached from, keeping
+    /// the new connection's key and token. Without one, because it expired, is still connected
+    /// or never existed, the client joins like anyone new. Gives the user to subscribe.
+    fn resume_user(
+        &mut self,
+        new: User,
+        token: &str,
+        channel: UnboundedSender<Event>,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) -> Result<User> {
+        let resumed = self.detached.remove(token).and_then(|(old, detached_at)| {
+            let user = self.state.find_user_mut(&old)?;
+            user.shared_secret = new.shared_secret.clone();
+            user.session_token = new.session_token.clone();
+            user.peer_addr = new.peer_addr;
+            user.protocol_version = new.protocol_version;
+            user.last_active = Utc::now();
+            Some((user.clone(), detached_at))
+        });
+        let Some((user, detached_at)) = resumed else {
+            if self
+                .all_users()
+                .iter()
+                .any(|user| user.has_session_token(Some(token)))
+            {
+                log::warn!(
+                    "{} tried to resume a session that is still connected",
+                    new.id
+                );
             }
+            self.dispatch(
+                new.clone(),
+                CommandPayload::RegisterUser(channel),
+                event_buf,
+            )?;
+            event_buf.push_back(Broadcast::reply(
+                &new,
+                "Your previous session could not be resumed, you have joined as a new user",
+            ));
+            return Ok(new);
+        };
 
-            CommandPayload::MoveUser { target_room } => {
-                match self.remove_occupant(&user) {
-                    Some(broadcast) => {
-                        event_buf.push_back(broadcast);
-                    }
-                    None => {
-                        log::error!("Failed to remove occupant: {user:?} in response to command.")
-                    }
-                }
-                event_buf.push_back(self.insert_occupant(&user, &target_room));
-                Ok(())
-            }
-            CommandPayload::RecordMessage { message } => {
-                let msg_log = MessageLog::from_user(&user, message);
-                let recipients: Vec<User> =
-                    Vec::from(self.state.record_chat_message(&user, msg_log.clone()));
+        log::info!("{} resumed their session", user.id);
+        let room = self.state.get_occupied_room(&user).unwrap_or_default();
+        // The room data stops where the client left off, the rest is replayed after it.
+        let mut snapshot = self.state.room_snapshot(&room);
+        let (missed, msg_log) = snapshot
+            .msg_log
+            .into_iter()
+            .partition(|msg| msg.timestamp > detached_at);
+        snapshot.msg_log = msg_log;
+        event_buf.push_back(Broadcast::new(
+            Event::SessionResumed {
+                user: user.clone(),
+                room: room.clone(),
+                snapshot,
+                missed,
+            },
+            vec![user.clone()],
+        ));
+        Ok(user)
+    }
 
-                let br = Broadcast::new(Event::MsgReceived { msg: msg_log }, recipients);
-                event_buf.push_back(br);
-                Ok(())
-            }
-            _ => Err(anyhow!("{:?} not implemented in CommandHandler", command)),
-        }
+    fn all_users(&self) -> Vec<User> {
+        self.state.occupancy.values().flatten().cloned().collect()
     }
 
-    fn handle_drop_user(&mut self, user: &User, event_buf: &mut VecDeque<Broadcast>) {
-        let room = self
-            .state
-            .get_occupied_room(&user)
-            .unwrap_or(Room::default());
-        let mut subscribers = self.state.room_subscribers(&room);
-        if !subscribers.contains(&user) {
-            subscribers.push(user.clone());
-        }
+    fn shutdown_deadline(&self) -> Option<Instant> {
+        self.shutdown_at
+    }
 
-        let broadcast = self.remove_occupant(&user).unwrap_or(Broadcast {
-            event: Event::UserLeft {
-                user: user.clone(),
-                room: room.clone(),
-                msg_log: vec![],
-                notifications: vec![],
-                occupant_names: self.state.occupant_names(&room),
-            },
-            subscribers,
-        });
-        event_buf.push_back(broadcast);
+    /// Once the users have all dropped out after a shutdown the App is free to exit.
+    fn ready_to_exit(&self) -> bool {
+        self.shutting_down && self.state.occupancy.values().all(Vec::is_empty)
     }
 
-    fn register_user(&mut self, user: User) -> Broadcast {
-        Broadcast::new(
-            Event::UserRegistered {
-                token: user.id.clone(),
-            },
-            vec![user.clone()],
-        )
+    fn schedule_shutdown(&mut self, admin: &User, delay_secs: u64) -> Broadcast {
+        if self.shutdown_at.is_some() {
+            return Broadcast::rejection(
+                admin,
+                "A shutdown is already pending, use /shutdown cancel to stop it",
+            );
+        }
+
+        self.shutdown_at = Some(Instant::now() + std::time::Duration::from_secs(delay_secs));
+        let notice = NotificationLog::new(format!(
+            "The server is shutting down in {delay_secs} second(s)"
+        ))
+        .with_severity(Severity::Critical);
+        Broadcast::new(Event::Notify { notice }, self.all_users())
     }
 
-    fn remove_occupant(&mut self, user: &User) -> Option<Broadcast> {
-        let Some(current_room) = self.state.get_occupied_room(user) else {
-            return None;
+    fn cancel_shutdown(&mut self, admin: &User) -> Broadcast {
+        if self.shutdown_at.take().is_none() {
+            return Broadcast::rejection(admin, "There is no pending shutdown to cancel");
+        }
+        let notice = NotificationLog::new(format!("{} cancelled the shutdown", admin.name))
+            .with_severity(Severity::Critical);
+        Broadcast::new(Event::Notify { notice }, self.all_users())
+    }
+
+    fn begin_shutdown(&mut self, event_buf: &mut VecDeque<Broadcast>) {
+        self.shutdown_at = None;
+        self.shutting_down = true;
+        // Before anyone is dropped, an emptied room would take its history with it.
+        self.save_transcripts();
+        event_buf.push_back(Broadcast::new(Event::ServerShutdown, self.all_users()));
+    }
+
+    /// Writes every room's chat and notifications to the transcript directory. A room that
+    /// can't be written is logged and the rest are still tried.
+    fn save_transcripts(&self) {
+        let Some(dir) = &self.transcript_dir else {
+            return;
         };
-        let notice = NotificationLog::new(format!("{} left {}", user.name, current_room.name));
+        for room in self.state.rooms_with_history() {
+            let text = transcript::render(
+                &self.state.room_chat_logs(&room),
+                &self.state.room_notifications(&room),
+                ExportFormat::JsonLines,
+            );
+            if text.is_empty() {
+                continue;
+            }
+            match transcript::save(dir, &room.name, &text) {
+                Ok(path) => log::info!("Saved the history of {} to {}", room.name, path.display()),
+                Err(e) => log::error!("Could not save the history of {}: {e}", room.name),
+            }
+        }
+    }
 
-        self.state.remove_user_from_room(user, notice);
-        Some(Broadcast::new(
-            Event::UserLeft {
-                user: user.clone(),
-                room: current_room.clone(),
-                occupant_names: self.state.occupant_names(&current_room),
-                notifications: self.state.room_notifications(&current_room),
-                msg_log: self.state.room_chat_logs(&current_room),
-            },
-            self.state.room_subscribers(&current_room),
-        ))
+    /// A room's owner counts as a moderator in it.
+    fn effective_role(&self, user: &User, room: &Room) -> Role {
+        match user.role {
+            Role::Member if self.state.is_owner(room, user) => Role::Moderator,
+            role => role,
+        }
     }
 
-    fn insert_occupant(&mut self, user: &User, room: &Room) -> Broadcast {
-        self.state.add_user_to_room(user, &room);
-        self.state.record_notification(
-            user,
-            NotificationLog::new(format!("{} joined {}", user.name, room.name)),
-        );
-        Broadcast::new(
-            Event::UserJoined {
-                user: user.clone(),
-                room: room.clone(),
-                msg_log: self.state.room_chat_logs(room),
-                notifications: self.state.room_notifications(room),
-                occupant_names: self.state.occupant_names(room),
-            },
-            self.state.room_subscribers(&room),
-        )
+    /// The role the user has in the room they are in now.
+    fn current_role(&self, user: &User) -> Role {
+        let room = self.state.get_occupied_room(user).unwrap_or_default();
+        self.effective_role(user, &room)
     }
-}
 
-pub struct App {
-    gateway_source: UnboundedReceiver<Command>,
-    command_handler: CommandHandler,
-    event_bus: EventBus,
-}
+    /// Moderation only reaches users below the moderator in the room it happens in, and
+    /// never the moderator themselves.
+    fn outranks(&self, moderator: &User, target: &User, room: &Room) -> bool {
+        target != moderator
+            && self.effective_role(moderator, room) > self.effective_role(target, room)
+    }
 
-impl App {
-    pub fn init(command_source: UnboundedReceiver<Command>) -> Self {
-        Self {
-            gateway_source: command_source,
-            command_handler: CommandHandler::new(AppState::new()),
-            event_bus: EventBus::new(),
+    /// Every command is gated here on its required role, handlers don't check roles
+    /// themselves.
+    fn check_permission(&self, user: &User, payload: &CommandPayload) -> Option<Broadcast> {
+        let required = payload.required_role();
+        let role = self.current_role(user);
+        (role < required).then(|| {
+            Broadcast::error(
+                user,
+                ErrorReason::PermissionDenied,
+                format!("That needs the {required:?} role, you are {role:?}"),
+            )
+        })
+    }
+
+    /// Replies to just the commander are stamped with the id of the request that caused them,
+    /// anything else a command broadcasts goes out without one.
+    fn handle(&mut self, command: Command, event_buf: &mut VecDeque<Broadcast>) -> Result<()> {
+        let Some(id) = command.request_id else {
+            return self.handle_unstamped(command, event_buf);
+        };
+        let commander = command.user.clone();
+        let queued = event_buf.len();
+        let result = self.handle_unstamped(command, event_buf);
+        for cast in event_buf.iter_mut().skip(queued) {
+            if cast.subscribers != [commander.clone()] {
+                continue;
+            }
+            if let Event::Reply { request_id, .. }
+            | Event::Rejected { request_id, .. }
+            | Event::SendAck { request_id, .. }
+            | Event::SendNack { request_id, .. } = &mut cast.event
+            {
+                *request_id = Some(id);
+            }
         }
+        result
     }
 
-    pub fn run(mut self) {
-        tokio::spawn(async move {
-            match self.work().await {
-                Err(e) => panic!("App exited unexpectedly with error {e}"),
-                _ => (),
+    fn handle_unstamped(
+        &mut self,
+        command: Command,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) -> Result<()> {
+        // Changed return from Result<Broadcast> -> Result<Vec<Broadcast>> -> Result<()>.
+        // This is because some Commands may produce multiple broadcasts,
+        // eg. MoveRoom should produce a UserLeft & UserJoined Broadcast for each event.
+        // Was creating an empty Vec<Broadcast> here but it seems wasteful,
+        // Decided to pass buffer instead?
+        // Still returning Result<()> for fault tolerance around publishing
+
+        // The session's copy of the user can be stale (e.g. after a rename), prefer ours.
+        self.state.touch_user(&command.user);
+        let user = match (self.state.find_user(&command.user), &command.payload) {
+            (Some(user), _) => user.clone(),
+            (
+                None,
+                CommandPayload::RegisterUser(..)
+                | CommandPayload::DropUser
+                | CommandPayload::DetachUser,
+            ) => command.user.clone(),
+            // A session can race its own drop, nothing it sends after that should land anywhere.
+            (None, payload) => {
+                log::warn!(
+                    "Dropping /{} from {}, who is no longer connected",
+                    payload.name(),
+                    command.user.id
+                );
+                return Ok(());
             }
-        });
+        };
+
+        if command.payload.is_rate_limited() {
+            if let Err(retry_after) = self.limiter.try_acquire(&user.id, Utc::now()) {
+                event_buf.push_back(Broadcast::error(
+                    &user,
+                    ErrorReason::RateLimited,
+                    format!("Too many commands, try again in {retry_after} second(s)"),
+                ));
+                return Ok(());
+            }
+        }
+        if let Some(rejection) = self.check_permission(&user, &command.payload) {
+            if command.payload.is_audited() {
+                self.audit(&user, &command.payload, AuditOutcome::Denied);
+            }
+            event_buf.push_back(rejection);
+            return Ok(());
+        }
+        let name = command.payload.name();
+        let cooled = !user.is_admin();
+        if cooled {
+            if let Err(remaining) = self.cooldowns.check(&user.id, name, Utc::now()) {
+                event_buf.push_back(Broadcast::error(
+                    &user,
+                    ErrorReason::RateLimited,
+                    format!("/{name} is cooling down, try again in {remaining} second(s)"),
+                ));
+                return Ok(());
+            }
+        }
+
+        let queued = event_buf.len();
+        let audited = command
+            .payload
+            .is_audited()
+            .then(|| command.payload.clone());
+        let result = self.dispatch(user.clone(), command.payload, event_buf);
+        let refusal = Self::refusal(&user, event_buf.iter().skip(queued));
+        // A command that was refused or failed didn't use up the user's turn.
+        if cooled && result.is_ok() && refusal.is_none() {
+            self.cooldowns.record(&user.id, name, Utc::now());
+        }
+        if let Some(payload) = audited {
+            let outcome = refusal.map_or(AuditOutcome::Done, AuditOutcome::Rejected);
+            self.audit(&user, &payload, outcome);
+        }
+        result
     }
 
-    pub async fn work(&mut self) -> Result<()> {
-        let mut event_buf: VecDeque<Broadcast> = VecDeque::new();
-        let mut defer_unsubscribe: Option<User> = None;
+    /// Handlers answer a refused command with a rejection addressed to just the user.
+    fn refusal<'a>(user: &User, queued: impl Iterator<Item = &'a Broadcast>) -> Option<String> {
+        queued
+            .filter(|cast| cast.subscribers.len() == 1 && cast.subscribers[0] == *user)
+            .find_map(|cast| match &cast.event {
+                Event::Rejected { reason, .. } => Some(reason.clone()),
+                _ => None,
+            })
+    }
 
-        while let Some(command) = self.gateway_source.next().await {
-            match command.clone() {
-                Command {
-                    user,
-                    payload: CommandPayload::RegisterUser(delivery_channel, ..),
-                } => self.event_bus.subscribe(user, delivery_channel),
-                Command {
-                    user,
-                    payload: CommandPayload::DropUser,
-                } => {
-                    defer_unsubscribe = Some(user.clone());
-                    Ok(())
+    fn audit(&mut self, user: &User, payload: &CommandPayload, outcome: AuditOutcome) {
+        self.audit_log.record(AuditEntry::new(
+            user,
+            payload.name(),
+            payload.target(),
+            format!("{payload:?}"),
+            outcome,
+        ));
+    }
+
+    /// One JSON object per registered command, `enabled` says whether this server can run it
+    /// at all and `available` whether this user can run it where they are now.
+    fn report_capabilities(&self, user: &User) -> Broadcast {
+        let role = self.current_role(user);
+        let lines: Vec<String> = CommandRegistry::all()
+            .iter()
+            .map(|spec| {
+                let enabled = !CommandRegistry::PLUGIN_ONLY.contains(&spec.name)
+                    || self.plugins.contains_key(spec.name);
+                format!(
+                    r#"{{"name":{},"args":{},"role":"{:?}","enabled":{enabled},"available":{}}}"#,
+                    transcript::json_string(spec.name),
+                    transcript::json_string(spec.args),
+                    spec.role,
+                    enabled && role >= spec.role
+                )
+            })
+            .collect();
+        Broadcast::reply(user, lines.join("\n"))
+    }
+
+    fn report_audit(&self, admin: &User, count: usize) -> Broadcast {
+        let entries = self.audit_log.recent(count);
+        if entries.is_empty() {
+            return Broadcast::reply(admin, "The audit log is empty");
+        }
+        let lines: Vec<String> = entries.iter().map(|entry| entry.describe()).collect();
+        Broadcast::reply(admin, lines.join("\n"))
+    }
+
+    fn dispatch(
+        &mut self,
+        user: User,
+        payload: CommandPayload,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) -> Result<()> {
+        if let Some(plugin) = self.plugins.get_mut(payload.name()) {
+            let mut ctx = CommandContext {
+                user: &user,
+                state: &mut self.state,
+                event_buf,
+            };
+            return plugin.handle(&mut ctx, payload);
+        }
+
+        match payload.clone() {
+            CommandPayload::DropUser => {
+                self.limiter.forget(&user.id);
+                self.cooldowns.forget(&user.id);
+                self.handle_drop_user(&user, event_buf);
+                Ok(())
+            }
+            CommandPayload::DetachUser
+                if self.resume_grace_secs == 0
+                    || self.shutting_down
+                    || self.state.find_user(&user).is_none() =>
+            {
+                self.dispatch(user, CommandPayload::DropUser, event_buf)
+            }
+            CommandPayload::DetachUser => {
+                log::info!("{} lost their connection, keeping them for now", user.id);
+                self.detached
+                    .insert(user.session_token.clone(), (user.clone(), Utc::now()));
+                event_buf.push_back(Broadcast::new(
+                    Event::UserDetached { user: user.clone() },
+                    vec![user],
+                ));
+                Ok(())
+            }
+            // The App resumes sessions itself, before any handler sees the command.
+            CommandPayload::ResumeUser { .. } => Ok(()),
+            CommandPayload::WentIdle => {
+                if !user.is_away() {
+                    let status = PresenceStatus::Away(Some(IDLE_AWAY_MESSAGE.to_string()));
+                    event_buf.extend(self.set_presence(&user, status, true).unwrap_or_default());
+                }
+                Ok(())
+            }
+            CommandPayload::ReportStats(stats) => {
+                if let Some(record) = self.state.find_user_mut(&user) {
+                    record.connection_stats = stats;
+                }
+                Ok(())
+            }
+            CommandPayload::Reauthenticate { token, key } => {
+                if let Some(record) = self.state.find_user_mut(&user) {
+                    record.session_token = token;
+                    record.shared_secret = key;
+                }
+                Ok(())
+            }
+
+            CommandPayload::RegisterUser(..) if self.shutting_down => {
+                event_buf.push_back(self.register_user(user.clone()));
+                event_buf.extend(self.insert_occupant(&user, &Room::lobby()));
+                event_buf.push_back(Broadcast::new(Event::ServerShutdown, vec![user.clone()]));
+                Ok(())
+            }
+            CommandPayload::RegisterUser(..) => {
+                event_buf.push_back(self.register_user(user.clone()));
+                if let Some(motd) = self.server_info.motd() {
+                    event_buf.push_back(Broadcast::new(Event::Motd { motd }, vec![user.clone()]));
+                }
+                event_buf.extend(self.insert_occupant(&user, &Room::lobby()));
+                Ok(())
+            }
+
+            CommandPayload::MoveUser { target_room } => {
+                self.handle_move_user(&user, target_room, event_buf);
+                Ok(())
+            }
+            CommandPayload::RecordMessage {
+                message,
+                attachment,
+                msg_id,
+            } => {
+                self.record_chat(
+                    &user,
+                    MessageLog::from_user(&user, message).with_attachment(attachment),
+                    msg_id,
+                    event_buf,
+                );
+                Ok(())
+            }
+            CommandPayload::Action(action) => {
+                if action.is_empty() {
+                    event_buf.push_back(Broadcast::rejection(&user, "Usage: /me <action>"));
+                } else {
+                    self.record_chat(
+                        &user,
+                        MessageLog::action_from_user(&user, action),
+                        None,
+                        event_buf,
+                    );
                 }
-                _ => Ok(()),
-            }?;
-            match self.command_handler.handle(command, &mut event_buf) {
-                Ok(_) => {
-                    while let Some(cast) = event_buf.pop_front() {
-                        self.event_bus.publish(&cast);
+                Ok(())
+            }
+            CommandPayload::History { limit, before } => {
+                event_buf.push_back(self.fetch_history(
+                    &user,
+                    limit,
+                    before.and_then(|ts| ts.into()),
+                ));
+                Ok(())
+            }
+            CommandPayload::Search { query, limit } => {
+                event_buf.push_back(self.search_room(&user, &query, limit));
+                Ok(())
+            }
+            CommandPayload::Export { format } => {
+                // Rendering and chunking happen in the session, only the snapshot is taken here.
+                event_buf.push_back(match self.state.get_occupied_room(&user) {
+                    None => Broadcast::error(
+                        &user,
+                        ErrorReason::RoomNotFound,
+                        "Could not find the room you are in",
+                    ),
+                    Some(room) => Broadcast::new(
+                        Event::Export {
+                            format,
+                            logs: self.state.room_chat_logs(&room),
+                            notifications: self.state.room_notifications(&room),
+                            room,
+                        },
+                        vec![user.clone()],
+                    ),
+                });
+                Ok(())
+            }
+            CommandPayload::ClearNotifications => {
+                // Read cursors are kept per session, the shared log stays as it is.
+                event_buf.push_back(match self.state.get_occupied_room(&user) {
+                    Some(room) => Broadcast::new(
+                        Event::NotificationsCleared {
+                            notifications: self.state.room_notifications(&room),
+                            room,
+                        },
+                        vec![user.clone()],
+                    ),
+                    None => Broadcast::error(
+                        &user,
+                        ErrorReason::RoomNotFound,
+                        "Could not find the room you are in",
+                    ),
+                });
+                Ok(())
+            }
+            CommandPayload::RoomStats => {
+                event_buf.push_back(self.report_room_stats(&user, Utc::now()));
+                Ok(())
+            }
+            CommandPayload::ServerStats => {
+                event_buf.push_back(Broadcast::reply(
+                    &user,
+                    format!("Frames sent since startup:\n{}", frame_metrics().report()),
+                ));
+                Ok(())
+            }
+            CommandPayload::Who => {
+                event_buf.push_back(self.list_occupants(&user));
+                Ok(())
+            }
+            CommandPayload::CurrentRoom => {
+                event_buf.push_back(self.describe_current_room(&user));
+                Ok(())
+            }
+            CommandPayload::Rename(new_name) => {
+                event_buf.push_back(self.rename_user(&user, new_name));
+                Ok(())
+            }
+            CommandPayload::ListRooms => {
+                event_buf.push_back(Broadcast::new(
+                    Event::RoomList {
+                        rooms: self.state.room_occupancy(),
+                    },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::DirectMessage { to, content } => {
+                self.handle_direct_message(&user, &to, content, event_buf);
+                Ok(())
+            }
+            CommandPayload::Promote { user: target, role } => {
+                self.change_role(&user, &target, role, true, event_buf);
+                Ok(())
+            }
+            CommandPayload::Demote { user: target, role } => {
+                self.change_role(&user, &target, role, false, event_buf);
+                Ok(())
+            }
+            CommandPayload::Kick(target) => {
+                self.handle_kick(&user, &target, event_buf);
+                Ok(())
+            }
+            CommandPayload::Ban(target) => {
+                self.handle_ban(&user, &target, event_buf);
+                Ok(())
+            }
+            CommandPayload::Unban(target) => {
+                event_buf.push_back(self.handle_unban(&user, &target));
+                Ok(())
+            }
+            CommandPayload::ListBans => {
+                event_buf.push_back(self.list_bans(&user));
+                Ok(())
+            }
+            CommandPayload::Mute {
+                user: target,
+                duration_secs,
+            } => {
+                event_buf.push_back(self.handle_mute(&user, &target, duration_secs));
+                Ok(())
+            }
+            CommandPayload::Unmute(target) => {
+                event_buf.push_back(self.handle_unmute(&user, &target));
+                Ok(())
+            }
+            CommandPayload::Silence {
+                user: target,
+                duration_secs,
+            } => {
+                let expiry = duration_secs
+                    .and_then(|secs| Duration::try_seconds(i64::try_from(secs).ok()?))
+                    .map(|duration| Utc::now() + duration);
+                self.moderate(&user, &target, Moderation::Silenced(expiry), event_buf);
+                Ok(())
+            }
+            CommandPayload::ShadowBan(target) => {
+                self.moderate(&user, &target, Moderation::ShadowBanned, event_buf);
+                Ok(())
+            }
+            CommandPayload::Pardon(target) => {
+                self.moderate(&user, &target, Moderation::Normal, event_buf);
+                Ok(())
+            }
+            CommandPayload::Purge(count) => {
+                event_buf.push_back(self.handle_purge(&user, count));
+                Ok(())
+            }
+            CommandPayload::Pin(id) => {
+                event_buf.push_back(self.set_pinned(&user, id, true));
+                Ok(())
+            }
+            CommandPayload::Unpin(id) => {
+                event_buf.push_back(self.set_pinned(&user, id, false));
+                Ok(())
+            }
+            CommandPayload::SlowMode(interval) => {
+                event_buf.push_back(self.set_slow_mode(&user, interval));
+                Ok(())
+            }
+            CommandPayload::SetTopic(topic) => {
+                event_buf.push_back(self.set_topic(&user, topic));
+                Ok(())
+            }
+            CommandPayload::Leave => {
+                self.handle_move_user(&user, Room::lobby(), event_buf);
+                Ok(())
+            }
+            CommandPayload::CreateRoom(name) => {
+                self.handle_create_room(&user, name, event_buf);
+                Ok(())
+            }
+            CommandPayload::DeleteRoom(name) => {
+                self.handle_delete_room(&user, Room::from(name.trim()), event_buf);
+                Ok(())
+            }
+            CommandPayload::SetPrivate(private) => {
+                event_buf.push_back(self.set_private(&user, private));
+                Ok(())
+            }
+            CommandPayload::LockRoom => {
+                event_buf.push_back(self.set_locked(&user, true));
+                Ok(())
+            }
+            CommandPayload::UnlockRoom => {
+                event_buf.push_back(self.set_locked(&user, false));
+                Ok(())
+            }
+            CommandPayload::Invite(target) => {
+                self.handle_invite(&user, &target, event_buf);
+                Ok(())
+            }
+            CommandPayload::Uninvite(target) => {
+                event_buf.push_back(self.handle_uninvite(&user, &target));
+                Ok(())
+            }
+            CommandPayload::WhoAmI => {
+                event_buf.push_back(Broadcast::new(
+                    Event::WhoAmI {
+                        user: user.clone(),
+                        room: self.state.get_occupied_room(&user),
+                    },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::Away(message) => {
+                match self.set_presence(&user, PresenceStatus::Away(message), true) {
+                    Some(broadcasts) => event_buf.extend(broadcasts),
+                    None => {
+                        event_buf.push_back(Broadcast::rejection(&user, "You are already away"))
                     }
                 }
-                Err(e) => {
-                    return Err(e);
+                Ok(())
+            }
+            CommandPayload::Back => {
+                match self.set_presence(&user, PresenceStatus::Online, true) {
+                    Some(broadcasts) => event_buf.extend(broadcasts),
+                    None => event_buf.push_back(Broadcast::rejection(&user, "You are not away")),
                 }
+                Ok(())
             }
-            if let Some(ref user) = defer_unsubscribe {
-                match self.event_bus.unsubscribe(user.clone()) {
-                    Err(e) => panic!("Failed to unsubscribe a user: {user:?} with Error: {e}"),
-                    _ => {}
-                };
-                defer_unsubscribe = None;
+            CommandPayload::Ignore(target) => {
+                event_buf.push_back(self.handle_ignore(&user, &target));
+                Ok(())
+            }
+            CommandPayload::Unignore(target) => {
+                event_buf.push_back(self.handle_unignore(&user, &target));
+                Ok(())
+            }
+            CommandPayload::Block(target) => {
+                event_buf.push_back(self.handle_block(&user, &target));
+                Ok(())
+            }
+            CommandPayload::Unblock(target) => {
+                event_buf.push_back(self.handle_unblock(&user, &target));
+                Ok(())
+            }
+            CommandPayload::ListBlocks => {
+                event_buf.push_back(self.list_blocks(&user));
+                Ok(())
+            }
+            CommandPayload::Capabilities => {
+                event_buf.push_back(self.report_capabilities(&user));
+                Ok(())
+            }
+            CommandPayload::Audit(count) => {
+                event_buf.push_back(self.report_audit(&user, count));
+                Ok(())
+            }
+            CommandPayload::WhoIs(name) => {
+                event_buf.push_back(self.describe_user(&user, &name));
+                Ok(())
+            }
+            CommandPayload::Seen(name) => {
+                event_buf.push_back(self.report_seen(&user, &name, Utc::now()));
+                Ok(())
+            }
+            CommandPayload::Motd => {
+                event_buf.push_back(match self.server_info.motd() {
+                    Some(motd) => Broadcast::new(Event::Motd { motd }, vec![user.clone()]),
+                    None => Broadcast::reply(&user, "No MOTD set"),
+                });
+                Ok(())
+            }
+            CommandPayload::SetMotd(motd) => {
+                event_buf.push_back(self.set_motd(&user, motd));
+                Ok(())
+            }
+            CommandPayload::Announce(text) => {
+                self.announce(&user, text, event_buf);
+                Ok(())
+            }
+            CommandPayload::Shutdown { delay_secs } => {
+                event_buf.push_back(self.schedule_shutdown(&user, delay_secs));
+                Ok(())
+            }
+            CommandPayload::CancelShutdown => {
+                event_buf.push_back(self.cancel_shutdown(&user));
+                Ok(())
+            }
+            CommandPayload::Uptime => {
+                event_buf.push_back(self.report_uptime(&user, Utc::now()));
+                Ok(())
+            }
+            // Plugin commands land here when their plugin isn't registered.
+            _ => {
+                event_buf.push_back(Broadcast::error(
+                    &user,
+                    ErrorReason::UnknownCommand,
+                    format!("/{} is not available on this server", payload.name()),
+                ));
+                Ok(())
             }
         }
+    }
 
-        Ok(())
+    /// Logs the chat and sends it to the room, then acks it to the sender. Anything that stops
+    /// it is nacked instead, with the client's id for the message when it gave one.
+    fn record_chat(
+        &mut self,
+        user: &User,
+        mut msg_log: MessageLog,
+        msg_id: Option<String>,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        if let Err((kind, reason)) = self.check_chat(user, &msg_log) {
+            event_buf.push_back(Broadcast::send_nack(user, msg_id, kind, reason));
+            return;
+        }
+        let Some(room) = self.state.get_occupied_room(user) else {
+            return;
+        };
+
+        // Echoed back as if it went to the room, so nothing gives the ban away.
+        if *user.moderation_at(Utc::now()) == Moderation::ShadowBanned {
+            msg_log.id = self.state.next_message_id(&room);
+            self.state.record_shadow_message(user, msg_log.clone());
+            let ack = Event::SendAck {
+                msg_id,
+                id: msg_log.id,
+                timestamp: msg_log.timestamp,
+                request_id: None,
+            };
+            event_buf.push_back(Broadcast::new(
+                Event::MsgReceived { msg: msg_log },
+                vec![user.clone()],
+            ));
+            event_buf.push_back(Broadcast::new(ack, vec![user.clone()]));
+            return;
+        }
+
+        // Not debounced, the room hears they're back before it sees what they said.
+        if user.is_away() {
+            if let Some(broadcasts) = self.set_presence(user, PresenceStatus::Online, false) {
+                event_buf.extend(broadcasts);
+            }
+        }
+
+        msg_log.id = self.state.next_message_id(&room);
+        if let Some(record) = self.state.find_user_mut(user) {
+            record.messages_sent += 1;
+        }
+        let mut recipients: Vec<User> =
+            Vec::from(self.state.record_chat_message(user, msg_log.clone()));
+        recipients.retain(|recipient| !recipient.ignores(user));
+
+        // Queued after the room's copy, the sender's session sees them in this order.
+        let ack = Event::SendAck {
+            msg_id,
+            id: msg_log.id,
+            timestamp: msg_log.timestamp,
+            request_id: None,
+        };
+        event_buf.push_back(Broadcast::new(
+            Event::MsgReceived { msg: msg_log },
+            recipients,
+        ));
+        event_buf.push_back(Broadcast::new(ack, vec![user.clone()]));
+    }
+
+    /// Why the user can't send this message right now, if anything stops them.
+    fn check_chat(
+        &mut self,
+        user: &User,
+        msg_log: &MessageLog,
+    ) -> Result<(), (ErrorReason, String)> {
+        if msg_log.contents.chars().count() > self.max_message_len {
+            return Err((
+                ErrorReason::MessageTooLarge,
+                format!(
+                    "Messages cannot be longer than {} characters",
+                    self.max_message_len
+                ),
+            ));
+        }
+
+        let Some(room) = self.state.get_occupied_room(user) else {
+            return Err((
+                ErrorReason::RoomNotFound,
+                "You are not in a room".to_string(),
+            ));
+        };
+        if let Some(expiry) = self.state.active_mute(&room, user) {
+            let until = match expiry {
+                Some(until) => format!("until {}", until.format("%H:%M:%S UTC")),
+                None => "until a moderator unmutes you".to_string(),
+            };
+            return Err((
+                ErrorReason::Muted,
+                format!("You are muted in {} {until}", room.name),
+            ));
+        }
+
+        Self::check_silence(user)?;
+
+        if let Some(wait) = self.state.slow_mode_wait(&room, user, Utc::now()) {
+            return Err((
+                ErrorReason::RateLimited,
+                format!(
+                    "{} is in slow mode, you can send another message in {wait} second(s)",
+                    room.name
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Silenced users can't send anything, to their room or to anyone directly. Shadow banned
+    /// ones are let through and their messages quietly go nowhere.
+    fn check_silence(user: &User) -> Result<(), (ErrorReason, String)> {
+        if let Moderation::Silenced(until) = user.moderation_at(Utc::now()) {
+            let until = match until {
+                Some(until) => format!("until {}", until.format("%H:%M:%S UTC")),
+                None => "until a moderator pardons you".to_string(),
+            };
+            return Err((ErrorReason::Muted, format!("You are silenced {until}")));
+        }
+        Ok(())
+    }
+
+    /// Tells the room about the change, or gives None if the status is already set. A change
+    /// hot on the heels of the last one the room heard, or one that only undoes a change the
+    /// room never heard of, is told to the user alone and `settle_presence` catches the room
+    /// up once the window is over.
+    fn set_presence(
+        &mut self,
+        user: &User,
+        status: PresenceStatus,
+        debounce: bool,
+    ) -> Option<Vec<Broadcast>> {
+        if user.status == status {
+            return None;
+        }
+        self.state.find_user_mut(user)?.status = status.clone();
+
+        let now = Utc::now();
+        let (heard_at, heard) = match self.presence_broadcasts.get(&user.id) {
+            Some((at, heard)) => (Some(*at), heard.clone()),
+            None => (None, PresenceStatus::Online),
+        };
+        let debounced = debounce
+            && heard_at.is_some_and(|last| {
+                now.signed_duration_since(last).num_seconds() < PRESENCE_DEBOUNCE_SECS
+            });
+        if debounced || heard == status {
+            return Some(vec![Broadcast::reply(
+                user,
+                presence_text(&user.name, &status),
+            )]);
+        }
+        Some(self.announce_presence(user, status, now))
+    }
+
+    fn announce_presence(
+        &mut self,
+        user: &User,
+        status: PresenceStatus,
+        now: DateTime<Utc>,
+    ) -> Vec<Broadcast> {
+        self.presence_broadcasts
+            .insert(user.id.clone(), (now, status.clone()));
+        let text = presence_text(&user.name, &status);
+        let room = self.state.get_occupied_room(user).unwrap_or_default();
+        let update = Broadcast::new(
+            Event::PresenceChanged {
+                user_name: user.name.clone(),
+                status,
+                timestamp: now,
+            },
+            self.state.room_subscribers(&room),
+        );
+        vec![
+            self.state
+                .broadcast_notification(&room, Severity::Info, text),
+            update,
+        ]
+    }
+
+    /// When the user's room is due to hear their latest presence, if the debounce held it back.
+    fn presence_due(&self, user: &User) -> Option<DateTime<Utc>> {
+        let (at, heard) = self.presence_broadcasts.get(&user.id)?;
+        (*heard != user.status).then(|| *at + Duration::seconds(PRESENCE_DEBOUNCE_SECS))
+    }
+
+    fn presence_deadline(&self) -> Option<Instant> {
+        let due = self
+            .all_users()
+            .iter()
+            .filter_map(|user| self.presence_due(user))
+            .min()?;
+        let wait = due
+            .signed_duration_since(Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        Some(Instant::now() + wait)
+    }
+
+    /// Rooms hear the presence changes the debounce held back once their window is over.
+    fn settle_presence(&mut self, now: DateTime<Utc>) -> Vec<Broadcast> {
+        let mut broadcasts = Vec::new();
+        for user in self.all_users() {
+            if self.presence_due(&user).is_some_and(|due| due <= now) {
+                let status = user.status.clone();
+                broadcasts.extend(self.announce_presence(&user, status, now));
+            }
+        }
+        broadcasts
+    }
+
+    fn handle_drop_user(&mut self, user: &User, event_buf: &mut VecDeque<Broadcast>) {
+        self.state.shadow_logs.remove(&user.id);
+        self.presence_broadcasts.remove(&user.id);
+        if let Some(room) = self.state.get_occupied_room(user) {
+            self.state.record_departure(user, &room);
+        }
+        // The session waits to hear that it has left, even if it was never in a room.
+        match self.remove_occupant(user) {
+            Some([mut left, notice]) => {
+                left.subscribers.push(user.clone());
+                event_buf.extend([left, notice]);
+            }
+            None => event_buf.push_back(Broadcast::new(
+                Event::UserLeft {
+                    user: user.clone(),
+                    room: Room::lobby(),
+                    snapshot: RoomSnapshot::default(),
+                },
+                vec![user.clone()],
+            )),
+        }
+    }
+
+    fn handle_move_user(
+        &mut self,
+        user: &User,
+        target_room: Room,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        // Anything that can refuse the move has to happen before the user is removed
+        // from their current room, otherwise they end up in neither.
+        if !self.state.room_exists(&target_room) {
+            if let Err(reason) = self.state.validate_new_room(&target_room) {
+                event_buf.push_back(Broadcast::rejection(user, reason));
+                return;
+            }
+        }
+
+        if self.state.is_banned(&target_room, user) {
+            event_buf.push_back(Broadcast::rejection(
+                user,
+                format!("You are banned from {}", target_room.name),
+            ));
+            return;
+        }
+
+        if !self.state.may_enter(&target_room, user) {
+            event_buf.push_back(Broadcast::rejection(
+                user,
+                format!(
+                    "{} is private, you need an invite to join",
+                    target_room.name
+                ),
+            ));
+            return;
+        }
+
+        if self.state.get_occupied_room(user).as_ref() == Some(&target_room) {
+            event_buf.push_back(Broadcast::reply(
+                user,
+                format!("You are already in {}", target_room.name),
+            ));
+            return;
+        }
+
+        if self.state.locked_out(&target_room, user) {
+            event_buf.push_back(Broadcast::rejection(
+                user,
+                format!("{} is locked", target_room.name),
+            ));
+            return;
+        }
+
+        if let Some(current_room) = self.state.get_occupied_room(user) {
+            let settings = self.state.room_settings_mut(&current_room);
+            if settings.locked {
+                settings.lock_leavers.insert(user.id.clone(), Utc::now());
+            }
+        }
+
+        match self.remove_occupant(user) {
+            Some(broadcasts) => event_buf.extend(broadcasts),
+            None => {
+                log::error!("Failed to remove occupant: {user:?} in response to command.")
+            }
+        }
+        event_buf.extend(self.insert_occupant(user, &target_room));
+    }
+
+    fn handle_create_room(
+        &mut self,
+        user: &User,
+        name: String,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let room = Room::from(name.trim());
+        if self.state.room_exists(&room) {
+            event_buf.push_back(Broadcast::rejection(
+                user,
+                format!("The room {} already exists", room.name),
+            ));
+            return;
+        }
+        if let Err(reason) = self.state.validate_new_room(&room) {
+            event_buf.push_back(Broadcast::rejection(user, reason));
+            return;
+        }
+
+        self.state.create_room(&room, user);
+        log::info!("{} ({}) created room {}", user.name, user.id, room.name);
+        self.handle_move_user(user, room, event_buf);
+    }
+
+    fn handle_delete_room(
+        &mut self,
+        admin: &User,
+        room: Room,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        if room.is_lobby() {
+            event_buf.push_back(Broadcast::rejection(
+                admin,
+                format!("{} cannot be deleted", room.name),
+            ));
+            return;
+        }
+        let Some(evacuees) = self.state.delete_room(&room) else {
+            event_buf.push_back(Broadcast::error(
+                admin,
+                ErrorReason::RoomNotFound,
+                format!("There is no room called {}", room.name),
+            ));
+            return;
+        };
+
+        log::info!("{} ({}) deleted room {}", admin.name, admin.id, room.name);
+        for evacuee in &evacuees {
+            event_buf.push_back(Broadcast::reply(
+                evacuee,
+                format!("{} was deleted by {}", room.name, admin.name),
+            ));
+            event_buf.extend(self.insert_occupant(evacuee, &Room::lobby()));
+        }
+        if !evacuees.contains(admin) {
+            event_buf.push_back(Broadcast::reply(
+                admin,
+                format!(
+                    "Deleted {}, {} occupant(s) moved to {}",
+                    room.name,
+                    evacuees.len(),
+                    Room::lobby().name
+                ),
+            ));
+        }
+    }
+
+    fn set_private(&mut self, owner: &User, private: bool) -> Broadcast {
+        let room = self.state.get_occupied_room(owner).unwrap_or_default();
+
+        if !self.state.is_owner(&room, owner) {
+            return Broadcast::rejection(owner, "Only the room owner can change its privacy");
+        }
+
+        self.state.room_settings_mut(&room).private = private;
+        let text = match private {
+            true => format!("{} is now private", room.name),
+            false => format!("{} is now public", room.name),
+        };
+        self.state
+            .broadcast_notification(&room, Severity::Info, text)
+    }
+
+    fn set_locked(&mut self, user: &User, locked: bool) -> Broadcast {
+        let room = self.state.get_occupied_room(user).unwrap_or_default();
+
+        if self.state.is_locked(&room) == locked {
+            return Broadcast::rejection(
+                user,
+                match locked {
+                    true => format!("{} is already locked", room.name),
+                    false => format!("{} is not locked", room.name),
+                },
+            );
+        }
+
+        let settings = self.state.room_settings_mut(&room);
+        settings.locked = locked;
+        settings.lock_leavers.clear();
+        let text = match locked {
+            true => format!("{} locked {}", user.name, room.name),
+            false => format!("{} unlocked {}", user.name, room.name),
+        };
+        self.state
+            .broadcast_notification(&room, Severity::Warning, text)
+    }
+
+    fn handle_invite(&mut self, owner: &User, target: &str, event_buf: &mut VecDeque<Broadcast>) {
+        let room = self.state.get_occupied_room(owner).unwrap_or_default();
+
+        if !self.state.is_owner(&room, owner) {
+            event_buf.push_back(Broadcast::rejection(
+                owner,
+                "Only the room owner can invite users",
+            ));
+            return;
+        }
+        let Some(invitee) = self.state.find_user_by_name(target).cloned() else {
+            event_buf.push_back(Broadcast::error(
+                owner,
+                ErrorReason::UserNotFound,
+                format!("{target} is not online"),
+            ));
+            return;
+        };
+
+        self.state
+            .room_settings_mut(&room)
+            .invited
+            .insert(invitee.id.clone());
+        event_buf.push_back(Broadcast::reply(
+            &invitee,
+            format!(
+                "{} invited you to {}, join with /mv {}",
+                owner.name, room.name, room.name
+            ),
+        ));
+        event_buf.push_back(Broadcast::reply(
+            owner,
+            format!("Invited {} to {}", invitee.name, room.name),
+        ));
+    }
+
+    fn handle_uninvite(&mut self, owner: &User, target: &str) -> Broadcast {
+        let room = self.state.get_occupied_room(owner).unwrap_or_default();
+
+        if !self.state.is_owner(&room, owner) {
+            return Broadcast::rejection(owner, "Only the room owner can revoke invites");
+        }
+        let Some(invitee) = self.state.find_user_by_name(target).cloned() else {
+            return Broadcast::error(
+                owner,
+                ErrorReason::UserNotFound,
+                format!("{target} is not online"),
+            );
+        };
+
+        if self
+            .state
+            .room_settings_mut(&room)
+            .invited
+            .remove(&invitee.id)
+        {
+            Broadcast::reply(
+                owner,
+                format!("Revoked {}'s invite to {}", invitee.name, room.name),
+            )
+        } else {
+            Broadcast::rejection(
+                owner,
+                format!("{} was not invited to {}", invitee.name, room.name),
+            )
+        }
+    }
+
+    fn describe_user(&self, admin: &User, name: &str) -> Broadcast {
+        if name.is_empty() {
+            return Broadcast::rejection(admin, "Usage: /whois <user>");
+        }
+
+        let format_time = |t: DateTime<Utc>| t.format("%Y-%m-%d %H:%M:%S UTC").to_string();
+        // Names aren't unique at login, so everyone going by the name is listed.
+        let sessions: Vec<String> = self
+            .state
+            .occupancy
+            .iter()
+            .flat_map(|(room, occupants)| occupants.iter().map(move |occupant| (room, occupant)))
+            .filter(|(_, occupant)| occupant.name == name)
+            .map(|(room, occupant)| {
+                format!(
+                    "name: {}\nid: {}\nroom: {}\nlogged in: {}\nlast active: {}\nrole: {:?}\nstatus: {:?}\nmoderation: {:?}\nmessages: {}\nsequence gaps: {}\nreceived: {}\naddress: {}",
+                    occupant.name,
+                    occupant.id,
+                    room.name,
+                    format_time(occupant.logged_in_at),
+                    format_time(occupant.last_active),
+                    occupant.role,
+                    occupant.status,
+                    occupant.moderation_at(Utc::now()),
+                    occupant.messages_sent,
+                    occupant.sequence_gap_count(),
+                    occupant.connection_stats,
+                    occupant
+                        .peer_addr
+                        .map(|addr| addr.to_string())
+                        .unwrap_or("unknown".into())
+                )
+            })
+            .collect();
+
+        if sessions.is_empty() {
+            return Broadcast::error(
+                admin,
+                ErrorReason::UserNotFound,
+                format!("{name} is not online"),
+            );
+        }
+        Broadcast::reply(admin, sessions.join("\n\n"))
+    }
+
+    fn report_seen(&self, asking: &User, name: &str, now: DateTime<Utc>) -> Broadcast {
+        if name.is_empty() {
+            return Broadcast::rejection(asking, "Usage: /seen <user>");
+        }
+
+        let format_time = |t: DateTime<Utc>| t.format("%Y-%m-%d %H:%M:%S UTC").to_string();
+
+        if let Some(target) = self.state.find_user_by_name(name) {
+            let room = self
+                .state
+                .get_occupied_room(target)
+                .map(|room| room.name)
+                .unwrap_or_default();
+            let idle = now.signed_duration_since(target.last_active);
+            let text = if idle.num_seconds() >= IDLE_AFTER_SECS {
+                format!(
+                    "{name} is connected in {room} but has been idle for {} minute(s), last active at {}",
+                    idle.num_minutes(),
+                    format_time(target.last_active)
+                )
+            } else {
+                format!(
+                    "{name} is connected in {room}, last active at {}",
+                    format_time(target.last_active)
+                )
+            };
+            return Broadcast::reply(asking, text);
+        }
+
+        match self.state.find_departure(name) {
+            Some(departure) => Broadcast::reply(
+                asking,
+                format!(
+                    "{name} disconnected from {} at {}, last active at {}",
+                    departure.room.name,
+                    format_time(departure.left_at),
+                    format_time(departure.last_active)
+                ),
+            ),
+            None => Broadcast::error(
+                asking,
+                ErrorReason::UserNotFound,
+                format!("Have not seen anyone called {name}"),
+            ),
+        }
+    }
+
+    fn announce(&mut self, admin: &User, text: String, event_buf: &mut VecDeque<Broadcast>) {
+        let text = text.trim();
+        if text.is_empty() {
+            event_buf.push_back(Broadcast::rejection(admin, "Usage: /announce <message>"));
+            return;
+        }
+
+        let notice = NotificationLog::new(text.to_string());
+        self.state.record_notification_everywhere(notice.clone());
+        let recipients = self.all_users();
+        let delivered = recipients.len();
+        event_buf.push_back(Broadcast::new(Event::Announcement { notice }, recipients));
+        event_buf.push_back(Broadcast::reply(
+            admin,
+            format!("Announcement sent to {delivered} user(s)"),
+        ));
+    }
+
+    fn set_motd(&mut self, admin: &User, motd: String) -> Broadcast {
+        self.server_info.motd = motd;
+        match self.server_info.motd() {
+            Some(_) => Broadcast::reply(admin, "MOTD updated"),
+            None => Broadcast::reply(admin, "MOTD cleared"),
+        }
+    }
+
+    fn report_uptime(&self, user: &User, now: DateTime<Utc>) -> Broadcast {
+        let uptime = self.server_info.uptime(now);
+        Broadcast::reply(
+            user,
+            format!(
+                "Up for {}d {:02}:{:02}:{:02} as of {}, {} user(s) in {} room(s), {} command(s) dropped while busy, {} unserializable message(s) replaced",
+                uptime.num_days(),
+                uptime.num_hours() % 24,
+                uptime.num_minutes() % 60,
+                uptime.num_seconds() % 60,
+                now.format("%Y-%m-%d %H:%M:%S UTC"),
+                self.state.occupancy.values().map(Vec::len).sum::<usize>(),
+                self.state.occupancy.len(),
+                self.server_info.dropped_commands.count(),
+                message_builder::fallback_frames_sent()
+            ),
+        )
+    }
+
+    /// Only clones the page, serializing and encrypting it happens in the session so room
+    /// traffic isn't held up behind a large history request.
+    fn fetch_history(&self, user: &User, limit: usize, before: Option<DateTime<Utc>>) -> Broadcast {
+        if limit == 0 || limit > MAX_HISTORY_LIMIT {
+            return Broadcast::rejection(
+                user,
+                format!("Usage: /history [count] [before], count must be between 1 and {MAX_HISTORY_LIMIT}"),
+            );
+        }
+        let Some(room) = self.state.get_occupied_room(user) else {
+            return Broadcast::error(
+                user,
+                ErrorReason::RoomNotFound,
+                "Could not find the room you are in",
+            );
+        };
+
+        let page = self
+            .state
+            .history_page(&room, &HistoryQuery { before, limit });
+        Broadcast::new(
+            Event::History {
+                occupant_names: self.state.occupant_names(&room),
+                room,
+                page,
+            },
+            vec![user.clone()],
+        )
+    }
+
+    fn report_room_stats(&mut self, user: &User, now: DateTime<Utc>) -> Broadcast {
+        let Some(room) = self.state.get_occupied_room(user) else {
+            return Broadcast::error(
+                user,
+                ErrorReason::RoomNotFound,
+                "Could not find the room you are in",
+            );
+        };
+        let stats = self.state.room_stats_mut(&room);
+        let recent = stats.messages_in_window(now);
+        Broadcast::reply(
+            user,
+            format!(
+                "{}: created {}, {} message(s) in total, {} in the last {} seconds, peak of {} occupant(s)",
+                room.name,
+                stats.created_at.format("%Y-%m-%d %H:%M:%S UTC"),
+                stats.total_messages,
+                recent,
+                RoomStats::WINDOW_SECS,
+                stats.peak_occupants
+            ),
+        )
+    }
+
+    fn search_room(&self, user: &User, query: &str, limit: usize) -> Broadcast {
+        let query = query.trim();
+        if query.chars().count() < MIN_SEARCH_LEN {
+            return Broadcast::rejection(
+                user,
+                format!("Usage: /search <text>, with at least {MIN_SEARCH_LEN} characters"),
+            );
+        }
+        let Some(room) = self.state.get_occupied_room(user) else {
+            return Broadcast::error(
+                user,
+                ErrorReason::RoomNotFound,
+                "Could not find the room you are in",
+            );
+        };
+
+        let needle = query.to_lowercase();
+        let matches = self
+            .state
+            .room_chat_logs(&room)
+            .into_iter()
+            .rev()
+            .filter(|msg| {
+                msg.contents.to_lowercase().contains(&needle)
+                    || msg.username.to_lowercase().contains(&needle)
+            })
+            .take(limit)
+            .collect();
+        Broadcast::new(
+            Event::SearchResults {
+                query: query.to_string(),
+                matches,
+            },
+            vec![user.clone()],
+        )
+    }
+
+    fn list_occupants(&self, user: &User) -> Broadcast {
+        let room = self.state.get_occupied_room(user).unwrap_or_default();
+        let names = self.state.occupant_names(&room);
+
+        Broadcast::reply(
+            user,
+            format!("{} in {}: {}", names.len(), room.name, names.join(", ")),
+        )
+    }
+
+    fn describe_current_room(&self, user: &User) -> Broadcast {
+        match self.state.get_occupied_room(user) {
+            Some(room) => Broadcast::reply(
+                user,
+                format!(
+                    "You are in {} ({:X}) with {} occupant(s)",
+                    room.name,
+                    room.hash_code(),
+                    self.state.room_subscribers(&room).len()
+                ),
+            ),
+            None => Broadcast::error(
+                user,
+                ErrorReason::RoomNotFound,
+                "Could not find the room you are in",
+            ),
+        }
+    }
+
+    fn rename_user(&mut self, user: &User, new_name: String) -> Broadcast {
+        let new_name = new_name.trim().to_string();
+        let room = self.state.get_occupied_room(user).unwrap_or_default();
+        let rejection = if new_name.is_empty() {
+            Some("Name cannot be empty. Usage: /nick <new_name>".to_string())
+        } else if new_name.chars().count() > MAX_USERNAME_LEN {
+            Some(format!(
+                "Name cannot be longer than {MAX_USERNAME_LEN} characters"
+            ))
+        } else if is_reserved_name(&new_name) {
+            Some(format!("The name {new_name} is reserved"))
+        } else if self.state.name_taken(&new_name, user) {
+            Some(format!("The name {new_name} is already taken"))
+        } else if self.state.is_name_banned(&room, &new_name) {
+            Some(format!("The name {new_name} is banned from {}", room.name))
+        } else {
+            None
+        };
+        if let Some(reason) = rejection {
+            return Broadcast::rejection(user, reason);
+        }
+
+        let text = format!("{} is now known as {}", user.name, new_name);
+        self.state.set_user_name(user, &new_name);
+
+        self.state
+            .broadcast_notification(&room, Severity::Info, text)
+    }
+
+    /// Direct messages are delivered straight to the recipient and never touch a room's chat log.
+    fn handle_direct_message(
+        &mut self,
+        sender: &User,
+        to: &str,
+        content: String,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let rejection = if to.is_empty() || content.is_empty() {
+            Some("Usage: /msg <user> <text>".to_string())
+        } else if sender.name.eq_ignore_ascii_case(to) {
+            Some("You cannot send a direct message to yourself".to_string())
+        } else if content.chars().count() > self.max_message_len {
+            Some(format!(
+                "Messages cannot be longer than {} characters",
+                self.max_message_len
+            ))
+        } else {
+            None
+        };
+        if let Some(reason) = rejection {
+            event_buf.push_back(Broadcast::rejection(sender, reason));
+            return;
+        }
+        if let Err((kind, reason)) = Self::check_silence(sender) {
+            event_buf.push_back(Broadcast::error(sender, kind, reason));
+            return;
+        }
+
+        let Some(recipient) = self.state.find_user_by_name(to).cloned() else {
+            event_buf.push_back(Broadcast::error(
+                sender,
+                ErrorReason::UserNotFound,
+                format!("{to} is not online"),
+            ));
+            return;
+        };
+
+        if recipient.blocks(sender) {
+            event_buf.push_back(Broadcast::rejection(
+                sender,
+                format!("{} has blocked you", recipient.name),
+            ));
+            return;
+        }
+
+        // Ignored and shadow banned senders still get the usual ack, so neither is revealed.
+        let shadow_banned = *sender.moderation_at(Utc::now()) == Moderation::ShadowBanned;
+        if !recipient.ignores(sender) && !shadow_banned {
+            // Sealed here with the recipient's key as the App has it, from the App's own
+            // record of the sender.
+            let sealed = SocketSendAdaptor::prepare_send_direct(
+                &recipient.shared_secret,
+                sender,
+                content,
+                Timestamp::from(Utc::now()),
+            );
+            match sealed {
+                Ok(frame) => event_buf.push_back(Broadcast::new(
+                    Event::Frame { frame },
+                    vec![recipient.clone()],
+                )),
+                Err(e) => {
+                    log::error!("Could not seal a direct message for {}: {e}", recipient.id);
+                    event_buf.push_back(Broadcast::error(
+                        sender,
+                        ErrorReason::Internal,
+                        format!("Could not deliver your message to {}", recipient.name),
+                    ));
+                    return;
+                }
+            }
+        }
+        let ack = match &recipient.status {
+            PresenceStatus::Online => format!("Message sent to {}", recipient.name),
+            PresenceStatus::Away(None) => {
+                format!(
+                    "{} is away. Message sent to {}",
+                    recipient.name, recipient.name
+                )
+            }
+            PresenceStatus::Away(Some(message)) => format!(
+                "{} is away: {message}. Message sent to {}",
+                recipient.name, recipient.name
+            ),
+        };
+        event_buf.push_back(Broadcast::reply(sender, ack));
+    }
+
+    fn handle_ignore(&mut self, user: &User, target: &str) -> Broadcast {
+        if target.is_empty() {
+            let names = self.state.online_names(&user.ignored);
+            if names.is_empty() {
+                return Broadcast::reply(user, "You are not ignoring anyone");
+            }
+            return Broadcast::reply(user, format!("Ignoring: {}", names.join(", ")));
+        }
+
+        if user.name == target {
+            return Broadcast::rejection(user, "You cannot ignore yourself");
+        }
+        let Some(ignored) = self.state.find_user_by_name(target).cloned() else {
+            return Broadcast::error(
+                user,
+                ErrorReason::UserNotFound,
+                format!("{target} is not online"),
+            );
+        };
+        if let Some(record) = self.state.find_user_mut(user) {
+            record.ignored.insert(ignored.id);
+        }
+        Broadcast::reply(user, format!("You are now ignoring {target}"))
+    }
+
+    fn handle_unignore(&mut self, user: &User, target: &str) -> Broadcast {
+        if target.is_empty() {
+            return Broadcast::rejection(user, "Usage: /unignore <user>");
+        }
+        let Some(ignored) = self.state.find_user_by_name(target).cloned() else {
+            return Broadcast::error(
+                user,
+                ErrorReason::UserNotFound,
+                format!("{target} is not online"),
+            );
+        };
+        if !user.ignores(&ignored) {
+            return Broadcast::rejection(user, format!("You are not ignoring {target}"));
+        }
+        if let Some(record) = self.state.find_user_mut(user) {
+            record.ignored.remove(&ignored.id);
+        }
+        Broadcast::reply(user, format!("You are no longer ignoring {target}"))
+    }
+
+    fn handle_block(&mut self, user: &User, target: &str) -> Broadcast {
+        if target.is_empty() {
+            return Broadcast::rejection(user, "Usage: /block <user>");
+        }
+        if user.name == target {
+            return Broadcast::rejection(user, "You cannot block yourself");
+        }
+        let Some(blocked) = self.state.find_user_by_name(target).cloned() else {
+            return Broadcast::error(
+                user,
+                ErrorReason::UserNotFound,
+                format!("{target} is not online"),
+            );
+        };
+        if let Some(record) = self.state.find_user_mut(user) {
+            record.blocked.insert(blocked.id);
+        }
+        Broadcast::reply(
+            user,
+            format!("You have blocked direct messages from {target}"),
+        )
+    }
+
+    fn handle_unblock(&mut self, user: &User, target: &str) -> Broadcast {
+        if target.is_empty() {
+            return Broadcast::rejection(user, "Usage: /unblock <user>");
+        }
+        let Some(blocked) = self.state.find_user_by_name(target).cloned() else {
+            return Broadcast::error(
+                user,
+                ErrorReason::UserNotFound,
+                format!("{target} is not online"),
+            );
+        };
+        if !user.blocks(&blocked) {
+            return Broadcast::rejection(user, format!("You have not blocked {target}"));
+        }
+        if let Some(record) = self.state.find_user_mut(user) {
+            record.blocked.remove(&blocked.id);
+        }
+        Broadcast::reply(user, format!("You have unblocked {target}"))
+    }
+
+    fn list_blocks(&self, user: &User) -> Broadcast {
+        let names = self.state.online_names(&user.blocked);
+        if names.is_empty() {
+            return Broadcast::reply(user, "You are not blocking anyone");
+        }
+        Broadcast::reply(user, format!("Blocked: {}", names.join(", ")))
+    }
+
+    fn change_role(
+        &mut self,
+        admin: &User,
+        target: &str,
+        role: Role,
+        promote: bool,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let command = if promote { "promote" } else { "demote" };
+        if target.is_empty() {
+            event_buf.push_back(Broadcast::rejection(
+                admin,
+                format!("Usage: /{command} <user> [role]"),
+            ));
+            return;
+        }
+        let Some(target_user) = self.state.find_user_by_name(target).cloned() else {
+            event_buf.push_back(Broadcast::error(
+                admin,
+                ErrorReason::UserNotFound,
+                format!("{target} is not online"),
+            ));
+            return;
+        };
+
+        let rejection = if promote && role <= target_user.role {
+            Some(format!(
+                "{target} is already {:?} or above",
+                target_user.role
+            ))
+        } else if !promote && role >= target_user.role {
+            Some(format!(
+                "{target} is already {:?} or below",
+                target_user.role
+            ))
+        } else if target_user.is_admin() && self.state.admin_count() <= 1 {
+            Some("Cannot demote the last remaining admin".to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = rejection {
+            event_buf.push_back(Broadcast::rejection(admin, reason));
+            return;
+        }
+
+        if let Some(record) = self.state.find_user_mut(&target_user) {
+            record.role = role;
+        }
+        event_buf.push_back(Broadcast::reply(
+            &target_user,
+            format!("{} made you {:?}", admin.name, role),
+        ));
+        if target_user != *admin {
+            event_buf.push_back(Broadcast::reply(
+                admin,
+                format!("{target} is now {:?}", role),
+            ));
+        }
+    }
+
+    /// Sends the target back to the lobby, or off the server for someone already in it.
+    fn handle_kick(&mut self, moderator: &User, target: &str, event_buf: &mut VecDeque<Broadcast>) {
+        let room = self.state.get_occupied_room(moderator).unwrap_or_default();
+
+        let Some(target_user) = self
+            .state
+            .room_subscribers(&room)
+            .into_iter()
+            .find(|occupant| occupant.name == target)
+        else {
+            event_buf.push_back(Broadcast::rejection(
+                moderator,
+                format!("{target} is not in {}", room.name),
+            ));
+            return;
+        };
+        if !self.outranks(moderator, &target_user, &room) {
+            event_buf.push_back(Broadcast::error(
+                moderator,
+                ErrorReason::PermissionDenied,
+                format!("You do not have permission to kick {target}"),
+            ));
+            return;
+        }
+
+        if !room.is_lobby() {
+            self.evict_to_lobby(&target_user, &room, moderator, "kicked", event_buf);
+            return;
+        }
+        // The session hangs up and drops the user, like any other that ends for good.
+        let text = format!(
+            "{} was kicked off the server by {}",
+            target_user.name, moderator.name
+        );
+        event_buf.push_back(
+            self.state
+                .broadcast_notification(&room, Severity::Warning, text),
+        );
+        event_buf.push_back(Broadcast::new(Event::Kicked, vec![target_user]));
+    }
+
+    /// Shared by the moderation commands, tells the room and the target what happened
+    /// before sending the target back to the lobby.
+    fn evict_to_lobby(
+        &mut self,
+        target: &User,
+        room: &Room,
+        moderator: &User,
+        action: &str,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let text = format!(
+            "{} was {action} from {} by {}",
+            target.name, room.name, moderator.name
+        );
+        event_buf.push_back(
+            self.state
+                .broadcast_notification(room, Severity::Warning, text),
+        );
+        event_buf.push_back(Broadcast::rejection(
+            target,
+            format!("You were {action} from {} by {}", room.name, moderator.name),
+        ));
+
+        if let Some(broadcasts) = self.remove_occupant(target) {
+            event_buf.extend(broadcasts);
+        }
+        // Being thrown out of a locked room is not leaving it, there's no way back in.
+        if self.state.room_exists(room) {
+            self.state
+                .room_settings_mut(room)
+                .lock_leavers
+                .remove(&target.id);
+        }
+        event_buf.extend(self.insert_occupant(target, &Room::lobby()));
+    }
+
+    fn handle_ban(&mut self, moderator: &User, target: &str, event_buf: &mut VecDeque<Broadcast>) {
+        let room = self.state.get_occupied_room(moderator).unwrap_or_default();
+
+        if room.is_lobby() {
+            event_buf.push_back(Broadcast::rejection(
+                moderator,
+                format!("Nobody can be banned from {}", room.name),
+            ));
+            return;
+        }
+        if target.is_empty() {
+            event_buf.push_back(Broadcast::rejection(moderator, "Usage: /ban <user>"));
+            return;
+        }
+        let occupant = self
+            .state
+            .room_subscribers(&room)
+            .into_iter()
+            .find(|occupant| occupant.name == target);
+        if let Some(found) = &occupant {
+            if !self.outranks(moderator, found, &room) {
+                event_buf.push_back(Broadcast::error(
+                    moderator,
+                    ErrorReason::PermissionDenied,
+                    format!("You do not have permission to ban {target}"),
+                ));
+                return;
+            }
+        }
+
+        let ban = self
+            .state
+            .room_settings_mut(&room)
+            .banned
+            .entry(Ban::key(target))
+            .or_insert_with(|| Ban::new(target));
+        if let Some(target_user) = &occupant {
+            ban.user_ids.insert(target_user.id.clone());
+        }
+
+        match occupant {
+            Some(target_user) => {
+                self.evict_to_lobby(&target_user, &room, moderator, "banned", event_buf)
+            }
+            None => {
+                let text = format!(
+                    "{target} was banned from {} by {}",
+                    room.name, moderator.name
+                );
+                event_buf.push_back(self.state.broadcast_notification(
+                    &room,
+                    Severity::Warning,
+                    text,
+                ));
+            }
+        }
+    }
+
+    fn handle_unban(&mut self, moderator: &User, target: &str) -> Broadcast {
+        let room = self.state.get_occupied_room(moderator).unwrap_or_default();
+
+        let unbanned = self
+            .state
+            .room_settings_mut(&room)
+            .banned
+            .remove(&Ban::key(target));
+        if unbanned.is_some() {
+            Broadcast::reply(
+                moderator,
+                format!("{target} is no longer banned from {}", room.name),
+            )
+        } else {
+            Broadcast::rejection(
+                moderator,
+                format!("{target} is not banned from {}", room.name),
+            )
+        }
+    }
+
+    fn list_bans(&self, moderator: &User) -> Broadcast {
+        let room = self.state.get_occupied_room(moderator).unwrap_or_default();
+
+        let mut banned: Vec<&str> = match self.state.settings.get(&room) {
+            Some(settings) => settings
+                .banned
+                .values()
+                .map(|ban| ban.name.as_str())
+                .collect(),
+            None => vec![],
+        };
+        banned.sort_unstable();
+
+        Broadcast::reply(
+            moderator,
+            format!(
+                "{} user(s) banned from {}: {}",
+                banned.len(),
+                room.name,
+                banned.join(", ")
+            ),
+        )
+    }
+
+    fn handle_mute(
+        &mut self,
+        moderator: &User,
+        target: &str,
+        duration_secs: Option<u64>,
+    ) -> Broadcast {
+        let room = self.state.get_occupied_room(moderator).unwrap_or_default();
+
+        let Some(target_user) = self
+            .state
+            .room_subscribers(&room)
+            .into_iter()
+            .find(|occupant| occupant.name == target)
+        else {
+            return Broadcast::rejection(moderator, format!("{target} is not in {}", room.name));
+        };
+        if !self.outranks(moderator, &target_user, &room) {
+            return Broadcast::error(
+                moderator,
+                ErrorReason::PermissionDenied,
+                format!("You do not have permission to mute {target}"),
+            );
+        }
+
+        let expiry = duration_secs
+            .and_then(|secs| Duration::try_seconds(i64::try_from(secs).ok()?))
+            .map(|duration| Utc::now() + duration);
+        self.state
+            .room_settings_mut(&room)
+            .muted
+            .insert(target_user.id.clone(), expiry);
+
+        let text = match duration_secs {
+            Some(secs) => format!(
+                "{} was muted in {} for {secs}s by {}",
+                target_user.name, room.name, moderator.name
+            ),
+            None => format!(
+                "{} was muted in {} by {}",
+                target_user.name, room.name, moderator.name
+            ),
+        };
+        self.state
+            .broadcast_notification(&room, Severity::Warning, text)
+    }
+
+    /// Silences, shadow bans or pardons a user wherever they are. A silenced user is told, a
+    /// shadow banned one must not be.
+    fn moderate(
+        &mut self,
+        moderator: &User,
+        target: &str,
+        moderation: Moderation,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let Some(target_user) = self.state.find_user_by_name(target).cloned() else {
+            event_buf.push_back(Broadcast::error(
+                moderator,
+                ErrorReason::UserNotFound,
+                format!("{target} is not online"),
+            ));
+            return;
+        };
+        let room = self.state.get_occupied_room(moderator).unwrap_or_default();
+        if !self.outranks(moderator, &target_user, &room) {
+            event_buf.push_back(Broadcast::error(
+                moderator,
+                ErrorReason::PermissionDenied,
+                format!("You do not have permission to moderate {target}"),
+            ));
+            return;
+        }
+        let previous = target_user.moderation_at(Utc::now()).clone();
+        if moderation == Moderation::Normal && previous == Moderation::Normal {
+            event_buf.push_back(Broadcast::rejection(
+                moderator,
+                format!("{target} is not silenced or shadow banned"),
+            ));
+            return;
+        }
+
+        let (reply, told) = match &moderation {
+            // Lifting a shadow ban is as quiet as imposing one.
+            Moderation::Normal if previous == Moderation::ShadowBanned => {
+                (format!("{target} was pardoned"), None)
+            }
+            Moderation::Normal => (
+                format!("{target} was pardoned"),
+                Some(format!("You were pardoned by {}", moderator.name)),
+            ),
+            Moderation::Silenced(Some(until)) => (
+                format!(
+                    "{target} is silenced until {}",
+                    until.format("%H:%M:%S UTC")
+                ),
+                Some(format!(
+                    "You were silenced by {} until {}",
+                    moderator.name,
+                    until.format("%H:%M:%S UTC")
+                )),
+            ),
+            Moderation::Silenced(None) => (
+                format!("{target} is silenced"),
+                Some(format!("You were silenced by {}", moderator.name)),
+            ),
+            Moderation::ShadowBanned => (format!("{target} is shadow banned"), None),
+        };
+        if let Some(record) = self.state.find_user_mut(&target_user) {
+            record.moderation = moderation;
+        }
+        event_buf.push_back(Broadcast::reply(moderator, reply));
+        if let Some(told) = told {
+            event_buf.push_back(Broadcast::reply(&target_user, told));
+        }
+    }
+
+    fn handle_unmute(&mut self, moderator: &User, target: &str) -> Broadcast {
+        let room = self.state.get_occupied_room(moderator).unwrap_or_default();
+
+        let Some(target_user) = self
+            .state
+            .room_subscribers(&room)
+            .into_iter()
+            .find(|occupant| occupant.name == target)
+        else {
+            return Broadcast::rejection(moderator, format!("{target} is not in {}", room.name));
+        };
+        if self.state.active_mute(&room, &target_user).is_none() {
+            return Broadcast::rejection(moderator, format!("{target} is not muted"));
+        }
+
+        self.state
+            .room_settings_mut(&room)
+            .muted
+            .remove(&target_user.id);
+        let text = format!(
+            "{} was unmuted in {} by {}",
+            target_user.name, room.name, moderator.name
+        );
+        self.state
+            .broadcast_notification(&room, Severity::Warning, text)
+    }
+
+    fn handle_purge(&mut self, moderator: &User, count: usize) -> Broadcast {
+        if count == 0 || count > MAX_PURGE {
+            return Broadcast::rejection(
+                moderator,
+                format!("Usage: /purge <count>, count must be between 1 and {MAX_PURGE}"),
+            );
+        }
+        let room = self.state.get_occupied_room(moderator).unwrap_or_default();
+
+        let purged = self.state.purge_messages(&room, count);
+        let text = format!("{} purged {purged} message(s)", moderator.name);
+        self.state
+            .broadcast_notification(&room, Severity::Warning, text)
+    }
+
+    fn set_pinned(&mut self, user: &User, id: MessageId, pinned: bool) -> Broadcast {
+        let room = self.state.get_occupied_room(user).unwrap_or_default();
+        self.state.prune_pins(&room);
+        let Some(message) = self.state.find_message(&room, id).cloned() else {
+            return Broadcast::rejection(
+                user,
+                format!("There is no message {id} in {}", room.name),
+            );
+        };
+        let pins = &mut self.state.room_settings_mut(&room).pinned;
+        let rejection = match (pinned, pins.contains(&id)) {
+            (true, true) => Some(format!("Message {id} is already pinned")),
+            (true, false) if pins.len() >= MAX_PINS => {
+                Some(format!("{} already has {MAX_PINS} pins", room.name))
+            }
+            (false, false) => Some(format!("Message {id} is not pinned")),
+            _ => None,
+        };
+        if let Some(reason) = rejection {
+            return Broadcast::rejection(user, reason);
+        }
+
+        let text = if pinned {
+            pins.push(id);
+            format!(
+                "{} pinned a message from {}: {}",
+                user.name,
+                message.username,
+                message.wire_contents()
+            )
+        } else {
+            pins.retain(|pin| *pin != id);
+            format!("{} unpinned a message from {}", user.name, message.username)
+        };
+        self.state
+            .broadcast_notification(&room, Severity::Info, text)
+    }
+
+    fn set_slow_mode(&mut self, moderator: &User, interval: u64) -> Broadcast {
+        let room = self.state.get_occupied_room(moderator).unwrap_or_default();
+
+        let settings = self.state.room_settings_mut(&room);
+        settings.slow_mode_secs = interval;
+        settings.last_sent.clear();
+        let text = match interval {
+            0 => format!("{} turned off slow mode", moderator.name),
+            secs => format!(
+                "{} turned on slow mode, one message every {secs} second(s)",
+                moderator.name
+            ),
+        };
+        self.state
+            .broadcast_notification(&room, Severity::Warning, text)
+    }
+
+    fn set_topic(&mut self, moderator: &User, topic: String) -> Broadcast {
+        let room = self.state.get_occupied_room(moderator).unwrap_or_default();
+        let topic = topic.trim().to_string();
+
+        if topic.chars().count() > self.state.max_topic_len {
+            return Broadcast::rejection(
+                moderator,
+                format!(
+                    "Topics cannot be longer than {} characters",
+                    self.state.max_topic_len
+                ),
+            );
+        }
+
+        let text = if topic.is_empty() {
+            self.state.room_settings_mut(&room).topic = None;
+            format!("{} cleared the topic", moderator.name)
+        } else {
+            let text = format!("{} changed the topic to: {topic}", moderator.name);
+            self.state.room_settings_mut(&room).topic = Some(topic);
+            text
+        };
+        self.state
+            .broadcast_notification(&room, Severity::Info, text)
+    }
+
+    fn register_user(&mut self, user: User) -> Broadcast {
+        Broadcast::new(
+            Event::UserRegistered {
+                token: user.id.clone(),
+            },
+            vec![user.clone()],
+        )
+    }
+
+    /// The UserLeft broadcast for the room the user was taken out of, `None` if they weren't
+    /// in one.
+    /// The room is told the user left before the notice saying so, which goes to whoever
+    /// is still there.
+    fn remove_occupant(&mut self, user: &User) -> Option<[Broadcast; 2]> {
+        let current_room = self.state.get_occupied_room(user)?;
+        // Recorded before the user goes, a room left empty may go with them.
+        let text = format!("{} left {}", user.name, current_room.name);
+        let mut notice = self
+            .state
+            .broadcast_notification(&current_room, Severity::Info, text);
+        notice.subscribers.retain(|occupant| occupant != user);
+
+        match self.state.remove_user_from_room(user) {
+            Ok(RemovalOutcome::Removed {
+                room,
+                collected: true,
+                ..
+            }) => log::info!(
+                "{} left {}, which was empty so it is gone",
+                user.id,
+                room.name
+            ),
+            Ok(RemovalOutcome::Removed {
+                room,
+                emptied: true,
+                ..
+            }) => log::debug!("{} left {}, which is now empty", user.id, room.name),
+            Ok(RemovalOutcome::Removed { room, .. }) => {
+                log::debug!("{} left {}", user.id, room.name)
+            }
+            Ok(RemovalOutcome::NotFound) => {
+                log::warn!("{} was not in any room when they left", user.id);
+                return None;
+            }
+            Err(e) => {
+                log::error!(
+                    "Could not remove {} from {}: {e}",
+                    user.id,
+                    current_room.name
+                );
+                return None;
+            }
+        }
+        let left = Broadcast::new(
+            Event::UserLeft {
+                user: user.clone(),
+                room: current_room.clone(),
+                snapshot: self.state.room_snapshot(&current_room),
+            },
+            self.state.room_subscribers(&current_room),
+        );
+        Some([left, notice])
+    }
+
+    /// The joining user has the notice in the RoomData they are sent, the rest of the room
+    /// hears it after the join itself.
+    fn insert_occupant(&mut self, user: &User, room: &Room) -> [Broadcast; 2] {
+        self.state.add_user_to_room(user, room);
+        let text = format!("{} joined {}", user.name, room.name);
+        let mut notice = self
+            .state
+            .broadcast_notification(room, Severity::Info, text);
+        notice.subscribers.retain(|occupant| occupant != user);
+        let joined = Broadcast::new(
+            Event::UserJoined {
+                user: user.clone(),
+                room: room.clone(),
+                snapshot: self.state.room_snapshot(room),
+            },
+            self.state.room_subscribers(room),
+        );
+        [joined, notice]
+    }
+}
+
+/// The App task is the only owner of the AppState, commands are handled one at a time so
+/// there are no locks to hold across a command. The flip side is that a slow command holds
+/// up every room, so anything expensive (rendering an export, say) belongs in the session
+/// and the App only hands over a snapshot.
+pub struct App {
+    gateway_source: Receiver<Command>,
+    command_handler: CommandHandler,
+    event_bus: EventBus,
+    /// Flipped to true when a shutdown begins so that no more connections are accepted.
+    shutdown_signal: watch::Sender<bool>,
+}
+
+impl App {
+    pub fn init(
+        command_source: Receiver<Command>,
+        server_info: ServerInfo,
+        shutdown_signal: watch::Sender<bool>,
+    ) -> Self {
+        Self::with_rng(
+            command_source,
+            server_info,
+            shutdown_signal,
+            Box::new(OsRng),
+        )
+    }
+
+    /// Lets tests swap in a seeded rng so that dice rolls are predictable.
+    pub fn with_rng(
+        command_source: Receiver<Command>,
+        server_info: ServerInfo,
+        shutdown_signal: watch::Sender<bool>,
+        rng: Box<dyn RngCore + Send>,
+    ) -> Self {
+        Self {
+            gateway_source: command_source,
+            command_handler: CommandHandler::new(AppState::new(), server_info),
+            event_bus: EventBus::new(),
+            shutdown_signal,
+        }
+        .with_plugin("roll", Box::new(DicePlugin::new(rng)))
+    }
+
+    pub fn with_cooldowns(mut self, cooldowns: Cooldowns) -> Self {
+        self.command_handler.cooldowns = cooldowns;
+        self
+    }
+
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.command_handler.audit_log = audit_log;
+        self
+    }
+
+    /// Registers a plugin under a command name, replacing anything registered there before.
+    pub fn with_plugin(mut self, name: &'static str, plugin: Box<dyn CommandPlugin>) -> Self {
+        self.command_handler.plugins.insert(name, plugin);
+        self
+    }
+
+    /// Where each room's history is written out when the server shuts down.
+    pub fn with_transcript_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.command_handler.transcript_dir = Some(dir.into());
+        self
+    }
+
+    /// How long a user who lost their connection is kept for, 0 drops them straight away.
+    pub fn with_resume_grace(mut self, secs: u64) -> Self {
+        self.command_handler.resume_grace_secs = secs;
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.command_handler.limiter = limiter;
+        self
+    }
+
+    /// Takes the App's share of the server config: command rate limits, how long detached
+    /// users are kept and how long a message may be.
+    pub fn with_config(mut self, config: &ServerConfig) -> Self {
+        self.command_handler.max_message_len = config.max_message_len;
+        // Validation has already turned away names that aren't commands.
+        self.command_handler.cooldowns =
+            Cooldowns::from_table(config.cooldowns.iter().filter_map(|(name, secs)| {
+                CommandRegistry::get(name).map(|spec| (spec.name, *secs))
+            }));
+        self.with_rate_limiter(RateLimiter::new(
+            config.command_burst,
+            config.command_window_secs,
+        ))
+        .with_resume_grace(config.resume_grace_secs)
+    }
+
+    /// The handle finishes once a shutdown has closed every session.
+    pub fn run(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            if let Err(e) = self.work().await {
+                panic!("App exited unexpectedly with error {e}")
+            }
+        })
+    }
+
+    /// A session that has gone away is dropped just as if it had sent DropUser itself, which
+    /// may queue more broadcasts for the users left behind.
+    fn publish_all(&mut self, event_buf: &mut VecDeque<Broadcast>) {
+        while let Some(cast) = event_buf.pop_front() {
+            for user in self.event_bus.publish(&cast) {
+                let drop = Command {
+                    user,
+                    payload: CommandPayload::DropUser,
+                    request_id: None,
+                };
+                if let Err(e) = self.command_handler.handle(drop, event_buf) {
+                    log::error!("Failed to drop a closed session: {e}");
+                }
+            }
+        }
+    }
+
+    pub async fn work(&mut self) -> Result<()> {
+        let mut event_buf: VecDeque<Broadcast> = VecDeque::new();
+        let mut sweep = interval(std::time::Duration::from_secs(DETACHED_SWEEP_SECS));
+
+        loop {
+            let deadline = self.command_handler.shutdown_deadline();
+            let presence_due = self.command_handler.presence_deadline();
+            tokio::select! {
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.command_handler.begin_shutdown(&mut event_buf);
+                    self.drop_expired_detachments(&mut event_buf);
+                    self.publish_all(&mut event_buf);
+                    let _ = self.shutdown_signal.send(true);
+                }
+                _ = sweep.tick() => {
+                    self.drop_expired_detachments(&mut event_buf);
+                }
+                _ = sleep_until(presence_due.unwrap_or_else(Instant::now)), if presence_due.is_some() => {
+                    self.settle_presence(&mut event_buf);
+                }
+                command = self.gateway_source.next() => {
+                    let Some(command) = command else {
+                        return Ok(());
+                    };
+                    self.work_on(command, &mut event_buf)?;
+                }
+            }
+            if self.command_handler.ready_to_exit() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Users who lost their connection and didn't come back in time leave for good.
+    fn drop_expired_detachments(&mut self, event_buf: &mut VecDeque<Broadcast>) {
+        for user in self.command_handler.expired_detachments(Utc::now()) {
+            let drop = Command {
+                user,
+                payload: CommandPayload::DropUser,
+                request_id: None,
+            };
+            if let Err(e) = self.command_handler.handle(drop, event_buf) {
+                log::error!("Failed to drop a detached user: {e}");
+            }
+        }
+        self.publish_all(event_buf);
+    }
+
+    fn settle_presence(&mut self, event_buf: &mut VecDeque<Broadcast>) {
+        event_buf.extend(self.command_handler.settle_presence(Utc::now()));
+        self.publish_all(event_buf);
+    }
+
+    fn work_on(&mut self, command: Command, event_buf: &mut VecDeque<Broadcast>) -> Result<()> {
+        self.drop_expired_detachments(event_buf);
+        let mut defer_unsubscribe: Option<User> = None;
+        match command.clone() {
+            Command {
+                user,
+                payload: CommandPayload::RegisterUser(delivery_channel, ..),
+                ..
+            } => self.event_bus.subscribe(user, delivery_channel),
+            Command {
+                user,
+                payload: CommandPayload::ResumeUser { token, channel },
+                ..
+            } => {
+                let user =
+                    self.command_handler
+                        .resume_user(user, &token, channel.clone(), event_buf)?;
+                self.event_bus.subscribe(user, channel)?;
+                self.publish_all(event_buf);
+                return Ok(());
+            }
+            Command {
+                ref user,
+                payload: CommandPayload::DropUser | CommandPayload::DetachUser,
+                ..
+            } if !self.event_bus.is_subscribed(user) => {
+                log::debug!(
+                    "{} was already dropped when their session closed",
+                    user.name
+                );
+                return Ok(());
+            }
+            Command {
+                user,
+                payload: CommandPayload::DropUser | CommandPayload::DetachUser,
+                ..
+            } => {
+                defer_unsubscribe = Some(user.clone());
+                Ok(())
+            }
+            _ => Ok(()),
+        }?;
+        self.command_handler.handle(command, event_buf)?;
+        self.publish_all(event_buf);
+        if let Some(user) = defer_unsubscribe {
+            // Publishing may already have unsubscribed a session that closed early.
+            if let Err(e) = self.event_bus.unsubscribe(user.clone()) {
+                log::debug!("Did not unsubscribe {}: {e}", user.name);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::commands::RequestId;
+    use crate::domain::room::LOBBY_NAME;
+    use futures_channel::mpsc::{channel, unbounded, UnboundedReceiver};
+    use marain_api::prelude::ServerMsgBody;
+    use rand_chacha::{rand_core::SeedableRng, ChaCha8Rng};
+    use uuid::Uuid;
+
+    /// Drives an App one command at a time, each user's events collect in their inbox.
+    struct TestServer {
+        app: App,
+        inboxes: HashMap<String, UnboundedReceiver<Event>>,
+    }
+
+    impl TestServer {
+        fn new() -> Self {
+            let (_, command_source) = channel(1);
+            let (shutdown_signal, _) = watch::channel(false);
+            let app = App::with_rng(
+                command_source,
+                ServerInfo::new(Utc::now(), String::new()),
+                shutdown_signal,
+                Box::new(ChaCha8Rng::seed_from_u64(1)),
+            );
+            Self {
+                app,
+                inboxes: HashMap::new(),
+            }
+        }
+
+        fn connect(&mut self, name: &str, role: Role) -> User {
+            let key = SessionKey::from_bytes([self.inboxes.len() as u8 + 1; 32]);
+            let mut user = User::new(format!("id-{name}"), name.to_string(), key);
+            user.role = role;
+            self.register(user)
+        }
+
+        fn register(&mut self, user: User) -> User {
+            let (channel, inbox) = unbounded();
+            self.inboxes.insert(user.id.clone(), inbox);
+            self.send(&user, CommandPayload::RegisterUser(channel));
+            self.events(&user);
+            user
+        }
+
+        fn send(&mut self, user: &User, payload: CommandPayload) {
+            self.dispatch(user, payload, None);
+        }
+
+        fn send_request(&mut self, user: &User, payload: CommandPayload, id: RequestId) {
+            self.dispatch(user, payload, Some(id));
+        }
+
+        fn dispatch(
+            &mut self,
+            user: &User,
+            payload: CommandPayload,
+            request_id: Option<RequestId>,
+        ) {
+            let command = Command {
+                user: user.clone(),
+                payload,
+                request_id,
+            };
+            self.app
+                .work_on(command, &mut VecDeque::new())
+                .expect("the App refused a command");
+        }
+
+        fn say(&mut self, user: &User, text: &str, msg_id: Option<&str>) {
+            self.send(
+                user,
+                CommandPayload::RecordMessage {
+                    message: text.to_string(),
+                    attachment: None,
+                    msg_id: msg_id.map(str::to_string),
+                },
+            );
+        }
+
+        /// A new connection presenting the token of an earlier session.
+        fn resume(&mut self, user: &User, token: &str) {
+            let (channel, inbox) = unbounded();
+            self.inboxes.insert(user.id.clone(), inbox);
+            self.send(
+                user,
+                CommandPayload::ResumeUser {
+                    token: token.to_string(),
+                    channel,
+                },
+            );
+        }
+
+        /// Everything the user was sent since the last call.
+        fn events(&mut self, user: &User) -> Vec<Event> {
+            let inbox = self.inboxes.get_mut(&user.id).expect("not connected");
+            std::iter::from_fn(|| inbox.try_recv().ok()).collect()
+        }
+
+        /// The kinds of the rejections the user was sent since the last call.
+        fn rejections(&mut self, user: &User) -> Vec<Option<ErrorReason>> {
+            self.events(user)
+                .into_iter()
+                .filter_map(|event| match event {
+                    Event::Rejected { kind, .. } => Some(kind),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        fn room_of(&self, user: &User) -> Room {
+            self.app
+                .command_handler
+                .state
+                .get_occupied_room(user)
+                .unwrap_or_default()
+        }
+
+        /// Makes the owner a room and brings the rest into it.
+        fn gather(&mut self, room: &str, owner: &User, rest: &[&User]) {
+            self.send(owner, CommandPayload::CreateRoom(room.to_string()));
+            for user in rest {
+                self.send(
+                    user,
+                    CommandPayload::MoveUser {
+                        target_room: Room::from(room),
+                    },
+                );
+            }
+            for user in std::iter::once(owner).chain(rest.iter().copied()) {
+                self.events(user);
+            }
+        }
+    }
+
+    #[test]
+    fn owners_moderate_members_in_their_room() {
+        let mut server = TestServer::new();
+        let owner = server.connect("owner", Role::Member);
+        let kicked = server.connect("kicked", Role::Member);
+        let banned = server.connect("banned", Role::Member);
+        let muted = server.connect("muted", Role::Member);
+        server.gather("den", &owner, &[&kicked, &banned, &muted]);
+
+        server.send(&owner, CommandPayload::Kick("kicked".into()));
+        server.send(&owner, CommandPayload::Ban("banned".into()));
+        server.send(
+            &owner,
+            CommandPayload::Mute {
+                user: "muted".into(),
+                duration_secs: None,
+            },
+        );
+
+        assert!(server.rejections(&owner).is_empty());
+        assert!(server.room_of(&kicked).is_lobby());
+        assert!(server.room_of(&banned).is_lobby());
+        assert_eq!(server.room_of(&muted), Room::from("den"));
+    }
+
+    #[test]
+    fn a_user_promoted_mid_session_can_use_what_they_were_refused() {
+        let mut server = TestServer::new();
+        let admin = server.connect("admin", Role::Admin);
+        let ann = server.connect("ann", Role::Member);
+
+        server.send(&ann, CommandPayload::ListBans);
+        assert_eq!(
+            server.rejections(&ann),
+            vec![Some(ErrorReason::PermissionDenied)]
+        );
+
+        server.send(
+            &admin,
+            CommandPayload::Promote {
+                user: "ann".into(),
+                role: Role::Moderator,
+            },
+        );
+        assert!(server.rejections(&admin).is_empty());
+        server.events(&ann);
+
+        // The session still holds the old role, the App goes by its own record.
+        server.send(&ann, CommandPayload::ListBans);
+        assert!(!server
+            .rejections(&ann)
+            .contains(&Some(ErrorReason::PermissionDenied)));
+        server.send(&ann, CommandPayload::WhoIs("admin".into()));
+        assert_eq!(
+            server.rejections(&ann),
+            vec![Some(ErrorReason::PermissionDenied)]
+        );
+    }
+
+    #[test]
+    fn each_role_gets_exactly_the_commands_at_or_below_it() {
+        let mut server = TestServer::new();
+        let commands = [
+            (Role::Member, CommandPayload::WhoAmI),
+            (Role::Moderator, CommandPayload::ListBans),
+            (Role::Admin, CommandPayload::WhoIs("admin".into())),
+        ];
+        for role in [Role::Member, Role::Moderator, Role::Admin] {
+            let user = server.connect(&format!("{role:?}").to_lowercase(), role);
+            server.events(&user);
+            for (required, payload) in &commands {
+                server.send(&user, payload.clone());
+                let denied = server
+                    .rejections(&user)
+                    .contains(&Some(ErrorReason::PermissionDenied));
+                assert_eq!(
+                    denied,
+                    role < *required,
+                    "{role:?} using /{}",
+                    payload.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn owners_do_not_outrank_staff_or_other_rooms() {
+        let mut server = TestServer::new();
+        let owner = server.connect("owner", Role::Member);
+        let moderator = server.connect("moderator", Role::Moderator);
+        let admin = server.connect("admin", Role::Admin);
+        server.gather("den", &owner, &[&moderator, &admin]);
+
+        for target in ["moderator", "admin"] {
+            server.send(&owner, CommandPayload::Kick(target.into()));
+            server.send(&owner, CommandPayload::Ban(target.into()));
+            server.send(
+                &owner,
+                CommandPayload::Mute {
+                    user: target.into(),
+                    duration_secs: None,
+                },
+            );
+        }
+        assert_eq!(
+            server.rejections(&owner),
+            vec![Some(ErrorReason::PermissionDenied); 6]
+        );
+
+        // Owning the den counts for nothing in someone else's room.
+        let member = server.connect("member", Role::Member);
+        let host = server.connect("host", Role::Member);
+        server.gather("hall", &host, &[&owner, &member]);
+        server.send(&owner, CommandPayload::Kick("member".into()));
+        assert_eq!(
+            server.rejections(&owner),
+            vec![Some(ErrorReason::PermissionDenied)]
+        );
+        assert_eq!(server.room_of(&member), Room::from("hall"));
+    }
+
+    #[test]
+    fn moderators_cannot_touch_admins_or_their_peers() {
+        let mut server = TestServer::new();
+        let host = server.connect("host", Role::Member);
+        let moderator = server.connect("moderator", Role::Moderator);
+        let peer = server.connect("peer", Role::Moderator);
+        let admin = server.connect("admin", Role::Admin);
+        let member = server.connect("member", Role::Member);
+        server.gather("den", &host, &[&moderator, &peer, &admin, &member]);
+
+        for target in ["peer", "admin", "moderator"] {
+            server.send(&moderator, CommandPayload::Kick(target.into()));
+            server.send(
+                &moderator,
+                CommandPayload::Mute {
+                    user: target.into(),
+                    duration_secs: None,
+                },
+            );
+        }
+        assert_eq!(
+            server.rejections(&moderator),
+            vec![Some(ErrorReason::PermissionDenied); 6]
+        );
+
+        // The owner is only a moderator in their own room, and moderators outrank members.
+        server.send(&moderator, CommandPayload::Kick("host".into()));
+        assert_eq!(
+            server.rejections(&moderator),
+            vec![Some(ErrorReason::PermissionDenied)]
+        );
+        server.send(&admin, CommandPayload::Kick("moderator".into()));
+        server.send(&admin, CommandPayload::Ban("member".into()));
+        assert!(server.rejections(&admin).is_empty());
+        assert_eq!(server.room_of(&host), Room::from("den"));
+        assert!(server.room_of(&moderator).is_lobby());
+        assert!(server.room_of(&member).is_lobby());
+    }
+
+    #[test]
+    fn commands_without_a_handler_are_refused_not_fatal() {
+        let mut server = TestServer::new();
+        server.app.command_handler.plugins.remove("roll");
+        let user = server.connect("user", Role::Member);
+
+        server.send(&user, CommandPayload::Roll { spec: "1d6".into() });
+
+        assert_eq!(
+            server.rejections(&user),
+            vec![Some(ErrorReason::UnknownCommand)]
+        );
+    }
+
+    #[test]
+    fn bans_survive_a_rename_and_catch_the_name_in_any_case() {
+        let mut server = TestServer::new();
+        let owner = server.connect("owner", Role::Member);
+        let banned = server.connect("Bob", Role::Member);
+        let other = server.connect("other", Role::Member);
+        server.gather("den", &owner, &[&banned, &other]);
+        let den = Room::from("den");
+
+        server.send(&owner, CommandPayload::Ban("Bob".into()));
+        server.send(&banned, CommandPayload::Rename("carl".into()));
+        server.send(
+            &banned,
+            CommandPayload::MoveUser {
+                target_room: den.clone(),
+            },
+        );
+        assert!(server.room_of(&banned).is_lobby());
+
+        server.send(&other, CommandPayload::Rename("BOB".into()));
+        assert_eq!(server.rejections(&other), vec![None]);
+        assert_eq!(server.room_of(&other), den);
+
+        server.send(&owner, CommandPayload::Unban("bob".into()));
+        server.send(
+            &banned,
+            CommandPayload::MoveUser {
+                target_room: den.clone(),
+            },
+        );
+        assert_eq!(server.room_of(&banned), den);
+    }
+
+    #[test]
+    fn empty_rooms_are_only_collected_when_nothing_was_set_in_them() {
+        let mut server = TestServer::new();
+        let moderator = server.connect("moderator", Role::Moderator);
+        let user = server.connect("user", Role::Member);
+        let to = |name: &str| CommandPayload::MoveUser {
+            target_room: Room::from(name),
+        };
+
+        server.send(&user, to("passing"));
+        server.send(&user, to(LOBBY_NAME));
+        assert!(!server
+            .app
+            .command_handler
+            .state
+            .room_exists(&Room::from("passing")));
+
+        server.send(&moderator, to("moderated"));
+        server.send(&user, to("moderated"));
+        server.send(&moderator, CommandPayload::Ban("troll".into()));
+        server.send(&moderator, CommandPayload::SetTopic("No trolls".into()));
+        server.send(&user, to(LOBBY_NAME));
+        server.send(&moderator, to(LOBBY_NAME));
+
+        let state = &server.app.command_handler.state;
+        let moderated = Room::from("moderated");
+        assert!(state.room_exists(&moderated));
+        let settings = &state.settings[&moderated];
+        assert!(settings.banned.contains_key("troll"));
+        assert_eq!(settings.topic.as_deref(), Some("No trolls"));
+    }
+
+    #[test]
+    fn removing_a_user_says_what_it_did_to_their_room() {
+        let mut server = TestServer::new();
+        let user = server.connect("user", Role::Member);
+        server.send(
+            &user,
+            CommandPayload::MoveUser {
+                target_room: Room::from("passing"),
+            },
+        );
+        let state = &mut server.app.command_handler.state;
+
+        match state.remove_user_from_room(&user).unwrap() {
+            RemovalOutcome::Removed {
+                room,
+                emptied,
+                collected,
+            } => {
+                assert_eq!(room, Room::from("passing"));
+                assert!(emptied);
+                assert!(collected);
+            }
+            outcome => panic!("expected the user to be removed, got {outcome:?}"),
+        }
+        assert!(!state.room_exists(&Room::from("passing")));
+
+        // Out of every room now, so there is no room to take them out of.
+        assert!(matches!(
+            state.remove_user_from_room(&user).unwrap(),
+            RemovalOutcome::NotFound
+        ));
+        assert!(state.room_exists(&Room::from(LOBBY_NAME)));
+    }
+
+    #[test]
+    fn nobody_can_take_the_server_name_or_message_themselves() {
+        let mut server = TestServer::new();
+        let user = server.connect("user", Role::Member);
+
+        server.send(&user, CommandPayload::Rename(" server ".into()));
+        server.send(
+            &user,
+            CommandPayload::DirectMessage {
+                to: "USER".into(),
+                content: "hi".into(),
+            },
+        );
+
+        assert_eq!(server.rejections(&user), vec![None, None]);
+        assert_eq!(
+            server
+                .app
+                .command_handler
+                .state
+                .find_user(&user)
+                .unwrap()
+                .name,
+            "user"
+        );
+    }
+
+    #[test]
+    fn a_session_that_closed_mid_command_is_dropped_and_the_app_carries_on() {
+        let mut server = TestServer::new();
+        let alive = server.connect("ann", Role::Member);
+        let gone = server.connect("bob", Role::Member);
+        server.events(&alive);
+
+        // Dropping the receiver is what a session that went away leaves behind.
+        server.inboxes.remove(&gone.id);
+        server.send(
+            &alive,
+            CommandPayload::RecordMessage {
+                message: "anyone there?".into(),
+                attachment: None,
+                msg_id: None,
+            },
+        );
+
+        assert!(server.app.command_handler.state.find_user(&gone).is_none());
+        assert!(!server.app.event_bus.is_subscribed(&gone));
+        assert!(server
+            .events(&alive)
+            .iter()
+            .any(|event| matches!(event, Event::UserLeft { .. })));
+
+        server.send(&alive, CommandPayload::Uptime);
+        assert!(server
+            .events(&alive)
+            .iter()
+            .any(|event| matches!(event, Event::Reply { .. })));
+    }
+
+    #[test]
+    fn a_command_from_a_user_who_already_left_is_dropped() {
+        let mut server = TestServer::new();
+        let alive = server.connect("ann", Role::Member);
+        let gone = server.connect("bob", Role::Member);
+        server.send(&gone, CommandPayload::DropUser);
+        server.events(&alive);
+        server.events(&gone);
+
+        server.send(
+            &gone,
+            CommandPayload::RecordMessage {
+                message: "too late".into(),
+                attachment: None,
+                msg_id: None,
+            },
+        );
+        server.send(&gone, CommandPayload::CreateRoom("ghost".into()));
+
+        assert!(server.app.command_handler.state.find_user(&gone).is_none());
+        assert!(server.events(&alive).is_empty());
+        assert!(server.events(&gone).is_empty());
+        let state = &server.app.command_handler.state;
+        assert!(state.room_chat_logs(&Room::lobby()).is_empty());
+        assert!(!state.chat_logs.contains_key(&Room::from("ghost")));
+    }
+
+    #[test]
+    fn an_export_hands_over_a_snapshot_and_chat_after_it_is_not_held_up() {
+        let mut server = TestServer::new();
+        let exporter = server.connect("ann", Role::Member);
+        let listener = server.connect("bob", Role::Member);
+        for n in 0..5_000 {
+            let msg = MessageLog::from_user(&listener, format!("message {n} {}", "x".repeat(200)));
+            server
+                .app
+                .command_handler
+                .state
+                .record_chat_message(&listener, msg);
+        }
+        server.events(&exporter);
+        server.events(&listener);
+
+        let started = std::time::Instant::now();
+        server.send(
+            &exporter,
+            CommandPayload::Export {
+                format: ExportFormat::JsonLines,
+            },
+        );
+        server.send(
+            &exporter,
+            CommandPayload::RecordMessage {
+                message: "still here".into(),
+                attachment: None,
+                msg_id: None,
+            },
+        );
+        let elapsed = started.elapsed();
+
+        // Rendering is left to the exporter's session, the App only clones the logs.
+        assert!(server
+            .events(&exporter)
+            .iter()
+            .any(|event| matches!(event, Event::Export { logs, .. } if !logs.is_empty())));
+        assert!(server
+            .events(&listener)
+            .iter()
+            .any(|event| matches!(event, Event::Frame { .. })));
+        assert!(
+            elapsed < std::time::Duration::from_millis(500),
+            "the App took {elapsed:?}"
+        );
+    }
+
+    #[test]
+    fn only_the_direct_reply_carries_the_request_id() {
+        let mut server = TestServer::new();
+        let owner = server.connect("ann", Role::Moderator);
+        let other = server.connect("bob", Role::Member);
+        server.events(&owner);
+
+        server.send_request(&owner, CommandPayload::Who, 2);
+        server.send_request(&other, CommandPayload::Who, 7);
+        server.send_request(&owner, CommandPayload::SetTopic("retro".into()), 3);
+
+        let owner_events = server.events(&owner);
+        let replies: Vec<Option<RequestId>> = owner_events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Reply { request_id, .. } => Some(*request_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(replies, vec![Some(2)]);
+        // The topic change reaches the owner as a room notification, which isn't tagged.
+        let topic = owner_events
+            .into_iter()
+            .find_map(|event| match event {
+                Event::Frame { frame } => Some(frame),
+                _ => None,
+            })
+            .unwrap();
+        let read = SocketSendAdaptor::read_server_msg(&owner.shared_secret, topic).unwrap();
+        let ServerMsgBody::ChatRecv { chat_msg, .. } = read.body else {
+            panic!("the topic notice arrives as ChatRecv");
+        };
+        assert!(
+            !chat_msg.content.starts_with("request"),
+            "{}",
+            chat_msg.content
+        );
+
+        assert!(server.events(&other).iter().any(|event| matches!(
+            event,
+            Event::Reply {
+                request_id: Some(7),
+                ..
+            }
+        )));
+    }
+
+    /// Every registered command as a moderator sees it. Adding a command to the registry
+    /// means adding its line here.
+    const CAPABILITIES_SNAPSHOT: &[&str] = &[
+        r#"{"name":"mv","args":"<room_name>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"create","args":"<room_name>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"delete-room","args":"<room_name>","role":"Admin","enabled":true,"available":false}"#,
+        r#"{"name":"private","args":"[on|off]","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"lock","args":"","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"unlock","args":"","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"invite","args":"<user>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"uninvite","args":"<user>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"leave","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"history","args":"[count] [before]","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"search","args":"<text>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"export","args":"[text|json]","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"clear","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"who","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"crm","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"stats","args":"[server]","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"whoami","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"whois","args":"<user>","role":"Admin","enabled":true,"available":false}"#,
+        r#"{"name":"audit","args":"[count]","role":"Admin","enabled":true,"available":false}"#,
+        r#"{"name":"seen","args":"<user>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"uptime","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"motd","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"setmotd","args":"[message]","role":"Admin","enabled":true,"available":false}"#,
+        r#"{"name":"announce","args":"<message>","role":"Admin","enabled":true,"available":false}"#,
+        r#"{"name":"shutdown","args":"[seconds|cancel]","role":"Admin","enabled":true,"available":false}"#,
+        r#"{"name":"rooms","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"me","args":"<action>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"roll","args":"<NdM[+K]>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"msg","args":"<user> <text>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"away","args":"[message]","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"back","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"ignore","args":"[user]","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"unignore","args":"<user>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"promote","args":"<user> [moderator|admin]","role":"Admin","enabled":true,"available":false}"#,
+        r#"{"name":"demote","args":"<user> [member|moderator]","role":"Admin","enabled":true,"available":false}"#,
+        r#"{"name":"block","args":"<user>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"unblock","args":"<user>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"blocks","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"kick","args":"<user>","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"ban","args":"<user>","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"unban","args":"<user>","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"bans","args":"","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"mute","args":"<user> [seconds]","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"unmute","args":"<user>","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"silence","args":"<user> [seconds]","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"shadowban","args":"<user>","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"pardon","args":"<user>","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"slowmode","args":"<seconds>","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"purge","args":"<count>","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"pin","args":"<message_id>","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"unpin","args":"<message_id>","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"topic","args":"[topic]","role":"Moderator","enabled":true,"available":true}"#,
+        r#"{"name":"nick","args":"<new_name>","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"ping","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"capabilities","args":"","role":"Member","enabled":true,"available":true}"#,
+        r#"{"name":"help","args":"[command]","role":"Member","enabled":true,"available":true}"#,
+    ];
+
+    fn capabilities(server: &mut TestServer, user: &User) -> String {
+        server.send(user, CommandPayload::Capabilities);
+        server
+            .events(user)
+            .into_iter()
+            .find_map(|event| match event {
+                Event::Reply { notice, .. } => Some(notice.contents),
+                _ => None,
+            })
+            .expect("no capabilities reply")
+    }
+
+    #[test]
+    fn capabilities_list_every_registered_command() {
+        let mut server = TestServer::new();
+        let moderator = server.connect("ann", Role::Moderator);
+
+        assert_eq!(
+            capabilities(&mut server, &moderator),
+            CAPABILITIES_SNAPSHOT.join("\n")
+        );
+        for spec in CommandRegistry::all() {
+            assert!(
+                !spec.description.is_empty(),
+                "/{} has no description",
+                spec.name
+            );
+        }
+    }
+
+    #[test]
+    fn capabilities_follow_the_users_role_and_the_loaded_plugins() {
+        let mut server = TestServer::new();
+        let member = server.connect("bob", Role::Member);
+        server.app.command_handler.plugins.remove("roll");
+
+        let lines = capabilities(&mut server, &member);
+        let line = |name: &str| {
+            lines
+                .lines()
+                .find(|line| line.starts_with(&format!(r#"{{"name":"{name}""#)))
+                .unwrap()
+                .to_string()
+        };
+        assert!(line("kick").ends_with(r#""enabled":true,"available":false}"#));
+        assert!(line("who").ends_with(r#""enabled":true,"available":true}"#));
+        assert!(line("roll").ends_with(r#""enabled":false,"available":false}"#));
+    }
+
+    #[test]
+    fn admins_see_the_frame_sizes_in_server_stats() {
+        let mut server = TestServer::new();
+        let admin = server.connect("ann", Role::Admin);
+        SocketSendAdaptor::on_login_failed().unwrap();
+
+        server.send(&admin, CommandPayload::ServerStats);
+
+        let shown = server.events(&admin).into_iter().any(|event| match event {
+            Event::Reply { notice, .. } => notice
+                .contents
+                .contains("Frames sent since startup:\nEmpty: serialized "),
+            _ => false,
+        });
+        assert!(shown);
+    }
+
+    /// What a chat line or notice the user was sent reads, however it was delivered.
+    fn chat_content(user: &User, event: &Event) -> Option<String> {
+        match event {
+            Event::MsgReceived { msg } => Some(msg.contents.clone()),
+            Event::Frame { frame } => {
+                let read =
+                    SocketSendAdaptor::read_server_msg(&user.shared_secret, frame.clone()).ok()?;
+                match read.body {
+                    ServerMsgBody::ChatRecv { chat_msg, .. } => Some(chat_msg.content),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The presence changes the user's room heard about since the last call.
+    fn presence_heard(server: &mut TestServer, listener: &User) -> Vec<PresenceStatus> {
+        server
+            .events(listener)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::PresenceChanged { status, .. } => Some(status),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Makes it look as if the room last heard about the user's presence a while ago.
+    fn end_presence_window(server: &mut TestServer, user: &User) {
+        let (heard_at, _) = server
+            .app
+            .command_handler
+            .presence_broadcasts
+            .get_mut(&user.id)
+            .expect("the room heard nothing yet");
+        *heard_at -= Duration::seconds(PRESENCE_DEBOUNCE_SECS + 1);
+    }
+
+    #[test]
+    fn debounced_presence_changes_reach_the_room_once_the_window_is_over() {
+        let mut server = TestServer::new();
+        let ann = server.connect("ann", Role::Member);
+        let bob = server.connect("bob", Role::Member);
+        server.events(&ann);
+
+        server.send(&ann, CommandPayload::Away(None));
+        assert_eq!(
+            presence_heard(&mut server, &bob),
+            vec![PresenceStatus::Away(None)]
+        );
+        server.send(&ann, CommandPayload::Back);
+        server.send(&ann, CommandPayload::Away(Some("lunch".into())));
+        assert!(presence_heard(&mut server, &bob).is_empty());
+        assert!(server.app.command_handler.presence_deadline().is_some());
+
+        // Still in the window, nothing is due yet.
+        server.app.settle_presence(&mut VecDeque::new());
+        assert!(presence_heard(&mut server, &bob).is_empty());
+
+        end_presence_window(&mut server, &ann);
+        server.app.settle_presence(&mut VecDeque::new());
+        assert_eq!(
+            presence_heard(&mut server, &bob),
+            vec![PresenceStatus::Away(Some("lunch".into()))]
+        );
+        server.app.settle_presence(&mut VecDeque::new());
+        assert!(presence_heard(&mut server, &bob).is_empty());
+        assert!(server.app.command_handler.presence_deadline().is_none());
+
+        // Undone inside the window, the room never hears of it.
+        server.send(&ann, CommandPayload::Back);
+        server.send(&ann, CommandPayload::Away(Some("lunch".into())));
+        assert!(server.app.command_handler.presence_deadline().is_none());
+        end_presence_window(&mut server, &ann);
+        server.app.settle_presence(&mut VecDeque::new());
+        assert!(presence_heard(&mut server, &bob).is_empty());
+        assert!(server
+            .events(&ann)
+            .iter()
+            .any(|event| matches!(event, Event::Reply { .. })));
+    }
+
+    #[test]
+    fn the_room_hears_a_user_is_back_before_their_message() {
+        let mut server = TestServer::new();
+        let ann = server.connect("ann", Role::Member);
+        let bob = server.connect("bob", Role::Member);
+
+        server.send(&ann, CommandPayload::Away(None));
+        server.events(&bob);
+        // Well inside the debounce window.
+        server.send(
+            &ann,
+            CommandPayload::RecordMessage {
+                message: "hi".into(),
+                attachment: None,
+                msg_id: None,
+            },
+        );
+
+        let seen: Vec<&str> = server
+            .events(&bob)
+            .iter()
+            .filter_map(|event| match event {
+                Event::PresenceChanged {
+                    status: PresenceStatus::Online,
+                    ..
+                } => Some("back"),
+                event => (chat_content(&bob, event).as_deref() == Some("hi")).then_some("chat"),
+            })
+            .collect();
+        assert_eq!(seen, vec!["back", "chat"]);
+    }
+
+    #[test]
+    fn a_user_warned_about_being_idle_goes_away() {
+        let mut server = TestServer::new();
+        let ann = server.connect("ann", Role::Member);
+        let bob = server.connect("bob", Role::Member);
+
+        server.send(&ann, CommandPayload::WentIdle);
+        let idle = PresenceStatus::Away(Some(IDLE_AWAY_MESSAGE.to_string()));
+        assert_eq!(presence_heard(&mut server, &bob), vec![idle.clone()]);
+        assert_eq!(
+            server
+                .app
+                .command_handler
+                .state
+                .find_user(&ann)
+                .unwrap()
+                .status,
+            idle
+        );
+
+        // Someone already away keeps their own message, and isn't told off for it.
+        server.send(&ann, CommandPayload::Back);
+        end_presence_window(&mut server, &ann);
+        server.send(&ann, CommandPayload::Away(Some("lunch".into())));
+        server.events(&ann);
+        server.events(&bob);
+        server.send(&ann, CommandPayload::WentIdle);
+        assert!(presence_heard(&mut server, &bob).is_empty());
+        assert!(server.rejections(&ann).is_empty());
+    }
+
+    /// The chat lines and notices the user was sent since the last call, as they read.
+    fn chat_seen(server: &mut TestServer, user: &User) -> Vec<String> {
+        server
+            .events(user)
+            .iter()
+            .filter_map(|event| chat_content(user, event))
+            .collect()
+    }
+
+    #[test]
+    fn a_shadow_banned_user_seems_to_chat_as_usual_but_reaches_nobody() {
+        let mut server = TestServer::new();
+        let moderator = server.connect("mod", Role::Moderator);
+        let ann = server.connect("ann", Role::Member);
+        let bob = server.connect("bob", Role::Member);
+        server.send(&moderator, CommandPayload::ShadowBan("ann".into()));
+        for user in [&moderator, &ann, &bob] {
+            server.events(user);
+        }
+
+        server.say(&ann, "anyone there?", Some("c1"));
+        let events = server.events(&ann);
+        let echoed = events
+            .iter()
+            .position(|event| chat_content(&ann, event).as_deref() == Some("anyone there?"))
+            .expect("the sender sees their own message");
+        let acked = events
+            .iter()
+            .position(
+                |event| matches!(event, Event::SendAck { msg_id: Some(id), .. } if id == "c1"),
+            )
+            .expect("the sender is acked");
+        assert!(echoed < acked);
+        assert!(chat_seen(&mut server, &bob).is_empty());
+        assert!(chat_seen(&mut server, &moderator).is_empty());
+
+        server.send(
+            &ann,
+            CommandPayload::DirectMessage {
+                to: "bob".into(),
+                content: "psst".into(),
+            },
+        );
+        assert!(server
+            .events(&ann)
+            .iter()
+            .any(|event| matches!(event, Event::Reply { notice, .. } if notice.contents == "Message sent to bob")));
+        assert!(server.events(&bob).is_empty());
+    }
+
+    #[test]
+    fn silenced_users_cannot_message_anyone_directly() {
+        let mut server = TestServer::new();
+        let moderator = server.connect("mod", Role::Moderator);
+        let ann = server.connect("ann", Role::Member);
+        let bob = server.connect("bob", Role::Member);
+        server.send(
+            &moderator,
+            CommandPayload::Silence {
+                user: "ann".into(),
+                duration_secs: None,
+            },
+        );
+        server.events(&ann);
+        server.events(&bob);
+
+        server.send(
+            &ann,
+            CommandPayload::DirectMessage {
+                to: "bob".into(),
+                content: "psst".into(),
+            },
+        );
+        assert_eq!(server.rejections(&ann), vec![Some(ErrorReason::Muted)]);
+        assert!(server.events(&bob).is_empty());
+    }
+
+    #[test]
+    fn users_are_found_by_name_whatever_its_case() {
+        let mut server = TestServer::new();
+        let ann = server.connect("ann", Role::Member);
+        let bob = server.connect("Bob", Role::Member);
+        server.events(&ann);
+        server.events(&bob);
+
+        server.send(
+            &ann,
+            CommandPayload::DirectMessage {
+                to: "bOB".into(),
+                content: "psst".into(),
+            },
+        );
+        assert!(server.rejections(&ann).is_empty());
+        assert!(!server.events(&bob).is_empty());
+    }
+
+    #[test]
+    fn mutes_and_shadow_bans_survive_a_room_move() {
+        let mut server = TestServer::new();
+        let owner = server.connect("owner", Role::Moderator);
+        let muted = server.connect("muted", Role::Member);
+        let hidden = server.connect("hidden", Role::Member);
+        let bob = server.connect("bob", Role::Member);
+        server.gather("den", &owner, &[&muted, &hidden, &bob]);
+        server.send(
+            &owner,
+            CommandPayload::Mute {
+                user: "muted".into(),
+                duration_secs: None,
+            },
+        );
+        server.send(&owner, CommandPayload::ShadowBan("hidden".into()));
+
+        for user in [&muted, &hidden, &bob] {
+            server.send(user, CommandPayload::Leave);
+            server.send(
+                user,
+                CommandPayload::MoveUser {
+                    target_room: Room::from("den"),
+                },
+            );
+        }
+        for user in [&owner, &muted, &hidden, &bob] {
+            server.events(user);
+        }
+        assert_eq!(server.room_of(&muted), Room::from("den"));
+
+        server.say(&muted, "am I back?", Some("m1"));
+        assert!(server.events(&muted).iter().any(|event| matches!(
+            event,
+            Event::SendNack { msg_id: Some(id), kind: ErrorReason::Muted, .. } if id == "m1"
+        )));
+        server.say(&hidden, "and me?", None);
+        assert!(chat_seen(&mut server, &hidden).contains(&"and me?".to_string()));
+        assert!(chat_seen(&mut server, &bob).is_empty());
+    }
+
+    /// Where the sender's own copy of the message and its ack came in, in that order.
+    fn echo_and_ack(sender: &User, events: &[Event], text: &str) -> (usize, usize) {
+        let echo = events
+            .iter()
+            .position(|event| chat_content(sender, event).as_deref() == Some(text))
+            .expect("the sender was sent their message");
+        let ack = events
+            .iter()
+            .position(|event| matches!(event, Event::SendAck { .. }))
+            .expect("the sender was acked");
+        (echo, ack)
+    }
+
+    #[test]
+    fn chat_is_acked_with_the_clients_id_after_the_senders_own_copy() {
+        let mut server = TestServer::new();
+        let ann = server.connect("ann", Role::Member);
+        let bob = server.connect("bob", Role::Member);
+        server.events(&ann);
+
+        server.say(&ann, "hello", Some("c-17"));
+        let events = server.events(&ann);
+        let (echo, ack) = echo_and_ack(&ann, &events, "hello");
+        assert!(echo < ack);
+        let Event::SendAck { msg_id, id, .. } = &events[ack] else {
+            unreachable!();
+        };
+        assert_eq!(msg_id.as_deref(), Some("c-17"));
+        let logged = &server.app.command_handler.state.chat_logs[&Room::default()];
+        assert_eq!(logged.back().unwrap().id, *id);
+        assert_eq!(chat_seen(&mut server, &bob), vec!["hello".to_string()]);
+
+        server.say(&ann, "no id", None);
+        let events = server.events(&ann);
+        let (_, ack) = echo_and_ack(&ann, &events, "no id");
+        assert!(matches!(events[ack], Event::SendAck { msg_id: None, .. }));
+    }
+
+    #[test]
+    fn chat_is_acked_when_the_sender_is_alone_in_the_room() {
+        let mut server = TestServer::new();
+        let owner = server.connect("owner", Role::Member);
+        server.gather("solo", &owner, &[]);
+
+        server.say(&owner, "just me", Some("c1"));
+        let events = server.events(&owner);
+        let (echo, ack) = echo_and_ack(&owner, &events, "just me");
+        assert!(echo < ack);
+        assert!(matches!(&events[ack], Event::SendAck { msg_id: Some(id), .. } if id == "c1"));
+    }
+
+    #[test]
+    fn chat_from_a_muted_user_is_nacked_with_the_clients_id() {
+        let mut server = TestServer::new();
+        let owner = server.connect("owner", Role::Member);
+        let muted = server.connect("muted", Role::Member);
+        let bob = server.connect("bob", Role::Member);
+        server.gather("den", &owner, &[&muted, &bob]);
+        server.send(
+            &owner,
+            CommandPayload::Mute {
+                user: "muted".into(),
+                duration_secs: None,
+            },
+        );
+        server.events(&muted);
+        server.events(&bob);
+
+        server.say(&muted, "let me talk", Some("c9"));
+        let events = server.events(&muted);
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::SendAck { .. })));
+        let nacks: Vec<(Option<String>, ErrorReason)> = events
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::SendNack { msg_id, kind, .. } => Some((msg_id, kind)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(nacks, vec![(Some("c9".to_string()), ErrorReason::Muted)]);
+        assert!(chat_seen(&mut server, &bob).is_empty());
+    }
+
+    /// Connects a user holding the given session token.
+    fn connect_with_token(server: &mut TestServer, name: &str, token: &str) -> User {
+        let key = SessionKey::from_bytes([server.inboxes.len() as u8 + 1; 32]);
+        let mut user = User::new(format!("id-{name}"), name.to_string(), key);
+        user.session_token = token.to_string();
+        server.register(user)
+    }
+
+    fn resume_reply(events: &[Event]) -> bool {
+        events.iter().any(|event| {
+            matches!(event, Event::Reply { notice, .. }
+                if notice.contents.contains("could not be resumed"))
+        })
+    }
+
+    #[test]
+    fn a_reconnect_inside_the_grace_window_picks_up_where_it_left_off() {
+        let mut server = TestServer::new();
+        let bob = server.connect("bob", Role::Member);
+        let ann = connect_with_token(&mut server, "ann", "tok-ann");
+        server.gather("den", &bob, &[&ann]);
+        server.say(&bob, "before", None);
+        server.send(&ann, CommandPayload::DetachUser);
+        server.say(&bob, "while you were out", None);
+        assert_eq!(server.room_of(&ann), Room::from("den"));
+
+        let mut again = User::new(
+            "id-ann-again".into(),
+            "ann".into(),
+            SessionKey::from_bytes([9; 32]),
+        );
+        again.session_token = "tok-again".into();
+        server.resume(&again, "tok-ann");
+
+        let events = server.events(&again);
+        assert!(!resume_reply(&events));
+        let Some(Event::SessionResumed {
+            user,
+            room,
+            snapshot,
+            missed,
+        }) = events
+            .into_iter()
+            .find(|event| matches!(event, Event::SessionResumed { .. }))
+        else {
+            panic!("the session was not resumed");
+        };
+        assert_eq!(user.id, ann.id);
+        assert_eq!(user.session_token, "tok-again");
+        assert_eq!(room, Room::from("den"));
+        let contents = |logs: &[MessageLog]| {
+            logs.iter()
+                .map(|msg| msg.contents.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(contents(&missed), vec!["while you were out".to_string()]);
+        assert!(contents(&snapshot.msg_log).contains(&"before".to_string()));
+        assert!(server.app.command_handler.detached.is_empty());
+    }
+
+    #[test]
+    fn a_token_is_refused_once_the_grace_window_is_over() {
+        let mut server = TestServer::new();
+        let bob = server.connect("bob", Role::Member);
+        let ann = connect_with_token(&mut server, "ann", "tok-ann");
+        server.send(&ann, CommandPayload::DetachUser);
+        let (_, detached_at) = server
+            .app
+            .command_handler
+            .detached
+            .get_mut("tok-ann")
+            .unwrap();
+        *detached_at -= Duration::seconds(DEFAULT_RESUME_GRACE_SECS as i64 + 1);
+
+        let later = User::new(
+            "id-ann-later".into(),
+            "ann".into(),
+            SessionKey::from_bytes([9; 32]),
+        );
+        server.resume(&later, "tok-ann");
+
+        let events = server.events(&later);
+        assert!(resume_reply(&events));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::SessionResumed { .. })));
+        let state = &server.app.command_handler.state;
+        assert!(state.find_user(&ann).is_none());
+        assert!(state.find_user(&later).is_some());
+        assert!(state.find_user(&bob).is_some());
+    }
+
+    #[test]
+    fn a_token_whose_session_is_still_connected_is_not_taken_over() {
+        let mut server = TestServer::new();
+        let ann = connect_with_token(&mut server, "ann", "tok-ann");
+
+        let intruder = User::new(
+            "id-intruder".into(),
+            "intruder".into(),
+            SessionKey::from_bytes([9; 32]),
+        );
+        server.resume(&intruder, "tok-ann");
+
+        assert!(resume_reply(&server.events(&intruder)));
+        let state = &server.app.command_handler.state;
+        let live = state
+            .find_user(&ann)
+            .expect("the live session is left alone");
+        assert!(live.has_session_token(Some("tok-ann")));
+        assert_eq!(live.shared_secret, ann.shared_secret);
+        assert!(state.find_user(&intruder).is_some());
+    }
+
+    #[test]
+    fn staff_kick_someone_in_the_lobby_off_the_server() {
+        let mut server = TestServer::new();
+        let moderator = server.connect("moderator", Role::Moderator);
+        let member = server.connect("member", Role::Member);
+        let bystander = server.connect("bystander", Role::Member);
+        server.events(&member);
+
+        server.send(&member, CommandPayload::Kick("bystander".into()));
+        assert_eq!(
+            server.rejections(&member),
+            vec![Some(ErrorReason::PermissionDenied)]
+        );
+        assert!(!server
+            .events(&bystander)
+            .iter()
+            .any(|event| matches!(event, Event::Kicked)));
+
+        server.send(&moderator, CommandPayload::Kick("member".into()));
+        assert!(server.rejections(&moderator).is_empty());
+        assert!(server
+            .events(&member)
+            .iter()
+            .any(|event| matches!(event, Event::Kicked)));
+        assert!(chat_seen(&mut server, &bystander)
+            .iter()
+            .any(|notice| notice.ends_with("member was kicked off the server by moderator")));
+    }
+
+    /// Counts the payloads it is handed and answers each one.
+    struct FakePlugin {
+        handled: std::sync::Arc<std::sync::Mutex<Vec<&'static str>>>,
+    }
+
+    impl CommandPlugin for FakePlugin {
+        fn handle(&mut self, ctx: &mut CommandContext, payload: CommandPayload) -> Result<()> {
+            self.handled.lock().unwrap().push(payload.name());
+            ctx.reply("handled by the plugin");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn commands_are_dispatched_to_the_plugin_registered_under_their_name() {
+        let mut server = TestServer::new();
+        let handled = std::sync::Arc::default();
+        server.app.command_handler.plugins.insert(
+            "stats",
+            Box::new(FakePlugin {
+                handled: std::sync::Arc::clone(&handled),
+            }),
+        );
+        let ann = server.connect("ann", Role::Member);
+        server.events(&ann);
+
+        server.send(&ann, CommandPayload::RoomStats);
+        let replies: Vec<String> = server
+            .events(&ann)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Reply { notice, .. } => Some(notice.contents),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(replies, vec!["handled by the plugin".to_string()]);
+
+        // Session bookkeeping has a name of its own and never reaches the /stats plugin.
+        server.send(&ann, CommandPayload::ReportStats(Default::default()));
+        server.send(&ann, CommandPayload::Uptime);
+        assert_eq!(*handled.lock().unwrap(), vec!["stats"]);
+        assert!(!server
+            .events(&ann)
+            .iter()
+            .any(|event| matches!(event, Event::Reply { notice, .. }
+                if notice.contents == "handled by the plugin")));
+    }
+
+    #[test]
+    fn a_join_notice_is_sent_live_and_listed_in_the_next_room_data() {
+        let mut server = TestServer::new();
+        let bob = server.connect("bob", Role::Member);
+        let ann = server.connect("ann", Role::Member);
+        let cat = server.connect("cat", Role::Member);
+        server.gather("den", &bob, &[]);
+        server.events(&ann);
+
+        server.send(
+            &ann,
+            CommandPayload::MoveUser {
+                target_room: Room::from("den"),
+            },
+        );
+        let live = chat_seen(&mut server, &bob);
+        assert_eq!(live, vec!["ann joined den".to_string()]);
+        // The joiner has it in their own RoomData instead.
+        assert!(chat_seen(&mut server, &ann).is_empty());
+
+        server.send(
+            &cat,
+            CommandPayload::MoveUser {
+                target_room: Room::from("den"),
+            },
+        );
+        let snapshot = server
+            .events(&bob)
+            .into_iter()
+            .find_map(|event| match event {
+                Event::UserJoined { snapshot, .. } => Some(snapshot),
+                _ => None,
+            })
+            .unwrap();
+        let listed: Vec<&str> = snapshot
+            .notifications
+            .iter()
+            .map(|notice| notice.contents.as_str())
+            .filter(|contents| contents.starts_with("ann "))
+            .collect();
+        assert_eq!(listed, vec![live[0].as_str()]);
+    }
+
+    #[test]
+    fn uptime_is_reported_from_a_fixed_start() {
+        let mut server = TestServer::new();
+        let started_at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        server.app.command_handler.server_info.started_at = started_at;
+        let user = server.connect("ann", Role::Member);
+
+        let now = started_at + Duration::seconds(93_784);
+        let cast = server.app.command_handler.report_uptime(&user, now);
+
+        assert_eq!(cast.subscribers, vec![user]);
+        let Event::Reply { notice, .. } = cast.event else {
+            panic!("uptime is a reply");
+        };
+        assert!(
+            notice.contents.starts_with(
+                "Up for 1d 02:03:04 as of 2023-11-16 00:16:24 UTC, 1 user(s) in 1 room(s)"
+            ),
+            "{}",
+            notice.contents
+        );
+    }
+
+    #[test]
+    fn shutting_down_saves_each_rooms_history() {
+        let dir = std::env::temp_dir().join(format!("marain-transcripts-{}", Uuid::new_v4()));
+        let mut server = TestServer::new();
+        server.app.command_handler.transcript_dir = Some(dir.clone());
+        let user = server.connect("ann", Role::Member);
+        server.send(
+            &user,
+            CommandPayload::RecordMessage {
+                message: "see you tomorrow".into(),
+                attachment: None,
+                msg_id: None,
+            },
+        );
+
+        server
+            .app
+            .command_handler
+            .begin_shutdown(&mut VecDeque::new());
+
+        let saved = std::fs::read_to_string(transcript::path_for(&dir, LOBBY_NAME)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(saved.contains("\"sender\":\"ann\",\"content\":\"see you tomorrow\""));
+    }
+
+    #[test]
+    fn a_refused_command_does_not_start_its_cooldown() {
+        let mut server = TestServer::new();
+        let user = server.connect("ann", Role::Member);
+        let search = |query: &str| CommandPayload::Search {
+            query: query.into(),
+            limit: 10,
+        };
+
+        server.send(&user, search("a"));
+        assert_eq!(server.rejections(&user), vec![None]);
+
+        server.send(&user, search("hello"));
+        assert!(server.rejections(&user).is_empty());
+
+        server.send(&user, search("hello"));
+        assert_eq!(
+            server.rejections(&user),
+            vec![Some(ErrorReason::RateLimited)]
+        );
+    }
+
+    #[test]
+    fn cooldowns_come_from_the_config() {
+        let (_, command_source) = channel(1);
+        let (shutdown_signal, _) = watch::channel(false);
+        let mut config = ServerConfig::default();
+        config.cooldowns.insert("search".into(), 0);
+        config.cooldowns.insert("who".into(), 60);
+        let app = App::with_rng(
+            command_source,
+            ServerInfo::new(Utc::now(), String::new()),
+            shutdown_signal,
+            Box::new(ChaCha8Rng::seed_from_u64(1)),
+        )
+        .with_config(&config);
+        let mut server = TestServer {
+            app,
+            inboxes: HashMap::new(),
+        };
+        let user = server.connect("ann", Role::Member);
+        let search = CommandPayload::Search {
+            query: "hello".into(),
+            limit: 10,
+        };
+
+        for _ in 0..2 {
+            server.send(&user, search.clone());
+        }
+        assert!(server.rejections(&user).is_empty());
+        server.send(&user, CommandPayload::Who);
+        server.send(&user, CommandPayload::Who);
+        assert_eq!(
+            server.rejections(&user),
+            vec![Some(ErrorReason::RateLimited)]
+        );
+    }
+
+    #[test]
+    fn direct_messages_are_sealed_for_the_recipient_under_the_senders_current_name() {
+        let mut server = TestServer::new();
+        let sender = server.connect("ann", Role::Member);
+        let recipient = server.connect("bob", Role::Member);
+        server.send(&sender, CommandPayload::Rename("anna".into()));
+        server.events(&recipient);
+
+        // The session still holds the pre-rename copy of the sender.
+        server.send(
+            &sender,
+            CommandPayload::DirectMessage {
+                to: "bob".into(),
+                content: "psst".into(),
+            },
+        );
+
+        let frames: Vec<Message> = server
+            .events(&recipient)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Frame { frame } => Some(frame),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(frames.len(), 1);
+        let read = SocketSendAdaptor::read_server_msg(&recipient.shared_secret, frames[0].clone())
+            .expect("the frame is sealed with the recipient's key");
+        let ServerMsgBody::ChatRecv { direct, chat_msg } = read.body else {
+            panic!("a direct message arrives as ChatRecv");
+        };
+        assert!(direct);
+        assert_eq!(chat_msg.sender, "anna");
+        assert_eq!(chat_msg.content, "psst");
+    }
+
+    #[test]
+    fn room_chat_is_sealed_once_per_version_for_each_occupant() {
+        let mut server = TestServer::new();
+        let sender = server.connect("sender", Role::Member);
+        let listeners: Vec<User> = [("old", 1), ("new", 3), ("newer", 3)]
+            .into_iter()
+            .map(|(name, version)| {
+                let key = SessionKey::from_bytes([version as u8 + 10; 32]);
+                let mut user = User::new(format!("id-{name}"), name.to_string(), key);
+                user.protocol_version = version;
+                server.register(user)
+            })
+            .collect();
+        server.gather("den", &sender, &listeners.iter().collect::<Vec<&User>>());
+
+        server.send(
+            &sender,
+            CommandPayload::RecordMessage {
+                message: "hello".into(),
+                attachment: None,
+                msg_id: None,
+            },
+        );
+
+        for user in &listeners {
+            let frames: Vec<Message> = server
+                .events(user)
+                .into_iter()
+                .filter_map(|event| match event {
+                    Event::Frame { frame } => Some(frame),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                frames.len(),
+                1,
+                "{} got {} frame(s)",
+                user.name,
+                frames.len()
+            );
+            let read = SocketSendAdaptor::read_server_msg(&user.shared_secret, frames[0].clone())
+                .expect("the frame is sealed with the listener's key");
+            let ServerMsgBody::ChatRecv { direct, chat_msg } = read.body else {
+                panic!("chat arrives as ChatRecv");
+            };
+            assert!(!direct);
+            assert_eq!(chat_msg.sender, "sender");
+            match user.protocol_version {
+                1 => assert_eq!(chat_msg.content, "hello"),
+                _ => assert!(chat_msg.content.ends_with("\nhello")),
+            }
+        }
     }
 }
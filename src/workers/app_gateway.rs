@@ -1,19 +1,24 @@
-use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures_channel::mpsc::Receiver;
 use futures_util::StreamExt;
 
 use anyhow::{anyhow, Result};
 
 use crate::domain::commands::Command;
+use crate::services::bounded_channel::{BoundedSender, Tracked};
+
+/// What sessions send their commands through. Each command counts against its session's
+/// in-flight cap until the gateway has handed it to the App.
+pub type GatewaySink = BoundedSender<Tracked<Command>>;
 
 pub struct AppGateway {
-    command_handler_sink: UnboundedSender<Command>,
-    session_worker_source: UnboundedReceiver<Command>,
+    command_handler_sink: BoundedSender<Command>,
+    session_worker_source: Receiver<Tracked<Command>>,
 }
 
 impl AppGateway {
     pub fn init(
-        app_sink: UnboundedSender<Command>,
-        sessions_source: UnboundedReceiver<Command>,
+        app_sink: BoundedSender<Command>,
+        sessions_source: Receiver<Tracked<Command>>,
     ) -> Self {
         Self {
             command_handler_sink: app_sink,
@@ -23,8 +28,14 @@ impl AppGateway {
 
     async fn session_worker_fan_in(&mut self) -> Result<()> {
         loop {
-            if let Some(s) = self.session_worker_source.next().await {
-                self.command_handler_sink.unbounded_send(s).unwrap()
+            if let Some(Tracked { item, permit }) = self.session_worker_source.next().await {
+                // Waiting here is what fills the sessions' queue when the App falls behind.
+                if self.command_handler_sink.send(item).await.is_err() {
+                    return Err(anyhow!(
+                        "App gateway worker stopped due to downstream channel closure"
+                    ));
+                }
+                drop(permit);
             } else {
                 return Err(anyhow!(
                     "App gateway worker stopped due to upstream channel closure"
@@ -35,9 +46,8 @@ impl AppGateway {
 
     pub fn run(mut self) {
         tokio::spawn(async move {
-            match self.session_worker_fan_in().await {
-                Err(e) => panic!("AppGateway exited abnormally with Error: {e}"),
-                _ => (),
+            if let Err(e) = self.session_worker_fan_in().await {
+                panic!("AppGateway exited abnormally with Error: {e}")
             }
         });
     }
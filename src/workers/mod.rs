@@ -1,5 +1,6 @@
 pub mod app;
 pub mod app_gateway;
+pub mod plugins;
 pub mod user_session;
 
 
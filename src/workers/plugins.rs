@@ -0,0 +1,35 @@
+use rand_core::RngCore;
+
+use anyhow::Result;
+
+use crate::domain::{commands::CommandPayload, dice::DiceSpec};
+
+use super::app::{CommandContext, CommandPlugin};
+
+/// `/roll`, which is the only command that needs an rng.
+pub struct DicePlugin {
+    rng: Box<dyn RngCore + Send>,
+}
+
+impl DicePlugin {
+    pub fn new(rng: Box<dyn RngCore + Send>) -> Self {
+        Self { rng }
+    }
+}
+
+impl CommandPlugin for DicePlugin {
+    fn handle(&mut self, ctx: &mut CommandContext, payload: CommandPayload) -> Result<()> {
+        let CommandPayload::Roll { spec } = payload else {
+            return Ok(());
+        };
+        match DiceSpec::parse(&spec) {
+            Err(reason) => ctx.reject(reason),
+            Ok(dice) => {
+                let rolls = dice.roll(self.rng.as_mut());
+                let text = format!("{} rolled {}", ctx.user.name, dice.describe(&rolls));
+                ctx.notify_room(text);
+            }
+        }
+        Ok(())
+    }
+}
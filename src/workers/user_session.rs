@@ -1,31 +1,377 @@
-use chrono::Utc;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
 use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures_util::stream::SplitStream;
-use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use futures_util::{SinkExt, StreamExt};
 use marain_api::prelude::{ClientMsg, ClientMsgBody, Timestamp};
-use sphinx::prelude::cbc_decode;
+use serde::{Deserialize, Serialize};
+use serde_json::error::Category;
 use tokio::net::TcpStream;
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio::time::{interval_at, sleep_until, Duration, Instant, MissedTickBehavior};
+use tokio_tungstenite::{
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+    WebSocketStream,
+};
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, ReusableSecret};
 
-use crate::domain::commands::{Command, CommandPayload};
+use crate::domain::attachment::{Attachment, AttachmentError, DEFAULT_MAX_ATTACHMENT_BYTES};
+use crate::domain::chat_log::{HistoryQuery, MessageId};
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::commands::{Command, CommandPayload, RequestId};
+use crate::domain::connection_stats::ConnectionStats;
+use crate::domain::crypto::SessionKey;
+use crate::domain::error_reason::ErrorReason;
 use crate::domain::events::Event;
+use crate::domain::notification_log::NotificationLog;
+use crate::domain::protocol::{
+    MIN_PROTOCOL_VERSION, OCCUPANT_DELTAS_VERSION, PRESENCE_UPDATES_VERSION,
+};
+use crate::domain::rate_limit::RateLimiter;
+use crate::domain::recent_ids::RecentIds;
 use crate::domain::room::Room;
+use crate::domain::sequence::SequenceTracker;
+use crate::domain::transcript;
 use crate::domain::user::User;
-use crate::services::message_builder::SocketSendAdaptor;
+use crate::services::bounded_channel::InFlight;
+use crate::services::command_parser::{self, ParseError};
+use crate::services::compression::Compression;
+use crate::services::message_builder::{
+    Delivery, SocketSendAdaptor, DEFAULT_MAX_OUTBOUND_FRAME_BYTES,
+};
+use crate::services::sanitize::sanitize;
+use crate::services::wire_trace;
+use crate::workers::app_gateway::GatewaySink;
 
 use anyhow::{anyhow, Result};
 
+/// Unreadable or unauthenticated frames in a row a session puts up with before hanging up
+/// on the client.
+pub const DEFAULT_MAX_FRAME_STRIKES: u32 = 3;
+
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 16 * 1024;
+
+/// Frames a second a connection may send once its burst is spent, commands and chat alike.
+pub const DEFAULT_FRAME_RATE: u32 = 5;
+pub const DEFAULT_FRAME_BURST: u32 = 20;
+/// How often a session logs its connection stats and reports them to the App for /whois.
+pub const STATS_REPORT_SECS: u64 = 60;
+
+/// Commands a connection can have waiting for the App before it is cut off for sending too
+/// fast.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 32;
+
+/// Dropped frames a connection can run up, refilling at the frame rate, before it is cut off.
+pub const MAX_FLOOD_DROPS: u32 = 100;
+
+pub const DEFAULT_KEEPALIVE_SECS: u64 = 30;
+pub const DEFAULT_MAX_MISSED_PONGS: u32 = 2;
+
+/// Pings the client every `interval_secs` and gives up on a connection once `max_missed` pings
+/// in a row have gone unanswered. An interval of 0 turns the keepalive off.
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    pub interval_secs: u64,
+    pub max_missed: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval_secs: DEFAULT_KEEPALIVE_SECS,
+            max_missed: DEFAULT_MAX_MISSED_PONGS,
+        }
+    }
+}
+
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 30 * 60;
+pub const DEFAULT_AWAY_IDLE_TIMEOUT_SECS: u64 = 4 * 60 * 60;
+pub const DEFAULT_IDLE_GRACE_SECS: u64 = 60;
+
+/// A client that sends nothing for `timeout_secs`, or `away_timeout_secs` while away, is
+/// warned and then disconnected if it is still quiet `grace_secs` later. A timeout of 0 turns
+/// that half off.
+#[derive(Debug, Clone, Copy)]
+pub struct IdleTimeout {
+    pub timeout_secs: u64,
+    pub away_timeout_secs: u64,
+    pub grace_secs: u64,
+}
+
+impl Default for IdleTimeout {
+    fn default() -> Self {
+        Self {
+            timeout_secs: DEFAULT_IDLE_TIMEOUT_SECS,
+            away_timeout_secs: DEFAULT_AWAY_IDLE_TIMEOUT_SECS,
+            grace_secs: DEFAULT_IDLE_GRACE_SECS,
+        }
+    }
+}
+
+/// How a client encodes the frames it sends, chosen by the frame type of its login.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Encrypted bincode in binary frames.
+    Bincode,
+    /// Plaintext JSON in text frames, only when MARAIN_ACCEPT_JSON allows it.
+    Json,
+}
+
+/// How far the connection has got with its keys. The client may only speak plaintext until it
+/// has been sent its LoginSuccess, after that every frame can be encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionPhase {
+    /// Logging in, there is no shared secret yet.
+    PreAuth,
+    /// The client has the server's public key and so the shared secret.
+    Established,
+}
+
+/// What an established session does with a text frame from a client that logged in encrypted.
+/// Clients that logged in with JSON chose plaintext and are left to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaintextPolicy {
+    /// Read it as before, if MARAIN_ACCEPT_JSON allows JSON at all.
+    Allow,
+    /// Read it as with `Allow`, but log it.
+    Warn,
+    /// Refuse it, and hang up on a client that keeps sending them.
+    Reject,
+}
+
+impl FromStr for PlaintextPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "allow" => Ok(PlaintextPolicy::Allow),
+            "warn" => Ok(PlaintextPolicy::Warn),
+            "reject" => Ok(PlaintextPolicy::Reject),
+            other => Err(format!("Unknown plaintext policy {other}")),
+        }
+    }
+}
+
+/// A frame that could not be read. The reason goes back to the client so it never repeats the
+/// payload, the detail is only logged.
+struct FrameError {
+    reason: String,
+    detail: String,
+    kind: ErrorReason,
+}
+
+impl FrameError {
+    fn unreadable(detail: String) -> Self {
+        Self {
+            reason: "Could not read your message".to_string(),
+            detail,
+            kind: ErrorReason::MalformedMessage,
+        }
+    }
+
+    fn unencrypted() -> Self {
+        Self {
+            reason: "Payload was not encrypted, encrypt it with your session key".to_string(),
+            detail: "binary frame is unencrypted bincode".to_string(),
+            kind: ErrorReason::Unencrypted,
+        }
+    }
+
+    fn json(e: serde_json::Error) -> Self {
+        let category = match e.classify() {
+            Category::Io => "io",
+            Category::Syntax => "syntax",
+            Category::Data => "data",
+            Category::Eof => "end of input",
+        };
+        Self {
+            reason: format!(
+                "Malformed JSON: {category} error at line {} column {}",
+                e.line(),
+                e.column()
+            ),
+            detail: e.to_string(),
+            kind: ErrorReason::MalformedMessage,
+        }
+    }
+}
+
+/// Fields a client may send alongside its ClientMsg, which has no room for them. A JSON client
+/// puts them in the same object as the message, a binary client appends them to the bincode
+/// ClientMsg as a JSON object, inside the encryption. Bincode ignores trailing bytes, so a
+/// binary client that sends none is read as before.
+#[derive(Debug, Default)]
+pub struct JsonExtras {
+    pub seq: Option<u64>,
+    pub msg_id: Option<String>,
+    pub protocol_version: Option<u32>,
+    /// Only read at login, `"deflate"` asks for large payloads to be compressed.
+    pub compression: Option<String>,
+    /// `{"filename", "mime_type", "size", "hash"}`, missing fields are left empty for
+    /// validation to refuse.
+    pub attachment: Option<Attachment>,
+}
+
+impl JsonExtras {
+    pub fn read(text: &str) -> Self {
+        serde_json::from_str::<serde_json::Value>(text)
+            .map(|value| JsonExtras::from_value(&value))
+            .unwrap_or_default()
+    }
+
+    /// What follows the ClientMsg in a binary frame, nothing or anything but a JSON object
+    /// carries no extras.
+    pub fn read_trailing(bytes: &[u8]) -> Self {
+        if bytes.is_empty() {
+            return JsonExtras::default();
+        }
+        serde_json::from_slice::<serde_json::Value>(bytes)
+            .map(|value| JsonExtras::from_value(&value))
+            .unwrap_or_default()
+    }
+
+    fn from_value(value: &serde_json::Value) -> Self {
+        JsonExtras {
+            seq: value.get("seq").and_then(|seq| seq.as_u64()),
+            msg_id: value
+                .get("msg_id")
+                .and_then(|id| id.as_str())
+                .map(str::to_string),
+            protocol_version: value
+                .get("protocol_version")
+                .and_then(|version| version.as_u64())
+                .and_then(|version| u32::try_from(version).ok()),
+            compression: value
+                .get("compression")
+                .and_then(|compression| compression.as_str())
+                .map(str::to_string),
+            attachment: value.get("attachment").map(|attachment| {
+                let text = |field| {
+                    attachment
+                        .get(field)
+                        .and_then(|value| value.as_str())
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                Attachment {
+                    filename: text("filename"),
+                    mime_type: text("mime_type"),
+                    size: attachment
+                        .get("size")
+                        .and_then(|size| size.as_u64())
+                        .unwrap_or_default(),
+                    hash: text("hash"),
+                }
+            }),
+        }
+    }
+}
+
+/// Application close code for a client that stopped answering keepalive pings.
+pub const KEEPALIVE_TIMEOUT_CODE: u16 = 4000;
+/// Application close code for a client that was quiet for too long.
+pub const IDLE_TIMEOUT_CODE: u16 = 4001;
+
+/// Why a session ended, which decides the close frame the client is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client closed the connection, there is nobody to tell.
+    ClientClosed,
+    /// Sending to the client failed, it may be back soon.
+    ConnectionLost,
+    ServerShutdown,
+    /// A moderator kicked the user off the server.
+    Kicked,
+    /// Too many oversized, unreadable or unauthenticated frames.
+    TooManyBadFrames,
+    /// Kept on sending well past its rate limit.
+    Flooding,
+    KeepaliveTimeout,
+    IdleTimeout,
+    ProtocolError,
+    /// Something failed on the server, the details stay in the server's log.
+    InternalError,
+    /// The App stopped taking commands, the session can't do anything for the client.
+    ServerUnavailable,
+}
+
+impl DisconnectReason {
+    /// The reason text is shown to the user, so it never carries error details.
+    pub fn close_frame(self) -> Option<CloseFrame<'static>> {
+        let (code, reason) = match self {
+            DisconnectReason::ClientClosed | DisconnectReason::ConnectionLost => return None,
+            DisconnectReason::ServerShutdown => (CloseCode::Away, "Server shutting down"),
+            DisconnectReason::Kicked => (CloseCode::Policy, "Kicked from the server"),
+            DisconnectReason::TooManyBadFrames => (CloseCode::Policy, "Too many invalid messages"),
+            DisconnectReason::Flooding => (CloseCode::Policy, "Sending too fast"),
+            DisconnectReason::KeepaliveTimeout => (
+                CloseCode::from(KEEPALIVE_TIMEOUT_CODE),
+                "Connection timed out",
+            ),
+            DisconnectReason::IdleTimeout => (
+                CloseCode::from(IDLE_TIMEOUT_CODE),
+                "Disconnected for being idle",
+            ),
+            DisconnectReason::ProtocolError => (CloseCode::Protocol, "Protocol error"),
+            DisconnectReason::InternalError => (CloseCode::Error, "Internal server error"),
+            DisconnectReason::ServerUnavailable => {
+                (CloseCode::Again, "Server unavailable, try again later")
+            }
+        };
+        Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })
+    }
+
+    /// Whether the user is kept on for the client to reconnect, rather than dropped. Only
+    /// connections that broke without anyone meaning them to.
+    pub fn may_resume(self) -> bool {
+        matches!(
+            self,
+            DisconnectReason::ConnectionLost
+                | DisconnectReason::ProtocolError
+                | DisconnectReason::KeepaliveTimeout
+        )
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "session ended: {self:?}")
+    }
+}
+
+/// Lets a handler that returns anyhow errors end the session for a particular reason.
+impl std::error::Error for DisconnectReason {}
+
 struct SessionBus {
-    app_gateway_sink: UnboundedSender<Command>,
+    app_gateway_sink: GatewaySink,
+    /// This session's commands that the gateway hasn't handed to the App yet.
+    in_flight: InFlight,
+    max_in_flight: usize,
     event_sink: Option<UnboundedSender<Event>>,
     event_source: UnboundedReceiver<Event>,
 }
 
 impl SessionBus {
-    fn new(gateway_sink: UnboundedSender<Command>) -> Self {
+    fn new(gateway_sink: GatewaySink) -> Self {
+        // Events stay unbounded, the App can't wait on one slow session and dropping an event
+        // would leave the client with the wrong picture of its room.
         let (sink, src) = unbounded();
         Self {
             app_gateway_sink: gateway_sink,
+            in_flight: InFlight::new(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
             event_sink: Some(sink),
             event_source: src,
         }
@@ -35,24 +381,105 @@ impl SessionBus {
         self.event_source.next().await
     }
 
-    fn send_command(&mut self, command: Command) {
-        self.app_gateway_sink.unbounded_send(command).unwrap()
+    /// Waits for room in the queue, which holds up the session's reads and so the client's
+    /// socket. A client that still gets more than its share of commands waiting is sending
+    /// too fast and the session should end, as it should when the App has stopped.
+    async fn send_command(&mut self, command: Command) -> Result<(), DisconnectReason> {
+        if self.in_flight.count() >= self.max_in_flight {
+            log::warn!(
+                "{} has {} commands waiting, dropped /{}",
+                command.user.id,
+                self.in_flight.count(),
+                command.payload.name()
+            );
+            self.app_gateway_sink.count_dropped();
+            return Err(DisconnectReason::Flooding);
+        }
+        self.send_essential_command(command).await
+    }
+
+    /// For joining and leaving, which must not be dropped however busy the App is.
+    async fn send_essential_command(&mut self, command: Command) -> Result<(), DisconnectReason> {
+        let user = command.user.clone();
+        let name = command.payload.name();
+        let tracked = self.in_flight.track(command);
+        self.app_gateway_sink.send(tracked).await.map_err(|e| {
+            log::warn!("Could not send /{name} for {} to the App: {e}", user.id);
+            DisconnectReason::ServerUnavailable
+        })
     }
 }
 
 pub struct SessionWorker {
     user: User,
     app_socket: SessionBus,
-    user_sink: SplitSink<WebSocketStream<TcpStream>, Message>,
+    /// Frames for the client, written and flushed in batches by the connection's OutboundWriter.
+    user_sink: UnboundedSender<Message>,
     user_source: SplitStream<WebSocketStream<TcpStream>>,
-    shared_secret: [u8; 32],
+    shared_secret: SessionKey,
+    /// Per room, the newest notification this user has cleared with /clear.
+    read_cursors: HashMap<Room, DateTime<Utc>>,
+    /// The id given to the client's most recent message.
+    last_request_id: RequestId,
+    /// Whether plaintext JSON text frames are read as well as encrypted binary ones.
+    accept_json: bool,
+    /// What the client logged in with. Either kind of frame is still read by its type, this is
+    /// only the client's preference.
+    inbound_format: WireFormat,
+    /// Frames in a row from this client that could not be read or carried the wrong token.
+    frame_strikes: u32,
+    max_frame_strikes: u32,
+    /// Frames bigger than this are refused before they are decrypted or parsed.
+    max_frame_bytes: usize,
+    /// Responses bigger than this go to the client in chunks.
+    max_outbound_frame_bytes: usize,
+    /// Shared by every frame from the connection, on top of the App's per-command limits.
+    frame_limiter: RateLimiter,
+    /// Spent by the frames that `frame_limiter` drops, running out means the client is flooding.
+    flood_limiter: RateLimiter,
+    /// Set once the client has been told it is over its rate, until a frame gets through again.
+    rate_limited: bool,
+    keepalive: Keepalive,
+    /// Set when a keepalive ping goes out, cleared by the client's pong.
+    awaiting_pong: bool,
+    missed_pongs: u32,
+    idle: IdleTimeout,
+    /// When the client last sent something of its own, keepalive pongs don't count.
+    last_inbound: Instant,
+    idle_warned: bool,
+    /// Follows the user's /away and /back so that the longer idle timeout applies.
+    away: bool,
+    /// The login token of a detached session the client wants back.
+    resume_token: Option<String>,
+    sequence: SequenceTracker,
+    /// Ids of the chat messages the App acked lately, with the id and time it logged each
+    /// under, so a retry isn't logged twice. Nacked ones aren't kept and can be sent again.
+    recent_msg_ids: RecentIds<(MessageId, DateTime<Utc>)>,
+    /// Agreed at login, for encoders that have to send older clients the shapes they know.
+    protocol_version: u32,
+    /// Whether the client asked for a version at login. Binary clients that didn't are
+    /// never told the one agreed, they wouldn't expect the notice.
+    version_claimed: bool,
+    /// Agreed at login, applies to the payloads that can get big.
+    compression: Compression,
+    /// For logging in again without reconnecting, there is no re-login without them.
+    server_keys: Option<(ReusableSecret, PublicKey)>,
+    stats: ConnectionStats,
+    phase: SessionPhase,
+    plaintext_policy: PlaintextPolicy,
+    /// Plaintext frames refused under `PlaintextPolicy::Reject`, unlike frame strikes these
+    /// are never forgiven.
+    plaintext_violations: u32,
+    max_attachment_bytes: u64,
+    /// Read once per response that stamps a time in more than one place.
+    clock: Arc<dyn Clock>,
 }
 
 impl SessionWorker {
     pub fn new(
         user: User,
-        gateway_sink: UnboundedSender<Command>,
-        user_sink: SplitSink<WebSocketStream<TcpStream>, Message>,
+        gateway_sink: GatewaySink,
+        user_sink: UnboundedSender<Message>,
         user_source: SplitStream<WebSocketStream<TcpStream>>,
     ) -> Self {
         SessionWorker {
@@ -61,9 +488,151 @@ impl SessionWorker {
             user_sink,
             user_source,
             shared_secret: user.shared_secret.clone(),
+            read_cursors: HashMap::new(),
+            last_request_id: 0,
+            accept_json: false,
+            inbound_format: WireFormat::Bincode,
+            frame_strikes: 0,
+            max_frame_strikes: DEFAULT_MAX_FRAME_STRIKES,
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_outbound_frame_bytes: DEFAULT_MAX_OUTBOUND_FRAME_BYTES,
+            frame_limiter: RateLimiter::per_second(DEFAULT_FRAME_RATE, DEFAULT_FRAME_BURST),
+            flood_limiter: RateLimiter::per_second(DEFAULT_FRAME_RATE, MAX_FLOOD_DROPS),
+            rate_limited: false,
+            keepalive: Keepalive::default(),
+            awaiting_pong: false,
+            missed_pongs: 0,
+            idle: IdleTimeout::default(),
+            last_inbound: Instant::now(),
+            idle_warned: false,
+            away: false,
+            resume_token: None,
+            sequence: SequenceTracker::new(),
+            recent_msg_ids: RecentIds::default(),
+            protocol_version: MIN_PROTOCOL_VERSION,
+            version_claimed: false,
+            compression: Compression::Off,
+            server_keys: None,
+            stats: ConnectionStats::default(),
+            phase: SessionPhase::PreAuth,
+            plaintext_policy: PlaintextPolicy::Allow,
+            plaintext_violations: 0,
+            max_attachment_bytes: DEFAULT_MAX_ATTACHMENT_BYTES,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn delivery(&self) -> Delivery {
+        Delivery {
+            compression: self.compression,
+            protocol_version: self.protocol_version,
+            max_frame_bytes: self.max_outbound_frame_bytes,
         }
     }
 
+    pub fn with_server_keys(mut self, secret: ReusableSecret, public_key: PublicKey) -> Self {
+        self.server_keys = Some((secret, public_key));
+        self
+    }
+
+    /// Called once the LoginSuccess carrying the server's public key has gone out.
+    pub fn key_established(mut self) -> Self {
+        self.phase = SessionPhase::Established;
+        self
+    }
+
+    pub fn phase(&self) -> SessionPhase {
+        self.phase
+    }
+
+    pub fn with_max_attachment_bytes(mut self, max_attachment_bytes: u64) -> Self {
+        self.max_attachment_bytes = max_attachment_bytes;
+        self
+    }
+
+    pub fn with_plaintext_policy(mut self, policy: PlaintextPolicy) -> Self {
+        self.plaintext_policy = policy;
+        self
+    }
+
+    pub fn with_protocol_version(mut self, version: u32) -> Self {
+        self.protocol_version = version;
+        self.user.protocol_version = version;
+        self
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    pub fn with_version_claimed(mut self, claimed: bool) -> Self {
+        self.version_claimed = claimed;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn resuming(mut self, token: Option<String>) -> Self {
+        self.resume_token = token;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle: IdleTimeout) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    pub fn with_inbound_format(mut self, format: WireFormat) -> Self {
+        log::debug!("{} logged in with {format:?}", self.user.id);
+        self.inbound_format = format;
+        self
+    }
+
+    pub fn with_max_frame_strikes(mut self, max_frame_strikes: u32) -> Self {
+        self.max_frame_strikes = max_frame_strikes.max(1);
+        self
+    }
+
+    pub fn with_max_frame_bytes(mut self, max_frame_bytes: usize) -> Self {
+        self.max_frame_bytes = max_frame_bytes;
+        self
+    }
+
+    pub fn with_max_outbound_frame_bytes(mut self, max_outbound_frame_bytes: usize) -> Self {
+        self.max_outbound_frame_bytes = max_outbound_frame_bytes;
+        self
+    }
+
+    pub fn with_frame_rate(mut self, rate: u32, burst: u32) -> Self {
+        self.frame_limiter = RateLimiter::per_second(rate, burst);
+        self.flood_limiter = RateLimiter::per_second(rate, MAX_FLOOD_DROPS);
+        self
+    }
+
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.app_socket.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// For clients from before frames were encrypted.
+    pub fn accept_json_frames(mut self, accept: bool) -> Self {
+        self.accept_json = accept;
+        self
+    }
+
     fn give_sink(&mut self) -> Result<UnboundedSender<Event>> {
         if let Some(s) = self.app_socket.event_sink.clone() {
             self.app_socket.event_sink = None;
@@ -75,59 +644,516 @@ impl SessionWorker {
         }
     }
 
-    fn decrypt(user_key: &[u8; 32], enc: Vec<u8>) -> Result<Vec<u8>> {
-        match cbc_decode(user_key.to_vec(), enc) {
-            Ok(dec) => Ok(dec),
-            Err(e) => {
-                log::error!("Failed to decode user message with error: {e}");
-                Err(anyhow!("{e:?}"))
+    /// A bincode ClientMsg and the extras after it, if the client appended any.
+    pub fn deserialize(msg: &[u8]) -> Result<(ClientMsg, JsonExtras)> {
+        let mut rest = msg;
+        let client_msg = bincode::deserialize_from::<_, ClientMsg>(&mut rest)
+            .map_err(|e| anyhow!("Deserialization error: {e}"))?;
+        Ok((client_msg, JsonExtras::read_trailing(rest)))
+    }
+
+    /// A frame that won't decrypt but is a ClientMsg as it stands was sent in the clear. The
+    /// client is told so and what it sent goes no further.
+    fn read_binary_frame(&self, data: Vec<u8>) -> Result<(ClientMsg, JsonExtras), FrameError> {
+        match SocketSendAdaptor::decrypt_message(&self.shared_secret, data.clone()) {
+            Ok(decrypted) => {
+                Self::deserialize(&decrypted).map_err(|e| FrameError::unreadable(e.to_string()))
             }
+            Err(_) if Self::deserialize(&data).is_ok() => Err(FrameError::unencrypted()),
+            Err(e) => Err(FrameError::unreadable(e.to_string())),
         }
     }
 
-    fn deserialize(msg: Vec<u8>) -> Result<ClientMsg, Box<bincode::ErrorKind>> {
-        bincode::deserialize::<ClientMsg>(&msg[..])
+    fn read_json_frame(text: &str) -> Result<ClientMsg, FrameError> {
+        serde_json::from_str::<ClientMsg>(text).map_err(FrameError::json)
     }
 
-    fn parse_client_msg(&mut self, msg: ClientMsg) -> Result<Command> {
-        match msg {
-            ClientMsg { body, .. } => match body {
-                ClientMsgBody::SendToRoom { contents: message } => Ok(Command {
-                    user: self.user.clone(),
-                    payload: CommandPayload::RecordMessage { message },
-                }),
-                ClientMsgBody::Move { target } => Ok(Command {
+    async fn report_stats(&mut self) -> Result<(), DisconnectReason> {
+        log::debug!("{} has sent {}", self.user.id, self.stats);
+        self.app_socket
+            .send_command(Command {
+                user: self.user.clone(),
+                payload: CommandPayload::ReportStats(self.stats),
+                request_id: None,
+            })
+            .await
+    }
+
+    /// Someone quiet enough to be warned about it is shown as away until they say something.
+    async fn report_idle(&mut self) -> Result<(), DisconnectReason> {
+        self.app_socket
+            .send_command(Command {
+                user: self.user.clone(),
+                payload: CommandPayload::WentIdle,
+                request_id: None,
+            })
+            .await
+    }
+
+    /// The version is agreed at login and can't change, a message claiming another one is
+    /// handled all the same but the client is told.
+    async fn flag_version(&mut self, claimed: u32) -> Result<(), DisconnectReason> {
+        if claimed == self.protocol_version {
+            return Ok(());
+        }
+        log::warn!(
+            "{} sent a protocol version {claimed} message on a version {} session",
+            self.user.id,
+            self.protocol_version
+        );
+        self.refuse_frame(
+            ErrorReason::UnsupportedVersion,
+            format!(
+                "This session speaks protocol version {}, not {claimed}",
+                self.protocol_version
+            ),
+        )
+        .await
+    }
+
+    /// A chat message whose id was acked lately is a retry. It is acked again, as it was the
+    /// first time, so the client stops sending it, and goes no further. A retry sent before
+    /// the first copy's ack came back isn't caught.
+    async fn is_retry(&mut self, msg: &ClientMsg, msg_id: Option<&str>) -> Result<bool> {
+        let Some(msg_id) = msg_id else {
+            return Ok(false);
+        };
+        if !matches!(msg.body, ClientMsgBody::SendToRoom { .. }) {
+            return Ok(false);
+        }
+        let Some((id, timestamp)) = self.recent_msg_ids.get(msg_id).cloned() else {
+            return Ok(false);
+        };
+        log::debug!("{} sent message {msg_id} again", self.user.id);
+        self.last_request_id += 1;
+        let ack = SocketSendAdaptor::prepare_send_ack(
+            &self.shared_secret,
+            Some(msg_id.to_string()),
+            id,
+            Timestamp::from(timestamp),
+            Some(self.last_request_id),
+        )?;
+        self.user_sink.send(ack).await?;
+        Ok(true)
+    }
+
+    /// Tells the client when its sequence numbers skip or go backwards. The message is still
+    /// handled, the notice is only there to help find what lost it.
+    async fn follow_sequence(&mut self, seq: u64) -> Result<(), DisconnectReason> {
+        let Some(gap) = self.sequence.observe(seq) else {
+            return Ok(());
+        };
+        self.user.sequence_gaps.fetch_add(1, Ordering::Relaxed);
+        log::debug!(
+            "{} sent message {} when {} was expected",
+            self.user.id,
+            gap.received,
+            gap.expected
+        );
+        let notice = NotificationLog::new(format!(
+            "Sequence gap: expected message {} but received {}",
+            gap.expected, gap.received
+        ));
+        let msg =
+            SocketSendAdaptor::prepare_send_notice(&self.shared_secret, notice).map_err(|e| {
+                log::error!(
+                    "Could not build a sequence notice for {}: {e}",
+                    self.user.id
+                );
+                DisconnectReason::InternalError
+            })?;
+        self.user_sink.send(msg).await.map_err(|e| {
+            log::debug!("Could not send a sequence notice to {}: {e}", self.user.id);
+            DisconnectReason::ConnectionLost
+        })
+    }
+
+    /// Tells the client why its frame was refused, or gives the reason to hang up once it
+    /// has had too many.
+    async fn strike(&mut self, kind: ErrorReason, reason: &str) -> Result<(), DisconnectReason> {
+        self.frame_strikes += 1;
+        if self.frame_strikes >= self.max_frame_strikes {
+            return Err(DisconnectReason::TooManyBadFrames);
+        }
+        self.refuse_frame(kind, reason.to_string()).await
+    }
+
+    /// Applies the plaintext policy to a text frame. `Ok(false)` means the frame was refused
+    /// and should go no further.
+    async fn allow_plaintext(&mut self) -> Result<bool, DisconnectReason> {
+        if self.phase == SessionPhase::PreAuth || self.inbound_format == WireFormat::Json {
+            return Ok(true);
+        }
+        match self.plaintext_policy {
+            PlaintextPolicy::Allow => Ok(true),
+            PlaintextPolicy::Warn => {
+                log::warn!("Plaintext frame from {} after login", self.user.id);
+                Ok(true)
+            }
+            PlaintextPolicy::Reject => {
+                self.plaintext_violations += 1;
+                log::warn!(
+                    "Refused plaintext frame {} from {} after login",
+                    self.plaintext_violations,
+                    self.user.id
+                );
+                if self.plaintext_violations >= self.max_frame_strikes {
+                    return Err(DisconnectReason::TooManyBadFrames);
+                }
+                self.refuse_frame(
+                    ErrorReason::Unencrypted,
+                    "Unencrypted messages are not accepted".to_string(),
+                )
+                .await?;
+                Ok(false)
+            }
+        }
+    }
+
+    async fn refuse_frame(
+        &mut self,
+        kind: ErrorReason,
+        reason: String,
+    ) -> Result<(), DisconnectReason> {
+        let msg = SocketSendAdaptor::prepare_send_error(&self.shared_secret, kind, reason, None)
+            .map_err(|e| {
+                log::error!("Could not build a rejection for {}: {e}", self.user.id);
+                DisconnectReason::InternalError
+            })?;
+        self.user_sink.send(msg).await.map_err(|e| {
+            log::debug!("Could not send a rejection to {}: {e}", self.user.id);
+            DisconnectReason::ConnectionLost
+        })
+    }
+
+    /// Lets a frame through if the connection is within its rate. The first frame over tells
+    /// the client when to try again, later ones are dropped quietly until the bucket refills.
+    async fn within_frame_rate(&mut self) -> Result<bool, DisconnectReason> {
+        let now = Utc::now();
+        let retry_after = match self.frame_limiter.try_acquire(&self.user.id, now) {
+            Ok(()) => {
+                self.rate_limited = false;
+                return Ok(true);
+            }
+            Err(retry_after) => retry_after,
+        };
+        if self.flood_limiter.try_acquire(&self.user.id, now).is_err() {
+            return Err(DisconnectReason::Flooding);
+        }
+        if !self.rate_limited {
+            self.rate_limited = true;
+            log::warn!("{} is sending too fast, dropping frames", self.user.id);
+            self.refuse_frame(
+                ErrorReason::RateLimited,
+                format!("You are sending too fast, try again in {retry_after} second(s)"),
+            )
+            .await?;
+        }
+        Ok(false)
+    }
+
+    /// The App brings an away user back when they chat, and so does this.
+    fn follow_presence(&mut self, payload: &CommandPayload) {
+        match payload {
+            CommandPayload::Away(_) => self.away = true,
+            CommandPayload::Back
+            | CommandPayload::RecordMessage { .. }
+            | CommandPayload::Action(_) => self.away = false,
+            _ => {}
+        }
+    }
+
+    /// When the session next has to act on the client being quiet: warn it, or once warned,
+    /// hang up on it.
+    fn idle_deadline(&self) -> Option<Instant> {
+        let timeout = if self.away {
+            self.idle.away_timeout_secs
+        } else {
+            self.idle.timeout_secs
+        };
+        if timeout == 0 {
+            return None;
+        }
+        let grace = if self.idle_warned {
+            self.idle.grace_secs
+        } else {
+            0
+        };
+        Some(self.last_inbound + Duration::from_secs(timeout + grace))
+    }
+
+    async fn warn_idle(&mut self) -> Result<()> {
+        self.idle_warned = true;
+        let notice = NotificationLog::new(format!(
+            "You have been idle for a while and will be disconnected in {} second(s) unless you send something",
+            self.idle.grace_secs
+        ));
+        let msg = SocketSendAdaptor::prepare_send_notice(&self.shared_secret, notice)?;
+        self.user_sink.send(msg).await?;
+        Ok(())
+    }
+
+    /// Counts the previous ping as missed if no pong came back for it, then sends the next.
+    async fn keepalive_ping(&mut self) -> Result<()> {
+        if self.awaiting_pong {
+            self.missed_pongs += 1;
+            if self.missed_pongs >= self.keepalive.max_missed {
+                return Err(anyhow!(
+                    "{} keepalive pings in a row went unanswered",
+                    self.missed_pongs
+                ));
+            }
+        }
+        self.awaiting_pong = true;
+        self.user_sink.send(Message::Ping(Vec::new())).await?;
+        Ok(())
+    }
+
+    fn parse_client_msg(
+        &mut self,
+        msg: ClientMsg,
+        attachment: Option<Attachment>,
+        msg_id: Option<String>,
+    ) -> Result<Command, ParseError> {
+        self.last_request_id += 1;
+        let request_id = self.last_request_id;
+        let ClientMsg {
+            body, timestamp, ..
+        } = msg;
+        // No wildcard, so a new ClientMsgBody has to be routed or refused with
+        // ParseError::UnexpectedMessage before the session compiles.
+        match body {
+            ClientMsgBody::SendToRoom { contents } => {
+                let message = sanitize(&contents);
+                if let Some(attachment) = &attachment {
+                    attachment
+                        .validate(self.max_attachment_bytes)
+                        .map_err(ParseError::InvalidAttachment)?;
+                } else if message.trim().is_empty() {
+                    return Err(ParseError::EmptyMessage);
+                }
+                Ok(Command {
                     user: self.user.clone(),
-                    payload: CommandPayload::MoveUser {
-                        target_room: Room { name: target },
+                    request_id: Some(request_id),
+                    payload: match command_parser::parse(&message)? {
+                        Some(_) if attachment.is_some() => {
+                            return Err(ParseError::InvalidAttachment(AttachmentError::NotChat));
+                        }
+                        // The registry only sees the text, so the client's own timestamp is filled in here.
+                        Some(CommandPayload::Ping { received_at, .. }) => CommandPayload::Ping {
+                            client_ts: Some(timestamp),
+                            received_at,
+                        },
+                        // Aliases expand to chat, which is acked like any other.
+                        Some(CommandPayload::RecordMessage {
+                            message: expanded, ..
+                        }) => CommandPayload::RecordMessage {
+                            message: expanded,
+                            attachment,
+                            msg_id,
+                        },
+                        Some(payload) => payload,
+                        None => CommandPayload::RecordMessage {
+                            message,
+                            attachment,
+                            msg_id,
+                        },
                     },
-                }),
-                ClientMsgBody::GetTime => Ok(Command {
-                    user: self.user.clone(),
-                    payload: CommandPayload::Time(Timestamp::from(Utc::now())),
-                }),
-                _ => {
-                    return Err(anyhow!("Cannot parse command. Command: {body:?}"));
-                }
-            },
+                })
+            }
+            ClientMsgBody::Move { target } => Ok(Command {
+                user: self.user.clone(),
+                request_id: Some(request_id),
+                payload: CommandPayload::MoveUser {
+                    target_room: Room { name: target },
+                },
+            }),
+            ClientMsgBody::GetTime => Ok(Command {
+                user: self.user.clone(),
+                request_id: Some(request_id),
+                payload: CommandPayload::Time {
+                    client_ts: Some(timestamp),
+                    server_ts: Timestamp::from(Utc::now()),
+                },
+            }),
+            // Only reached by a session that has no way to log in again.
+            ClientMsgBody::Login(..) => Err(ParseError::AlreadyLoggedIn),
+        }
+    }
+
+    fn unread(&self, room: &Room, notifications: Vec<NotificationLog>) -> Vec<NotificationLog> {
+        match self.read_cursors.get(room) {
+            Some(cursor) => notifications
+                .into_iter()
+                .filter(|notice| notice.timestamp > *cursor)
+                .collect(),
+            None => notifications,
+        }
+    }
+
+    /// Logs the client in again without touching its connection or room: a fresh token, and a
+    /// fresh key from the public key in the Login. The run loop reads one message at a time,
+    /// so two re-logins on a connection never overlap, and nothing changes until the new
+    /// LoginSuccess has gone out.
+    async fn relogin(&mut self, token: Option<String>, client_public_key: [u8; 32]) -> Result<()> {
+        // The frame already had to be readable with the session key, and it has to carry the
+        // current token as well. Compared in constant time.
+        if !self.user.has_session_token(token.as_deref()) {
+            log::warn!(
+                "Refused a re-login from {} with the wrong token",
+                self.user.id
+            );
+            let refusal = SocketSendAdaptor::prepare_send_rejection(
+                &self.shared_secret,
+                "Login refused, send your current token".to_string(),
+                None,
+            )?;
+            self.user_sink.send(refusal).await?;
+            return Ok(());
         }
+        let Some((server_secret, server_public_key)) = &self.server_keys else {
+            return Err(anyhow!("Re-login without the server keys"));
+        };
+
+        let shared_secret = SessionKey::from_key_exchange(
+            server_secret.diffie_hellman(&PublicKey::from(client_public_key)),
+        );
+        let session_token = format!("{:X}", Uuid::new_v4().as_u128());
+        let success = SocketSendAdaptor::on_login_success(
+            session_token.clone(),
+            server_public_key.to_bytes(),
+        )?;
+        self.user_sink.send(success).await?;
+
+        log::info!("{} logged in again", self.user.id);
+        self.user.shared_secret = shared_secret.clone();
+        self.shared_secret = shared_secret;
+        self.user.session_token = session_token.clone();
+        self.app_socket
+            .send_essential_command(Command {
+                user: self.user.clone(),
+                payload: CommandPayload::Reauthenticate {
+                    token: session_token,
+                    key: self.shared_secret.clone(),
+                },
+                request_id: None,
+            })
+            .await?;
+        Ok(())
     }
 
-    async fn handle_client_msg(&mut self, msg: ClientMsg) -> Result<()> {
-        match self.parse_client_msg(msg) {
+    async fn handle_client_msg(
+        &mut self,
+        msg: ClientMsg,
+        attachment: Option<Attachment>,
+        msg_id: Option<String>,
+    ) -> Result<()> {
+        self.user.last_active = Utc::now();
+        if let ClientMsg {
+            token,
+            body: ClientMsgBody::Login(_, client_public_key),
+            ..
+        } = &msg
+        {
+            if self.server_keys.is_some() {
+                return self.relogin(token.clone(), *client_public_key).await;
+            }
+        }
+        let parsed = self.parse_client_msg(msg, attachment, msg_id);
+        match &parsed {
+            Ok(cmd) => wire_trace::routed(&self.user.id, &cmd.payload),
+            Err(parse_error) => wire_trace::refused(&self.user.id, parse_error),
+        }
+        match parsed {
             Ok(cmd) => match cmd.payload {
-                CommandPayload::Time(t) => {
-                    let ts = SocketSendAdaptor::prepare_send_time(&self.shared_secret, t)?;
+                CommandPayload::Time {
+                    client_ts,
+                    server_ts,
+                } => {
+                    self.stats.answered += 1;
+                    let ts = SocketSendAdaptor::prepare_send_time(
+                        &self.shared_secret,
+                        client_ts,
+                        server_ts,
+                        cmd.request_id,
+                    )?;
                     self.user_sink.send(ts).await?;
                     Ok(())
                 }
+                CommandPayload::Ping {
+                    client_ts,
+                    received_at,
+                } => {
+                    self.stats.answered += 1;
+                    let pong = SocketSendAdaptor::prepare_send_pong(
+                        &self.shared_secret,
+                        client_ts,
+                        received_at,
+                        cmd.request_id,
+                    )?;
+                    self.user_sink.send(pong).await?;
+                    Ok(())
+                }
+                CommandPayload::Help(command) => {
+                    self.stats.answered += 1;
+                    let help = SocketSendAdaptor::prepare_send_help(
+                        &self.shared_secret,
+                        command,
+                        cmd.request_id,
+                    )?;
+                    self.user_sink.send(help).await?;
+                    Ok(())
+                }
                 _ => {
-                    self.app_socket.send_command(cmd);
+                    self.follow_presence(&cmd.payload);
+                    self.app_socket.send_command(cmd).await?;
+                    self.stats.forwarded += 1;
                     Ok(())
                 }
             },
-            Err(e) => return Err(anyhow!("Error in handle_command: {e:?}")),
+            // A mistyped command is the user's problem, not the session's.
+            Err(parse_error) => {
+                self.stats.parse_failures += 1;
+                log::debug!(
+                    "Could not parse message from {}: {parse_error}",
+                    self.user.name
+                );
+                let error = SocketSendAdaptor::prepare_send_parse_error(
+                    &self.shared_secret,
+                    &parse_error,
+                    Some(self.last_request_id),
+                )?;
+                self.user_sink.send(error).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Frames that belong together, queued back to back so nothing lands between them.
+    async fn send_frames(&mut self, frames: Vec<Message>) -> Result<()> {
+        for frame in frames {
+            self.user_sink.feed(frame).await?;
         }
+        self.user_sink.flush().await?;
+        Ok(())
+    }
+
+    /// Sent to the rest of a room in place of a whole RoomData when someone joins or leaves.
+    async fn send_occupant_update(
+        &mut self,
+        joined: Vec<String>,
+        left: Vec<String>,
+        epoch: u64,
+    ) -> Result<()> {
+        let msg = SocketSendAdaptor::prepare_send_occupant_update(
+            &self.shared_secret,
+            joined,
+            left,
+            epoch,
+            Timestamp::from(self.clock.now()),
+        )?;
+        self.user_sink.send(msg).await?;
+        Ok(())
     }
 
     async fn handle_event(&mut self, event: Event) -> Result<()> {
@@ -136,134 +1162,1301 @@ impl SessionWorker {
                 log::info!("Successfully registered User: {token}");
                 Ok(())
             }
-            Event::MsgReceived { msg } => {
-                let msg =
-                    SocketSendAdaptor::prepare_send_msg_log(msg, &self.user, &self.shared_secret)?;
-                self.user_sink.send(msg).await?;
-                Ok(())
-            }
-            Event::UserLeft {
+            Event::History {
                 room,
+                page,
                 occupant_names,
-                notifications,
-                msg_log,
-                ..
             } => {
-                let msg = SocketSendAdaptor::room_data_response(
+                let frames = SocketSendAdaptor::prepare_send_history(
                     &self.shared_secret,
-                    msg_log,
-                    notifications,
+                    &room,
+                    page,
                     occupant_names,
+                    self.delivery(),
+                    Timestamp::from(self.clock.now()),
+                )?;
+                self.send_frames(frames).await
+            }
+            Event::Export {
+                room,
+                format,
+                logs,
+                notifications,
+            } => {
+                let transcript = transcript::render(&logs, &notifications, format);
+                let frames = SocketSendAdaptor::prepare_send_export(
+                    &self.shared_secret,
                     &room,
+                    &transcript,
+                    self.compression,
+                    Timestamp::from(self.clock.now()),
+                )?;
+                self.send_frames(frames).await
+            }
+            Event::SearchResults { query, matches } => {
+                let msg = SocketSendAdaptor::prepare_send_search_results(
+                    &self.shared_secret,
+                    query,
+                    matches,
+                    self.compression,
                 )?;
                 self.user_sink.send(msg).await?;
-
                 Ok(())
             }
-            Event::UserJoined {
-                msg_log,
-                notifications,
-                occupant_names,
+            Event::NotificationsCleared {
                 room,
-                ..
+                notifications,
             } => {
-                let msg = SocketSendAdaptor::room_data_response(
+                let unread = self.unread(&room, notifications);
+                if let Some(latest) = unread.iter().map(|notice| notice.timestamp).max() {
+                    self.read_cursors.insert(room, latest);
+                }
+                let notice =
+                    NotificationLog::new(format!("Cleared {} notification(s)", unread.len()));
+                let msg = SocketSendAdaptor::prepare_send_notice(&self.shared_secret, notice)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::MsgReceived { msg } => {
+                let msg = SocketSendAdaptor::prepare_send_msg_log(
+                    msg,
                     &self.shared_secret,
-                    msg_log,
-                    notifications,
-                    occupant_names,
-                    &room,
+                    self.protocol_version,
                 )?;
                 self.user_sink.send(msg).await?;
-                // let msg =
-                //     SocketSendAdaptor::user_join_notification(&self.shared_secret, &user, &room)?;
-                // self.user_sink.send(msg).await?;
                 Ok(())
             }
-        }
-    }
-
-    pub async fn end_session(&mut self) {
-        self.app_socket.send_command(Command {
-            user: self.user.clone(),
-            payload: CommandPayload::DropUser,
-        });
-        loop {
-            match self.app_socket.next_event().await {
-                Some(Event::UserLeft { user, .. }) if user == self.user => {
-                    return;
-                }
-                _ => {
-                    continue;
-                }
-            };
-        }
-    }
-
-    pub async fn run(&mut self) -> Result<()> {
-        let event_sink = self.give_sink()?;
-        let register = Command {
-            user: self.user.clone(),
-            payload: CommandPayload::RegisterUser(event_sink),
-        };
+            Event::Frame { frame } => {
+                self.user_sink.send(frame).await?;
+                Ok(())
+            }
+            Event::WhoAmI { user, room } => {
+                let msg = SocketSendAdaptor::prepare_send_whoami(&self.shared_secret, &user, room)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::RoomList { rooms } => {
+                let msg = SocketSendAdaptor::prepare_send_room_list(&self.shared_secret, rooms)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Motd { motd } => {
+                let msg = SocketSendAdaptor::prepare_send_motd(&self.shared_secret, motd)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Announcement { notice } => {
+                let msg =
+                    SocketSendAdaptor::prepare_send_announcement(&self.shared_secret, notice)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Notify { notice } => {
+                let msg =
+                    SocketSendAdaptor::prepare_send_notification(&self.shared_secret, notice)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::SendAck {
+                msg_id,
+                id,
+                timestamp,
+                request_id,
+            } => {
+                if let Some(msg_id) = &msg_id {
+                    self.recent_msg_ids.insert(msg_id.clone(), (id, timestamp));
+                }
+                let msg = SocketSendAdaptor::prepare_send_ack(
+                    &self.shared_secret,
+                    msg_id,
+                    id,
+                    Timestamp::from(timestamp),
+                    request_id,
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::SendNack {
+                msg_id,
+                kind,
+                reason,
+                request_id,
+            } => {
+                let msg = SocketSendAdaptor::prepare_send_nack(
+                    &self.shared_secret,
+                    msg_id,
+                    kind,
+                    reason,
+                    request_id,
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Reply { notice, request_id } => {
+                let msg =
+                    SocketSendAdaptor::prepare_send_reply(&self.shared_secret, notice, request_id)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Rejected {
+                reason,
+                request_id,
+                kind,
+            } => {
+                let msg = match kind {
+                    Some(kind) => SocketSendAdaptor::prepare_send_error(
+                        &self.shared_secret,
+                        kind,
+                        reason,
+                        request_id,
+                    )?,
+                    None => SocketSendAdaptor::prepare_send_rejection(
+                        &self.shared_secret,
+                        reason,
+                        request_id,
+                    )?,
+                };
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::PresenceChanged {
+                user_name,
+                status,
+                timestamp,
+            } => {
+                if self.protocol_version < PRESENCE_UPDATES_VERSION {
+                    return Ok(());
+                }
+                let msg = SocketSendAdaptor::prepare_send_presence_update(
+                    &self.shared_secret,
+                    &user_name,
+                    &status,
+                    Timestamp::from(timestamp),
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            // The run loop hangs up with the shutdown or kick close frame.
+            Event::ServerShutdown | Event::Kicked => Ok(()),
+            // Only ever seen by a session that is already ending.
+            Event::UserDetached { .. } => Ok(()),
+            Event::SessionResumed {
+                user,
+                room,
+                mut snapshot,
+                missed,
+            } => {
+                log::info!("{} picked up where {} left off", self.user.id, user.id);
+                self.away = user.is_away();
+                self.user = user;
+                // The resync: whatever the client numbered before is forgotten, and it is told
+                // to start again.
+                self.sequence.reset();
+                self.user.sequence_gaps.store(0, Ordering::Relaxed);
+                snapshot.notifications = self.unread(&room, snapshot.notifications);
+                let frames = SocketSendAdaptor::room_data_response(
+                    &self.shared_secret,
+                    &room,
+                    snapshot,
+                    &HistoryQuery::default(),
+                    self.delivery(),
+                    Timestamp::from(self.clock.now()),
+                )?;
+                self.send_frames(frames).await?;
+                for missed_msg in missed {
+                    let msg = SocketSendAdaptor::prepare_send_msg_log(
+                        missed_msg,
+                        &self.shared_secret,
+                        self.protocol_version,
+                    )?;
+                    self.user_sink.send(msg).await?;
+                }
+                let resync = NotificationLog::new(
+                    "Session resumed, sequence numbers start again from your next message".into(),
+                );
+                let msg = SocketSendAdaptor::prepare_send_notice(&self.shared_secret, resync)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::UserLeft { user, snapshot, .. }
+                if user != self.user && self.protocol_version >= OCCUPANT_DELTAS_VERSION =>
+            {
+                let epoch = snapshot.occupancy_epoch;
+                self.send_occupant_update(vec![], vec![user.name.clone()], epoch)
+                    .await?;
+                Ok(())
+            }
+            Event::UserLeft {
+                room, mut snapshot, ..
+            } => {
+                snapshot.notifications = self.unread(&room, snapshot.notifications);
+                let frames = SocketSendAdaptor::room_data_response(
+                    &self.shared_secret,
+                    &room,
+                    snapshot,
+                    &HistoryQuery::default(),
+                    self.delivery(),
+                    Timestamp::from(self.clock.now()),
+                )?;
+                self.send_frames(frames).await?;
+                Ok(())
+            }
+            Event::UserJoined { user, snapshot, .. }
+                if user != self.user && self.protocol_version >= OCCUPANT_DELTAS_VERSION =>
+            {
+                let epoch = snapshot.occupancy_epoch;
+                self.send_occupant_update(vec![user.name.clone()], vec![], epoch)
+                    .await?;
+                Ok(())
+            }
+            Event::UserJoined {
+                room, mut snapshot, ..
+            } => {
+                snapshot.notifications = self.unread(&room, snapshot.notifications);
+                let frames = SocketSendAdaptor::room_data_response(
+                    &self.shared_secret,
+                    &room,
+                    snapshot,
+                    &HistoryQuery::default(),
+                    self.delivery(),
+                    Timestamp::from(self.clock.now()),
+                )?;
+                self.send_frames(frames).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Says goodbye to the client, if it is still there to hear it, then waits for the App to
+    /// let the user go or keep them detached for a reconnect.
+    pub async fn end_session(&mut self, reason: DisconnectReason) {
+        log::info!(
+            "Ending session for {}: {reason:?}, received {}",
+            self.user.id,
+            self.stats
+        );
+        self.recent_msg_ids.clear();
+        if let Some(frame) = reason.close_frame() {
+            if let Err(e) = self.user_sink.send(Message::Close(Some(frame))).await {
+                log::debug!("Could not send close frame to {}: {e}", self.user.id);
+            }
+        }
+        let payload = if reason.may_resume() {
+            CommandPayload::DetachUser
+        } else {
+            CommandPayload::DropUser
+        };
+        let leave = Command {
+            user: self.user.clone(),
+            payload,
+            request_id: None,
+        };
+        // Without an App there is no room to leave and nothing to wait for.
+        if self.app_socket.send_essential_command(leave).await.is_err() {
+            return;
+        }
+        loop {
+            match self.app_socket.next_event().await {
+                Some(Event::UserLeft { user, .. } | Event::UserDetached { user })
+                    if user == self.user =>
+                {
+                    return;
+                }
+                // The App has gone, there is nothing left to wait for.
+                None => return,
+                _ => continue,
+            };
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let event_sink = self.give_sink()?;
+        let payload = match self.resume_token.take() {
+            Some(token) => CommandPayload::ResumeUser {
+                token,
+                channel: event_sink,
+            },
+            None => CommandPayload::RegisterUser(event_sink),
+        };
+        let register = Command {
+            user: self.user.clone(),
+            payload,
+            request_id: None,
+        };
 
-        self.app_socket.send_command(register);
+        if let Err(reason) = self.app_socket.send_essential_command(register).await {
+            self.end_session(reason).await;
+            return Ok(());
+        }
+
+        let version = match self.inbound_format {
+            WireFormat::Json => Some(Ok(SocketSendAdaptor::prepare_send_protocol_version(
+                self.protocol_version,
+                self.compression,
+            ))),
+            WireFormat::Bincode if self.version_claimed => {
+                Some(SocketSendAdaptor::prepare_send_protocol_notice(
+                    &self.shared_secret,
+                    self.protocol_version,
+                    self.compression,
+                ))
+            }
+            WireFormat::Bincode => None,
+        };
+        if let Some(version) = version {
+            let version = match version {
+                Ok(version) => version,
+                Err(e) => {
+                    log::error!(
+                        "Could not build a protocol notice for {}: {e}",
+                        self.user.id
+                    );
+                    self.end_session(DisconnectReason::InternalError).await;
+                    return Ok(());
+                }
+            };
+            if let Err(e) = self.user_sink.send(version).await {
+                log::debug!("Could not tell {} the protocol version: {e}", self.user.id);
+                self.end_session(DisconnectReason::ConnectionLost).await;
+                return Ok(());
+            }
+        }
+
+        let stats_period = Duration::from_secs(STATS_REPORT_SECS);
+        let mut stats_report = interval_at(Instant::now() + stats_period, stats_period);
 
-        'main_loop: loop {
+        let period = Duration::from_secs(self.keepalive.interval_secs.max(1));
+        let mut keepalive = interval_at(Instant::now() + period, period);
+        keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let reason = 'main_loop: loop {
+            let idle_deadline = self.idle_deadline();
             tokio::select! {
                 Some(msg) = self.user_source.next() => {
-                    let msg_bytes = match msg {
-                        Ok(Message::Binary(data)) => data,
+                    if let Ok(frame) = &msg {
+                        self.stats.record_frame(frame);
+                        wire_trace::inbound_frame(&self.user.id, frame);
+                    }
+                    // Any frame counts, pongs included: what the timeout frees is a connection
+                    // nothing comes in on at all.
+                    if msg.is_ok() {
+                        self.last_inbound = Instant::now();
+                        self.idle_warned = false;
+                    }
+                    let size = match &msg {
+                        Ok(Message::Binary(data)) => data.len(),
+                        Ok(Message::Text(text)) => text.len(),
+                        _ => 0,
+                    };
+                    if size > self.max_frame_bytes {
+                        log::warn!("Dropped a {size} byte frame from {}", self.user.id);
+                        let reason = format!("Message too large, the limit is {} bytes", self.max_frame_bytes);
+                        if let Err(reason) = self.strike(ErrorReason::MessageTooLarge, &reason).await {
+                            break 'main_loop reason;
+                        }
+                        continue;
+                    }
+
+                    if matches!(msg, Ok(Message::Text(_))) {
+                        match self.allow_plaintext().await {
+                            Ok(true) => {}
+                            Ok(false) => continue,
+                            Err(reason) => break 'main_loop reason,
+                        }
+                    }
+
+                    let mut extras = JsonExtras::default();
+                    let frame = match msg {
+                        Ok(Message::Binary(data)) => {
+                            self.read_binary_frame(data).map(|(client_msg, trailing)| {
+                                extras = trailing;
+                                client_msg
+                            })
+                        }
+                        Ok(Message::Text(text)) if self.accept_json => {
+                            extras = JsonExtras::read(&text);
+                            SessionWorker::read_json_frame(&text)
+                        }
                         Err(e) => {
                             log::error!("Invalid protocol, ending session. Error: {e}");
-                            break 'main_loop;
+                            break 'main_loop DisconnectReason::ProtocolError;
                         },
                         Ok(Message::Close {..}) => {
-                            break 'main_loop;
+                            break 'main_loop DisconnectReason::ClientClosed;
                         },
+                        Ok(Message::Ping(payload)) => {
+                            if let Err(e) = self.user_sink.send(Message::Pong(payload)).await {
+                                log::warn!("Could not answer a ping from {}: {e}", self.user.id);
+                                break 'main_loop DisconnectReason::ConnectionLost;
+                            }
+                            continue;
+                        }
+                        Ok(Message::Pong(_)) => {
+                            self.awaiting_pong = false;
+                            self.missed_pongs = 0;
+                            continue;
+                        }
                         _ => {
                             log::warn!("Unhandled message: {msg:?}");
                             continue;
                         }
                     };
 
-                    let decrypted = match SessionWorker::decrypt(&self.shared_secret, msg_bytes) {
-                        Ok(data) => data,
+                    let deserialized = match frame {
+                        Ok(client_msg) if self.user.has_session_token(client_msg.token.as_deref()) => {
+                            self.frame_strikes = 0;
+                            client_msg
+                        }
+                        Ok(_) => {
+                            log::warn!("Message from {} with a missing or wrong token", self.user.id);
+                            if let Err(reason) = self.strike(ErrorReason::InvalidToken, "Invalid session").await {
+                                break 'main_loop reason;
+                            }
+                            continue;
+                        }
                         Err(e) => {
-                            log::error!("Decryption error, ending session. Error: {e}");
-                            break 'main_loop;
+                            log::warn!(
+                                "Unreadable {size} byte frame from {}, who logged in with {:?}: {}",
+                                self.user.id,
+                                self.inbound_format,
+                                e.detail
+                            );
+                            self.stats.parse_failures += 1;
+                            if e.kind == ErrorReason::Unencrypted {
+                                self.stats.unencrypted_frames += 1;
+                            }
+                            if let Err(reason) = self.strike(e.kind, &e.reason).await {
+                                break 'main_loop reason;
+                            }
+                            continue;
                         }
                     };
 
-                    let deserialized = match SessionWorker::deserialize(decrypted) {
-                        Ok(data) => data,
-                        Err(e) => {
-                            log::error!("Deserialization error: {e}");
+                    wire_trace::decoded(&self.user.id, &deserialized);
+
+                    if let Some(claimed) = extras.protocol_version {
+                        if let Err(reason) = self.flag_version(claimed).await {
+                            break 'main_loop reason;
+                        }
+                    }
+
+                    if let Some(seq) = extras.seq {
+                        if let Err(reason) = self.follow_sequence(seq).await {
+                            break 'main_loop reason;
+                        }
+                    }
+
+                    match self.within_frame_rate().await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            self.stats.rate_limited += 1;
                             continue;
                         }
-                    };
+                        Err(reason) => break 'main_loop reason,
+                    }
 
-                    match self.handle_client_msg(deserialized).await {
+                    match self.is_retry(&deserialized, extras.msg_id.as_deref()).await {
+                        Ok(false) => {}
+                        Ok(true) => continue,
                         Err(e) => {
+                            log::debug!("Could not acknowledge a retry from {}: {e}", self.user.id);
+                            break 'main_loop DisconnectReason::ConnectionLost;
+                        }
+                    }
+
+                    if let Err(e) = self
+                        .handle_client_msg(deserialized, extras.attachment, extras.msg_id)
+                        .await { match e.downcast_ref::<DisconnectReason>() {
+                        Some(reason) => break 'main_loop *reason,
+                        None => {
                             log::error!("Failed to push user message downstream, exiting user session. Error: {e}");
-                            break 'main_loop;
-                        },
-                        _ => {}
-                    };
+                            break 'main_loop DisconnectReason::InternalError;
+                        }
+                    } };
+                }
+
+                _ = sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                    if self.idle_warned {
+                        break 'main_loop DisconnectReason::IdleTimeout;
+                    }
+                    if let Err(e) = self.warn_idle().await {
+                        log::warn!("Could not warn {} about being idle: {e}", self.user.id);
+                        break 'main_loop DisconnectReason::ConnectionLost;
+                    }
+                    if let Err(reason) = self.report_idle().await {
+                        break 'main_loop reason;
+                    }
+                }
+
+                _ = stats_report.tick() => {
+                    if let Err(reason) = self.report_stats().await {
+                        break 'main_loop reason;
+                    }
+                }
+
+                _ = keepalive.tick(), if self.keepalive.interval_secs > 0 => {
+                    if let Err(e) = self.keepalive_ping().await {
+                        log::warn!("Connection to {} is dead, ending session. Error: {e}", self.user.id);
+                        break 'main_loop DisconnectReason::KeepaliveTimeout;
+                    }
                 }
 
                 Some(event) = self.app_socket.next_event() => {
+                    let closing = match event {
+                        Event::ServerShutdown => Some(DisconnectReason::ServerShutdown),
+                        Event::Kicked => Some(DisconnectReason::Kicked),
+                        _ => None,
+                    };
                     match self.handle_event(event).await {
                         Ok(_) => {},
                         Err(e) => {
                             log::warn!("Error in SessionWorker event handler. Error: {e:?}");
-                            break 'main_loop;
+                            break 'main_loop DisconnectReason::InternalError;
                         }
                     }
+                    if let Some(reason) = closing {
+                        break 'main_loop reason;
+                    }
                 }
             }
+        };
+        self.end_session(reason).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::chat_log::MessageLog;
+    use crate::domain::clock::FixedClock;
+    use crate::domain::events::RoomSnapshot;
+    use crate::services::bounded_channel::{bounded, DropCounter, Tracked};
+    use futures_channel::mpsc::Receiver;
+    use marain_api::prelude::{ServerMsg, ServerMsgBody};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+    use tokio::time::{sleep, timeout};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    /// A session on a loopback socket. What the client writes reaches the session, what the
+    /// session sends back lands in `outbound` and its commands in `gateway`.
+    struct TestSession {
+        client: WebSocketStream<TcpStream>,
+        outbound: UnboundedReceiver<Message>,
+        gateway: Receiver<Tracked<Command>>,
+        key: SessionKey,
+        /// Gives the session back once it has ended.
+        task: JoinHandle<SessionWorker>,
+    }
+
+    impl TestSession {
+        async fn start(configure: impl FnOnce(SessionWorker) -> SessionWorker) -> Self {
+            Self::start_with_queue(16, configure).await
+        }
+
+        /// With room for `capacity` commands between the session and the App.
+        async fn start_with_queue(
+            capacity: usize,
+            configure: impl FnOnce(SessionWorker) -> SessionWorker,
+        ) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+            let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+            let (_, source) = server.split();
+
+            let key = SessionKey::from_bytes([5; 32]);
+            let mut user = User::new("id-ann".into(), "ann".into(), key.clone());
+            user.session_token = "token".into();
+            let (gateway_sink, gateway) = bounded(capacity, DropCounter::new());
+            let (user_sink, outbound) = unbounded();
+            let mut session = configure(SessionWorker::new(user, gateway_sink, user_sink, source));
+            let task = tokio::spawn(async move {
+                session.run().await.unwrap();
+                session
+            });
+            Self {
+                client,
+                outbound,
+                gateway,
+                key,
+                task,
+            }
+        }
+
+        async fn send(&mut self, frame: Message) {
+            self.client.send(frame).await.unwrap();
+        }
+
+        async fn send_sealed(&mut self, client_msg: &ClientMsg) {
+            let frame = SocketSendAdaptor::encrypt_message(
+                &self.key,
+                bincode::serialize(client_msg).unwrap(),
+            )
+            .unwrap();
+            self.send(frame).await;
+        }
+
+        /// The next command the session hands on, after the registration.
+        async fn command(&mut self) -> CommandPayload {
+            loop {
+                let Tracked { item, .. } = timeout(Duration::from_secs(5), self.gateway.next())
+                    .await
+                    .expect("no command from the session")
+                    .expect("the session hung up");
+                if !matches!(item.payload, CommandPayload::RegisterUser(_)) {
+                    return item.payload;
+                }
+            }
+        }
+
+        /// What the session hung up with, skipping whatever it sent first.
+        async fn close_frame(&mut self) -> CloseFrame<'static> {
+            loop {
+                match timeout(Duration::from_secs(5), self.outbound.next()).await {
+                    Ok(Some(Message::Close(frame))) => return frame.unwrap(),
+                    Ok(Some(_)) => continue,
+                    other => panic!("the session did not hang up: {other:?}"),
+                }
+            }
+        }
+
+        /// Where the App would send this session's events, taken from its registration.
+        async fn event_sink(&mut self) -> UnboundedSender<Event> {
+            let Tracked { item, .. } = timeout(Duration::from_secs(5), self.gateway.next())
+                .await
+                .expect("no registration from the session")
+                .unwrap();
+            let CommandPayload::RegisterUser(sink) = item.payload else {
+                panic!("the session's first command is not its registration");
+            };
+            sink
+        }
+
+        /// The next encrypted message the session sends the client.
+        async fn server_msg(&mut self) -> ServerMsg {
+            loop {
+                let frame = timeout(Duration::from_secs(5), self.outbound.next())
+                    .await
+                    .expect("nothing from the session")
+                    .expect("the session hung up");
+                if let Ok(read) = SocketSendAdaptor::read_server_msg(&self.key, frame) {
+                    return read;
+                }
+            }
+        }
+
+        /// The content of the next chat message the session sends the client.
+        async fn reply(&mut self) -> String {
+            loop {
+                if let ServerMsgBody::ChatRecv { chat_msg, .. } = self.server_msg().await.body {
+                    return chat_msg.content;
+                }
+            }
+        }
+    }
+
+    fn client_msg(contents: &str) -> ClientMsg {
+        ClientMsg {
+            token: Some("token".into()),
+            timestamp: Timestamp::from(Utc::now()),
+            body: ClientMsgBody::SendToRoom {
+                contents: contents.into(),
+            },
+        }
+    }
+
+    fn chat(contents: &str) -> Vec<u8> {
+        bincode::serialize(&client_msg(contents)).unwrap()
+    }
+
+    fn is_chat(payload: &CommandPayload, text: &str) -> bool {
+        matches!(payload, CommandPayload::RecordMessage { message, .. } if message == text)
+    }
+
+    #[tokio::test]
+    async fn bincode_and_json_frames_are_routed_alike() {
+        let mut session = TestSession::start(|session| {
+            session
+                .accept_json_frames(true)
+                .with_inbound_format(WireFormat::Json)
+        })
+        .await;
+
+        session.send_sealed(&client_msg("in bincode")).await;
+        assert!(is_chat(&session.command().await, "in bincode"));
+
+        let json = serde_json::to_string(&client_msg("in json")).unwrap();
+        session.send(Message::Text(json)).await;
+        assert!(is_chat(&session.command().await, "in json"));
+        session.task.abort();
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_are_refused_alike_in_either_format() {
+        let mut session = TestSession::start(|session| {
+            session
+                .accept_json_frames(true)
+                .with_inbound_format(WireFormat::Json)
+        })
+        .await;
+
+        let garbage = SocketSendAdaptor::encrypt_message(&session.key, vec![0xff; 40]).unwrap();
+        session.send(garbage).await;
+        let from_bincode = session.reply().await;
+        session.send(Message::Text("{\"token\":".into())).await;
+        let from_json = session.reply().await;
+
+        for refusal in [&from_bincode, &from_json] {
+            assert!(
+                refusal.starts_with("error malformed_message\n"),
+                "{refusal}"
+            );
+        }
+        session.task.abort();
+    }
+
+    fn json_refusal(text: &str) -> String {
+        match SessionWorker::read_json_frame(text) {
+            Ok(_) => panic!("{text} was read"),
+            Err(e) => e.reason,
+        }
+    }
+
+    #[test]
+    fn malformed_json_is_described_without_echoing_it() {
+        let truncated = json_refusal(r#"{"token":"hunter2","body":{"SendToRoom""#);
+        let wrong_type = json_refusal(r#"{"token":"hunter2","timestamp":"noon","body":7}"#);
+        let wrong_shape = json_refusal(r#"["hunter2", "hello"]"#);
+
+        assert_eq!(
+            truncated,
+            "Malformed JSON: end of input error at line 1 column 39"
+        );
+        assert!(
+            wrong_type.starts_with("Malformed JSON: data error at line 1"),
+            "{wrong_type}"
+        );
+        assert!(
+            wrong_shape.starts_with("Malformed JSON: data error at line 1"),
+            "{wrong_shape}"
+        );
+        for reason in [truncated, wrong_type, wrong_shape] {
+            assert!(!reason.contains("hunter2"), "{reason}");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_client_sending_garbage_is_hung_up_on_at_the_strike_limit() {
+        let mut session = TestSession::start(|session| {
+            session
+                .accept_json_frames(true)
+                .with_inbound_format(WireFormat::Json)
+                .with_max_frame_strikes(2)
+        })
+        .await;
+
+        session.send(Message::Text("{".into())).await;
+        assert!(session
+            .reply()
+            .await
+            .starts_with("error malformed_message\n"));
+        session.send(Message::Text("{".into())).await;
+
+        assert_eq!(
+            Some(session.close_frame().await),
+            DisconnectReason::TooManyBadFrames.close_frame()
+        );
+        session.task.abort();
+    }
+
+    #[tokio::test]
+    async fn a_session_whose_app_has_stopped_hangs_up_instead_of_panicking() {
+        let mut session = TestSession::start(|session| session).await;
+        let register = timeout(Duration::from_secs(5), session.gateway.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            register.item.payload,
+            CommandPayload::RegisterUser(_)
+        ));
+
+        // What the command queue looks like to a session once the App has gone.
+        session.gateway.close();
+        session.send_sealed(&client_msg("anyone?")).await;
+
+        assert_eq!(
+            Some(session.close_frame().await),
+            DisconnectReason::ServerUnavailable.close_frame()
+        );
+        timeout(Duration::from_secs(5), session.task)
+            .await
+            .expect("the session kept running")
+            .expect("the session panicked");
+    }
+
+    #[tokio::test]
+    async fn a_full_command_queue_holds_up_reading_instead_of_buffering() {
+        let mut session = TestSession::start_with_queue(1, |session| {
+            session
+                .with_frame_rate(10_000, 10_000)
+                .with_max_in_flight(100)
+        })
+        .await;
+
+        for n in 0..50 {
+            session.send_sealed(&client_msg(&format!("chat {n}"))).await;
+        }
+        sleep(Duration::from_millis(200)).await;
+        assert!(!session.task.is_finished());
+
+        for n in 0..50 {
+            assert!(is_chat(&session.command().await, &format!("chat {n}")));
+        }
+        session.task.abort();
+    }
+
+    #[tokio::test]
+    async fn a_client_past_the_in_flight_cap_is_cut_off() {
+        let mut session = TestSession::start(|session| {
+            session
+                .with_frame_rate(10_000, 10_000)
+                .with_max_in_flight(4)
+        })
+        .await;
+
+        // Nothing takes the commands off the queue, so they all stay in flight.
+        for n in 0..20 {
+            session.send_sealed(&client_msg(&format!("chat {n}"))).await;
+        }
+
+        assert_eq!(
+            Some(session.close_frame().await),
+            DisconnectReason::Flooding.close_frame()
+        );
+        let mut queued = 0;
+        while let Ok(Tracked { item, .. }) = session.gateway.try_recv() {
+            if matches!(item.payload, CommandPayload::RecordMessage { .. }) {
+                queued += 1;
+            }
         }
-        self.end_session().await;
-        return Ok(());
+        // The registration is in flight as well.
+        assert_eq!(queued, 3);
+        session.task.abort();
+    }
+
+    #[tokio::test]
+    async fn chat_is_sanitized_before_it_reaches_the_app() {
+        let mut session = TestSession::start(|session| session).await;
+
+        session
+            .send_sealed(&client_msg("\u{1b}[2Jhi\0 there"))
+            .await;
+        assert!(is_chat(&session.command().await, "[2Jhi there"));
+
+        session.send_sealed(&client_msg("\u{202E}\u{7}\0")).await;
+        let refusal = session.reply().await;
+        assert!(
+            refusal.contains("\nMessages cannot be empty\n"),
+            "{refusal}"
+        );
+        session.task.abort();
+    }
+
+    #[tokio::test]
+    async fn the_session_counts_what_the_client_sends() {
+        let mut session = TestSession::start(|session| {
+            session
+                .accept_json_frames(true)
+                .with_inbound_format(WireFormat::Json)
+                .with_frame_rate(1, 4)
+        })
+        .await;
+        let key = session.key.clone();
+        let seal = |client_msg: &ClientMsg| {
+            SocketSendAdaptor::encrypt_message(&key, bincode::serialize(client_msg).unwrap())
+                .unwrap()
+        };
+        let time = ClientMsg {
+            body: ClientMsgBody::GetTime,
+            ..client_msg("")
+        };
+        let script = vec![
+            Message::Text(serde_json::to_string(&client_msg("one")).unwrap()),
+            seal(&client_msg("two")),
+            Message::Ping(vec![1, 2, 3]),
+            seal(&time),
+            SocketSendAdaptor::encrypt_message(&key, vec![0xff; 40]).unwrap(),
+            Message::Binary(chat("in the clear")),
+            seal(&client_msg("three")),
+            seal(&client_msg("one too many")),
+        ];
+        let bytes: usize = script.iter().map(Message::len).sum();
+        for frame in script {
+            session.send(frame).await;
+        }
+        for text in ["one", "two", "three"] {
+            assert!(is_chat(&session.command().await, text));
+        }
+        session.client.close(None).await.unwrap();
+
+        let stats = timeout(Duration::from_secs(5), session.task)
+            .await
+            .expect("the session kept running")
+            .unwrap()
+            .stats;
+        assert_eq!(stats.text_frames, 1);
+        assert_eq!(stats.binary_frames, 6);
+        assert_eq!(stats.ping_frames, 1);
+        assert_eq!(stats.close_frames, 1);
+        assert_eq!(stats.bytes, bytes as u64);
+        assert_eq!(stats.parse_failures, 2);
+        assert_eq!(stats.unencrypted_frames, 1);
+        assert_eq!(stats.forwarded, 3);
+        assert_eq!(stats.answered, 1);
+        assert_eq!(stats.rate_limited, 1);
+    }
+
+    #[tokio::test]
+    async fn get_time_is_answered_by_the_session_with_the_clients_time() {
+        let mut session = TestSession::start(|session| session).await;
+        let asked = ClientMsg {
+            body: ClientMsgBody::GetTime,
+            ..client_msg("")
+        };
+        let sent: Option<DateTime<Utc>> = asked.timestamp.clone().into();
+
+        session.send_sealed(&asked).await;
+
+        let reply = session.reply().await;
+        let (server, client) = reply
+            .strip_prefix("request 1\ntime ")
+            .and_then(|times| times.split_once(" client "))
+            .unwrap_or_else(|| panic!("not a time reply: {reply}"));
+        let server: i64 = server.parse().unwrap();
+        let client: i64 = client.parse().unwrap();
+        assert_eq!(client, sent.unwrap().timestamp_millis());
+        assert!(client <= server);
+        session.task.abort();
+    }
+
+    #[tokio::test]
+    async fn room_data_is_stamped_once_from_the_sessions_clock() {
+        let at = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let mut session =
+            TestSession::start(|session| session.with_clock(Arc::new(FixedClock(at)))).await;
+        let events = session.event_sink().await;
+
+        let bob = User::new("id-bob".into(), "bob".into(), SessionKey::default());
+        let snapshot = RoomSnapshot {
+            msg_log: vec![MessageLog::from_user(&bob, "bye".into())],
+            occupant_names: vec!["ann".into()],
+            ..Default::default()
+        };
+        events
+            .unbounded_send(Event::UserLeft {
+                user: bob,
+                room: Room::lobby(),
+                snapshot,
+            })
+            .unwrap();
+
+        let read = session.server_msg().await;
+        let ServerMsgBody::RoomData {
+            query_ts,
+            notifications,
+            ..
+        } = read.body
+        else {
+            panic!("the room arrives as RoomData");
+        };
+        let stamp = |ts: Timestamp| Option::<DateTime<Utc>>::from(ts);
+        assert_eq!(stamp(read.timestamp), Some(at));
+        assert_eq!(stamp(query_ts), Some(at));
+        // The paging notice has no time of its own, so it gets the response's.
+        assert_eq!(stamp(notifications[0].timestamp.clone()), Some(at));
+        session.task.abort();
+    }
+
+    #[tokio::test]
+    async fn a_quiet_client_is_warned_and_reported_idle() {
+        let mut session = TestSession::start(|session| {
+            session.with_idle_timeout(IdleTimeout {
+                timeout_secs: 1,
+                away_timeout_secs: 1,
+                grace_secs: 60,
+            })
+        })
+        .await;
+
+        assert!(matches!(session.command().await, CommandPayload::WentIdle));
+        assert!(!session.task.is_finished());
+    }
+
+    #[tokio::test]
+    async fn a_kicked_user_is_hung_up_on_and_dropped() {
+        let mut session = TestSession::start(|session| session).await;
+        let events = session.event_sink().await;
+        events.unbounded_send(Event::Kicked).unwrap();
+
+        let frame = session.close_frame().await;
+        assert_eq!(frame.code, CloseCode::Policy);
+        assert_eq!(frame.reason, "Kicked from the server");
+        assert!(matches!(session.command().await, CommandPayload::DropUser));
+    }
+
+    #[tokio::test]
+    async fn a_client_still_quiet_after_its_warning_is_hung_up_on() {
+        let mut session = TestSession::start(|session| {
+            session.with_idle_timeout(IdleTimeout {
+                timeout_secs: 1,
+                away_timeout_secs: 1,
+                grace_secs: 0,
+            })
+        })
+        .await;
+
+        let frame = session.close_frame().await;
+        assert_eq!(frame.code, CloseCode::from(IDLE_TIMEOUT_CODE));
+        assert_eq!(frame.reason, "Disconnected for being idle");
+    }
+
+    #[tokio::test]
+    async fn a_frame_that_breaks_the_websocket_protocol_closes_the_session() {
+        let mut session = TestSession::start(|session| session).await;
+        // Clients have to mask their frames, this one isn't.
+        session
+            .client
+            .get_mut()
+            .write_all(&[0x81, 0x02, b'h', b'i'])
+            .await
+            .unwrap();
+
+        let frame = session.close_frame().await;
+        assert_eq!(frame.code, CloseCode::Protocol);
+        assert_eq!(frame.reason, "Protocol error");
+        // A broken connection may still be resumed.
+        assert!(matches!(
+            session.command().await,
+            CommandPayload::DetachUser
+        ));
+    }
+
+    #[tokio::test]
+    async fn pongs_keep_an_otherwise_quiet_connection_from_timing_out() {
+        let mut session = TestSession::start(|session| {
+            session.with_idle_timeout(IdleTimeout {
+                timeout_secs: 1,
+                away_timeout_secs: 1,
+                grace_secs: 0,
+            })
+        })
+        .await;
+
+        for _ in 0..6 {
+            session.send(Message::Pong(Vec::new())).await;
+            sleep(Duration::from_millis(300)).await;
+        }
+        assert!(
+            session.outbound.try_recv().is_err(),
+            "the session warned or closed"
+        );
+        assert!(!session.task.is_finished());
+    }
+
+    #[tokio::test]
+    async fn a_retried_message_is_acked_again_without_reaching_the_app_twice() {
+        let mut session = TestSession::start(|session| session).await;
+        let events = session.event_sink().await;
+        let mut frame = chat("hello");
+        frame.extend_from_slice(br#"{"msg_id":"m-1"}"#);
+        let sealed = SocketSendAdaptor::encrypt_message(&session.key, frame).unwrap();
+
+        session.send(sealed.clone()).await;
+        assert!(matches!(
+            session.command().await,
+            CommandPayload::RecordMessage { message, msg_id: Some(msg_id), .. }
+                if message == "hello" && msg_id == "m-1"
+        ));
+        events
+            .unbounded_send(Event::SendAck {
+                msg_id: Some("m-1".into()),
+                id: 4,
+                timestamp: Utc::now(),
+                request_id: None,
+            })
+            .unwrap();
+        assert_eq!(session.reply().await, r#"ack 4 "m-1""#);
+
+        session.send(sealed).await;
+        let retry = session.reply().await;
+        assert_eq!(retry.lines().last(), Some(r#"ack 4 "m-1""#));
+
+        // The retry went no further, the next thing the App hears is the next message.
+        session.send_sealed(&client_msg("next")).await;
+        assert!(is_chat(&session.command().await, "next"));
+    }
+
+    fn with_token(contents: &str, token: Option<&str>) -> ClientMsg {
+        ClientMsg {
+            token: token.map(str::to_string),
+            ..client_msg(contents)
+        }
+    }
+
+    #[tokio::test]
+    async fn a_message_with_the_session_token_goes_through() {
+        let mut session = TestSession::start(|session| session).await;
+        session.send_sealed(&client_msg("hello")).await;
+        assert!(is_chat(&session.command().await, "hello"));
+    }
+
+    #[tokio::test]
+    async fn a_forged_token_is_refused_and_repeated_forgeries_close_the_session() {
+        let mut session = TestSession::start(|session| session.with_max_frame_strikes(2)).await;
+
+        session
+            .send_sealed(&with_token("let me in", Some("forged")))
+            .await;
+        assert_eq!(
+            session.reply().await,
+            "error invalid_token\nInvalid session"
+        );
+        session
+            .send_sealed(&with_token("let me in", Some("forged")))
+            .await;
+
+        let frame = session.close_frame().await;
+        assert_eq!(frame.code, CloseCode::Policy);
+        assert_eq!(frame.reason, "Too many invalid messages");
+        // Nothing the forger sent reached the App.
+        assert!(matches!(session.command().await, CommandPayload::DropUser));
+    }
+
+    #[tokio::test]
+    async fn a_message_without_a_token_is_refused() {
+        let mut session = TestSession::start(|session| session).await;
+
+        session.send_sealed(&with_token("who am I", None)).await;
+        assert_eq!(
+            session.reply().await,
+            "error invalid_token\nInvalid session"
+        );
+
+        // The session carries on, a good message after it still goes through.
+        session.send_sealed(&client_msg("sorry")).await;
+        assert!(is_chat(&session.command().await, "sorry"));
+        assert!(!session.task.is_finished());
+    }
+
+    /// Whether a plaintext chat from a client that logged in with bincode gets to the App.
+    async fn plaintext_goes_through(established: bool, policy: PlaintextPolicy) -> bool {
+        let mut session = TestSession::start(|session| {
+            let session = session
+                .accept_json_frames(true)
+                .with_plaintext_policy(policy);
+            if established {
+                session.key_established()
+            } else {
+                session
+            }
+        })
+        .await;
+
+        let json = serde_json::to_string(&client_msg("in the clear")).unwrap();
+        session.send(Message::Text(json)).await;
+        // Sent after it, so whatever came of the text frame has happened by the time this
+        // arrives.
+        session.send_sealed(&client_msg("sealed")).await;
+        let first = session.command().await;
+        session.task.abort();
+        is_chat(&first, "in the clear")
+    }
+
+    #[tokio::test]
+    async fn plaintext_is_let_through_or_refused_by_phase_and_policy() {
+        let cases = [
+            (false, PlaintextPolicy::Allow, true),
+            (false, PlaintextPolicy::Reject, true),
+            (true, PlaintextPolicy::Allow, true),
+            (true, PlaintextPolicy::Warn, true),
+            (true, PlaintextPolicy::Reject, false),
+        ];
+        for (established, policy, expected) in cases {
+            assert_eq!(
+                plaintext_goes_through(established, policy).await,
+                expected,
+                "established: {established}, policy: {policy:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn the_strict_policy_refuses_plaintext_then_hangs_up() {
+        let mut session = TestSession::start(|session| {
+            session
+                .accept_json_frames(true)
+                .with_plaintext_policy(PlaintextPolicy::Reject)
+                .with_max_frame_strikes(2)
+                .key_established()
+        })
+        .await;
+        let json = serde_json::to_string(&client_msg("in the clear")).unwrap();
+
+        session.send(Message::Text(json.clone())).await;
+        assert_eq!(
+            session.reply().await,
+            "error unencrypted\nUnencrypted messages are not accepted"
+        );
+        session.send(Message::Text(json)).await;
+
+        let frame = session.close_frame().await;
+        assert_eq!(frame.code, CloseCode::Policy);
+        assert_eq!(frame.reason, "Too many invalid messages");
+    }
+
+    #[test]
+    fn a_binary_frame_carries_the_extras_after_the_message() {
+        let mut frame = chat("hello");
+        frame.extend_from_slice(
+            br#"{"seq":7,"msg_id":"m-1","protocol_version":3,"attachment":{"filename":"a.png","size":10}}"#,
+        );
+
+        let (client_msg, extras) = SessionWorker::deserialize(&frame).unwrap();
+
+        assert!(matches!(
+            client_msg.body,
+            ClientMsgBody::SendToRoom { ref contents } if contents == "hello"
+        ));
+        assert_eq!(extras.seq, Some(7));
+        assert_eq!(extras.msg_id.as_deref(), Some("m-1"));
+        assert_eq!(extras.protocol_version, Some(3));
+        let attachment = extras.attachment.unwrap();
+        assert_eq!(attachment.filename, "a.png");
+        assert_eq!(attachment.size, 10);
+    }
+
+    #[test]
+    fn a_binary_frame_without_extras_reads_as_before() {
+        let (client_msg, extras) = SessionWorker::deserialize(&chat("hello")).unwrap();
+
+        assert_eq!(client_msg.token.as_deref(), Some("token"));
+        assert!(extras.seq.is_none() && extras.msg_id.is_none());
+    }
+
+    #[test]
+    fn trailing_bytes_that_are_not_json_carry_no_extras() {
+        let mut frame = chat("hello");
+        frame.extend_from_slice(b"\x00\x01junk");
+
+        let (_, extras) = SessionWorker::deserialize(&frame).unwrap();
+
+        assert!(extras.seq.is_none() && extras.attachment.is_none());
     }
 }